@@ -1,5 +1,62 @@
-// Initialize `esp-idf`.
+// Initialize `esp-idf`, check the swarm key and bundle the game modes.
+
+use std::path::Path;
 
 fn main() {
+    #[cfg(feature = "esp-idf")]
     embuild::espidf::sysenv::output();
+
+    check_swarm_key();
+
+    if let Err(e) = bundle_game_modes() {
+        panic!("Failed to bundle the game modes in config/: {}", e);
+    }
+}
+
+/// Make sure `LEDSWARM_SWARM_KEY` holds a key of 32 hexadecimal digits, which the firmware can't be built without, so
+/// no swarm ends up with a key anyone could look up.
+fn check_swarm_key() {
+    println!("cargo:rerun-if-env-changed=LEDSWARM_SWARM_KEY");
+
+    match std::env::var("LEDSWARM_SWARM_KEY") {
+        Ok(key) => {
+            if key.len() != 32 || !key.chars().all(|c| c.is_ascii_hexdigit()) {
+                panic!("LEDSWARM_SWARM_KEY has to be 32 hexadecimal digits");
+            }
+        },
+        // Builds for the host only run the simulator and tests, which use a fixed key.
+        Err(_) if std::env::var_os("CARGO_FEATURE_ESP_IDF").is_none() => {},
+        Err(_) => panic!("Set LEDSWARM_SWARM_KEY to 32 hexadecimal digits shared by all controllers of the swarm, e.g. from `openssl rand -hex 16`"),
+    }
+}
+
+/// Convert every rule file in `config/` to compact JSON and list them in `$OUT_DIR/game_modes.rs`,
+/// so the firmware can run them without carrying a YAML parser.
+fn bundle_game_modes() -> Result<(), Box<dyn std::error::Error>> {
+    println!("cargo:rerun-if-changed=config");
+
+    let mut paths: Vec<_> = std::fs::read_dir("config")?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|extension| extension == "yml" || extension == "yaml"))
+        .collect();
+    paths.sort();
+
+    let mut entries = String::new();
+
+    for path in paths {
+        let name = path.file_stem().ok_or("Rule file without a name")?.to_string_lossy();
+        let yaml = std::fs::read_to_string(&path)?;
+        let definition: serde_yaml::Value = serde_yaml::from_str(&yaml)
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+
+        entries += &format!("    ({:?}, {:?}),\n", name, serde_json::to_string(&definition)?);
+    }
+
+    let out_dir = std::env::var("OUT_DIR")?;
+    std::fs::write(
+        Path::new(&out_dir).join("game_modes.rs"),
+        format!("/// Names and JSON definitions of the game modes in `config/`.\npub const GAME_MODES: &[(&str, &str)] = &[\n{}];\n", entries),
+    )?;
+
+    Ok(())
 }
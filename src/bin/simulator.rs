@@ -0,0 +1,175 @@
+//! Runs a swarm of simulated controllers on the host and records their modes and LED colors over time.
+//!
+//! ```text
+//! cargo run --bin simulator --no-default-features --features std,simulator -- --nodes 4 --loss 0.1 \
+//!     --send 5000:0:'{"StartRound":"last_one_standing"}' --jolt 8000:2:0.6 --record swarm.csv
+//! ```
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use ledswarm_protocol::ClientMessage;
+
+use ledswarm_firmware::controller::Command;
+use ledswarm_firmware::sim::{Input, NodeState, SimConfig, Swarm};
+
+const USAGE: &str = "\
+Usage: simulator [OPTIONS]
+
+Options:
+    --nodes N               Number of controllers in the swarm (default 3)
+    --duration-ms MS        Simulated time to run for (default 20000)
+    --latency-us US         Fixed radio latency (default 2000)
+    --jitter-us US          Maximum random extra latency (default 500)
+    --loss P                Probability between 0 and 1 that a receiver misses a frame (default 0)
+    --range-m M             Maximum radio range in meters (default 30)
+    --collisions on|off     Whether frames overlapping at a receiver are lost (default on)
+    --spacing-m M           Distance between nodes placed on a line (default 2)
+    --position X,Y          Position of the next node in meters, may be repeated
+    --stagger-ms MS         Delay between switching on consecutive nodes (default 500)
+    --seed N                Seed for latency jitter and frame loss (default 1)
+    --sample-ms MS          Interval at which node states are recorded (default 100)
+    --send MS:NODE:JSON     Deliver a JSON client message to a node, like the WebSocket API would
+    --command MS:NODE:JSON  Deliver a JSON command beyond the protocol to a node, like the WebSocket API would
+    --jolt MS:NODE:DELTA    Report an accelerometer jolt to a node
+    --reboot MS:NODE:OFF_MS Switch a node off and on again after OFF_MS, keeping its stored unique ID
+    --broken-sts NODE       Let the radio of a node fail every STS check, may be repeated
+    --antenna-delay-error N Let the antenna delay of every node deviate by up to N DW3000 time units (default 0)
+    --record FILE           Write the recorded states as CSV to a file instead of stdout
+";
+
+struct Options {
+    config: SimConfig,
+    duration_us: u64,
+    sample_us: u64,
+    inputs: Vec<(u64, usize, Input)>,
+    record: Option<String>,
+}
+
+fn parse_scheduled(value: &str) -> anyhow::Result<(u64, usize, &str)> {
+    let mut parts = value.splitn(3, ':');
+    let (Some(at_ms), Some(node), Some(rest)) = (parts.next(), parts.next(), parts.next()) else {
+        anyhow::bail!("Expected MS:NODE:VALUE, got {}", value);
+    };
+
+    Ok((at_ms.parse::<u64>()? * 1000, node.parse()?, rest))
+}
+
+fn parse_args() -> anyhow::Result<Options> {
+    let mut options = Options {
+        config: SimConfig::default(),
+        duration_us: 20_000_000,
+        sample_us: 100_000,
+        inputs: vec![],
+        record: None,
+    };
+
+    let mut args = std::env::args().skip(1);
+
+    while let Some(arg) = args.next() {
+        if arg == "--help" || arg == "-h" {
+            print!("{}", USAGE);
+            std::process::exit(0);
+        }
+
+        let Some(value) = args.next() else {
+            anyhow::bail!("Missing value for {}\n\n{}", arg, USAGE);
+        };
+
+        match arg.as_str() {
+            "--nodes" => options.config.nodes = value.parse()?,
+            "--duration-ms" => options.duration_us = value.parse::<u64>()? * 1000,
+            "--latency-us" => options.config.medium.latency_us = value.parse()?,
+            "--jitter-us" => options.config.medium.jitter_us = value.parse()?,
+            "--loss" => options.config.medium.loss = value.parse()?,
+            "--range-m" => options.config.medium.range_m = value.parse()?,
+            "--collisions" => options.config.medium.collisions = match value.as_str() {
+                "on" => true,
+                "off" => false,
+                _ => anyhow::bail!("Expected on or off, got {}", value),
+            },
+            "--spacing-m" => options.config.spacing_m = value.parse()?,
+            "--position" => {
+                let Some((x, y)) = value.split_once(',') else {
+                    anyhow::bail!("Expected X,Y, got {}", value);
+                };
+                options.config.positions.push((x.parse()?, y.parse()?));
+            },
+            "--stagger-ms" => options.config.boot_stagger_us = value.parse::<u64>()? * 1000,
+            "--seed" => options.config.seed = value.parse()?,
+            "--sample-ms" => options.sample_us = value.parse::<u64>()?.max(1) * 1000,
+            "--send" => {
+                let (at_us, node, json) = parse_scheduled(&value)?;
+                let msg: ClientMessage = serde_json::from_str(json)?;
+                options.inputs.push((at_us, node, Input::ClientMessage(msg)));
+            },
+            "--command" => {
+                let (at_us, node, json) = parse_scheduled(&value)?;
+                let command: Command = serde_json::from_str(json)?;
+                options.inputs.push((at_us, node, Input::Command(command)));
+            },
+            "--jolt" => {
+                let (at_us, node, delta) = parse_scheduled(&value)?;
+                options.inputs.push((at_us, node, Input::Jolt(delta.parse()?)));
+            },
+            "--reboot" => {
+                let (at_us, node, off_ms) = parse_scheduled(&value)?;
+                options.inputs.push((at_us, node, Input::Reboot { off_us: off_ms.parse::<u64>()? * 1000 }));
+            },
+            "--broken-sts" => options.config.broken_sts.push(value.parse()?),
+            "--antenna-delay-error" => options.config.max_antenna_delay_error = value.parse()?,
+            "--record" => options.record = Some(value),
+            _ => anyhow::bail!("Unknown option {}\n\n{}", arg, USAGE),
+        }
+    }
+
+    Ok(options)
+}
+
+fn main() -> anyhow::Result<()> {
+    let options = parse_args()?;
+
+    let mut out: Box<dyn Write> = match &options.record {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(std::io::stdout()),
+    };
+    writeln!(out, "time_ms,node,mode,red,green,blue,white")?;
+
+    let mut swarm = Swarm::new(options.config.clone());
+    for (at_us, node, input) in options.inputs {
+        swarm.schedule(at_us, node, input);
+    }
+
+    let mut last_states: Vec<Option<NodeState>> = vec![None; options.config.nodes];
+    let mut next_sample_us = 0;
+    let mut result = Ok(());
+
+    swarm.run_until(options.duration_us, |now_us, nodes| {
+        if now_us < next_sample_us || result.is_err() {
+            return;
+        }
+        next_sample_us = now_us + options.sample_us;
+
+        for (i, node) in nodes.iter().enumerate() {
+            let state = node.state();
+
+            // Only record changes to keep the output readable.
+            if last_states[i].as_ref() != Some(&state) {
+                let (r, g, b, w) = state.color;
+                result = writeln!(out, "{},{},{},{},{},{},{}", now_us / 1000, i, state.mode, r, g, b, w);
+                last_states[i] = Some(state);
+            }
+        }
+    });
+    result?;
+
+    out.flush()?;
+    eprintln!(
+        "Simulation finished, {} frame deliveries dropped, {} lost to collisions, {} inputs dropped",
+        swarm.medium.dropped,
+        swarm.medium.collisions,
+        swarm.dropped_inputs,
+    );
+
+    Ok(())
+}
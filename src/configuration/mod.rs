@@ -7,4 +7,25 @@ pub struct ControllerConfig {
     pub initial_brightness: f32,
     /// How long to wait for a Wi-Fi connection to the master node before going into server meditation.
     pub wifi_join_timeout: Duration,
+}
+
+/// How the master decides whether its clients are still around.
+#[derive(Debug, Clone)]
+pub struct LivenessConfig {
+    /// How often clients send a heartbeat to the master.
+    pub heartbeat_interval: Duration,
+    /// Time without a heartbeat after which a client is shown as stale.
+    pub stale_after: Duration,
+    /// Time without a heartbeat after which a client is considered gone and leaves the running round.
+    pub lost_after: Duration,
+}
+
+impl Default for LivenessConfig {
+    fn default() -> Self {
+        Self {
+            heartbeat_interval: Duration::from_secs(1),
+            stale_after: Duration::from_secs(3),
+            lost_after: Duration::from_secs(10),
+        }
+    }
 }
\ No newline at end of file
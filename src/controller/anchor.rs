@@ -0,0 +1,138 @@
+//! Anchors, controllers fixed at known coordinates of the venue, which all others locate themselves against.
+//!
+//! An anchor keeps its coordinates in storage and tells the master about them whenever it joins. It is part of the mesh
+//! and ranges with everyone like any other client, but it sits out every round, never opens a mesh of its own and is
+//! never elected master. The master lists the anchors in its roster, and every other controller locates itself in the
+//! venue from its smoothed distances to them at a fixed interval. The location goes to the game logic through
+//! [`Sensors::location`] and to the WebSocket clients through the events tagged [`POSITIONS`](event_bus::POSITIONS).
+//!
+//! [`Sensors::location`]: super::Sensors::location
+
+use crate::event_bus::{self, Event};
+use crate::hal::Storage;
+use crate::mesh::SessionMessage;
+use crate::radio::RadioCommand;
+use crate::ranging::positioning::{self, Anchor, Position};
+
+use super::{Controller, ControllerMode, PeerStatus, RemoteController};
+
+/// The key under which the coordinates of an anchor are kept in storage.
+const ANCHOR_KEY: &str = "anchor";
+
+/// Time between two attempts of a roaming controller to locate itself, in microseconds.
+const LOCATION_INTERVAL_US: u64 = 1_000_000;
+
+impl Controller<'_> {
+    /// Make the controller with the given mesh ID an anchor at the given coordinates as the master, or a roaming
+    /// controller again.
+    pub(super) fn assign_anchor(&mut self, id: u16, anchor: Option<Anchor>) {
+        let ControllerMode::Master { controllers, .. } = &mut self.mode else {
+            println!("Only the master can assign anchors");
+            return;
+        };
+        let Some(peer) = controllers.iter_mut().find(|c| c.id == id) else {
+            println!("Can't make controller {} an anchor, it isn't part of the mesh", id);
+            return;
+        };
+
+        peer.anchor = anchor;
+        match anchor {
+            Some(anchor) => println!("Controller {} is an anchor at ({:.2}, {:.2}) m", id, anchor.x_m, anchor.y_m),
+            None => println!("Controller {} is no anchor anymore", id),
+        }
+
+        if anchor.is_some() {
+            // Anchors don't play, so one which just became an anchor sits out the rest of the round.
+            self.leave_round(id);
+        }
+        self.send_radio_command(RadioCommand::SendReliable { dst: id, message: SessionMessage::Anchor { anchor } });
+        self.event_bus.publish(event_bus::MESH, Event::Anchor { id, anchor });
+    }
+
+    /// Become an anchor at the coordinates the master assigned, or a roaming controller again, and keep it for good.
+    pub(super) fn handle_anchor(&mut self, src: u16, anchor: Option<Anchor>) {
+        if src != 0 || !matches!(self.mode, ControllerMode::Client { .. }) {
+            return;
+        }
+
+        if anchor.is_some() {
+            if let ControllerMode::Client { game, .. } = &mut self.mode {
+                *game = None;
+            }
+            self.stop_rules();
+            self.sensors.location = None;
+        }
+
+        self.anchor = anchor;
+        if let Err(e) = self.storage.store(ANCHOR_KEY, &serde_json::to_string(&anchor).expect("Anchors always serialize")) {
+            println!("Failed to store the anchor coordinates, they will be lost on the next boot: {}", e);
+        }
+    }
+
+    /// Where the anchors of the mesh are, as far as this controller knows.
+    pub(super) fn anchor_positions(&self) -> Vec<Position> {
+        let members: &[RemoteController] = match &self.mode {
+            ControllerMode::Master { controllers, .. } => controllers,
+            ControllerMode::Client { .. } => &self.roster,
+            _ => &[],
+        };
+
+        members.iter()
+            .filter(|member| member.status != PeerStatus::Lost)
+            .filter_map(|member| member.anchor.map(|anchor| Position { id: member.id, x_m: anchor.x_m, y_m: anchor.y_m }))
+            .collect()
+    }
+
+    /// Whether the controller with the mesh ID is an anchor, as far as this controller knows.
+    pub(super) fn is_anchor(&self, id: u16) -> bool {
+        self.anchor_positions().iter().any(|anchor| anchor.id == id)
+    }
+
+    /// Locate this controller among the anchors once the interval passed, unless it is an anchor itself.
+    pub(super) fn run_location(&mut self) {
+        let now_us = self.clock.now_us();
+        if now_us.saturating_sub(self.located_us) < LOCATION_INTERVAL_US {
+            return;
+        }
+        self.located_us = now_us;
+
+        let Some(id) = self.mesh_id().filter(|_| self.anchor.is_none()) else {
+            return;
+        };
+
+        let ranges: Vec<(Position, f32)> = self.anchor_positions()
+            .into_iter()
+            .filter_map(|anchor| self.sensors.filters.get(&anchor.id).map(|filter| (anchor, filter.estimate().distance_m)))
+            .collect();
+
+        let Some(fix) = positioning::locate(id, &ranges) else {
+            self.sensors.location = None;
+            return;
+        };
+
+        self.sensors.location = Some(fix.position);
+        self.event_bus.publish(event_bus::POSITIONS, Event::Located {
+            position: fix.position,
+            residual_m: fix.residual_m,
+            anchors: ranges.len(),
+        });
+    }
+}
+
+/// Load the coordinates of this controller if it is an anchor.
+pub(super) fn load_anchor(storage: &mut dyn Storage) -> Option<Anchor> {
+    match storage.load(ANCHOR_KEY) {
+        Ok(Some(json)) => match serde_json::from_str(&json) {
+            Ok(anchor) => anchor,
+            Err(e) => {
+                println!("Ignoring stored anchor coordinates which fail to parse: {}", e);
+                None
+            },
+        },
+        Ok(None) => None,
+        Err(e) => {
+            println!("Failed to load the anchor coordinates: {}", e);
+            None
+        },
+    }
+}
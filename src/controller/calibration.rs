@@ -0,0 +1,160 @@
+//! Calibration of the antenna delays of this controller against a reference controller at a known distance.
+//!
+//! The DW3000 timestamps frames inside the chip, a board-specific delay after they arrive at the antenna and before they
+//! leave it, and ranging is only accurate if the radio knows these delays. During calibration, this controller collects
+//! measurements with a reference controller whose delays are known to be right. Whatever the measurements are off by
+//! on average is due to this board, and is split evenly between its transmit and receive delay. The spread remaining
+//! around the known distance is reported as the residual error, and the delays are stored to be used from then on.
+
+use serde::{Deserialize, Serialize};
+
+use crate::event_bus::{self, Event};
+use crate::hal::Storage;
+use crate::radio::RadioCommand;
+use crate::ranging::twr::{SPEED_OF_LIGHT_M_S, TIME_UNIT_S};
+use crate::ranging::{Measurement, RangingConfig};
+
+use super::Controller;
+
+/// The key under which the calibrated antenna delays are kept in storage.
+const ANTENNA_DELAYS_KEY: &str = "antenna_delays";
+
+/// Measurements with the reference controller the delays are derived from.
+const CALIBRATION_SAMPLES: usize = 50;
+
+/// Time after which a calibration which hasn't collected enough measurements is given up, in microseconds.
+const CALIBRATION_TIMEOUT_US: u64 = 30_000_000;
+
+/// The antenna delays of this board in DW3000 time units.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct AntennaDelays {
+    pub tx: u16,
+    pub rx: u16,
+}
+
+impl Default for AntennaDelays {
+    fn default() -> Self {
+        let ranging = RangingConfig::default();
+
+        Self {
+            tx: ranging.tx_antenna_delay,
+            rx: ranging.rx_antenna_delay,
+        }
+    }
+}
+
+/// A calibration in progress.
+pub(super) struct Calibration {
+    reference: u16,
+    distance_m: f32,
+    started_us: u64,
+    samples: Vec<f32>,
+}
+
+impl Controller<'_> {
+    /// Start collecting measurements with the reference controller with the given mesh ID, placed at the given distance.
+    pub(super) fn start_calibration(&mut self, reference: u16, distance_m: f32) {
+        println!("Calibrating the antenna delays against controller {} at {:.2} m", reference, distance_m);
+
+        self.calibration = Some(Calibration {
+            reference,
+            distance_m,
+            started_us: self.clock.now_us(),
+            samples: Vec::with_capacity(CALIBRATION_SAMPLES),
+        });
+    }
+
+    /// Count a measurement towards the running calibration, finishing it once there are enough.
+    pub(super) fn calibrate(&mut self, measurement: &Measurement) {
+        let Some(calibration) = &mut self.calibration else {
+            return;
+        };
+        if measurement.peer != calibration.reference {
+            return;
+        }
+
+        calibration.samples.push(measurement.distance_m);
+        if calibration.samples.len() < CALIBRATION_SAMPLES {
+            return;
+        }
+
+        let Some(calibration) = self.calibration.take() else {
+            return;
+        };
+        let mut samples = calibration.samples;
+        samples.sort_by(f32::total_cmp);
+        // The median keeps the odd reflection from spoiling the result.
+        let median_m = samples[samples.len() / 2];
+        let bias_m = median_m - calibration.distance_m;
+        let residual_m = (samples.iter().map(|sample| (sample - median_m).powi(2)).sum::<f32>() / samples.len() as f32).sqrt();
+
+        // Both delays of this board add up to twice the error of the time of flight.
+        let correction = (bias_m as f64 / (SPEED_OF_LIGHT_M_S * TIME_UNIT_S)).round() as i32;
+        let adjust = |delay: u16| (delay as i32 + correction).clamp(0, u16::MAX as i32) as u16;
+        let delays = AntennaDelays {
+            tx: adjust(self.antenna_delays.tx),
+            rx: adjust(self.antenna_delays.rx),
+        };
+
+        println!(
+            "Calibrated the antenna delays to {} (TX) and {} (RX), measurements were off by {:.3} m with a residual error of {:.3} m",
+            delays.tx, delays.rx, bias_m, residual_m,
+        );
+        self.set_antenna_delays(delays);
+        if let Err(e) = self.storage.store(ANTENNA_DELAYS_KEY, &serde_json::to_string(&delays).expect("Antenna delays always serialize")) {
+            println!("Failed to store the antenna delays, they will be lost on the next boot: {}", e);
+        }
+
+        self.event_bus.publish(event_bus::MESH, Event::Calibrated {
+            reference: calibration.reference,
+            bias_m,
+            residual_m,
+            tx_antenna_delay: delays.tx,
+            rx_antenna_delay: delays.rx,
+        });
+    }
+
+    /// Give up a calibration which doesn't get enough measurements, keeping the delays as they were.
+    pub(super) fn check_calibration(&mut self) {
+        let now_us = self.clock.now_us();
+        if !self.calibration.as_ref().is_some_and(|calibration| now_us - calibration.started_us >= CALIBRATION_TIMEOUT_US) {
+            return;
+        }
+        let Some(calibration) = self.calibration.take() else {
+            return;
+        };
+
+        println!(
+            "Calibration against controller {} timed out with only {} of {} measurements",
+            calibration.reference, calibration.samples.len(), CALIBRATION_SAMPLES,
+        );
+        self.event_bus.publish(event_bus::MESH, Event::CalibrationFailed {
+            reference: calibration.reference,
+            samples: calibration.samples.len(),
+        });
+    }
+
+    /// Use the given antenna delays for ranging from now on.
+    pub(super) fn set_antenna_delays(&mut self, delays: AntennaDelays) {
+        self.antenna_delays = delays;
+        self.send_radio_command(RadioCommand::SetAntennaDelays { tx: delays.tx, rx: delays.rx });
+    }
+}
+
+/// Load the antenna delays calibrated for this board, if it ever was.
+pub(super) fn load_antenna_delays(storage: &mut dyn Storage) -> Option<AntennaDelays> {
+    match storage.load(ANTENNA_DELAYS_KEY) {
+        Ok(Some(json)) => match serde_json::from_str(&json) {
+            Ok(delays) => Some(delays),
+            Err(e) => {
+                println!("Ignoring stored antenna delays which fail to parse: {}", e);
+                None
+            },
+        },
+        Ok(None) => None,
+        Err(e) => {
+            println!("Failed to load the antenna delays: {}", e);
+            None
+        },
+    }
+}
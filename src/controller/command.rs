@@ -0,0 +1,46 @@
+//! Requests from the WebSocket clients which go beyond the messages of the protocol.
+//!
+//! The server parses them from the same stream as [`ClientMessage`](ledswarm_protocol::ClientMessage)s and hands them to
+//! the controller through the channel from [`Controller::command_sender`], which applies them on its next loop iteration.
+
+use ledswarm_protocol::ClientMessage;
+use serde::{Deserialize, Serialize};
+
+use crate::radio::profile::RadioProfile;
+use crate::radio::RadioCommand;
+use crate::ranging::positioning::Anchor;
+
+use super::{Controller, ControllerMode};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Command {
+    /// Move the whole mesh to another radio profile, which only the master can do.
+    SetRadioProfile(RadioProfile),
+    /// Calibrate the antenna delays of this controller against the one with the given mesh ID, placed at the given
+    /// distance in meters.
+    CalibrateAntennaDelay { reference: u16, distance_m: f32 },
+    /// Make the controller with the given mesh ID an anchor fixed at the given coordinates in meters, or a roaming
+    /// controller again without them, which only the master can do.
+    SetAnchor { id: u16, anchor: Option<Anchor> },
+    /// Apply a message on every controller of the mesh at once, the given number of milliseconds from now, like the
+    /// start of a round after a countdown. Only the master can do this.
+    Schedule { delay_ms: u32, message: ClientMessage },
+}
+
+impl Controller<'_> {
+    pub(super) fn handle_command(&mut self, command: Command) {
+        match command {
+            Command::SetRadioProfile(profile) => match self.mode {
+                ControllerMode::ServerMeditation | ControllerMode::Master { .. } => {
+                    println!("Switching the mesh to the {:?} radio profile", profile);
+                    self.send_radio_command(RadioCommand::SwitchProfile(profile));
+                },
+                _ => println!("Only the master can switch the radio profile of the mesh"),
+            },
+            Command::CalibrateAntennaDelay { reference, distance_m } => self.start_calibration(reference, distance_m),
+            Command::SetAnchor { id: 0, .. } => println!("The master runs the mesh, so it can't be an anchor"),
+            Command::SetAnchor { id, anchor } => self.assign_anchor(id, anchor),
+            Command::Schedule { delay_ms, message } => self.schedule_client_msg(delay_ms, message),
+        }
+    }
+}
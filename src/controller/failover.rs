@@ -0,0 +1,138 @@
+//! Keeps the mesh alive when its master disappears.
+//!
+//! The master regularly broadcasts the roster of the mesh, which doubles as its keepalive. When the clients stop
+//! hearing it, they all drop the old master from their copy of the roster and elect the controller with the lowest
+//! unique ID among the rest. The elected client becomes the master under mesh ID 0, hosts the Wi-Fi network in place
+//! of the old master and keeps the mesh IDs of all other controllers, which simply carry on as its clients. Anchors are
+//! never elected, since they keep their Wi-Fi off and never run a mesh.
+
+use crate::mesh::SessionMessage;
+use crate::radio::RadioCommand;
+use crate::ranging::positioning::{Anchor, Position};
+
+use super::{Controller, ControllerMode, PeerStatus, RemoteController};
+
+/// How often the master broadcasts the roster, in microseconds.
+const ROSTER_INTERVAL_US: u64 = 1_000_000;
+
+/// Time without a roster after which the clients consider the master gone, in microseconds.
+const MASTER_TIMEOUT_US: u64 = 5_000_000;
+
+impl Controller<'_> {
+    /// Broadcast the roster as the master, and elect a new master as a client once the master went silent.
+    pub(super) fn run_failover(&mut self) {
+        let now_us = self.clock.now_us();
+
+        match &self.mode {
+            ControllerMode::Master { .. } if now_us - self.roster_sent_us >= ROSTER_INTERVAL_US => self.announce_roster(),
+            ControllerMode::Client { .. } if now_us - self.master_seen_us >= MASTER_TIMEOUT_US => self.elect(),
+            _ => {},
+        }
+    }
+
+    fn announce_roster(&mut self) {
+        let ControllerMode::Master { controllers, .. } = &self.mode else {
+            return;
+        };
+
+        // Lost controllers would only hold up the election if they were picked.
+        let members = std::iter::once((0, self.unique_id.clone()))
+            .chain(controllers.iter().filter(|c| c.status != PeerStatus::Lost).map(|c| (c.id, c.unique_id.clone())))
+            .collect();
+
+        let anchors = self.anchor_positions();
+
+        self.roster_sent_us = self.clock.now_us();
+        self.broadcast(SessionMessage::Roster { members, anchors });
+    }
+
+    /// Remember the roster of the master, or settle which master stays if another one is already around.
+    pub(super) fn handle_roster(&mut self, src: u16, members: Vec<(u16, String)>, anchors: Vec<Position>) {
+        if src != 0 {
+            return;
+        }
+
+        match &self.mode {
+            ControllerMode::Client { .. } => {
+                self.master_seen_us = self.clock.now_us();
+                self.roster = members.into_iter()
+                    .map(|(id, unique_id)| RemoteController {
+                        anchor: anchors.iter().find(|anchor| anchor.id == id).map(|anchor| Anchor { x_m: anchor.x_m, y_m: anchor.y_m }),
+                        ..RemoteController::new(unique_id, id)
+                    })
+                    .collect();
+            },
+            ControllerMode::Master { .. } => {
+                // Two masters may briefly coexist after an election, when the old one was only out of range.
+                let other = members.iter().find(|(id, _)| *id == 0).map(|(_, unique_id)| unique_id);
+
+                if other.is_some_and(|other| *other < self.unique_id) {
+                    println!("Master {} is already running the mesh, stepping down", other.unwrap());
+                    self.begin();
+                }
+            },
+            _ => {},
+        }
+    }
+
+    /// Give up on the silent master and let the client with the lowest unique ID take over.
+    fn elect(&mut self) {
+        println!("Lost the master, electing a new one");
+
+        // Whoever was expected to run the mesh under ID 0 did not show up.
+        self.roster.retain(|member| member.id != 0);
+        self.master_seen_us = self.clock.now_us();
+
+        // Rounds can't continue without the master keeping score.
+        if let ControllerMode::Client { game, .. } = &mut self.mode {
+            *game = None;
+        }
+        self.stop_rules();
+        self.celebration = None;
+
+        let candidates = self.roster.iter_mut().filter(|member| member.anchor.is_none());
+        let Some(elected) = candidates.min_by(|a, b| a.unique_id.cmp(&b.unique_id)) else {
+            println!("Nobody left to take over, looking for a mesh again");
+            self.begin();
+            return;
+        };
+
+        if elected.unique_id == self.unique_id {
+            self.promote();
+        } else {
+            println!("Waiting for controller {} to take over", elected.id);
+            elected.id = 0;
+        }
+    }
+
+    /// Become the master of the mesh, adopting the other controllers with their mesh IDs.
+    fn promote(&mut self) {
+        let own_id = self.mesh_id().unwrap_or_default();
+        let now_us = self.clock.now_us();
+        let controllers: Vec<RemoteController> = self.roster.drain(..)
+            .filter(|member| member.unique_id != self.unique_id)
+            .map(|member| RemoteController { last_seen_us: now_us, ..member })
+            .collect();
+        // New controllers must not get the ID of anyone in the mesh, including the one this controller leaves behind.
+        let id_counter = controllers.iter().map(|c| c.id).chain(std::iter::once(own_id)).max().unwrap_or_default();
+
+        println!("Taking over as master of {} controllers", controllers.len());
+        let peers = controllers.iter().map(|c| c.id).collect();
+        self.mode = ControllerMode::Master {
+            controllers,
+            id_counter,
+            game: None,
+        };
+        self.send_radio_command(RadioCommand::SetNodeId(Some(0)));
+        self.send_radio_command(RadioCommand::SetPeers(peers));
+        self.send_radio_command(RadioCommand::PublishSchedule(true));
+
+        if let Some(wifi) = self.wifi.as_mut() {
+            if let Err(e) = wifi.take_over_network() {
+                println!("Failed to take over the Wi-Fi network: {}", e);
+            }
+        }
+
+        self.announce_roster();
+    }
+}
@@ -0,0 +1,148 @@
+//! Keeps track of which clients are still around.
+//!
+//! Clients send a heartbeat to the master at a fixed interval, along with their battery level. The master remembers
+//! when it last heard from each of them and how many of the expected heartbeats arrived. A client which misses its
+//! heartbeats is first marked as stale and eventually as lost, which takes it out of the running round and the radio
+//! schedule. The WebSocket clients are told about all of this through the events tagged [`MESH`](event_bus::MESH).
+
+use crate::event_bus::{self, Event};
+use crate::mesh::SessionMessage;
+use crate::radio::RadioCommand;
+
+use super::{Controller, ControllerMode, GameState, PeerStatus};
+
+/// How much a single heartbeat moves the link quality of a client.
+const LINK_QUALITY_WEIGHT: f32 = 0.2;
+
+impl Controller<'_> {
+    /// Send the heartbeat as a client, and check on the clients as the master.
+    pub(super) fn run_liveness(&mut self) {
+        let now_us = self.clock.now_us();
+
+        match &self.mode {
+            ControllerMode::Client { .. } if now_us - self.heartbeat_sent_us >= self.liveness.heartbeat_interval.as_micros() as u64 => {
+                self.send_heartbeat();
+            },
+            ControllerMode::Master { .. } => self.check_peers(),
+            _ => {},
+        }
+    }
+
+    fn send_heartbeat(&mut self) {
+        self.heartbeat_sent_us = self.clock.now_us();
+
+        let battery_percent = self.battery.as_mut().and_then(|battery| match battery.percent() {
+            Ok(percent) => Some(percent),
+            Err(e) => {
+                println!("Failed to read the battery level: {}", e);
+                None
+            },
+        });
+
+        let distances = self.distance_report();
+        self.unicast(0, SessionMessage::Heartbeat { battery_percent, distances });
+    }
+
+    /// Update the liveness of a client on the master from its heartbeat.
+    pub(super) fn handle_heartbeat(&mut self, src: u16, battery_percent: Option<u8>, distances: &[(u16, f32)]) {
+        let now_us = self.clock.now_us();
+        let interval_us = self.liveness.heartbeat_interval.as_micros().max(1) as f32;
+
+        let ControllerMode::Master { controllers, .. } = &mut self.mode else {
+            return;
+        };
+        let Some(peer) = controllers.iter_mut().find(|c| c.id == src) else {
+            return;
+        };
+
+        // Every interval which passed since the last heartbeat should have brought one.
+        let expected = ((now_us.saturating_sub(peer.last_seen_us) as f32 / interval_us).round() as u32).max(1);
+        peer.link_quality += LINK_QUALITY_WEIGHT * (1.0 / expected as f32 - peer.link_quality);
+        peer.battery_percent = battery_percent;
+
+        let event = Event::Heartbeat {
+            id: src,
+            link_quality: peer.link_quality,
+            battery_percent,
+        };
+
+        self.mark_seen(src, false);
+        self.record_distances(src, distances);
+        self.event_bus.publish(event_bus::MESH, event);
+    }
+
+    /// Note that the client with the mesh ID is around, announcing it if it just joined or was lost before.
+    pub(super) fn mark_seen(&mut self, id: u16, joined: bool) {
+        let now_us = self.clock.now_us();
+
+        let ControllerMode::Master { controllers, .. } = &mut self.mode else {
+            return;
+        };
+        let Some(peer) = controllers.iter_mut().find(|c| c.id == id) else {
+            return;
+        };
+
+        let returned = peer.status == PeerStatus::Lost;
+        peer.last_seen_us = now_us;
+        peer.status = PeerStatus::Online;
+        let unique_id = peer.unique_id.clone();
+
+        if joined || returned {
+            println!("Controller {} is in the mesh", id);
+            self.send_radio_command(RadioCommand::SetPeers(vec![id]));
+            self.event_bus.publish(event_bus::MESH, Event::Joined { id, unique_id });
+        }
+    }
+
+    /// Mark clients whose heartbeats stopped as stale or lost.
+    fn check_peers(&mut self) {
+        let now_us = self.clock.now_us();
+        let stale_after_us = self.liveness.stale_after.as_micros() as u64;
+        let lost_after_us = self.liveness.lost_after.as_micros() as u64;
+
+        let ControllerMode::Master { controllers, .. } = &mut self.mode else {
+            return;
+        };
+
+        let mut stale = vec![];
+        let mut lost = vec![];
+
+        for peer in controllers.iter_mut() {
+            let silent_us = now_us.saturating_sub(peer.last_seen_us);
+
+            match peer.status {
+                PeerStatus::Online | PeerStatus::Stale if silent_us >= lost_after_us => {
+                    peer.status = PeerStatus::Lost;
+                    lost.push(peer.id);
+                },
+                PeerStatus::Online if silent_us >= stale_after_us => {
+                    peer.status = PeerStatus::Stale;
+                    stale.push(peer.id);
+                },
+                _ => {},
+            }
+        }
+
+        for id in stale {
+            println!("Controller {} missed its heartbeats", id);
+            self.event_bus.publish(event_bus::MESH, Event::Stale { id });
+        }
+
+        for id in lost {
+            println!("Controller {} left the mesh", id);
+            self.send_radio_command(RadioCommand::RemovePeer(id));
+            self.event_bus.publish(event_bus::MESH, Event::Left { id });
+            self.leave_round(id);
+        }
+    }
+
+    /// Take a controller which left the mesh out of the running round.
+    pub(super) fn leave_round(&mut self, id: u16) {
+        match &self.mode {
+            // Leaving counts as being knocked out.
+            ControllerMode::Master { game: Some(GameState::LastOneStanding { .. }), .. } => self.eliminate(id),
+            ControllerMode::Master { game: Some(GameState::Territory { .. }), .. } => self.leave_territory(id),
+            _ => {},
+        }
+    }
+}
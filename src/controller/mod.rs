@@ -3,36 +3,85 @@
 use std::sync::mpsc;
 use std::time::{Instant, /*Duration*/};
 use std::sync::Arc;
-use std::collections::HashMap;
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashMap};
 
 use colored::Colorize;
-use esp_idf_svc::{
-    sys::EspError,
-    wifi::{EspWifi, AsyncWifi},
-};
 use nanoid::nanoid;
 
 use crate::led::{Led, LedConfig};
-use crate::network::wifi::WifiController;
-use crate::event_bus::EventBus;
+use crate::led::blink::LedTimeline;
+use crate::configuration::LivenessConfig;
+use crate::hal::{BatteryGauge, Clock, LedSink, Storage, UwbTransport, WifiManager};
+use crate::event_bus::{self, Event, EventBus};
+use crate::mesh::{self, Resume, SessionMessage, BROADCAST};
+use crate::radio::diagnostics::LinkStats;
+use crate::radio::profile::RadioProfile;
+use crate::radio::sync::NetworkTime;
+use crate::radio::tdma;
+use crate::radio::{RadioCommand, RadioEvent};
+use crate::ranging::Measurement;
+use crate::ranging::filter::{DistanceEstimate, DistanceFilter};
+use crate::ranging::positioning::{Anchor, Position};
+use crate::rules::{self, Action, GameDefinition, Inputs, RuleEngine};
 
 use ledswarm_protocol::{ClientMessage, ControllerMessage, Frame, FramePayload, GameMode, InternalMessage};
 
+mod anchor;
+mod calibration;
+mod command;
+mod failover;
+mod liveness;
+mod positioning;
+mod schedule;
+mod sync;
+mod territory;
+#[cfg(test)]
+mod tests;
+
+pub use calibration::AntennaDelays;
+pub use command::Command;
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct RemoteController {
+    /// The persistent ID the controller generated on its first boot.
     pub unique_id: String,
     pub id: u16,
+    /// When the master last heard from the controller.
+    pub last_seen_us: u64,
+    /// Moving average of the share of expected heartbeats which arrived, between 0 and 1.
+    pub link_quality: f32,
+    /// The battery level the controller reported with its last heartbeat, if it can measure it.
+    pub battery_percent: Option<u8>,
+    pub status: PeerStatus,
+    /// Where the controller is fixed in the venue, if it is an anchor.
+    pub anchor: Option<Anchor>,
 }
 
 impl RemoteController {
-    pub fn new(id: u16) -> Self {
+    pub fn new(unique_id: String, id: u16) -> Self {
         Self {
-            unique_id: nanoid!(10),
+            unique_id,
             id,
+            last_seen_us: 0,
+            link_quality: 1.0,
+            battery_percent: None,
+            status: PeerStatus::Online,
+            anchor: None,
         }
     }
 }
 
+/// Whether the master still hears the heartbeats of a client.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PeerStatus {
+    Online,
+    /// The client missed its recent heartbeats.
+    Stale,
+    /// The client has been silent for so long that it left the mesh.
+    Lost,
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum ControllerMode {
     /// The controller is currently trying to find nearby devices to build a mesh with.
@@ -73,6 +122,13 @@ pub enum GameState {
         /// The IDs of the controllers that have been eliminated from the round.
         exited_controller_ids: Vec<usize>,
     },
+    Territory {
+        /// The team of every controller in the round, keyed by mesh ID.
+        teams: BTreeMap<u16, u8>,
+        ends_us: u64,
+        /// When the state of the round was last broadcast to the clients.
+        announced_us: u64,
+    },
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -80,37 +136,154 @@ pub enum ClientGameState {
     LastOneStanding {
         /// Whether the client is currently active in the round.
         is_active: bool,
+        /// When the client last told the master that it is out, which it repeats until the round is over.
+        reported_us: Option<u64>,
     },
+    Territory {
+        /// The team the client currently belongs to.
+        team: u8,
+        ends_us: u64,
+    },
+}
+
+/// The end of a round, shown on the LEDs before the controller goes back to the lobby.
+struct Celebration {
+    until_us: u64,
+    /// The result of the round, which the master keeps announcing until the animation ends.
+    announcement: Option<SessionMessage>,
+    announced_us: u64,
+    timeline: LedTimeline,
 }
 
+/// Accelerometer jolt above which a player is out in Last One Standing.
+const DELTA_THRESHOLD: f32 = 0.4;
+
+/// Time after which a controller still looking for a mesh asks for a master again, in microseconds.
+const JOIN_RETRY_US: u64 = 2_000_000;
+
+/// How often an eliminated client repeats its report until the master ends the round, in microseconds.
+const ELIMINATION_REPORT_INTERVAL_US: u64 = 1_000_000;
+
+/// The key under which the unique ID of the controller is kept in storage.
+pub const UNIQUE_ID_KEY: &str = "unique_id";
+
+/// How long the win or lose animation plays before returning to the lobby, in microseconds.
+const CELEBRATION_US: u64 = 5_000_000;
+
+/// How often the master repeats the winner during the animation, in case a client missed it, in microseconds.
+const ROUND_OVER_REPEAT_US: u64 = 1_000_000;
+
 pub struct Controller<'a> {
     pub mode: ControllerMode,
+    /// The persistent ID of this controller, which lets the master recognize it when it rejoins after a reboot.
+    pub unique_id: String,
     pub connected_controllers: Vec<RemoteController>,
     /// Maps message types to pieces of code which then perform the desired behavior on the controller.
     pub messagelets: HashMap<String, &'static dyn Messagelet>,
+    /// Maps method names used in rule files to the actions implementing them.
+    pub actions: HashMap<String, &'static dyn Action>,
+    /// The LED color chosen by the actions of the game mode running on the rule engine.
+    pub game_color: Option<(u8, u8, u8, u8)>,
     pub sensors: Sensors,
     pub event_bus: Arc<EventBus>,
+    /// Timeouts of the heartbeats between the clients and the master.
+    pub liveness: LivenessConfig,
+    /// The radio profile this controller looks for a mesh on first, and opens its own mesh on.
+    pub radio_profile: RadioProfile,
+    /// The antenna delays the radio of this board ranges with, as last calibrated.
+    pub antenna_delays: AntennaDelays,
+    /// Where this controller is fixed in the venue, if it is an anchor which never plays.
+    pub anchor: Option<Anchor>,
     rx: mpsc::Receiver<ControllerMode>,
     tx: mpsc::Sender<ControllerMode>,
     msg_rx: flume::Receiver<InternalMessage>,
-    uwb_out_tx: flume::Sender<Frame>,
+    commands_rx: flume::Receiver<Command>,
+    commands_tx: flume::Sender<Command>,
+    uwb: Box<dyn UwbTransport>,
     pub start_time: Instant,
-    wifi: Option<WifiController<'a>>,
+    wifi: Option<Box<dyn WifiManager + 'a>>,
+    battery: Option<Box<dyn BatteryGauge + 'a>>,
     led:  Led,
+    clock: Box<dyn Clock>,
+    storage: Box<dyn Storage + 'a>,
+    /// When the last join request was sent, so it can be repeated if it got lost.
+    join_requested_us: u64,
+    /// The game mode currently running on the rule engine, if any.
+    rules: Option<RuleEngine>,
+    celebration: Option<Celebration>,
+    calibration: Option<calibration::Calibration>,
+    /// The members of the mesh as last announced by the master, which the clients elect a new master from.
+    roster: Vec<RemoteController>,
+    /// When a client last heard from the master.
+    master_seen_us: u64,
+    /// When the master last broadcast the roster.
+    roster_sent_us: u64,
+    /// When a client last sent its heartbeat.
+    heartbeat_sent_us: u64,
+    /// The latest distances between the controllers of the mesh, which the master places them from.
+    distance_table: positioning::DistanceTable,
+    /// When the master last placed the controllers of the mesh.
+    positioned_us: u64,
+    /// When this controller last tried to locate itself among the anchors.
+    located_us: u64,
+    /// The nonce of the session this controller belongs to, which the master hands to every controller it welcomes.
+    session_nonce: Option<[u8; 16]>,
+    /// The network time the radio last reported, which the controller runs on from with its own clock.
+    network_time: Option<NetworkTime>,
+    /// The latest network time handed out, which it never goes below unless it moved back a long way.
+    network_floor_us: Cell<u64>,
+    /// What this controller does at a later network time, in the order it is due.
+    scheduled: Vec<schedule::Scheduled>,
 }
 
 pub struct Sensors {
     /// The current average jolt (change of acceleration) experienced by the controller enclosure,
     /// a moving average of recent vector sums of the X, Y and Z readings from the accelerometer.
     pub accelerometer_jolt: f32,
+    /// The latest UWB ranging result for each peer, keyed by its mesh ID.
+    pub distances: HashMap<u16, Measurement>,
+    /// The smoothed distance to each peer, keyed by its mesh ID.
+    pub filters: HashMap<u16, DistanceFilter>,
+    /// Where each controller of the mesh is relative to the master, keyed by its mesh ID, which only the master knows.
+    pub positions: HashMap<u16, Position>,
+    /// Where this controller is in the venue, if it has distances to enough anchors.
+    pub location: Option<Position>,
+    /// The signal quality of the frames recently received from each peer, keyed by its mesh ID.
+    pub links: HashMap<u16, LinkStats>,
+    /// The last known LED color of each peer, keyed by its mesh ID.
+    pub peer_colors: HashMap<u16, (u8, u8, u8, u8)>,
 }
 
 impl Sensors {
     pub fn new() -> Self {
         Self {
             accelerometer_jolt: 0.0,
+            distances: HashMap::new(),
+            filters: HashMap::new(),
+            positions: HashMap::new(),
+            location: None,
+            links: HashMap::new(),
+            peer_colors: HashMap::new(),
         }
     }
+
+    /// The smoothed distance to the closest peer, if any peer has been ranged yet.
+    pub fn closest_peer(&self) -> Option<DistanceEstimate> {
+        self.filters.values().map(DistanceFilter::estimate).min_by(|a, b| a.distance_m.total_cmp(&b.distance_m))
+    }
+
+    /// Fold a ranging result into the smoothed distance to its peer.
+    fn add_measurement(&mut self, measurement: Measurement, now_us: u64) {
+        match self.filters.get_mut(&measurement.peer) {
+            Some(filter) => {
+                filter.update(&measurement, now_us);
+            },
+            None => {
+                self.filters.insert(measurement.peer, DistanceFilter::new(&measurement, now_us));
+            },
+        }
+        self.distances.insert(measurement.peer, measurement);
+    }
 }
 
 /// A piece of code responsible for executing a specific behavior on the controller, depending on its mode and state.
@@ -123,46 +296,509 @@ pub trait Messagelet {
 }
 
 impl<'a> Controller<'a> {
-    pub fn new(msg_rx: flume::Receiver<InternalMessage>, uwb_out_tx: flume::Sender<Frame>) -> Self {
+    pub fn new(
+        msg_rx: flume::Receiver<InternalMessage>,
+        uwb:    Box<dyn UwbTransport>,
+        led:    Box<dyn LedSink>,
+        clock:  Box<dyn Clock>,
+        mut storage: Box<dyn Storage + 'a>,
+    ) -> Self {
         let (tx, rx): (mpsc::Sender<ControllerMode>, mpsc::Receiver<ControllerMode>) = mpsc::channel();
+        let (commands_tx, commands_rx) = flume::bounded(16);
 
-        Self {
+        let mut controller = Self {
             mode:       ControllerMode::Discovery,
+            unique_id:  load_unique_id(storage.as_mut()),
+            anchor:     anchor::load_anchor(storage.as_mut()),
             messagelets: HashMap::new(),
+            actions:    HashMap::new(),
+            game_color: None,
             connected_controllers: vec![],
             sensors:    Sensors::new(),
             event_bus:  Arc::new(EventBus::new()),
+            liveness:   LivenessConfig::default(),
+            radio_profile: RadioProfile::default(),
+            antenna_delays: AntennaDelays::default(),
             rx,
             tx,
             msg_rx,
-            uwb_out_tx,
+            commands_rx,
+            commands_tx,
+            uwb,
             start_time: Instant::now(),
             wifi:       None,
-            led:        Led::new(LedConfig { pin: 0, intensity: 0.3 }),
+            battery:    None,
+            led:        Led::new(LedConfig { intensity: 0.3 }, led),
+            clock,
+            storage,
+            join_requested_us: 0,
+            rules:      None,
+            celebration: None,
+            calibration: None,
+            roster:     vec![],
+            master_seen_us: 0,
+            roster_sent_us: 0,
+            heartbeat_sent_us: 0,
+            distance_table: HashMap::new(),
+            positioned_us: 0,
+            located_us: 0,
+            session_nonce: None,
+            network_time: None,
+            network_floor_us: Cell::new(0),
+            scheduled: vec![],
+        };
+        rules::actions::register_builtin(&mut controller);
+        if let Some(delays) = calibration::load_antenna_delays(controller.storage.as_mut()) {
+            controller.set_antenna_delays(delays);
         }
+
+        controller
     }
 
-    pub async fn init_wifi(
+    /// A sender for mode changes requested by the peripherals, which are applied on the next loop iteration.
+    pub fn mode_sender(&self) -> mpsc::Sender<ControllerMode> {
+        self.tx.clone()
+    }
+
+    /// A sender for requests of the WebSocket clients beyond the protocol, which are applied on the next loop iteration.
+    pub fn command_sender(&self) -> flume::Sender<Command> {
+        self.commands_tx.clone()
+    }
+
+    /// Make an action available to rule files under the given method name.
+    pub fn register_action(&mut self, name: &str, action: &'static dyn Action) {
+        self.actions.insert(name.to_string(), action);
+    }
+
+    /// Run a game mode defined as data, replacing the one running before.
+    pub fn start_rules(&mut self, definition: GameDefinition) -> Result<(), String> {
+        for action in &definition.actions {
+            if !self.actions.contains_key(&action.on_success.method) {
+                return Err(format!("Action {} uses unknown method {}", action.name, action.on_success.method));
+            }
+        }
+
+        println!("Starting rule-based game mode {}", definition.name);
+        self.rules = Some(RuleEngine::new(definition)?);
+        self.game_color = None;
+
+        Ok(())
+    }
+
+    /// Stop the game mode running on the rule engine.
+    pub fn stop_rules(&mut self) {
+        self.rules = None;
+        self.game_color = None;
+    }
+
+    /// Evaluate the rules of the running game mode and execute the actions they trigger.
+    fn run_rules(&mut self) {
+        let Some(engine) = self.rules.as_mut() else {
+            return;
+        };
+
+        let inputs = Inputs {
+            jolt: self.sensors.accelerometer_jolt,
+            closest: self.sensors.closest_peer(),
+        };
+
+        for invocation in engine.tick(&inputs) {
+            let Some(action) = self.actions.get(&invocation.method).copied() else {
+                println!("Rules invoked unknown action {}", invocation.method);
+                continue;
+            };
+
+            if let Err(e) = action.execute(self, &invocation.parameters) {
+                println!("Action {} failed: {}", invocation.method, e);
+            }
+        }
+    }
+
+    pub fn init_wifi(
         &mut self,
-        wifi: AsyncWifi<EspWifi<'a>>,
-    ) -> Result<(), EspError> {
-        println!("Starting controller Wi-Fi");
-        let mut wifi_controller = WifiController::new(
-            wifi,
-            self.tx.clone(),
-        );
+        mut wifi: Box<dyn WifiManager + 'a>,
+    ) -> anyhow::Result<()> {
+        // Anchors never run a mesh, so they must not open a network either.
+        if self.anchor.is_some() {
+            println!("Leaving the Wi-Fi off, anchors only take part in the UWB mesh");
+            return Ok(());
+        }
 
-        wifi_controller.join_or_create_network().await?;
-        self.wifi = Some(wifi_controller);
+        println!("Starting controller Wi-Fi");
+        wifi.join_or_create_network()?;
+        self.wifi = Some(wifi);
 
         Ok(())
     }
 
-    fn send_uwb_frame(&mut self, frame: Frame) {
-        self.uwb_out_tx.try_send(frame).unwrap();
+    /// Report the battery level with the heartbeats from now on.
+    pub fn init_battery(&mut self, battery: Box<dyn BatteryGauge + 'a>) {
+        self.battery = Some(battery);
     }
 
-    fn handle_client_msg(&mut self, msg: ClientMessage) {
+    fn send_radio_command(&mut self, command: RadioCommand) {
+        if let Err(e) = self.uwb.command(command) {
+            println!("{}", e);
+        }
+    }
+
+    /// Send a message to the controller with the given mesh ID, which the radios of all others ignore.
+    fn unicast(&mut self, dst: u16, message: SessionMessage) {
+        self.send_radio_command(RadioCommand::Send { dst, message });
+    }
+
+    /// Send a message to every controller in the session.
+    fn broadcast(&mut self, message: SessionMessage) {
+        self.send_radio_command(RadioCommand::Send { dst: BROADCAST, message });
+    }
+
+    fn handle_radio_event(&mut self, event: RadioEvent) {
+        match event {
+            RadioEvent::Distance(measurement) => {
+                self.calibrate(&measurement);
+                self.sensors.add_measurement(measurement, self.clock.now_us());
+            },
+            RadioEvent::Received { src, message } => self.handle_session_msg(src, message),
+            RadioEvent::Delivered { dst, message } => self.handle_delivery(dst, message, true),
+            RadioEvent::Undelivered { dst, message } => self.handle_delivery(dst, message, false),
+            RadioEvent::Incomplete { src, received, count } => {
+                println!("Dropped a message from controller {}, only {} of its {} fragments arrived", src, received, count);
+            },
+            RadioEvent::Rejected { src, reason } => {
+                println!("Rejected a message from controller {}: {}", src, reason);
+            },
+            RadioEvent::StsFailed => self.handle_sts_failure(),
+            RadioEvent::ProfileChanged(profile) => {
+                println!("Radio switched to the {:?} profile", profile);
+                self.event_bus.publish(event_bus::MESH, Event::RadioProfile { profile });
+            },
+            RadioEvent::Links(links) => {
+                self.sensors.links = links.iter().map(|link| (link.peer, link.clone())).collect();
+                self.event_bus.publish(event_bus::LINKS, Event::Links { links });
+            },
+            RadioEvent::NetworkTime(time) => self.handle_network_time(time),
+        }
+    }
+
+    /// Keep track of which controllers got a reliable message, taking the ones which missed a round start out of it.
+    fn handle_delivery(&mut self, dst: u16, message: SessionMessage, delivered: bool) {
+        // A scheduled round only starts at its time, so the outcome waits for it.
+        if let SessionMessage::StartRound { at_us: Some(at_us), .. } | SessionMessage::Territory { at_us: Some(at_us), .. } = message {
+            if at_us > self.network_time_us() {
+                self.schedule(at_us, schedule::Task::Delivery { dst, message, delivered });
+                return;
+            }
+        }
+
+        // Anchors get the start of every round too, but sit it out.
+        let starts_round = matches!(message, SessionMessage::StartRound { .. } | SessionMessage::Territory { .. }) && !self.is_anchor(dst);
+        let in_round = matches!(self.mode, ControllerMode::Master { game: Some(_), .. });
+
+        match (starts_round && in_round, delivered) {
+            (true, true) => {
+                println!("Controller {} started the round", dst);
+                self.event_bus.publish(event_bus::GAME, Event::RoundStarted { id: dst });
+            },
+            (true, false) => {
+                println!("Controller {} never got the start of the round, leaving it out", dst);
+                self.event_bus.publish(event_bus::GAME, Event::RoundMissed { id: dst });
+                self.leave_round(dst);
+            },
+            (false, true) => {},
+            (false, false) => println!("Controller {} never got {:?}", dst, message),
+        }
+    }
+
+    fn handle_session_msg(&mut self, src: u16, msg: SessionMessage) {
+        match msg {
+            SessionMessage::Join { unique_id, anchor } => {
+                self.admit(unique_id, anchor);
+            },
+            SessionMessage::Welcome { unique_id, assigned_id, resume, session_nonce } => {
+                self.handle_welcome(unique_id, assigned_id, resume, session_nonce)
+            },
+
+            SessionMessage::Roster { members, anchors } => self.handle_roster(src, members, anchors),
+            SessionMessage::Heartbeat { battery_percent, distances } => self.handle_heartbeat(src, battery_percent, &distances),
+            SessionMessage::StsFailed => {
+                if matches!(self.mode, ControllerMode::Master { .. }) {
+                    println!("Controller {} can't sync to the scrambled timestamp sequences, letting it range without", src);
+                    self.send_radio_command(RadioCommand::DisableSts(src));
+                }
+            },
+
+            SessionMessage::Anchor { anchor } => self.handle_anchor(src, anchor),
+
+            SessionMessage::Brightness { brightness, at_us } => {
+                if src == 0 {
+                    self.handle_master_msg(ClientMessage::SetBrightness(brightness), at_us);
+                }
+            },
+            SessionMessage::StartRound { game, at_us } => {
+                if src == 0 && self.anchor.is_none() {
+                    self.handle_master_msg(ClientMessage::StartRound(game), at_us);
+                }
+            },
+            SessionMessage::Eliminated => self.eliminate(src),
+
+            SessionMessage::RoundOver { winner } => {
+                // The master repeats the winner, so only the first announcement ends the round.
+                if src == 0 {
+                    if let ControllerMode::Client { game: game @ Some(ClientGameState::LastOneStanding { .. }), .. } = &mut self.mode {
+                        *game = None;
+                        let won = winner.is_some() && winner == self.mesh_id();
+                        self.celebrate(if won { LedTimeline::victory() } else { LedTimeline::defeat() }, None);
+                    }
+                }
+            },
+
+            SessionMessage::Territory { teams, remaining_ms, at_us } => {
+                if src == 0 {
+                    match at_us {
+                        Some(at_us) => self.schedule(at_us, schedule::Task::Territory { teams, remaining_ms }),
+                        None => self.handle_territory_state(teams, remaining_ms),
+                    }
+                }
+            },
+            SessionMessage::Team { id, team } => self.handle_team_change(src, id, team),
+            SessionMessage::TerritoryOver { winner } => self.handle_territory_over(src, winner),
+        }
+    }
+
+    /// Fall back to ranging without scrambled timestamp sequences, which the master has to allow for the whole mesh.
+    fn handle_sts_failure(&mut self) {
+        println!("The radio can't sync to the scrambled timestamp sequences of the mesh");
+
+        match self.mode {
+            ControllerMode::ServerMeditation | ControllerMode::Master { .. } => self.send_radio_command(RadioCommand::DisableSts(0)),
+            ControllerMode::Client { .. } => {
+                self.send_radio_command(RadioCommand::SendReliable { dst: 0, message: SessionMessage::StsFailed });
+            },
+            _ => {},
+        }
+    }
+
+    /// Let a controller join the mesh on the master, handing out its old mesh ID again if it is rejoining.
+    fn admit(&mut self, unique_id: String, anchor: Option<Anchor>) -> Option<u16> {
+        let (assigned_id, is_new) = match &mut self.mode {
+            ControllerMode::ServerMeditation => {
+                // This is the first controller joining the master, set the assigned ID to 1 and the ID counter to 2.
+                println!("Switching to Master mode");
+                self.mode = ControllerMode::Master {
+                    controllers: vec![RemoteController { anchor, ..RemoteController::new(unique_id.clone(), 1) }],
+                    id_counter: 2,
+                    game: None,
+                };
+                self.send_radio_command(RadioCommand::SetNodeId(Some(0)));
+                (1, true)
+            },
+            ControllerMode::Master { controllers, id_counter, .. } => {
+                if let Some(known) = controllers.iter_mut().find(|c| c.unique_id == unique_id) {
+                    // Joins are repeated until the welcome arrives, and rebooted controllers come back with the same ID.
+                    println!("Controller {} rejoined as {}", unique_id, known.id);
+                    known.anchor = anchor;
+                    (known.id, false)
+                } else if controllers.len() + 1 >= tdma::MAX_MEMBERS {
+                    println!("Turning away controller {}, the mesh is full with {} controllers", unique_id, tdma::MAX_MEMBERS);
+                    return None;
+                } else {
+                    // Use the incremental nature of the counter to assign new IDs to joining controllers.
+                    println!("Adding new controller {} to mesh", unique_id);
+                    *id_counter += 1;
+                    controllers.push(RemoteController { anchor, ..RemoteController::new(unique_id.clone(), *id_counter) });
+                    (*id_counter, true)
+                }
+            },
+            // Only the master hands out IDs, everyone else is looking for a mesh themselves.
+            _ => return None,
+        };
+
+        self.mark_seen(assigned_id, is_new);
+
+        if let Some(session_nonce) = self.session_nonce {
+            let resume = self.resume(assigned_id);
+            // The joining controller has no mesh ID yet, so it picks the welcome out of the broadcasts by its unique ID.
+            self.broadcast(SessionMessage::Welcome { unique_id, assigned_id, resume, session_nonce });
+        }
+
+        Some(assigned_id)
+    }
+
+    /// The part the controller with the mesh ID plays in the running round on the master, if any.
+    fn resume(&self, id: u16) -> Option<Resume> {
+        let now_us = self.clock.now_us();
+
+        match &self.mode {
+            ControllerMode::Master { game: Some(GameState::LastOneStanding { active_controller_ids, exited_controller_ids }), .. } => {
+                let is_active = active_controller_ids.contains(&(id as usize));
+                (is_active || exited_controller_ids.contains(&(id as usize))).then_some(Resume::LastOneStanding { is_active })
+            },
+            ControllerMode::Master { game: Some(GameState::Territory { teams, ends_us, .. }), .. } => {
+                teams.get(&id).map(|team| Resume::Territory {
+                    team: *team,
+                    remaining_ms: (ends_us.saturating_sub(now_us) / 1000) as u32,
+                })
+            },
+            _ => None,
+        }
+    }
+
+    /// Become a client of the mesh if the welcome is meant for this controller, picking up the running round.
+    fn handle_welcome(&mut self, unique_id: String, assigned_id: u16, resume: Option<Resume>, session_nonce: [u8; 16]) {
+        if unique_id != self.unique_id || !matches!(self.mode, ControllerMode::Discovery | ControllerMode::Connecting) {
+            return;
+        }
+
+        println!("Joined the mesh as controller {}", assigned_id);
+        self.master_seen_us = self.clock.now_us();
+        self.mode = ControllerMode::Client {
+            id: assigned_id as usize,
+            game: None,
+        };
+        self.session_nonce = Some(session_nonce);
+        self.send_radio_command(RadioCommand::SetSession(Some(session_nonce)));
+        self.send_radio_command(RadioCommand::SetNodeId(Some(assigned_id)));
+        // Range with the master right away, the other clients are discovered on the air.
+        self.send_radio_command(RadioCommand::SetPeers(vec![0]));
+
+        match resume {
+            Some(Resume::LastOneStanding { is_active }) => {
+                println!("Resuming Last One Standing, active: {}", is_active);
+                if let ControllerMode::Client { game, .. } = &mut self.mode {
+                    *game = Some(ClientGameState::LastOneStanding { is_active, reported_us: None });
+                }
+            },
+            Some(Resume::Territory { team, remaining_ms }) => self.handle_territory_state(vec![(assigned_id, team)], remaining_ms),
+            None => {},
+        }
+    }
+
+    /// The ID of this controller in the mesh, if it is part of one.
+    fn mesh_id(&self) -> Option<u16> {
+        match self.mode {
+            ControllerMode::ServerMeditation | ControllerMode::Master { .. } => Some(0),
+            ControllerMode::Client { id, .. } => Some(id as u16),
+            _ => None,
+        }
+    }
+
+    /// Knock a controller out of the running Last One Standing round, ending it once a single player is left.
+    fn eliminate(&mut self, id: u16) {
+        let ControllerMode::Master {
+            game: Some(GameState::LastOneStanding { active_controller_ids, exited_controller_ids }),
+            ..
+        } = &mut self.mode else {
+            return;
+        };
+
+        // Clients repeat their reports, so they may arrive after the controller is already out.
+        let Some(index) = active_controller_ids.iter().position(|active| *active == id as usize) else {
+            return;
+        };
+
+        active_controller_ids.remove(index);
+        exited_controller_ids.push(id as usize);
+        let remaining = active_controller_ids.clone();
+
+        println!("Controller {} is out of the round, {} left", id, remaining.len());
+        self.event_bus.publish(event_bus::GAME, Event::Eliminated { id });
+
+        if remaining.len() <= 1 {
+            self.end_round(remaining.first().map(|id| *id as u16));
+        }
+    }
+
+    /// End the running round on the master and tell the clients and WebSocket who won.
+    fn end_round(&mut self, winner: Option<u16>) {
+        if let ControllerMode::Master { game, .. } = &mut self.mode {
+            *game = None;
+        }
+
+        println!("Round over, winner: {:?}", winner);
+        self.event_bus.publish(event_bus::GAME, Event::RoundOver { winner });
+
+        let announcement = SessionMessage::RoundOver { winner };
+        self.broadcast(announcement.clone());
+
+        let won = winner == Some(0);
+        self.celebrate(if won { LedTimeline::victory() } else { LedTimeline::defeat() }, Some(announcement));
+    }
+
+    /// Play the animation at the end of a round, after which the controller is back in the lobby.
+    ///
+    /// The master passes the result of the round, which it keeps repeating in case a client missed it.
+    fn celebrate(&mut self, timeline: LedTimeline, announcement: Option<SessionMessage>) {
+        let now_us = self.clock.now_us();
+
+        self.celebration = Some(Celebration {
+            until_us: now_us + CELEBRATION_US,
+            announcement,
+            announced_us: now_us,
+            timeline,
+        });
+    }
+
+    /// Advance the running Last One Standing round by knocking this controller out on a jolt, and finish the animation
+    /// at the end of any round.
+    fn run_game(&mut self) {
+        let now_us = self.clock.now_us();
+        let jolted = self.sensors.accelerometer_jolt >= DELTA_THRESHOLD;
+
+        let mut repeat = None;
+        if let Some(celebration) = &mut self.celebration {
+            if now_us >= celebration.until_us {
+                println!("Round animation finished, back to the lobby");
+                self.celebration = None;
+            } else if now_us - celebration.announced_us >= ROUND_OVER_REPEAT_US {
+                celebration.announced_us = now_us;
+                repeat = celebration.announcement.clone();
+            }
+        }
+        if let Some(message) = repeat {
+            self.broadcast(message);
+        }
+
+        match &mut self.mode {
+            ControllerMode::Client { game: Some(ClientGameState::LastOneStanding { is_active, reported_us }), .. } => {
+                if *is_active && jolted {
+                    println!("Knocked out of the round");
+                    *is_active = false;
+                }
+
+                if !*is_active && reported_us.map_or(true, |reported| now_us - reported >= ELIMINATION_REPORT_INTERVAL_US) {
+                    *reported_us = Some(now_us);
+                    self.unicast(0, SessionMessage::Eliminated);
+                }
+            },
+            // The master plays along as controller 0.
+            ControllerMode::Master { game: Some(GameState::LastOneStanding { active_controller_ids, .. }), .. }
+                if jolted && active_controller_ids.contains(&0) => self.eliminate(0),
+            _ => {},
+        }
+    }
+
+    /// Whether this controller is out of the running Last One Standing round.
+    fn is_eliminated(&self) -> bool {
+        match &self.mode {
+            ControllerMode::Client { game: Some(ClientGameState::LastOneStanding { is_active, .. }), .. } => !is_active,
+            ControllerMode::Master { game: Some(GameState::LastOneStanding { active_controller_ids, .. }), .. } => {
+                !active_controller_ids.contains(&0)
+            },
+            _ => false,
+        }
+    }
+
+    /// Apply a message the master passed on from its WebSocket clients, at the network time it was scheduled for if any.
+    fn handle_master_msg(&mut self, msg: ClientMessage, at_us: Option<u64>) {
+        match at_us {
+            Some(at_us) => self.schedule(at_us, schedule::Task::Client(msg)),
+            None => self.handle_client_msg(msg, None),
+        }
+    }
+
+    /// Apply a message of the WebSocket clients, which the master passes on to the clients unless it was scheduled for
+    /// the given network time, since the master passed it on when it was scheduled then.
+    fn handle_client_msg(&mut self, msg: ClientMessage, at_us: Option<u64>) {
         match msg {
             ClientMessage::SetBrightness(brightness) => {
                 println!("Handling SetBrightness message");
@@ -170,30 +806,53 @@ impl<'a> Controller<'a> {
                 self.led.config.intensity = brightness.clamp(0.0, 1.0);
 
                 match self.mode {
-                    ControllerMode::Master { .. } => {
+                    ControllerMode::Master { .. } if at_us.is_none() => {
                         println!("Sending brightness change over UWB out tx");
                         // Broadcast brightness to all clients
-                        self.uwb_out_tx.try_send(Frame::new().client_message(ClientMessage::SetBrightness(brightness))).unwrap();
+                        self.broadcast(SessionMessage::Brightness { brightness, at_us: None });
                     },
                     _ => {},
                 }
             },
             
-            // TODO: Hardcoded to Last One Standing for now
+            // TODO: Hardcoded to Last One Standing for now, unless a game mode with that name is bundled from config/
             ClientMessage::StartRound(game_identifier) => {
+                if game_identifier == territory::NAME {
+                    self.start_territory(None);
+                    return;
+                }
+
+                if let Some(definition) = rules::bundled(&game_identifier) {
+                    if let Err(e) = definition.and_then(|definition| self.start_rules(definition)) {
+                        println!("Failed to start game mode {}: {}", game_identifier, e);
+                        return;
+                    }
+
+                    if let (ControllerMode::Master { .. }, None) = (&self.mode, at_us) {
+                        self.broadcast_round_start(game_identifier, None);
+                    }
+                    return;
+                }
+
+                self.celebration = None;
+
                 match &mut self.mode {
-                    ControllerMode::Master { game, .. } => {
+                    ControllerMode::Master { controllers, game, .. } => {
+                        // The master plays along as controller 0, the anchors don't.
+                        let players = controllers.iter().filter(|c| c.anchor.is_none()).map(|c| c.id as usize);
                         *game = Some(GameState::LastOneStanding {
-                            active_controller_ids: self.connected_controllers.iter().map(|c| c.id as usize).collect(),
+                            active_controller_ids: std::iter::once(0).chain(players).collect(),
                             exited_controller_ids: vec![],
                         });
 
-                        // Broadcast round start to all clients
-                        self.uwb_out_tx.try_send(Frame::new().client_message(ClientMessage::StartRound(game_identifier))).unwrap();
+                        if at_us.is_none() {
+                            self.broadcast_round_start(game_identifier, None);
+                        }
                     },
                     ControllerMode::Client { game, .. } => {
                         *game = Some(ClientGameState::LastOneStanding {
                             is_active: true,
+                            reported_us: None,
                         });
                     },
                     _ => println!("Implement StartRound handling for non-master modes"),
@@ -204,9 +863,15 @@ impl<'a> Controller<'a> {
         }
     }
 
+    /// Tell all clients about the round the master started or starts at the given network time, until each of them
+    /// confirmed it.
+    fn broadcast_round_start(&mut self, game: String, at_us: Option<u64>) {
+        self.send_radio_command(RadioCommand::SendReliable { dst: BROADCAST, message: SessionMessage::StartRound { game, at_us } });
+    }
+
     fn handle_internal_msg(&mut self, time: u16, msg: InternalMessage) {
         match msg {
-            InternalMessage::ClientMessage(client_msg) => self.handle_client_msg(client_msg),
+            InternalMessage::ClientMessage(client_msg) => self.handle_client_msg(client_msg, None),
             InternalMessage::AccelerometerJoltDelta(delta) => self.sensors.accelerometer_jolt = delta,
             InternalMessage::Frame(frame) => self.handle_uwb_frame(time, *frame),
             _ => println!("Unhandled internal message: {:?}", msg),
@@ -217,49 +882,41 @@ impl<'a> Controller<'a> {
         match frame.payload {
             FramePayload::ControllerMessage(controller_msg) => {
                 match controller_msg {
+                    // Joins carry the unique ID of the controller in `SessionMessage::Join` and `SessionMessage::Welcome`,
+                    // since a broadcast join response can't tell several joining controllers apart. Controllers with an
+                    // older firmware still join without one, so each of their requests counts as a new controller.
                     ControllerMessage::JoinRequest => {
-                        println!("Received UWB join request from controller at time {} (Mode {:?})", time, self.mode);
-
-                        let mut assigned_id = 65535;
-
-                        if self.mode == ControllerMode::ServerMeditation {
-                            // This is the first controller joining the master, set the assigned ID to 1 and the ID counter to 2.
-                            println!("Switching to Master mode");
-                            assigned_id = 1;
-                            self.mode = ControllerMode::Master {
-                                controllers: vec![RemoteController::new(assigned_id)],
-                                id_counter: 2,
-                                game: None,
-                            };
-                        } else if let ControllerMode::Master { controllers, id_counter, game } = &mut self.mode {
-                            // Use the incremental nature of the counter to assign new IDs to joining controllers.
-                            println!("Adding new controller to mesh");
-                            *id_counter += 1;
-                            assigned_id = *id_counter;
-                            controllers.push(RemoteController::new(assigned_id));
+                        println!("Received UWB join request without a unique ID at time {}", time);
+                        if let Some(assigned_id) = self.admit(nanoid!(10), None) {
+                            self.send_radio_command(RadioCommand::Transmit(Frame::join_response(time, assigned_id)));
                         }
-
-                        self.send_uwb_frame(Frame::join_response(time, assigned_id));
                     },
-
-                    // We have just successfully joined a mesh.
-                    ControllerMessage::JoinResponse { assigned_id } => {
-                        println!("Received UWB join response from controller at time {}", time);
-                        self.mode = ControllerMode::Client {
-                            id: assigned_id as usize,
-                            game: None,
-                        };
+                    // This controller joins with its unique ID, so a response without one is meant for someone else.
+                    ControllerMessage::JoinResponse { .. } => {
+                        println!("Ignoring UWB join response without a unique ID at time {}", time);
                     },
                 }
             },
 
-            FramePayload::ClientMessage(client_msg) => self.handle_client_msg(client_msg),
+            FramePayload::ClientMessage(client_msg) => self.handle_client_msg(client_msg, None),
 
             _ => println!("Unhandled UWB frame: {:?}", frame),
         }
     }
 
     fn led_pattern(&mut self, time: u16, delta_threshold: f32, current_delta: f32, mut stay_red: bool) {
+        // Game modes running on the rule engine pick their colors through actions.
+        if let (Some(_), Some((red, green, blue, white))) = (&self.rules, self.game_color) {
+            self.led.set_rgbw(red, green, blue, white);
+            return;
+        }
+
+        if let Some(celebration) = &self.celebration {
+            let (red, green, blue, white) = celebration.timeline.get_current_color(time);
+            self.led.set_rgbw(red, green, blue, white);
+            return;
+        }
+
         match &self.mode {
             ControllerMode::Discovery | ControllerMode::Connecting => {
                 // println!("Mode: Discovery | Connecting");
@@ -396,46 +1053,112 @@ impl<'a> Controller<'a> {
                         self.led.set_rgbw(red, 255 - red, 0, 0);
                     },
                     GameMode::Territory => {
-                        println!("Territory mode not implemented yet");
+                        // Territory rounds are started by the master and followed by `run_territory`, so outside
+                        // of one this only shows the team color last taken, or a dim white.
+                        let (red, green, blue, white) = self.game_color.unwrap_or((0, 0, 0, 30));
+                        self.led.set_rgbw(red, green, blue, white);
                     },
                 }
             },
         }
     }
 
-    pub fn start_event_loop(&mut self, timer: esp_idf_hal::timer::TimerDriver) -> Result<(), EspError> {
-        println!("## {}  Initializing controller loop", "[Controller]".bright_blue().bold());
-        let mut time = 0u16;
-        let delta_threshold = 0.4;
-        let mut current_delta = 0.0;
-
-        let mut stay_red = false;
-
+    /// Reset the controller to discovery mode and ask the UWB mesh whether there is a master.
+    pub fn begin(&mut self) {
         self.mode = ControllerMode::Discovery;
+        self.sensors.distances.clear();
+        self.sensors.filters.clear();
+        self.sensors.positions.clear();
+        self.sensors.location = None;
+        self.distance_table.clear();
+        self.sensors.links.clear();
+        self.roster.clear();
+        // Whatever the old mesh scheduled is none of this controller's business any more.
+        self.scheduled.clear();
+        self.send_radio_command(RadioCommand::SetNodeId(None));
+        self.send_radio_command(RadioCommand::SetPanId(None));
+        self.send_radio_command(RadioCommand::SetProfile(self.radio_profile));
+        self.session_nonce = None;
+        self.send_radio_command(RadioCommand::SetSession(None));
+        self.send_radio_command(RadioCommand::PublishSchedule(false));
 
         println!("## {}  Controller Init to Discovery Mode", "[Controller]".bright_blue().bold());
 
-        let delay = esp_idf_hal::delay::Delay::new_default();
+        self.request_join();
+    }
 
-        self.uwb_out_tx.try_send(Frame::join_request(time)).unwrap();
-        println!("Sent UWB join request to see if there is a master");
+    /// Ask the UWB mesh whether there is a master which lets this controller join.
+    fn request_join(&mut self) {
+        self.join_requested_us = self.clock.now_us();
+        self.unicast(0, SessionMessage::Join { unique_id: self.unique_id.clone(), anchor: self.anchor });
+        println!("Sent UWB join request as {} to see if there is a master", self.unique_id);
+    }
 
-        loop {
-            // Send tick message for time synchronization. This should keep the UWB connection alive.
-            // self.uwb_out_tx.try_send(Frame::tick(time)).unwrap();
+    /// Run a single iteration of the event loop: apply pending mode changes, handle at most one message and update the LEDs.
+    pub fn step(&mut self, time: u16) {
+        //println!("Loop iteration {}", time);
 
-            //println!("Loop iteration {}", time);
+        // Check for new channel messages
+        if let Ok(mode) = self.rx.try_recv() {
+            println!("Mode change: {:?}", mode);
 
-            // Check for new channel messages
-            if let Ok(mode) = self.rx.try_recv() {
-                println!("Mode change: {:?}", mode);
+            if mode == ControllerMode::ServerMeditation && self.anchor.is_some() {
+                println!("Anchors never open a mesh of their own, still looking for a master");
+            } else {
                 self.mode = mode;
             }
-            if let Ok(internal_msg) = self.msg_rx.try_recv() {
-                self.handle_internal_msg(time, internal_msg);
+
+            if self.mode == ControllerMode::ServerMeditation {
+                // Open the mesh right away, so joining controllers find the schedule and send in the join slot.
+                self.send_radio_command(RadioCommand::SetPanId(Some(mesh::session_pan_id(&self.unique_id))));
+                // The radio may have been looking for a mesh on another profile.
+                self.send_radio_command(RadioCommand::SetProfile(self.radio_profile));
+                // Every session gets a key of its own, so traffic recorded in an earlier one is worthless.
+                let session_nonce = uuid::Uuid::new_v4().into_bytes();
+                self.session_nonce = Some(session_nonce);
+                self.send_radio_command(RadioCommand::SetSession(Some(session_nonce)));
+                self.send_radio_command(RadioCommand::SetNodeId(Some(0)));
+                self.send_radio_command(RadioCommand::PublishSchedule(true));
             }
+        }
+        if self.mode == ControllerMode::Discovery && self.clock.now_us().saturating_sub(self.join_requested_us) >= JOIN_RETRY_US {
+            self.request_join();
+        }
+        if let Ok(internal_msg) = self.msg_rx.try_recv() {
+            self.handle_internal_msg(time, internal_msg);
+        }
+        if let Ok(command) = self.commands_rx.try_recv() {
+            self.handle_command(command);
+        }
+        while let Some(event) = self.uwb.poll_event() {
+            self.handle_radio_event(event);
+        }
 
-            self.led_pattern(time, delta_threshold, current_delta, stay_red);
+        self.run_schedule();
+        self.run_failover();
+        self.run_liveness();
+        self.run_rules();
+        self.run_game();
+        self.run_territory();
+        self.run_positioning();
+        self.run_location();
+        self.check_calibration();
+
+        let current_delta = 0.0;
+        let eliminated = self.is_eliminated();
+        // Patterns follow the network time, so all controllers of the mesh show them in step.
+        let pattern_time = (self.network_time_us() / sync::PATTERN_TICK_US) as u16;
+        self.led_pattern(pattern_time, DELTA_THRESHOLD, current_delta, eliminated);
+    }
+
+    pub fn start_event_loop(&mut self) -> anyhow::Result<()> {
+        println!("## {}  Initializing controller loop", "[Controller]".bright_blue().bold());
+        let mut time = 0u16;
+
+        self.begin();
+
+        loop {
+            self.step(time);
 
             if time < u16::MAX {
                 time += 1;
@@ -443,11 +1166,24 @@ impl<'a> Controller<'a> {
                 time = 0;
             }
 
-            delay.delay_us(100);
+            self.clock.delay_us(100);
         }
+    }
+}
 
-        println!("Loop done");
+/// Load the unique ID of this controller, generating and storing one on the first boot.
+fn load_unique_id(storage: &mut dyn Storage) -> String {
+    match storage.load(UNIQUE_ID_KEY) {
+        Ok(Some(unique_id)) => return unique_id,
+        Ok(None) => {},
+        Err(e) => println!("Failed to load the unique ID: {}", e),
+    }
 
-        Ok(())
+    let unique_id = nanoid!(10);
+    println!("Generated unique ID {}", unique_id);
+    if let Err(e) = storage.store(UNIQUE_ID_KEY, &unique_id) {
+        println!("Failed to store the unique ID, it will change on the next boot: {}", e);
     }
-}
\ No newline at end of file
+
+    unique_id
+}
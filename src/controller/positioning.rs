@@ -0,0 +1,95 @@
+//! Places every controller of the mesh on a plane, which the master works out from the distances between them.
+//!
+//! Clients report their smoothed distances to all peers along with their heartbeat. The master keeps the latest
+//! distance of every pair, along with its own, and solves the layout from them at a fixed interval. The positions are
+//! relative to the master, which is at the origin, and go to the game logic through [`Sensors::positions`] and to the
+//! WebSocket clients through the events tagged [`POSITIONS`](event_bus::POSITIONS).
+//!
+//! [`Sensors::positions`]: super::Sensors::positions
+
+use std::collections::HashMap;
+
+use crate::event_bus::{self, Event};
+use crate::ranging::positioning::{self, Edge};
+
+use super::{Controller, ControllerMode, PeerStatus};
+
+/// Time between two layouts on the master, in microseconds.
+const POSITIONING_INTERVAL_US: u64 = 1_000_000;
+
+/// Time after which a reported distance is left out of the layout, in microseconds.
+const DISTANCE_STALE_US: u64 = 5_000_000;
+
+/// A distance between two controllers, as last reported by either of them.
+pub(super) struct ReportedDistance {
+    distance_m: f32,
+    reported_us: u64,
+}
+
+/// The distances the master knows of, keyed by the mesh IDs of both controllers with the lower one first.
+pub(super) type DistanceTable = HashMap<(u16, u16), ReportedDistance>;
+
+impl Controller<'_> {
+    /// The smoothed distances to all peers, rounded to centimeters, for the heartbeat to the master.
+    pub(super) fn distance_report(&self) -> Vec<(u16, f32)> {
+        self.sensors.filters
+            .values()
+            .map(|filter| filter.estimate())
+            .map(|estimate| (estimate.peer, (estimate.distance_m * 100.0).round() / 100.0))
+            .collect()
+    }
+
+    /// Remember the distances a client reported on the master.
+    pub(super) fn record_distances(&mut self, src: u16, distances: &[(u16, f32)]) {
+        let now_us = self.clock.now_us();
+
+        for (peer, distance_m) in distances {
+            self.distance_table.insert((src.min(*peer), src.max(*peer)), ReportedDistance {
+                distance_m: *distance_m,
+                reported_us: now_us,
+            });
+        }
+    }
+
+    /// Solve the layout of the mesh on the master once the interval passed.
+    pub(super) fn run_positioning(&mut self) {
+        let now_us = self.clock.now_us();
+        if now_us.saturating_sub(self.positioned_us) < POSITIONING_INTERVAL_US {
+            return;
+        }
+        self.positioned_us = now_us;
+
+        let ControllerMode::Master { controllers, .. } = &self.mode else {
+            return;
+        };
+
+        // The master goes first, so it ends up at the origin.
+        let ids: Vec<u16> = std::iter::once(0)
+            .chain(controllers.iter().filter(|c| c.status != PeerStatus::Lost).map(|c| c.id))
+            .collect();
+
+        self.distance_table.retain(|_, reported| now_us - reported.reported_us < DISTANCE_STALE_US);
+        for estimate in self.sensors.filters.values().map(|filter| filter.estimate()) {
+            self.distance_table.insert((0, estimate.peer), ReportedDistance {
+                distance_m: estimate.distance_m,
+                reported_us: now_us,
+            });
+        }
+
+        let edges: Vec<Edge> = self.distance_table
+            .iter()
+            .map(|(&(a, b), reported)| Edge { a, b, distance_m: reported.distance_m, weight: 1.0 })
+            .collect();
+
+        let Some(layout) = positioning::solve(&ids, &edges, &self.sensors.positions) else {
+            return;
+        };
+
+        self.sensors.positions = layout.positions.iter().map(|position| (position.id, *position)).collect();
+        self.event_bus.publish(event_bus::POSITIONS, Event::Positions {
+            positions: layout.positions,
+            residual_m: layout.residual_m,
+            outliers: layout.outliers,
+        });
+    }
+}
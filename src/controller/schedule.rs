@@ -0,0 +1,97 @@
+//! Commands which take effect at a given network time, so every controller of the mesh applies them at once.
+//!
+//! The master passes a scheduled command on to the clients well ahead of time, along with the network time it takes
+//! effect at, and every controller keeps it until then. A command arriving after its time has passed is applied right
+//! away. Whether a client got the start of a scheduled round only counts once the round started, so the master leaves
+//! the clients which never confirmed it out of the round at that moment. Territory deals its teams when it is scheduled,
+//! so the clients know their team ahead of the start.
+
+use ledswarm_protocol::ClientMessage;
+
+use crate::event_bus::{self, Event};
+use crate::mesh::{SessionMessage, BROADCAST};
+use crate::radio::RadioCommand;
+
+use super::{territory, Controller, ControllerMode};
+
+/// Something the controller does at a given network time.
+pub(super) enum Task {
+    /// Apply a message of the WebSocket clients, which the master already passed on to the clients.
+    Client(ClientMessage),
+    /// Note whether a client confirmed a scheduled message, once the message took effect.
+    Delivery { dst: u16, message: SessionMessage, delivered: bool },
+    /// Start a Territory round with the teams the master dealt when it was scheduled.
+    Territory { teams: Vec<(u16, u8)>, remaining_ms: u32 },
+}
+
+pub(super) struct Scheduled {
+    at_us: u64,
+    task: Task,
+}
+
+impl Controller<'_> {
+    /// Apply a message of the WebSocket clients on all controllers the given number of milliseconds from now, which
+    /// only the master can do.
+    pub(super) fn schedule_client_msg(&mut self, delay_ms: u32, message: ClientMessage) {
+        if !matches!(self.mode, ControllerMode::ServerMeditation | ControllerMode::Master { .. }) {
+            println!("Only the master can schedule messages for the mesh");
+            return;
+        }
+
+        let at_us = self.network_time_us() + delay_ms as u64 * 1_000;
+        println!("Scheduling {:?} for network time {} us, {} ms from now", message, at_us, delay_ms);
+
+        self.event_bus.publish(event_bus::GAME, Event::Scheduled { at_us, delay_ms });
+
+        match &message {
+            ClientMessage::SetBrightness(brightness) => {
+                let message = SessionMessage::Brightness { brightness: *brightness, at_us: Some(at_us) };
+                self.send_radio_command(RadioCommand::SendReliable { dst: BROADCAST, message });
+            },
+            // Territory schedules the start of its teams itself.
+            ClientMessage::StartRound(game) if game == territory::NAME => {
+                self.start_territory(Some(at_us));
+                return;
+            },
+            ClientMessage::StartRound(game) => self.broadcast_round_start(game.clone(), Some(at_us)),
+            _ => {},
+        }
+        self.schedule(at_us, Task::Client(message));
+    }
+
+    /// Run a task at the given network time, or right away if that has passed.
+    pub(super) fn schedule(&mut self, at_us: u64, task: Task) {
+        let now_us = self.network_time_us();
+        if at_us <= now_us {
+            if let Task::Client(message) = &task {
+                println!("{:?} was due {} us ago, applying it right away", message, now_us - at_us);
+            }
+            self.run_task(at_us, task);
+            return;
+        }
+
+        // Tasks due at the same time run in the order they were scheduled.
+        let index = self.scheduled.partition_point(|scheduled| scheduled.at_us <= at_us);
+        self.scheduled.insert(index, Scheduled { at_us, task });
+    }
+
+    /// Run every task whose network time has come.
+    pub(super) fn run_schedule(&mut self) {
+        let now_us = self.network_time_us();
+        while self.scheduled.first().is_some_and(|scheduled| scheduled.at_us <= now_us) {
+            let scheduled = self.scheduled.remove(0);
+            self.run_task(scheduled.at_us, scheduled.task);
+        }
+    }
+
+    fn run_task(&mut self, at_us: u64, task: Task) {
+        match task {
+            Task::Client(message) => self.handle_client_msg(message, Some(at_us)),
+            Task::Delivery { dst, message, delivered } => self.handle_delivery(dst, message, delivered),
+            Task::Territory { teams, remaining_ms } => match self.mode {
+                ControllerMode::Master { .. } => self.begin_territory(teams, remaining_ms),
+                _ => self.handle_territory_state(teams, remaining_ms),
+            },
+        }
+    }
+}
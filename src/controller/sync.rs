@@ -0,0 +1,52 @@
+//! The network time on the side of the controller, which patterns and game events are timed against.
+//!
+//! The radio reports the network time at a moment of the controller's own clock whenever a beacon went out or came in,
+//! and the controller runs on from there with its own clock and the drift the radio measured. Before the radio reports
+//! a network time, the controller's own clock stands in for it.
+//!
+//! The network time never runs backwards: when a beacon sets it back by a little, it stands still until it caught up.
+//! Only when it moves back a long way, after joining another mesh, it starts over from the time of that mesh.
+
+use crate::radio::sync::NetworkTime;
+
+use super::Controller;
+
+/// How far the network time may move back before it starts over instead of standing still, in microseconds.
+const MAX_HOLD_US: u64 = 100_000;
+
+/// How long one step of the LED patterns takes, in microseconds.
+pub(super) const PATTERN_TICK_US: u64 = 100;
+
+impl Controller<'_> {
+    /// The network time shared by all controllers of the mesh, in microseconds.
+    pub fn network_time_us(&self) -> u64 {
+        let now_us = self.clock.now_us();
+        let network_us = match self.network_time {
+            Some(time) => {
+                let elapsed_us = now_us.saturating_sub(time.local_us) as f64 * (1.0 + time.drift_ppm as f64 * 1e-6);
+                time.network_us + elapsed_us as u64
+            },
+            None => now_us,
+        };
+
+        let floor_us = self.network_floor_us.get();
+        if network_us >= floor_us || floor_us - network_us > MAX_HOLD_US {
+            self.network_floor_us.set(network_us);
+            network_us
+        } else {
+            floor_us
+        }
+    }
+
+    /// Whether the network time has been taken from the mesh, rather than from the controller's own clock.
+    pub fn has_network_time(&self) -> bool {
+        self.network_time.is_some()
+    }
+
+    pub(super) fn handle_network_time(&mut self, time: NetworkTime) {
+        if self.network_time.is_none() {
+            println!("Following the network time of the mesh, {} us at the moment", time.network_us);
+        }
+        self.network_time = Some(time);
+    }
+}
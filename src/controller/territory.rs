@@ -0,0 +1,363 @@
+//! The Territory game mode.
+//!
+//! The master splits the swarm into teams and shows each team in its own color. On a jolt, the rules in
+//! `config/territory.yml` let a player take over the color of the closest controller in range, which moves the player
+//! to that team. The master keeps the score of every team, and when the time is up, the team with the most controllers
+//! wins and all controllers show its color.
+
+use std::collections::BTreeMap;
+
+use crate::event_bus::{self, Event};
+use crate::led::blink::LedTimeline;
+use crate::mesh::{SessionMessage, BROADCAST};
+use crate::radio::RadioCommand;
+use crate::rules::{self, GameDefinition};
+
+use super::{schedule, ClientGameState, Controller, ControllerMode, GameState};
+
+/// The name under which the Territory round is started and its rules are bundled.
+pub const NAME: &str = "territory";
+
+/// The color of each team, indexed by team number.
+pub const TEAM_COLORS: [(u8, u8, u8, u8); 2] = [
+    (255, 0, 0, 0),
+    (0, 40, 255, 0),
+];
+
+/// Length of a round if the rule file does not define one, in seconds.
+const DEFAULT_DURATION_S: u32 = 60;
+
+/// How often the master repeats the state of the round, in microseconds.
+const STATE_REPEAT_US: u64 = 2_000_000;
+
+/// How long a client waits for the result after its round should have ended, before it gives up, in microseconds.
+const RESULT_GRACE_US: u64 = 5_000_000;
+
+/// The team shown in the given color, if it is a team color at all.
+fn team_of(color: (u8, u8, u8, u8)) -> Option<u8> {
+    TEAM_COLORS.iter().position(|team_color| *team_color == color).map(|team| team as u8)
+}
+
+/// The rules of Territory bundled from `config/territory.yml`.
+fn territory_definition() -> Result<GameDefinition, String> {
+    rules::bundled(NAME).unwrap_or_else(|| Err(format!("no rules bundled from config/{}.yml", NAME)))
+}
+
+/// The animation at the end of a round, pulsing in the color of the winning team or in white on a draw.
+fn result_timeline(winner: Option<u8>) -> LedTimeline {
+    match winner {
+        Some(team) => LedTimeline::pulse(TEAM_COLORS[team as usize]),
+        None => LedTimeline::pulse((0, 0, 0, 255)),
+    }
+}
+
+impl Controller<'_> {
+    /// Start a Territory round on the master, dealing the controllers in the mesh into teams, either right away or at
+    /// the given network time.
+    pub(super) fn start_territory(&mut self, at_us: Option<u64>) {
+        let ControllerMode::Master { controllers, .. } = &self.mode else {
+            println!("Only the master can start a Territory round");
+            return;
+        };
+        // The master plays along as controller 0, the anchors don't.
+        let ids: Vec<u16> = std::iter::once(0).chain(controllers.iter().filter(|c| c.anchor.is_none()).map(|c| c.id)).collect();
+
+        let definition = match territory_definition() {
+            Ok(definition) => definition,
+            Err(e) => {
+                println!("Failed to start Territory: {}", e);
+                return;
+            },
+        };
+        let remaining_ms = definition.duration_s.unwrap_or(DEFAULT_DURATION_S) * 1000;
+
+        let teams: Vec<(u16, u8)> = ids.iter()
+            .enumerate()
+            .map(|(index, id)| (*id, (index % TEAM_COLORS.len()) as u8))
+            .collect();
+
+        let Some(at_us) = at_us else {
+            self.begin_territory(teams, remaining_ms);
+
+            // The first announcement starts the round on the clients, so it has to reach every one of them.
+            if let Some(message) = self.territory_state() {
+                self.send_radio_command(RadioCommand::SendReliable { dst: BROADCAST, message });
+            }
+            return;
+        };
+
+        // The clients learn their teams ahead of time and start along with the master.
+        let message = SessionMessage::Territory { teams: teams.clone(), remaining_ms, at_us: Some(at_us) };
+        self.send_radio_command(RadioCommand::SendReliable { dst: BROADCAST, message });
+        self.schedule(at_us, schedule::Task::Territory { teams, remaining_ms });
+    }
+
+    /// Run a Territory round with the given teams on the master.
+    pub(super) fn begin_territory(&mut self, teams: Vec<(u16, u8)>, remaining_ms: u32) {
+        let definition = match territory_definition() {
+            Ok(definition) => definition,
+            Err(e) => {
+                println!("Failed to start Territory: {}", e);
+                return;
+            },
+        };
+        if let Err(e) = self.start_rules(definition) {
+            println!("Failed to start Territory: {}", e);
+            return;
+        }
+
+        let teams: BTreeMap<u16, u8> = teams.into_iter().collect();
+        let now_us = self.clock.now_us();
+
+        println!("Starting Territory with teams {:?}", teams);
+        self.celebration = None;
+        self.game_color = teams.get(&0).map(|team| TEAM_COLORS[*team as usize]);
+        self.sensors.peer_colors = teams.iter().map(|(id, team)| (*id, TEAM_COLORS[*team as usize])).collect();
+
+        if let ControllerMode::Master { game, .. } = &mut self.mode {
+            *game = Some(GameState::Territory {
+                teams,
+                ends_us: now_us + remaining_ms as u64 * 1000,
+                announced_us: now_us,
+            });
+        }
+
+        self.publish_scores();
+    }
+
+    /// Broadcast the teams and the remaining time of the running round to the clients.
+    fn announce_territory(&mut self) {
+        if let Some(message) = self.territory_state() {
+            self.broadcast(message);
+        }
+    }
+
+    /// The state of the running round for the clients, noting that it is announced now.
+    fn territory_state(&mut self) -> Option<SessionMessage> {
+        let now_us = self.clock.now_us();
+
+        let ControllerMode::Master { game: Some(GameState::Territory { teams, ends_us, announced_us }), .. } = &mut self.mode else {
+            return None;
+        };
+        *announced_us = now_us;
+
+        Some(SessionMessage::Territory {
+            teams: teams.iter().map(|(id, team)| (*id, *team)).collect(),
+            remaining_ms: (ends_us.saturating_sub(now_us) / 1000) as u32,
+            at_us: None,
+        })
+    }
+
+    /// The number of controllers in each team of the running round on the master.
+    fn scores(&self) -> Vec<u16> {
+        let mut scores = vec![0; TEAM_COLORS.len()];
+
+        if let ControllerMode::Master { game: Some(GameState::Territory { teams, .. }), .. } = &self.mode {
+            for team in teams.values() {
+                scores[*team as usize] += 1;
+            }
+        }
+
+        scores
+    }
+
+    fn publish_scores(&self) {
+        let scores = self.scores();
+        println!("Territory scores: {:?}", scores);
+        self.event_bus.publish(event_bus::GAME, Event::TeamScores { scores });
+    }
+
+    /// Join or catch up with the round described by the master.
+    pub(super) fn handle_territory_state(&mut self, teams: Vec<(u16, u8)>, remaining_ms: u32) {
+        let ControllerMode::Client { id, .. } = self.mode else {
+            return;
+        };
+        // Controllers which joined the mesh after the round started sit it out.
+        let Some(assigned) = teams.iter().find(|(member, _)| *member as usize == id).map(|(_, team)| *team) else {
+            return;
+        };
+        if assigned as usize >= TEAM_COLORS.len() {
+            return;
+        }
+
+        let now_us = self.clock.now_us();
+        let round_ends_us = now_us + remaining_ms as u64 * 1000;
+
+        for (member, team) in &teams {
+            if let Some(color) = TEAM_COLORS.get(*team as usize) {
+                self.sensors.peer_colors.insert(*member, *color);
+            }
+        }
+
+        if let ControllerMode::Client { game: Some(ClientGameState::Territory { team, ends_us }), .. } = &mut self.mode {
+            *ends_us = round_ends_us;
+
+            // The master missed our last team change, so tell it again.
+            if *team != assigned {
+                let change = SessionMessage::Team { id: id as u16, team: *team };
+                self.send_radio_command(RadioCommand::SendReliable { dst: 0, message: change });
+            }
+            return;
+        }
+
+        let definition = match territory_definition() {
+            Ok(definition) => definition,
+            Err(e) => {
+                println!("Failed to join Territory: {}", e);
+                return;
+            },
+        };
+        if let Err(e) = self.start_rules(definition) {
+            println!("Failed to join Territory: {}", e);
+            return;
+        }
+
+        println!("Joining Territory in team {}", assigned);
+        self.celebration = None;
+        self.game_color = Some(TEAM_COLORS[assigned as usize]);
+
+        if let ControllerMode::Client { game, .. } = &mut self.mode {
+            *game = Some(ClientGameState::Territory {
+                team: assigned,
+                ends_us: round_ends_us,
+            });
+        }
+    }
+
+    /// Remember the new team of another controller.
+    ///
+    /// The master only takes a client's word for its own team, updates the score and passes the change on to the
+    /// mesh, while the clients only take changes passed on by the master.
+    pub(super) fn handle_team_change(&mut self, src: u16, id: u16, team: u8) {
+        let Some(color) = TEAM_COLORS.get(team as usize) else {
+            return;
+        };
+
+        match &mut self.mode {
+            ControllerMode::Master { game: Some(GameState::Territory { teams, .. }), .. } => {
+                if id != src {
+                    return;
+                }
+                match teams.get_mut(&id) {
+                    Some(current) if *current != team => *current = team,
+                    _ => return,
+                }
+            },
+            ControllerMode::Client { id: own_id, .. } => {
+                if src != 0 || id as usize == *own_id {
+                    return;
+                }
+                self.sensors.peer_colors.insert(id, *color);
+                return;
+            },
+            _ => return,
+        }
+
+        println!("Controller {} switched to team {}", id, team);
+        self.sensors.peer_colors.insert(id, *color);
+        self.send_radio_command(RadioCommand::SendReliable { dst: BROADCAST, message: SessionMessage::Team { id, team } });
+        self.publish_scores();
+    }
+
+    /// Take a controller which left the mesh out of the running round on the master.
+    pub(super) fn leave_territory(&mut self, id: u16) {
+        let ControllerMode::Master { game: Some(GameState::Territory { teams, .. }), .. } = &mut self.mode else {
+            return;
+        };
+
+        if teams.remove(&id).is_some() {
+            println!("Controller {} left the round", id);
+            self.publish_scores();
+        }
+    }
+
+    /// End the running round on a client once the master announced the winning team.
+    pub(super) fn handle_territory_over(&mut self, src: u16, winner: Option<u8>) {
+        // Only the master keeps the score.
+        if src != 0 {
+            return;
+        }
+
+        // The master repeats the result, so only the first announcement ends the round.
+        let ControllerMode::Client { game: game @ Some(ClientGameState::Territory { .. }), .. } = &mut self.mode else {
+            return;
+        };
+        *game = None;
+
+        println!("Territory over, winning team: {:?}", winner);
+        self.stop_rules();
+        self.celebrate(result_timeline(winner), None);
+    }
+
+    /// Follow the color picked by the rules into its team, and end the round when the time is up.
+    pub(super) fn run_territory(&mut self) {
+        let now_us = self.clock.now_us();
+        let adopted = self.game_color.and_then(team_of);
+
+        match &mut self.mode {
+            ControllerMode::Client { id, game: game @ Some(ClientGameState::Territory { .. }) } => {
+                let Some(ClientGameState::Territory { team, ends_us }) = game else {
+                    return;
+                };
+
+                if now_us >= *ends_us + RESULT_GRACE_US {
+                    println!("Territory result never arrived, back to the lobby");
+                    *game = None;
+                    self.stop_rules();
+                    return;
+                }
+
+                if let Some(adopted) = adopted.filter(|adopted| adopted != team) {
+                    println!("Switched to team {}", adopted);
+                    *team = adopted;
+                    let change = SessionMessage::Team { id: *id as u16, team: adopted };
+                    self.send_radio_command(RadioCommand::SendReliable { dst: 0, message: change });
+                }
+            },
+            ControllerMode::Master { game: Some(GameState::Territory { teams, ends_us, announced_us }), .. } => {
+                if now_us >= *ends_us {
+                    self.end_territory();
+                    return;
+                }
+
+                let repeat = now_us - *announced_us >= STATE_REPEAT_US;
+
+                if let Some(adopted) = adopted.filter(|adopted| teams.get(&0) != Some(adopted)) {
+                    println!("Switched to team {}", adopted);
+                    teams.insert(0, adopted);
+                    let change = SessionMessage::Team { id: 0, team: adopted };
+                    self.send_radio_command(RadioCommand::SendReliable { dst: BROADCAST, message: change });
+                    self.publish_scores();
+                }
+
+                if repeat {
+                    self.announce_territory();
+                }
+            },
+            _ => {},
+        }
+    }
+
+    /// End the running round on the master, letting the team with the most controllers win.
+    fn end_territory(&mut self) {
+        let scores = self.scores();
+        let best = scores.iter().copied().max().unwrap_or_default();
+        // A tie for the most controllers is a draw.
+        let winner = match scores.iter().filter(|score| **score == best).count() {
+            1 => scores.iter().position(|score| *score == best).map(|team| team as u8),
+            _ => None,
+        };
+
+        if let ControllerMode::Master { game, .. } = &mut self.mode {
+            *game = None;
+        }
+        self.stop_rules();
+
+        println!("Territory over, winning team: {:?}, scores: {:?}", winner, scores);
+        self.event_bus.publish(event_bus::GAME, Event::TerritoryOver { winner, scores });
+
+        let announcement = SessionMessage::TerritoryOver { winner };
+        self.broadcast(announcement.clone());
+        self.celebrate(result_timeline(winner), Some(announcement));
+    }
+}
@@ -0,0 +1,241 @@
+//! Host tests of the controller against the in-memory hardware.
+
+use ledswarm_protocol::{ClientMessage, Frame};
+
+use crate::hal::memory::{ManualClock, MemoryLed, MemoryStorage, MemoryTransport};
+use crate::mesh::{SessionMessage, BROADCAST};
+use crate::radio::sync::NetworkTime;
+use crate::radio::RadioCommand;
+
+use super::{ClientGameState, Controller, ControllerMode, GameState, RemoteController, DELTA_THRESHOLD};
+
+/// A controller on the in-memory hardware, along with handles to inspect what it did.
+fn controller() -> (Controller<'static>, MemoryLed, MemoryTransport) {
+    controller_with_clock(ManualClock::new())
+}
+
+/// A controller like [`controller`], whose time moves along with the given clock.
+fn controller_with_clock(clock: ManualClock) -> (Controller<'static>, MemoryLed, MemoryTransport) {
+    let (_msg_tx, msg_rx) = flume::bounded(16);
+    let led = MemoryLed::new();
+    let transport = MemoryTransport::new();
+
+    let controller = Controller::new(
+        msg_rx,
+        Box::new(transport.clone()),
+        Box::new(led.clone()),
+        Box::new(clock),
+        Box::new(MemoryStorage::new()),
+    );
+    transport.take_commands();
+
+    (controller, led, transport)
+}
+
+fn master_with_client() -> ControllerMode {
+    ControllerMode::Master {
+        controllers: vec![RemoteController::new("client".into(), 1)],
+        id_counter: 2,
+        game: None,
+    }
+}
+
+/// The color the LEDs show for the given one at the configured intensity.
+fn dimmed(controller: &Controller, (red, green, blue, white): (u8, u8, u8, u8)) -> (u8, u8, u8, u8) {
+    let intensity = controller.led.config.intensity;
+    let dim = |value: u8| (value as f32 * intensity) as u8;
+    (dim(red), dim(green), dim(blue), dim(white))
+}
+
+#[test]
+fn uwb_frame_with_client_message_sets_brightness() {
+    let (mut controller, _, _) = controller();
+    let frame = Frame::new().client_message(ClientMessage::SetBrightness(0.8));
+
+    controller.handle_uwb_frame(0, frame);
+
+    assert_eq!(controller.led.config.intensity, 0.8);
+}
+
+#[test]
+fn uwb_join_frame_without_unique_id_is_answered_with_a_new_mesh_id() {
+    let (mut controller, _, transport) = controller();
+    controller.mode = master_with_client();
+    controller.handle_uwb_frame(7, Frame::join_request(7));
+
+    let ControllerMode::Master { controllers, .. } = &controller.mode else {
+        panic!("The master stepped down: {:?}", controller.mode);
+    };
+    assert_eq!(controllers.iter().map(|c| c.id).collect::<Vec<_>>(), [1, 3]);
+    assert!(transport.take_commands().iter().any(|command| matches!(
+        command,
+        RadioCommand::Transmit(frame) if *frame == Frame::join_response(7, 3)
+    )));
+}
+
+#[test]
+fn uwb_join_response_without_unique_id_is_ignored() {
+    let (mut controller, _, transport) = controller();
+    controller.handle_uwb_frame(0, Frame::join_response(0, 4));
+
+    assert_eq!(controller.mode, ControllerMode::Discovery);
+    assert!(transport.take_commands().is_empty());
+}
+
+#[test]
+fn client_message_brightness_is_clamped_and_passed_on_by_master() {
+    let (mut controller, _, transport) = controller();
+    controller.mode = master_with_client();
+
+    controller.handle_client_msg(ClientMessage::SetBrightness(1.5), None);
+
+    assert_eq!(controller.led.config.intensity, 1.0);
+    let commands = transport.take_commands();
+    assert!(commands.iter().any(|command| matches!(
+        command,
+        RadioCommand::Send { dst: BROADCAST, message: SessionMessage::Brightness { brightness, at_us: None } } if *brightness == 1.5
+    )));
+}
+
+#[test]
+fn client_message_start_round_on_master_starts_last_one_standing() {
+    let (mut controller, _, transport) = controller();
+    controller.mode = master_with_client();
+
+    controller.handle_client_msg(ClientMessage::StartRound("last_one_standing".into()), None);
+
+    let ControllerMode::Master { game: Some(GameState::LastOneStanding { active_controller_ids, exited_controller_ids }), .. } = &controller.mode else {
+        panic!("The round didn't start: {:?}", controller.mode);
+    };
+    assert_eq!(active_controller_ids, &vec![0, 1]);
+    assert!(exited_controller_ids.is_empty());
+    assert!(transport.take_commands().iter().any(|command| matches!(
+        command,
+        RadioCommand::SendReliable { dst: BROADCAST, message: SessionMessage::StartRound { game, .. } } if game == "last_one_standing"
+    )));
+}
+
+#[test]
+fn round_over_is_only_taken_from_the_master() {
+    let (mut controller, _, _) = controller();
+    let playing = ControllerMode::Client { id: 1, game: Some(ClientGameState::LastOneStanding { is_active: true, reported_us: None }) };
+    controller.mode = playing.clone();
+
+    controller.handle_session_msg(2, SessionMessage::RoundOver { winner: Some(2) });
+    assert_eq!(controller.mode, playing);
+
+    controller.handle_session_msg(0, SessionMessage::RoundOver { winner: Some(1) });
+    assert_eq!(controller.mode, ControllerMode::Client { id: 1, game: None });
+}
+
+#[test]
+fn led_pattern_shows_client_and_master_colors() {
+    let (mut controller, led, _) = controller();
+
+    controller.mode = ControllerMode::Client { id: 1, game: None };
+    controller.led_pattern(0, DELTA_THRESHOLD, 0.0, false);
+    assert_eq!(led.color(), dimmed(&controller, (250, 80, 0, 0)));
+
+    controller.mode = master_with_client();
+    controller.led_pattern(0, DELTA_THRESHOLD, 0.0, false);
+    assert_eq!(led.color(), dimmed(&controller, (0, 30, 255, 0)));
+}
+
+#[test]
+fn led_pattern_turns_red_once_out_of_the_round() {
+    let (mut controller, led, _) = controller();
+    controller.mode = master_with_client();
+    controller.handle_client_msg(ClientMessage::StartRound("last_one_standing".into()), None);
+
+    controller.led_pattern(0, DELTA_THRESHOLD, 0.0, false);
+    assert_eq!(led.color(), dimmed(&controller, (0, 255, 0, 0)));
+
+    controller.led_pattern(0, DELTA_THRESHOLD, 0.0, true);
+    assert_eq!(led.color(), dimmed(&controller, (255, 0, 0, 0)));
+}
+
+#[test]
+fn team_change_is_only_taken_from_the_client_itself_and_passed_on_by_master() {
+    let (mut controller, _, transport) = controller();
+    controller.mode = master_with_client();
+    controller.handle_client_msg(ClientMessage::StartRound("territory".into()), None);
+    transport.take_commands();
+
+    controller.handle_session_msg(2, SessionMessage::Team { id: 1, team: 0 });
+    assert!(transport.take_commands().is_empty());
+
+    controller.handle_session_msg(1, SessionMessage::Team { id: 1, team: 0 });
+    let ControllerMode::Master { game: Some(GameState::Territory { teams, .. }), .. } = &controller.mode else {
+        panic!("The round didn't start: {:?}", controller.mode);
+    };
+    assert_eq!(teams.get(&1), Some(&0));
+    assert!(transport.take_commands().iter().any(|command| matches!(
+        command,
+        RadioCommand::SendReliable { dst: BROADCAST, message: SessionMessage::Team { id: 1, team: 0 } }
+    )));
+}
+
+#[test]
+fn start_round_of_a_bundled_rule_file_runs_it_on_the_rule_engine() {
+    let (mut controller, _, transport) = controller();
+    controller.mode = master_with_client();
+
+    controller.handle_client_msg(ClientMessage::StartRound("hot_and_cold".into()), None);
+
+    assert_eq!(controller.rules.as_ref().map(|engine| engine.definition.name.as_str()), Some("Hot and Cold"));
+    assert!(transport.take_commands().iter().any(|command| matches!(
+        command,
+        RadioCommand::SendReliable { dst: BROADCAST, message: SessionMessage::StartRound { game, at_us: None } } if game == "hot_and_cold"
+    )));
+}
+
+#[test]
+fn territory_result_is_only_taken_from_the_master() {
+    let (mut controller, _, _) = controller();
+    let playing = ControllerMode::Client { id: 1, game: Some(ClientGameState::Territory { team: 0, ends_us: 60_000_000 }) };
+    controller.mode = playing.clone();
+
+    controller.handle_session_msg(2, SessionMessage::TerritoryOver { winner: Some(1) });
+    assert_eq!(controller.mode, playing);
+
+    controller.handle_session_msg(0, SessionMessage::TerritoryOver { winner: Some(0) });
+    assert_eq!(controller.mode, ControllerMode::Client { id: 1, game: None });
+}
+
+#[test]
+fn network_time_never_runs_backwards() {
+    let clock = ManualClock::new();
+    let (mut controller, _, _) = controller_with_clock(clock.clone());
+    clock.set_us(1_000_000);
+    assert_eq!(controller.network_time_us(), 1_000_000);
+
+    controller.handle_network_time(NetworkTime { local_us: 1_000_000, network_us: 5_000_000, drift_ppm: 0.0 });
+    clock.advance_us(10_000);
+    assert_eq!(controller.network_time_us(), 5_010_000);
+
+    // A beacon setting the network time back by a little makes it stand still until it caught up.
+    controller.handle_network_time(NetworkTime { local_us: 1_010_000, network_us: 5_006_000, drift_ppm: 0.0 });
+    assert_eq!(controller.network_time_us(), 5_010_000);
+    clock.advance_us(3_000);
+    assert_eq!(controller.network_time_us(), 5_010_000);
+    clock.advance_us(2_000);
+    assert_eq!(controller.network_time_us(), 5_011_000);
+
+    // Joining another mesh moves it back a long way, so it starts over from there.
+    controller.handle_network_time(NetworkTime { local_us: 1_015_000, network_us: 2_000_000, drift_ppm: 0.0 });
+    assert_eq!(controller.network_time_us(), 2_000_000);
+    clock.advance_us(1_000);
+    assert_eq!(controller.network_time_us(), 2_001_000);
+}
+
+#[test]
+fn network_time_runs_on_with_the_drift_of_the_mesh() {
+    let clock = ManualClock::new();
+    let (mut controller, _, _) = controller_with_clock(clock.clone());
+
+    controller.handle_network_time(NetworkTime { local_us: 0, network_us: 5_000_000, drift_ppm: 100.0 });
+    clock.set_us(1_000_000);
+
+    assert!(controller.has_network_time());
+    assert_eq!(controller.network_time_us(), 6_000_100);
+}
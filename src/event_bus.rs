@@ -1,11 +1,70 @@
 use async_channel::{unbounded, Sender, Receiver};
+use serde::Serialize;
+
+use crate::radio::diagnostics::LinkStats;
+use crate::radio::profile::RadioProfile;
+use crate::ranging::positioning::{Anchor, Position};
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 
-use ledswarm_protocol::InternalMessage;
+/// Tag of the events about running games.
+pub const GAME: &str = "game";
+/// Tag of the events about controllers joining and leaving the mesh.
+pub const MESH: &str = "mesh";
+/// Tag of the events about where the controllers are.
+pub const POSITIONS: &str = "positions";
+/// Tag of the events about the signal quality of the radio links.
+pub const LINKS: &str = "links";
+
+/// Something that happened in the swarm, published by the controller for the WebSocket clients.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub enum Event {
+    /// The master scheduled a message of a WebSocket client for the given network time, `delay_ms` from now.
+    Scheduled { at_us: u64, delay_ms: u32 },
+    /// A controller was knocked out of the running round.
+    Eliminated { id: u16 },
+    /// A controller confirmed that it got the start of the running round.
+    RoundStarted { id: u16 },
+    /// A controller never confirmed the start of the running round, and was left out of it.
+    RoundMissed { id: u16 },
+    /// The running round ended, along with the mesh ID of the winner if there is one.
+    RoundOver { winner: Option<u16> },
+    /// A controller joined the mesh, or came back after it was lost.
+    Joined { id: u16, unique_id: String },
+    /// A controller sent a heartbeat, along with the share of recent heartbeats which arrived and its battery level.
+    Heartbeat { id: u16, link_quality: f32, battery_percent: Option<u8> },
+    /// A controller missed its recent heartbeats.
+    Stale { id: u16 },
+    /// A controller has not been heard from for too long and left the mesh.
+    Left { id: u16 },
+    /// The master made a controller an anchor at the given coordinates, or a roaming controller again.
+    Anchor { id: u16, anchor: Option<Anchor> },
+    /// The number of controllers in each team of the running Territory round changed.
+    TeamScores { scores: Vec<u16> },
+    /// The running Territory round ended, along with the winning team if there is one.
+    TerritoryOver { winner: Option<u8>, scores: Vec<u16> },
+    /// The radio of this controller moved to another profile.
+    RadioProfile { profile: RadioProfile },
+    /// This controller calibrated its antenna delays against a reference controller, whose measurements were off by
+    /// `bias_m` before and spread by `residual_m` around the known distance.
+    Calibrated { reference: u16, bias_m: f32, residual_m: f32, tx_antenna_delay: u16, rx_antenna_delay: u16 },
+    /// A calibration of the antenna delays got too few measurements with the reference controller in time.
+    CalibrationFailed { reference: u16, samples: usize },
+    /// The master placed the controllers of the mesh relative to itself, with the root mean square error of the
+    /// distances it placed them from and the number of distances it dropped as outliers.
+    Positions { positions: Vec<Position>, residual_m: f32, outliers: usize },
+    /// This controller located itself in the venue from its distances to the given number of anchors, with the root
+    /// mean square error of these distances.
+    Located { position: Position, residual_m: f32, anchors: usize },
+    /// The signal quality of the frames this controller recently received from each peer.
+    Links { links: Vec<LinkStats> },
+}
+
+/// The channels of the subscribers of each tag.
+type Subscribers = HashMap<String, Vec<Sender<(String, Event)>>>;
 
 pub struct EventBus {
-    subscribers: Arc<Mutex<HashMap<String, Vec<Sender<(String, InternalMessage)>>>>>,
+    subscribers: Arc<Mutex<Subscribers>>,
 }
 
 impl EventBus {
@@ -15,19 +74,28 @@ impl EventBus {
         }
     }
 
-    async fn subscribe(&self, tag: &str) -> Receiver<(String, InternalMessage)> {
+    pub fn subscribe(&self, tag: &str) -> Receiver<(String, Event)> {
         let (tx, rx) = unbounded();
         let mut subs = self.subscribers.lock().unwrap();
         subs.entry(tag.to_string()).or_default().push(tx);
         rx
     }
 
-    async fn publish(&self, tag: &str, event: InternalMessage) {
-        let subs = self.subscribers.lock().unwrap();
-        if let Some(subscribers) = subs.get(tag) {
-            for tx in subscribers {
-                let _ = tx.send((tag.to_string(), event.clone())).await;
-            }
+    /// Receive the events of all the given tags through a single channel.
+    pub fn subscribe_many(&self, tags: &[&str]) -> Receiver<(String, Event)> {
+        let (tx, rx) = unbounded();
+        let mut subs = self.subscribers.lock().unwrap();
+        for tag in tags {
+            subs.entry(tag.to_string()).or_default().push(tx.clone());
+        }
+        rx
+    }
+
+    /// Hand an event to all subscribers of the tag without blocking, forgetting the ones which went away.
+    pub fn publish(&self, tag: &str, event: Event) {
+        let mut subs = self.subscribers.lock().unwrap();
+        if let Some(subscribers) = subs.get_mut(tag) {
+            subscribers.retain(|tx| tx.try_send((tag.to_string(), event.clone())).is_ok());
         }
     }
-}
\ No newline at end of file
+}
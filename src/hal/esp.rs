@@ -0,0 +1,107 @@
+//! Implementations of the hardware traits for the ESP32 peripherals through ESP-IDF.
+
+use adxl343::accelerometer::Accelerometer;
+use adxl343::Adxl343;
+use accelerometer::vector::F32x3;
+use esp_idf_hal::delay::Delay;
+use esp_idf_hal::i2c::I2cDriver;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use smart_leds_trait::{SmartLedsWrite, White};
+use ws2812_esp32_rmt_driver::driver::color::LedPixelColorGrbw32;
+use ws2812_esp32_rmt_driver::{LedPixelEsp32Rmt, RGBW8};
+
+use crate::network::wifi::WifiController;
+
+use super::{AccelerometerSource, Clock, LedSink, Storage, WifiManager};
+
+/// The NeoPixel Jewel driven through the RMT peripheral.
+pub struct Ws2812Sink {
+    driver: LedPixelEsp32Rmt<RGBW8, LedPixelColorGrbw32>,
+}
+
+impl Ws2812Sink {
+    pub fn new(channel: u8, pin: u32) -> Self {
+        Self {
+            driver: LedPixelEsp32Rmt::<RGBW8, LedPixelColorGrbw32>::new(channel, pin).unwrap(),
+        }
+    }
+}
+
+impl LedSink for Ws2812Sink {
+    fn write_rgbw(&mut self, color: (u8, u8, u8, u8)) {
+        let pixels = std::iter::repeat(RGBW8::from((
+            color.0,
+            color.1,
+            color.2,
+            White(color.3),
+        ))).take(8);
+
+        self.driver.write(pixels).unwrap();
+    }
+}
+
+impl AccelerometerSource for Adxl343<I2cDriver<'static>> {
+    fn read(&mut self) -> anyhow::Result<F32x3> {
+        self.accel_norm().map_err(|e| anyhow::anyhow!("Failed to read accelerometer: {:?}", e))
+    }
+}
+
+impl<'a> WifiManager for WifiController<'a> {
+    fn join_or_create_network(&mut self) -> anyhow::Result<()> {
+        futures::executor::block_on(WifiController::join_or_create_network(self))?;
+        Ok(())
+    }
+
+    fn take_over_network(&mut self) -> anyhow::Result<()> {
+        futures::executor::block_on(WifiController::take_over_network(self))?;
+        Ok(())
+    }
+}
+
+/// The high-resolution `esp_timer`, which counts microseconds since boot.
+pub struct EspClock {
+    delay: Delay,
+}
+
+impl EspClock {
+    pub fn new() -> Self {
+        Self {
+            delay: Delay::new_default(),
+        }
+    }
+}
+
+impl Clock for EspClock {
+    fn now_us(&self) -> u64 {
+        unsafe { esp_idf_svc::sys::esp_timer_get_time() as u64 }
+    }
+
+    fn delay_us(&self, us: u32) {
+        self.delay.delay_us(us);
+    }
+}
+
+/// The `nvs` partition, keeping all values in the `ledswarm` namespace.
+pub struct NvsStorage {
+    nvs: EspNvs<NvsDefault>,
+}
+
+impl NvsStorage {
+    pub fn new(partition: EspDefaultNvsPartition) -> anyhow::Result<Self> {
+        Ok(Self {
+            nvs: EspNvs::new(partition, "ledswarm", true)?,
+        })
+    }
+}
+
+impl Storage for NvsStorage {
+    fn load(&mut self, key: &str) -> anyhow::Result<Option<String>> {
+        let mut buffer = [0u8; 64];
+        Ok(self.nvs.get_str(key, &mut buffer)?.map(|value| value.to_string()))
+    }
+
+    fn store(&mut self, key: &str, value: &str) -> anyhow::Result<()> {
+        self.nvs.set_str(key, value)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,255 @@
+//! In-memory implementations of the hardware traits for running the controller on a host machine.
+//!
+//! All of them hand out cloneable handles to their internal state, so a test or simulator can keep one handle to
+//! inspect what the controller did while the controller owns the other.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Instant;
+
+use accelerometer::vector::F32x3;
+
+use ledswarm_protocol::Frame;
+
+use crate::controller::ControllerMode;
+use crate::radio::{RadioCommand, RadioEvent};
+
+use super::{AccelerometerSource, BatteryGauge, Clock, LedSink, Storage, UwbTransport, WifiManager};
+
+/// Remembers the last color written to the LEDs.
+#[derive(Clone, Default)]
+pub struct MemoryLed {
+    color: Arc<Mutex<(u8, u8, u8, u8)>>,
+}
+
+impl MemoryLed {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The RGBW color currently shown on the LEDs.
+    pub fn color(&self) -> (u8, u8, u8, u8) {
+        *self.color.lock().unwrap()
+    }
+}
+
+impl LedSink for MemoryLed {
+    fn write_rgbw(&mut self, color: (u8, u8, u8, u8)) {
+        *self.color.lock().unwrap() = color;
+    }
+}
+
+/// Collects all commands the controller sends to the radio, and plays back events queued by the test.
+#[derive(Clone, Default)]
+pub struct MemoryTransport {
+    commands: Arc<Mutex<VecDeque<RadioCommand>>>,
+    events: Arc<Mutex<VecDeque<RadioEvent>>>,
+}
+
+impl MemoryTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Remove and return all commands sent since the last call.
+    pub fn take_commands(&self) -> Vec<RadioCommand> {
+        self.commands.lock().unwrap().drain(..).collect()
+    }
+
+    /// Remove and return all frames sent since the last call, discarding any other commands.
+    pub fn take_sent(&self) -> Vec<Frame> {
+        self.take_commands()
+            .into_iter()
+            .filter_map(|command| match command {
+                RadioCommand::Transmit(frame) => Some(frame),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Queue an event which the controller will receive as if the radio had reported it.
+    pub fn push_event(&self, event: RadioEvent) {
+        self.events.lock().unwrap().push_back(event);
+    }
+}
+
+impl UwbTransport for MemoryTransport {
+    fn command(&mut self, command: RadioCommand) -> anyhow::Result<()> {
+        self.commands.lock().unwrap().push_back(command);
+        Ok(())
+    }
+
+    fn poll_event(&mut self) -> Option<RadioEvent> {
+        self.events.lock().unwrap().pop_front()
+    }
+}
+
+/// Plays back a queue of accelerometer readings, repeating the resting reading once the queue runs dry.
+#[derive(Clone)]
+pub struct ScriptedAccelerometer {
+    readings: Arc<Mutex<VecDeque<F32x3>>>,
+    resting: F32x3,
+}
+
+impl ScriptedAccelerometer {
+    pub fn new() -> Self {
+        Self {
+            readings: Arc::new(Mutex::new(VecDeque::new())),
+            resting: F32x3 { x: 0.0, y: 0.0, z: 1.0 },
+        }
+    }
+
+    /// Queue a reading which will be returned by a later call to [`AccelerometerSource::read`].
+    pub fn push(&self, reading: F32x3) {
+        self.readings.lock().unwrap().push_back(reading);
+    }
+}
+
+impl Default for ScriptedAccelerometer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AccelerometerSource for ScriptedAccelerometer {
+    fn read(&mut self) -> anyhow::Result<F32x3> {
+        Ok(self.readings.lock().unwrap().pop_front().unwrap_or(self.resting))
+    }
+}
+
+/// A battery whose charge is set from the outside.
+#[derive(Clone)]
+pub struct MemoryBattery {
+    percent: Arc<AtomicU8>,
+}
+
+impl MemoryBattery {
+    pub fn new(percent: u8) -> Self {
+        Self {
+            percent: Arc::new(AtomicU8::new(percent)),
+        }
+    }
+
+    pub fn set_percent(&self, percent: u8) {
+        self.percent.store(percent.min(100), Ordering::SeqCst);
+    }
+}
+
+impl BatteryGauge for MemoryBattery {
+    fn percent(&mut self) -> anyhow::Result<u8> {
+        Ok(self.percent.load(Ordering::SeqCst))
+    }
+}
+
+/// Pretends to scan for the LEDswarm network, which "exists" once any controller sharing the same `air` created it.
+pub struct MemoryWifi {
+    air: Arc<AtomicBool>,
+    tx: mpsc::Sender<ControllerMode>,
+}
+
+impl MemoryWifi {
+    pub fn new(air: Arc<AtomicBool>, tx: mpsc::Sender<ControllerMode>) -> Self {
+        Self {
+            air,
+            tx,
+        }
+    }
+}
+
+impl WifiManager for MemoryWifi {
+    fn join_or_create_network(&mut self) -> anyhow::Result<()> {
+        if !self.air.swap(true, Ordering::SeqCst) {
+            self.tx.send(ControllerMode::ServerMeditation)?;
+        }
+
+        Ok(())
+    }
+
+    fn take_over_network(&mut self) -> anyhow::Result<()> {
+        self.air.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+/// Keeps stored values in memory, so they survive a simulated reboot as long as a handle is kept around.
+#[derive(Clone, Default)]
+pub struct MemoryStorage {
+    values: Arc<Mutex<HashMap<String, String>>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn load(&mut self, key: &str) -> anyhow::Result<Option<String>> {
+        Ok(self.values.lock().unwrap().get(key).cloned())
+    }
+
+    fn store(&mut self, key: &str, value: &str) -> anyhow::Result<()> {
+        self.values.lock().unwrap().insert(key.to_string(), value.to_string());
+        Ok(())
+    }
+}
+
+/// A clock backed by [`std::time::Instant`] which really sleeps.
+pub struct SystemClock {
+    start: Instant,
+}
+
+impl SystemClock {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for SystemClock {
+    fn now_us(&self) -> u64 {
+        self.start.elapsed().as_micros() as u64
+    }
+
+    fn delay_us(&self, us: u32) {
+        std::thread::sleep(std::time::Duration::from_micros(us as u64));
+    }
+}
+
+/// A clock which only moves when told to, either explicitly or by delaying on it.
+#[derive(Clone, Default)]
+pub struct ManualClock {
+    now_us: Arc<AtomicU64>,
+}
+
+impl ManualClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn advance_us(&self, us: u64) {
+        self.now_us.fetch_add(us, Ordering::SeqCst);
+    }
+
+    pub fn set_us(&self, us: u64) {
+        self.now_us.store(us, Ordering::SeqCst);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now_us(&self) -> u64 {
+        self.now_us.load(Ordering::SeqCst)
+    }
+
+    fn delay_us(&self, us: u32) {
+        self.advance_us(us as u64);
+    }
+}
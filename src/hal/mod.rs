@@ -0,0 +1,72 @@
+//! Hardware abstraction traits which decouple the controller logic from the ESP32 peripherals.
+//!
+//! The [`Controller`](crate::controller::Controller) only talks to its LEDs, radio, Wi-Fi and clock through these
+//! traits. The ESP-IDF implementations in [`esp`] are compiled with the `esp-idf` feature, while [`memory`] provides
+//! in-memory implementations so the mode and game logic can be exercised on a host machine.
+
+use accelerometer::vector::F32x3;
+
+use ledswarm_protocol::Frame;
+
+use crate::radio::{RadioCommand, RadioEvent};
+
+#[cfg(feature = "esp-idf")]
+pub mod esp;
+pub mod memory;
+
+/// A sink for the final RGBW color of the LEDs, with the brightness already applied.
+pub trait LedSink {
+    /// Show the same RGBW color on all pixels.
+    fn write_rgbw(&mut self, color: (u8, u8, u8, u8));
+}
+
+/// Hands frames and commands over to the ultra-wideband radio and collects what it reports back.
+pub trait UwbTransport {
+    /// Queue a command for the radio, failing if it can't accept any more commands.
+    fn command(&mut self, command: RadioCommand) -> anyhow::Result<()>;
+
+    /// Take the next event reported by the radio, if there is one.
+    fn poll_event(&mut self) -> Option<RadioEvent>;
+
+    /// Queue a frame for transmission.
+    fn send(&mut self, frame: Frame) -> anyhow::Result<()> {
+        self.command(RadioCommand::Transmit(frame))
+    }
+}
+
+/// A source of normalized accelerometer readings in multiples of g.
+pub trait AccelerometerSource {
+    fn read(&mut self) -> anyhow::Result<F32x3>;
+}
+
+/// Measures the charge left in the battery.
+pub trait BatteryGauge {
+    /// The remaining charge as a percentage between 0 and 100.
+    fn percent(&mut self) -> anyhow::Result<u8>;
+}
+
+/// Brings up the Wi-Fi network used by the web interface.
+pub trait WifiManager {
+    /// Join an existing LEDswarm network, or create a new one and put the controller into server meditation.
+    fn join_or_create_network(&mut self) -> anyhow::Result<()>;
+
+    /// Host the network in place of a master which disappeared, without entering server meditation.
+    fn take_over_network(&mut self) -> anyhow::Result<()>;
+}
+
+/// Key-value storage which survives reboots, like the `nvs` partition of the ESP32.
+pub trait Storage {
+    /// The value stored under the key, if there is one.
+    fn load(&mut self, key: &str) -> anyhow::Result<Option<String>>;
+
+    fn store(&mut self, key: &str, value: &str) -> anyhow::Result<()>;
+}
+
+/// A monotonic time source which can also put the calling thread to sleep.
+pub trait Clock {
+    /// Microseconds elapsed since an arbitrary but fixed point in time.
+    fn now_us(&self) -> u64;
+
+    /// Block for at least the given amount of microseconds.
+    fn delay_us(&self, us: u32);
+}
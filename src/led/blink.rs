@@ -102,6 +102,30 @@ impl LedTimeline {
             states,
         }
     }
+
+    /// Quickly flashes green and white for the winner of a round.
+    pub fn victory() -> Self {
+        Self::new(vec![
+            LedState::all(150, (0, 255, 0, 0)),
+            LedState::all(150, (0, 0, 0, 255)),
+        ])
+    }
+
+    /// Pulses in the given color, like the color of the team which won a round.
+    pub fn pulse(color: (u8, u8, u8, u8)) -> Self {
+        Self::new(vec![
+            LedState::all(600, color),
+            LedState::all(300, (color.0 / 6, color.1 / 6, color.2 / 6, color.3 / 6)),
+        ])
+    }
+
+    /// Slowly pulses red for everyone who lost a round.
+    pub fn defeat() -> Self {
+        Self::new(vec![
+            LedState::all(800, (255, 0, 0, 0)),
+            LedState::all(400, (40, 0, 0, 0)),
+        ])
+    }
 }
 
 impl From<&ControllerMode> for LedTimeline {
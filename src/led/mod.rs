@@ -1,32 +1,29 @@
-use smart_leds_trait::{SmartLedsWrite, White};
-use ws2812_esp32_rmt_driver::driver::color::LedPixelColorGrbw32;
-use ws2812_esp32_rmt_driver::{LedPixelEsp32Rmt, RGBW8};
-
 use crate::controller::ControllerMode;
+use crate::hal::LedSink;
 
 pub mod blink;
 
 use blink::{LedState, LedTimeline};
 
 pub struct LedConfig {
-    pub pin: u32,
     pub intensity: f32,
 }
 
 /// Driver for the NeoPixel Jewel, a small two-inch circular PCB with seven SK6812 LEDs.
 ///
-/// This struct abstracts the interface to the `ws2812_esp32_rmt_driver` to provide a method API
+/// This struct abstracts the interface to an [`LedSink`] to provide a method API, on the board usually the
+/// [`Ws2812Sink`](crate::hal::esp::Ws2812Sink) driving the LEDs through the RMT peripheral.
 pub struct Led {
-    driver: LedPixelEsp32Rmt::<RGBW8, LedPixelColorGrbw32>,
+    sink: Box<dyn LedSink>,
     last_controller_mode: Option<ControllerMode>,
     timeline: LedTimeline,
     pub config: LedConfig,
 }
 
 impl Led {
-    pub fn new(config: LedConfig) -> Self {
+    pub fn new(config: LedConfig, sink: Box<dyn LedSink>) -> Self {
         Self {
-            driver: LedPixelEsp32Rmt::<RGBW8, LedPixelColorGrbw32>::new(0, config.pin).unwrap(),
+            sink,
             last_controller_mode: None,
             timeline: LedTimeline::new(vec![
                 LedState::all(1000, (0, 255, 255, 0)),
@@ -57,13 +54,11 @@ impl Led {
         blue: u8,
         white: u8
     ) {
-        let pixels = std::iter::repeat(RGBW8::from((
+        self.sink.write_rgbw((
             (red as f32 * self.config.intensity) as u8,
             (green as f32 * self.config.intensity) as u8,
             (blue as f32 * self.config.intensity) as u8,
-            White((white as f32 * self.config.intensity) as u8,),
-        ))).take(8);
-
-        self.driver.write(pixels).unwrap();
+            (white as f32 * self.config.intensity) as u8,
+        ));
     }
 }
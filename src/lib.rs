@@ -0,0 +1,39 @@
+//! ![banner](https://ledswarm-book.s3.nl-ams.scw.cloud/Slim_LEDswarm_Banner2.svg)
+//!
+//! The official firmware for ESP32-based LEDswarm controller boards.
+//!
+//! This crate implements a main loop along with several peripheral threads which share memory through communicating. All of the threads communicate via channels with the main loop,
+//! using the [`InternalMessage`](ledswarm_protocol::InternalMessage) enum from the `ledswarm_protocol` library to exchange commands and data packets.
+//!
+//! The peripherals are accessed through the traits in [`hal`], so everything except the ESP-IDF drivers also builds
+//! on a host machine with `--no-default-features --features std`.
+
+use serde::{Deserialize, Serialize};
+
+//pub mod display;
+pub mod configuration;
+pub mod controller;
+pub mod event_bus;
+pub mod hal;
+pub mod imu;
+pub mod led;
+pub mod mesh;
+pub mod moving_average;
+pub mod radio;
+pub mod ranging;
+pub mod rules;
+#[cfg(feature = "simulator")]
+pub mod sim;
+pub mod util;
+#[cfg(feature = "esp-idf")]
+pub mod network;
+#[cfg(feature = "esp-idf")]
+pub mod server;
+#[cfg(feature = "esp-idf")]
+pub mod uwb;
+
+#[derive(Serialize, Deserialize)]
+/// A JSON document at the server root `/` which provides basic information about the configuration of the master node.
+pub struct RootDocument {
+    pub version: String,
+}
@@ -1,9 +1,6 @@
 //! ![banner](https://ledswarm-book.s3.nl-ams.scw.cloud/Slim_LEDswarm_Banner2.svg)
 //!
-//! The official firmware for ESP32-based LEDswarm controller boards.
-//! 
-//! This crate implements a main loop along with several peripheral threads which share memory through communicating. All of the threads communicate via channels with the main loop,
-//! using the [`InternalMessage`] enum from the `ledswarm_protocol` library to exchange commands and data packets.
+//! Entry point of the LEDswarm firmware, wiring the ESP32 peripherals up to the controller.
 
 use colored::*;
 use esp_idf_hal::sys::EspError;
@@ -16,34 +13,16 @@ use esp_idf_svc::{
 };
 
 use esp_idf_svc::timer::EspTaskTimerService;
-use serde::{Deserialize, Serialize};
 
-use ledswarm_protocol::{Frame, InternalMessage};
+use ledswarm_protocol::InternalMessage;
 
-//pub mod display;
-pub mod configuration;
-pub mod controller;
-pub mod led;
-pub mod network;
-pub mod imu;
-pub mod moving_average;
-pub mod util;
-pub mod server;
-pub mod uwb;
-pub mod event_bus;
-
-use controller::Controller;
-
-use std::sync::mpsc;
+use ledswarm_firmware::controller::Controller;
+use ledswarm_firmware::hal::esp::{EspClock, NvsStorage, Ws2812Sink};
+use ledswarm_firmware::network::wifi::WifiController;
+use ledswarm_firmware::{radio, server, util, uwb};
 
 pub const STACK_SIZE: usize = 10240;
 
-#[derive(Serialize, Deserialize)]
-/// A JSON document at the server root `/` which provides basic information about the configuration of the master node.
-struct RootDocument {
-    version: String,
-}
-
 fn initialize_esp32_wifi<'a>(
     modem: esp_idf_hal::modem::Modem,
     sys_loop: EspSystemEventLoop,
@@ -78,18 +57,27 @@ fn init_firmware() -> anyhow::Result<()> {
     let sys_loop = EspSystemEventLoop::take().unwrap();
     let nvs = EspDefaultNvsPartition::take().unwrap();
 
+    let storage = NvsStorage::new(nvs.clone())?;
+
     let wifi = initialize_esp32_wifi(peripherals.modem, sys_loop.clone(), nvs.clone(), timer.clone())?;
 
     let (msg_tx, msg_rx): (flume::Sender<InternalMessage>, flume::Receiver<InternalMessage>)  = flume::bounded(512);
-    let (uwb_out_tx, uwb_out_rx): (flume::Sender<Frame>, flume::Receiver<Frame>)     = flume::bounded(512);
+    let (radio_link, radio_endpoint) = radio::link();
 
     println!("{}  Initializing controller ...", "[LEDswarm]".yellow().bold());
-    let mut controller = Controller::new(msg_rx, uwb_out_tx);
+    let mut controller = Controller::new(
+        msg_rx,
+        Box::new(radio_link),
+        Box::new(Ws2812Sink::new(0, 0)),
+        Box::new(EspClock::new()),
+        Box::new(storage),
+    );
     println!("{}  Starting controller Wi-Fi ...", "[LEDswarm]".yellow().bold());
-    futures::executor::block_on(controller.init_wifi(wifi))?;
+    let wifi_controller = WifiController::new(wifi, controller.mode_sender());
+    controller.init_wifi(Box::new(wifi_controller))?;
 
     println!("{}  Creating server endpoints ...", "[LEDswarm]".yellow().bold());
-    server::create_endpoints(msg_tx.clone())?;
+    server::create_endpoints(msg_tx.clone(), controller.command_sender(), controller.event_bus.clone())?;
     println!("{}  Starting controller IMU ...", "[LEDswarm]".yellow().bold());
 
     /*
@@ -112,7 +100,7 @@ fn init_firmware() -> anyhow::Result<()> {
     std::thread::Builder::new().stack_size(8192).spawn(move || {
         uwb::start(
             msg_tx.clone(),
-            uwb_out_rx,
+            radio_endpoint,
             spi,
             serial_out,
             serial_in,
@@ -123,25 +111,11 @@ fn init_firmware() -> anyhow::Result<()> {
         ).expect("Failed to initialize UWB");
     })?;
 
-    // Configure and Initialize Timer Drivers
-    let config = esp_idf_hal::timer::config::Config::new();
-    let mut timer1 = esp_idf_hal::timer::TimerDriver::new(peripherals.timer00, &config).unwrap();
-    let mut timer2 = esp_idf_hal::timer::TimerDriver::new(peripherals.timer10, &config).unwrap();
-
-    // Set Counter Start Value to Zero
-    timer1.set_counter(0_u64).unwrap();
-    timer2.set_counter(0_u64).unwrap();
-
-    // Enable Counter
-    timer1.enable(true).unwrap();
-    timer2.enable(true).unwrap();
-
-
     println!("{}  Launched UWB thread", "[LEDswarm]".yellow().bold());
 
     println!("{}  Starting controller event loop", "[LEDswarm]".yellow().bold());
 
-    controller.start_event_loop(timer1).expect("Failed to start controller event loop"); 
+    controller.start_event_loop().expect("Failed to start controller event loop");
 
     Ok(())
 }
@@ -0,0 +1,524 @@
+//! Firmware-level messages which controllers exchange over UWB next to the `ledswarm_protocol` frames.
+//!
+//! Mesh packets travel in IEEE 802.15.4 data frames like the protocol frames do, and are told apart from them by a
+//! two-byte magic prefix in front of their JSON encoding. Payloads too large for a single frame travel in
+//! [`Fragment`]s, encrypted payloads in [`Sealed`] envelopes and payloads passed on for other controllers in [`Relayed`]
+//! envelopes, which have magic prefixes of their own.
+
+use serde::{Deserialize, Serialize};
+
+use ledswarm_protocol::Frame;
+
+use crate::radio::tdma::Schedule;
+use crate::ranging::positioning::{Anchor, Position};
+
+/// The destination address of packets meant for every node in range.
+pub const BROADCAST: u16 = 0xFFFF;
+
+/// The PAN ID of packets from controllers which aren't part of a session yet, which every controller accepts.
+pub const BROADCAST_PAN_ID: u16 = 0xFFFF;
+
+/// Prefix of every mesh packet payload, chosen so it can't be the start of a JSON document.
+const MAGIC: [u8; 2] = [0xA5, 0x5A];
+
+/// Prefix of every fragment payload.
+const FRAGMENT_MAGIC: [u8; 2] = [0xA5, 0x5F];
+
+/// Length of the fragment prefix, the sender, the receiver, the payload ID, the index and the number of fragments.
+const FRAGMENT_HEADER_LEN: usize = 10;
+
+/// Prefix of every sealed payload.
+const SEALED_MAGIC: [u8; 2] = [0xA5, 0x5C];
+
+/// Length of the sealed prefix, the key, the sender, the receiver, the epoch and the counter.
+const SEALED_HEADER_LEN: usize = 19;
+
+/// Prefix of every relayed payload.
+const RELAYED_MAGIC: [u8; 2] = [0xA5, 0x5E];
+
+/// Length of the relayed prefix, the relaying controller, the next hop, the origin, the destination, the hops left,
+/// the time held, the epoch and counter of the relaying controller and the tag authenticating all of it.
+const RELAYED_HEADER_LEN: usize = 35;
+
+/// Length of the tag which authenticates a relayed payload on every hop.
+const RELAYED_TAG_LEN: usize = 8;
+
+/// The largest frame the DW3000 sends and receives, including the MAC header and the frame check sequence.
+pub const MAX_FRAME_LEN: usize = 1023;
+
+/// Length of the MAC header written by [`mac_header`].
+const MAC_HEADER_LEN: usize = 9;
+
+/// Length of the frame check sequence the radio appends to every frame.
+const FCS_LEN: usize = 2;
+
+/// The most bytes of a payload a single fragment carries.
+pub const MAX_FRAGMENT_DATA: usize = MAX_FRAME_LEN - MAC_HEADER_LEN - FCS_LEN - FRAGMENT_HEADER_LEN;
+
+/// IEEE 802.15.4 frame control for a 2006 data frame with PAN ID compression and short addresses.
+const FRAME_CONTROL: u16 = 0x9841;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum MeshMessage {
+    /// Opens a superframe and tells every controller in range when it may transmit.
+    Beacon { schedule: Schedule },
+    /// Starts a double-sided two-way ranging exchange.
+    RangingPoll { seq: u8 },
+    /// Answers a poll after a fixed reply delay.
+    RangingResponse { seq: u8 },
+    /// Completes the exchange with the initiator's timestamps, in DW3000 time units.
+    RangingFinal {
+        seq: u8,
+        poll_tx: u64,
+        response_rx: u64,
+        final_tx: u64,
+    },
+    /// Tells the initiator about the distance the responder computed.
+    RangingReport {
+        seq: u8,
+        distance_m: f32,
+        quality: f32,
+        clock_drift_ppm: f32,
+    },
+    /// Confirms that a packet asking for it arrived, by its sequence number.
+    Ack { seq: u16 },
+    /// A message for the controller rather than the radio.
+    Session(SessionMessage),
+}
+
+/// The part a rejoining controller plays in the running round.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Resume {
+    LastOneStanding { is_active: bool },
+    Territory { team: u8, remaining_ms: u32 },
+}
+
+/// Messages between the controllers themselves, which the radio passes on to the controller.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum SessionMessage {
+    /// A controller looking for a mesh asks the master to let it join, identifying itself by its persistent unique ID
+    /// and telling where it is fixed if it is an anchor.
+    Join {
+        unique_id: String,
+        #[serde(default)]
+        anchor: Option<Anchor>,
+    },
+    /// The master let the controller with the unique ID join under the assigned mesh ID.
+    ///
+    /// A controller which rejoins after a reboot gets its old mesh ID back, along with its part in the running round.
+    /// Joining controllers repeat their join until they are welcomed, so the welcome itself needs no acknowledgement.
+    /// It is sealed with the swarm key and hands over the nonce the key of the session is derived from.
+    Welcome {
+        unique_id: String,
+        assigned_id: u16,
+        resume: Option<Resume>,
+        session_nonce: [u8; 16],
+    },
+    /// The keepalive of the master listing every controller in the mesh as pairs of mesh ID and unique ID, so the
+    /// clients notice when the master is gone and can agree on who takes over, along with where the anchors are.
+    Roster {
+        members: Vec<(u16, String)>,
+        #[serde(default)]
+        anchors: Vec<Position>,
+    },
+    /// The periodic sign of life of a client to the master, along with its battery level if it can measure it and its
+    /// distances to the other controllers as pairs of mesh ID and meters.
+    Heartbeat {
+        battery_percent: Option<u8>,
+        #[serde(default)]
+        distances: Vec<(u16, f32)>,
+    },
+    /// A client whose radio keeps failing to sync to the scrambled timestamp sequences of the others asks the master to
+    /// let it range without them.
+    StsFailed,
+    /// The master made the receiving controller an anchor at the given coordinates, or a roaming controller again.
+    Anchor { anchor: Option<Anchor> },
+    /// The master changed the brightness of the LEDs in the session, or will at the given network time.
+    Brightness {
+        brightness: f32,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        at_us: Option<u64>,
+    },
+    /// The master started a round of the named game mode, or will at the given network time.
+    StartRound {
+        game: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        at_us: Option<u64>,
+    },
+    /// A client was knocked out of the running Last One Standing round.
+    Eliminated,
+    /// The master ended the running round, along with the mesh ID of the winner if there is one.
+    RoundOver { winner: Option<u16> },
+    /// The state of the running Territory round, which the master repeats so late or missed updates catch up.
+    Territory {
+        /// The team of every controller in the round as pairs of mesh ID and team.
+        teams: Vec<(u16, u8)>,
+        remaining_ms: u32,
+        /// The network time at which a scheduled round starts, with the whole `remaining_ms` still ahead of it.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        at_us: Option<u64>,
+    },
+    /// A controller switched to another team in the running Territory round.
+    ///
+    /// Clients report their own switch to the master, which passes every switch on to the whole mesh.
+    Team { id: u16, team: u8 },
+    /// The master ended the running Territory round, along with the winning team if there is one.
+    TerritoryOver { winner: Option<u8> },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MeshPacket {
+    /// The mesh ID of the sending controller.
+    pub src: u16,
+    /// The mesh ID of the receiving controller, or [`BROADCAST`].
+    pub dst: u16,
+    /// The PAN ID of the session, which travels in the MAC header rather than the JSON encoding.
+    #[serde(skip)]
+    pub pan_id: u16,
+    /// The sequence number of a packet the receiver has to acknowledge, see [`crate::radio::delivery`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u16>,
+    pub message: MeshMessage,
+}
+
+impl MeshPacket {
+    pub fn new(src: u16, dst: u16, message: MeshMessage) -> Self {
+        Self {
+            pan_id: BROADCAST_PAN_ID,
+            src,
+            dst,
+            seq: None,
+            message,
+        }
+    }
+
+    /// Whether a controller with the given mesh ID should process this packet.
+    pub fn is_for(&self, id: u16) -> bool {
+        self.dst == id || self.dst == BROADCAST
+    }
+}
+
+/// A numbered piece of a payload which is too large for a single frame.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fragment {
+    /// The PAN ID of the session, which travels in the MAC header.
+    pub pan_id: u16,
+    /// The mesh ID of the sending controller.
+    pub src: u16,
+    /// The mesh ID of the receiving controller, or [`BROADCAST`].
+    pub dst: u16,
+    /// Tells the payloads of the same sender apart.
+    pub id: u16,
+    pub index: u8,
+    /// The number of fragments the payload was split into.
+    pub count: u8,
+    pub data: Vec<u8>,
+}
+
+impl Fragment {
+    /// Whether a controller with the given mesh ID should collect this fragment.
+    pub fn is_for(&self, id: u16) -> bool {
+        self.dst == id || self.dst == BROADCAST
+    }
+}
+
+/// The key a [`Sealed`] payload is encrypted with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum KeyKind {
+    /// The key shared by all controllers of the swarm, which protects joining a session.
+    Swarm,
+    /// The key derived for the running session, which protects everything else.
+    Session,
+}
+
+/// An encrypted and authenticated payload, see [`crate::radio::security`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sealed {
+    /// The PAN ID of the session, which travels in the MAC header.
+    pub pan_id: u16,
+    pub key: KeyKind,
+    /// The mesh ID of the sending controller.
+    pub src: u16,
+    /// The mesh ID of the receiving controller, or [`BROADCAST`].
+    pub dst: u16,
+    /// Drawn by the sender on every boot, so its counter never repeats under the same key.
+    pub epoch: [u8; 8],
+    /// Counts up with every payload the sender seals.
+    pub counter: u32,
+    /// The encrypted payload followed by its authentication tag.
+    pub ciphertext: Vec<u8>,
+}
+
+impl Sealed {
+    /// Whether a controller with the given mesh ID should open this payload.
+    pub fn is_for(&self, id: u16) -> bool {
+        self.dst == id || self.dst == BROADCAST
+    }
+
+    /// The encoded header in front of the ciphertext, starting with the magic prefix.
+    pub fn header(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(SEALED_HEADER_LEN);
+        bytes.extend_from_slice(&SEALED_MAGIC);
+        bytes.push(match self.key {
+            KeyKind::Swarm => 0,
+            KeyKind::Session => 1,
+        });
+        bytes.extend_from_slice(&self.src.to_le_bytes());
+        bytes.extend_from_slice(&self.dst.to_le_bytes());
+        bytes.extend_from_slice(&self.epoch);
+        bytes.extend_from_slice(&self.counter.to_le_bytes());
+        bytes
+    }
+}
+
+/// A payload passed on by one controller for another one out of range of its destination, see [`crate::radio::relay`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Relayed {
+    /// The PAN ID of the session, which travels in the MAC header.
+    pub pan_id: u16,
+    /// The mesh ID of the controller sending this copy.
+    pub src: u16,
+    /// The mesh ID of the controller which should take this copy, or [`BROADCAST`] for everyone in range.
+    pub via: u16,
+    /// The mesh ID of the controller the payload comes from.
+    pub origin: u16,
+    /// The mesh ID of the controller the payload is meant for, or [`BROADCAST`].
+    pub dst: u16,
+    /// How many more times the payload may be passed on.
+    pub ttl: u8,
+    /// How long the controllers passing the payload on held it so far, in microseconds.
+    pub held_us: u32,
+    /// The epoch and counter of the controller sending this copy, which it signed the copy with.
+    pub epoch: [u8; 8],
+    pub counter: u32,
+    /// Authenticates everything else of this copy under the swarm key, see [`crate::radio::security`].
+    pub tag: [u8; RELAYED_TAG_LEN],
+    /// The payload as encoded by [`Payload::to_message_bytes`].
+    pub data: Vec<u8>,
+}
+
+impl Relayed {
+    /// Whether a controller with the given mesh ID should take this copy.
+    pub fn is_for(&self, id: u16) -> bool {
+        self.via == id || self.via == BROADCAST
+    }
+
+    /// The encoded header in front of the payload, starting with the magic prefix.
+    pub fn header(&self) -> Vec<u8> {
+        let mut bytes = self.signed_header();
+        bytes.extend_from_slice(&self.tag);
+        bytes
+    }
+
+    /// The encoded header up to the tag, which the tag authenticates along with the payload.
+    pub fn signed_header(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(RELAYED_HEADER_LEN);
+        bytes.extend_from_slice(&RELAYED_MAGIC);
+        bytes.extend_from_slice(&self.src.to_le_bytes());
+        bytes.extend_from_slice(&self.via.to_le_bytes());
+        bytes.extend_from_slice(&self.origin.to_le_bytes());
+        bytes.extend_from_slice(&self.dst.to_le_bytes());
+        bytes.push(self.ttl);
+        bytes.extend_from_slice(&self.held_us.to_le_bytes());
+        bytes.extend_from_slice(&self.epoch);
+        bytes.extend_from_slice(&self.counter.to_le_bytes());
+        bytes
+    }
+}
+
+/// Anything which can be sent over the radio in a single frame.
+#[derive(Debug, Clone)]
+pub enum Payload {
+    Frame(Frame),
+    Mesh(MeshPacket),
+    Fragment(Fragment),
+    Sealed(Sealed),
+    Relayed(Relayed),
+}
+
+impl Payload {
+    /// Encode the payload into the bytes handed to the DW3000 for transmission.
+    pub fn to_bytes(&self, seq: u8) -> Vec<u8> {
+        match self {
+            Payload::Frame(frame) => {
+                let bytes = Vec::from(frame.clone());
+                bytes[0 .. bytes.len() - 4].to_vec()
+            },
+            Payload::Mesh(packet) => {
+                let mut bytes = mac_header(seq, packet.pan_id, packet.dst, packet.src);
+                bytes.extend(self.to_message_bytes());
+                bytes
+            },
+            Payload::Fragment(fragment) => {
+                let mut bytes = mac_header(seq, fragment.pan_id, fragment.dst, fragment.src);
+                bytes.extend_from_slice(&FRAGMENT_MAGIC);
+                bytes.extend_from_slice(&fragment.src.to_le_bytes());
+                bytes.extend_from_slice(&fragment.dst.to_le_bytes());
+                bytes.extend_from_slice(&fragment.id.to_le_bytes());
+                bytes.push(fragment.index);
+                bytes.push(fragment.count);
+                bytes.extend_from_slice(&fragment.data);
+                bytes
+            },
+            Payload::Sealed(sealed) => {
+                let mut bytes = mac_header(seq, sealed.pan_id, sealed.dst, sealed.src);
+                bytes.extend(self.to_message_bytes());
+                bytes
+            },
+            Payload::Relayed(relayed) => {
+                let mut bytes = mac_header(seq, relayed.pan_id, relayed.via, relayed.src);
+                bytes.extend(self.to_message_bytes());
+                bytes
+            },
+        }
+    }
+
+    /// Encode the payload as [`Payload::from_mac_payload`] reads it back, which is what fragments carry in pieces.
+    pub fn to_message_bytes(&self) -> Vec<u8> {
+        match self {
+            Payload::Frame(frame) => Vec::from(frame.clone()),
+            Payload::Mesh(packet) => {
+                let mut bytes = MAGIC.to_vec();
+                bytes.extend(serde_json::to_vec(packet).expect("Mesh packets always serialize"));
+                bytes
+            },
+            Payload::Sealed(sealed) => {
+                let mut bytes = sealed.header();
+                bytes.extend_from_slice(&sealed.ciphertext);
+                bytes
+            },
+            Payload::Relayed(relayed) => {
+                let mut bytes = relayed.header();
+                bytes.extend_from_slice(&relayed.data);
+                bytes
+            },
+            Payload::Fragment(_) => unreachable!("Fragments are never split again"),
+        }
+    }
+
+    /// The PAN ID of the session the payload belongs to, unless it is a protocol frame which doesn't tell.
+    pub fn pan_id(&self) -> Option<u16> {
+        match self {
+            Payload::Frame(_) => None,
+            Payload::Mesh(packet) => Some(packet.pan_id),
+            Payload::Fragment(fragment) => Some(fragment.pan_id),
+            Payload::Sealed(sealed) => Some(sealed.pan_id),
+            Payload::Relayed(relayed) => Some(relayed.pan_id),
+        }
+    }
+
+    /// The mesh ID of the sending controller, unless it is a protocol frame which doesn't tell.
+    pub fn src(&self) -> Option<u16> {
+        match self {
+            Payload::Frame(_) => None,
+            Payload::Mesh(packet) => Some(packet.src),
+            Payload::Fragment(fragment) => Some(fragment.src),
+            Payload::Sealed(sealed) => Some(sealed.src),
+            Payload::Relayed(relayed) => Some(relayed.src),
+        }
+    }
+
+    /// Send the payload within the session with the given PAN ID.
+    ///
+    /// Sealed payloads keep the PAN ID they were sealed with, since it is authenticated along with them, and so do the
+    /// relayed ones which may carry them.
+    pub fn set_pan_id(&mut self, pan_id: u16) {
+        match self {
+            Payload::Frame(_) | Payload::Sealed(_) | Payload::Relayed(_) => {},
+            Payload::Mesh(packet) => packet.pan_id = pan_id,
+            Payload::Fragment(fragment) => fragment.pan_id = pan_id,
+        }
+    }
+
+    /// Whether the payload needs to be split into fragments to fit into a frame.
+    pub fn is_oversized(&self) -> bool {
+        self.to_bytes(0).len() + FCS_LEN > MAX_FRAME_LEN
+    }
+
+    /// Decode the MAC payload of a received frame, which was sent to the given PAN ID.
+    pub fn from_mac_payload(pan_id: u16, bytes: &[u8]) -> Option<Self> {
+        if let Some(header) = bytes.strip_prefix(&FRAGMENT_MAGIC) {
+            if header.len() < FRAGMENT_HEADER_LEN - FRAGMENT_MAGIC.len() {
+                return None;
+            }
+
+            Some(Payload::Fragment(Fragment {
+                pan_id,
+                src: u16::from_le_bytes([header[0], header[1]]),
+                dst: u16::from_le_bytes([header[2], header[3]]),
+                id: u16::from_le_bytes([header[4], header[5]]),
+                index: header[6],
+                count: header[7],
+                data: header[8 ..].to_vec(),
+            }))
+        } else if let Some(header) = bytes.strip_prefix(&SEALED_MAGIC) {
+            if header.len() < SEALED_HEADER_LEN - SEALED_MAGIC.len() {
+                return None;
+            }
+
+            Some(Payload::Sealed(Sealed {
+                pan_id,
+                key: match header[0] {
+                    0 => KeyKind::Swarm,
+                    1 => KeyKind::Session,
+                    _ => return None,
+                },
+                src: u16::from_le_bytes([header[1], header[2]]),
+                dst: u16::from_le_bytes([header[3], header[4]]),
+                epoch: header[5 .. 13].try_into().expect("The header holds an epoch of eight bytes"),
+                counter: u32::from_le_bytes([header[13], header[14], header[15], header[16]]),
+                ciphertext: header[17 ..].to_vec(),
+            }))
+        } else if let Some(header) = bytes.strip_prefix(&RELAYED_MAGIC) {
+            if header.len() < RELAYED_HEADER_LEN - RELAYED_MAGIC.len() {
+                return None;
+            }
+
+            Some(Payload::Relayed(Relayed {
+                pan_id,
+                src: u16::from_le_bytes([header[0], header[1]]),
+                via: u16::from_le_bytes([header[2], header[3]]),
+                origin: u16::from_le_bytes([header[4], header[5]]),
+                dst: u16::from_le_bytes([header[6], header[7]]),
+                ttl: header[8],
+                held_us: u32::from_le_bytes([header[9], header[10], header[11], header[12]]),
+                epoch: header[13 .. 21].try_into().expect("The header holds an epoch of eight bytes"),
+                counter: u32::from_le_bytes([header[21], header[22], header[23], header[24]]),
+                tag: header[25 .. 33].try_into().expect("The header holds a tag of eight bytes"),
+                data: header[33 ..].to_vec(),
+            }))
+        } else if let Some(json) = bytes.strip_prefix(&MAGIC) {
+            // The radio may hand over trailing bytes after the JSON document, so only read the first value.
+            serde_json::Deserializer::from_slice(json)
+                .into_iter::<MeshPacket>()
+                .next()?
+                .ok()
+                .map(|packet| Payload::Mesh(MeshPacket { pan_id, ..packet }))
+        } else {
+            Frame::try_from(bytes.to_vec()).ok().map(Payload::Frame)
+        }
+    }
+}
+
+/// The PAN ID of the session hosted by the master with the given unique ID.
+///
+/// Sessions of different masters get different PAN IDs, so they can share a room without hearing each other, and the
+/// session keeps its PAN ID when another controller takes over as master.
+pub fn session_pan_id(unique_id: &str) -> u16 {
+    // FNV-1a, which is stable across builds unlike the hasher of the standard library.
+    let hash = unique_id.bytes().fold(0x811C_9DC5u32, |hash, byte| (hash ^ byte as u32).wrapping_mul(0x0100_0193));
+    let pan_id = (hash >> 16) as u16 ^ hash as u16;
+
+    if pan_id == BROADCAST_PAN_ID { 0 } else { pan_id }
+}
+
+/// Write the MAC header of a data frame, leaving the frame check sequence to the radio.
+pub fn mac_header(seq: u8, pan_id: u16, dst: u16, src: u16) -> Vec<u8> {
+    let mut header = Vec::with_capacity(9);
+    header.extend_from_slice(&FRAME_CONTROL.to_le_bytes());
+    header.push(seq);
+    header.extend_from_slice(&pan_id.to_le_bytes());
+    header.extend_from_slice(&dst.to_le_bytes());
+    header.extend_from_slice(&src.to_le_bytes());
+    header
+}
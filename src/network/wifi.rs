@@ -98,6 +98,13 @@ impl<'a> WifiController<'a> {
         Ok(())
     }
 
+    /// Replace the network of a master which disappeared, switching from client to access point mode.
+    pub async fn take_over_network(&mut self) -> Result<(), EspError> {
+        println!("--> Taking over the LEDswarm network");
+        self.wifi.stop().await?;
+        self.create_network().await
+    }
+
     /// Initiate the controller Wi-Fi, either connecting to an existing network or creating a new one.
     pub async fn join_or_create_network(&mut self) -> Result<(), EspError> {
         self.wifi.set_configuration(&Configuration::Client(ClientConfiguration {
@@ -0,0 +1,280 @@
+//! Acknowledged delivery of session messages which must not get lost, like the start of a round.
+//!
+//! Reliable packets carry a sequence number, which every controller they are meant for answers with an
+//! [`Ack`](MeshMessage::Ack) in its next data slot, even when it already got the packet before. The sender repeats the
+//! packet with a growing delay until all of them acknowledged it or it runs out of attempts, and reports the outcome
+//! for each of them. Receivers remember which packets they recently passed on, so a repeated packet reaches the
+//! controller only once. Messages which are sent frequently anyway, like heartbeats, go without all of this.
+
+use std::collections::VecDeque;
+
+use crate::mesh::{MeshMessage, MeshPacket, SessionMessage};
+
+use super::RadioEvent;
+
+/// How often a reliable packet is sent before the recipients which didn't acknowledge it are given up.
+const MAX_ATTEMPTS: u32 = 5;
+
+/// The delay between the first attempts is doubled up to this many times.
+const MAX_BACKOFF_DOUBLINGS: u32 = 2;
+
+/// How long a received reliable packet is remembered to drop its repetitions, in microseconds.
+///
+/// Covers all attempts of the sender, but lets a controller which rebooted and numbers its packets from the start
+/// again be heard soon after.
+const DUPLICATE_WINDOW_US: u64 = 20_000_000;
+
+/// A reliable packet which not everyone acknowledged yet.
+struct Pending {
+    packet: MeshPacket,
+    /// The mesh IDs of the recipients which haven't acknowledged the packet yet.
+    awaiting: Vec<u16>,
+    attempts: u32,
+    /// When the packet is sent again, or given up after the last attempt.
+    next_us: u64,
+}
+
+pub struct Delivery {
+    next_seq: u16,
+    pending: Vec<Pending>,
+    /// Acknowledgements waiting for a data slot.
+    acks: VecDeque<MeshPacket>,
+    /// Recently received reliable packets as their sender, sequence number and time of arrival.
+    received: VecDeque<(u16, u16, u64)>,
+}
+
+impl Default for Delivery {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Delivery {
+    pub fn new() -> Self {
+        Self {
+            next_seq: 0,
+            pending: vec![],
+            acks: VecDeque::new(),
+            received: VecDeque::new(),
+        }
+    }
+
+    /// Number the packet and send it until the controllers with the given mesh IDs acknowledged it.
+    ///
+    /// Without any recipients, the packet is still sent once.
+    pub fn send(&mut self, mut packet: MeshPacket, recipients: Vec<u16>) {
+        self.next_seq = self.next_seq.wrapping_add(1);
+        packet.seq = Some(self.next_seq);
+
+        self.pending.push(Pending {
+            packet,
+            awaiting: recipients,
+            attempts: 0,
+            next_us: 0,
+        });
+    }
+
+    /// Whether an acknowledgement or a reliable packet is waiting for a data slot.
+    pub fn is_due(&self, now_us: u64) -> bool {
+        !self.acks.is_empty() || self.pending.iter().any(|pending| pending.attempts < MAX_ATTEMPTS && now_us >= pending.next_us)
+    }
+
+    /// The next acknowledgement or reliable packet to send, if one is due.
+    ///
+    /// A packet sent now is repeated after `retry_us` at the earliest, and later attempts wait longer.
+    pub fn poll(&mut self, now_us: u64, retry_us: u64) -> Option<MeshPacket> {
+        if let Some(ack) = self.acks.pop_front() {
+            return Some(ack);
+        }
+
+        let index = self.pending.iter().position(|pending| pending.attempts < MAX_ATTEMPTS && now_us >= pending.next_us)?;
+        let pending = &mut self.pending[index];
+        pending.next_us = now_us + (retry_us << pending.attempts.min(MAX_BACKOFF_DOUBLINGS));
+        pending.attempts += 1;
+
+        let packet = pending.packet.clone();
+        if pending.awaiting.is_empty() {
+            self.pending.remove(index);
+        }
+
+        Some(packet)
+    }
+
+    /// Give up the recipients of packets which ran out of attempts, reporting them as unreachable.
+    pub fn expire(&mut self, now_us: u64) -> Vec<RadioEvent> {
+        let (expired, pending) = self.pending
+            .drain(..)
+            .partition(|pending| pending.attempts >= MAX_ATTEMPTS && now_us >= pending.next_us);
+        self.pending = pending;
+
+        expired.into_iter().flat_map(undelivered).collect()
+    }
+
+    /// Stop waiting for a controller which left, reporting the packets it never acknowledged.
+    pub fn forget(&mut self, peer: u16) -> Vec<RadioEvent> {
+        let mut events = vec![];
+
+        for pending in self.pending.iter_mut() {
+            if let Some(index) = pending.awaiting.iter().position(|awaiting| *awaiting == peer) {
+                pending.awaiting.remove(index);
+                events.extend(session_event(&pending.packet, |message| RadioEvent::Undelivered { dst: peer, message }));
+            }
+        }
+        self.pending.retain(|pending| !pending.awaiting.is_empty());
+
+        events
+    }
+
+    /// Acknowledge a reliable packet received by the controller with the given mesh ID.
+    ///
+    /// Returns whether the packet is new, rather than a repetition the controller already got.
+    pub fn on_received(&mut self, own_id: u16, packet: &MeshPacket, seq: u16, now_us: u64) -> bool {
+        // Every copy is acknowledged, since the acknowledgement of the first one may have been lost.
+        self.acks.push_back(MeshPacket::new(own_id, packet.src, MeshMessage::Ack { seq }));
+
+        while self.received.front().is_some_and(|(_, _, at_us)| now_us - at_us >= DUPLICATE_WINDOW_US) {
+            self.received.pop_front();
+        }
+
+        if self.received.iter().any(|(src, received, _)| *src == packet.src && *received == seq) {
+            return false;
+        }
+
+        self.received.push_back((packet.src, seq, now_us));
+        true
+    }
+
+    /// Note that a recipient got a reliable packet, reporting the delivery the first time.
+    pub fn on_ack(&mut self, src: u16, seq: u16) -> Option<RadioEvent> {
+        let index = self.pending.iter().position(|pending| pending.packet.seq == Some(seq))?;
+        let pending = &mut self.pending[index];
+        let position = pending.awaiting.iter().position(|awaiting| *awaiting == src)?;
+        pending.awaiting.remove(position);
+
+        let event = session_event(&pending.packet, |message| RadioEvent::Delivered { dst: src, message });
+        if pending.awaiting.is_empty() {
+            self.pending.remove(index);
+        }
+
+        event
+    }
+}
+
+/// The event reporting the session message in the packet, if it carries one.
+fn session_event(packet: &MeshPacket, event: impl FnOnce(SessionMessage) -> RadioEvent) -> Option<RadioEvent> {
+    match &packet.message {
+        MeshMessage::Session(message) => Some(event(message.clone())),
+        _ => None,
+    }
+}
+
+/// The events reporting every recipient which never acknowledged the packet.
+fn undelivered(pending: Pending) -> Vec<RadioEvent> {
+    pending.awaiting
+        .iter()
+        .filter_map(|dst| session_event(&pending.packet, |message| RadioEvent::Undelivered { dst: *dst, message }))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::hal::memory::ManualClock;
+    use crate::hal::Clock;
+
+    use super::*;
+
+    const RETRY_US: u64 = 1_000;
+
+    fn start_round() -> MeshPacket {
+        MeshPacket::new(1, crate::mesh::BROADCAST, MeshMessage::Session(SessionMessage::StartRound {
+            game: "hot_and_cold".to_string(),
+            at_us: None,
+        }))
+    }
+
+    /// Send everything which is due at the given time, returning the sequence numbers of the reliable packets.
+    fn poll_at(delivery: &mut Delivery, clock: &ManualClock, us: u64) -> Vec<Option<u16>> {
+        clock.set_us(us);
+        std::iter::from_fn(|| delivery.poll(clock.now_us(), RETRY_US)).map(|packet| packet.seq).collect()
+    }
+
+    #[test]
+    fn packet_is_repeated_with_growing_delays_until_everyone_acknowledged_it() {
+        let clock = ManualClock::new();
+        let mut delivery = Delivery::default();
+        delivery.send(start_round(), vec![2, 3]);
+
+        assert_eq!(poll_at(&mut delivery, &clock, 0), [Some(1)]);
+        assert_eq!(poll_at(&mut delivery, &clock, 999), []);
+        assert_eq!(poll_at(&mut delivery, &clock, 1_000), [Some(1)]);
+        assert_eq!(poll_at(&mut delivery, &clock, 2_999), []);
+        assert_eq!(poll_at(&mut delivery, &clock, 3_000), [Some(1)]);
+        assert_eq!(poll_at(&mut delivery, &clock, 6_999), []);
+        assert_eq!(poll_at(&mut delivery, &clock, 7_000), [Some(1)]);
+        // The delay stops growing after two doublings.
+        assert_eq!(poll_at(&mut delivery, &clock, 10_999), []);
+
+        assert!(matches!(delivery.on_ack(2, 1), Some(RadioEvent::Delivered { dst: 2, .. })));
+        assert!(delivery.on_ack(2, 1).is_none());
+        assert_eq!(poll_at(&mut delivery, &clock, 11_000), [Some(1)]);
+
+        assert!(matches!(delivery.on_ack(3, 1), Some(RadioEvent::Delivered { dst: 3, .. })));
+        assert!(!delivery.is_due(100_000));
+        assert!(delivery.expire(100_000).is_empty());
+    }
+
+    #[test]
+    fn recipients_are_given_up_after_the_last_attempt() {
+        let clock = ManualClock::new();
+        let mut delivery = Delivery::default();
+        delivery.send(start_round(), vec![2, 3]);
+        delivery.on_ack(3, 1);
+
+        for us in [0, 1_000, 3_000, 7_000, 11_000] {
+            assert_eq!(poll_at(&mut delivery, &clock, us), [Some(1)]);
+        }
+        assert_eq!(poll_at(&mut delivery, &clock, 100_000), []);
+
+        // The last attempt still gets its time to be acknowledged.
+        assert!(delivery.expire(14_999).is_empty());
+        let events = delivery.expire(15_000);
+        assert_eq!(events.len(), 1);
+        assert!(matches!(events[0], RadioEvent::Undelivered { dst: 2, .. }));
+        assert!(delivery.expire(100_000).is_empty());
+    }
+
+    #[test]
+    fn packet_without_recipients_is_sent_once() {
+        let clock = ManualClock::new();
+        let mut delivery = Delivery::default();
+        delivery.send(start_round(), vec![]);
+
+        assert_eq!(poll_at(&mut delivery, &clock, 0), [Some(1)]);
+        assert_eq!(poll_at(&mut delivery, &clock, 100_000), []);
+        assert!(delivery.expire(100_000).is_empty());
+    }
+
+    #[test]
+    fn repetitions_are_acknowledged_but_passed_on_once() {
+        let clock = ManualClock::new();
+        let mut delivery = Delivery::default();
+        let mut packet = start_round();
+        packet.seq = Some(7);
+
+        assert!(delivery.on_received(2, &packet, 7, clock.now_us()));
+        clock.advance_us(1_000);
+        assert!(!delivery.on_received(2, &packet, 7, clock.now_us()));
+
+        let acks: Vec<_> = std::iter::from_fn(|| delivery.poll(clock.now_us(), RETRY_US)).collect();
+        assert_eq!(acks.len(), 2);
+        assert!(acks.iter().all(|ack| ack.dst == 1 && matches!(ack.message, MeshMessage::Ack { seq: 7 })));
+
+        // Other senders number their packets on their own.
+        let other = MeshPacket { src: 3, ..packet.clone() };
+        assert!(delivery.on_received(2, &other, 7, clock.now_us()));
+
+        // A sender which rebooted starts over with the same numbers.
+        clock.advance_us(DUPLICATE_WINDOW_US);
+        assert!(delivery.on_received(2, &packet, 7, clock.now_us()));
+    }
+}
@@ -0,0 +1,160 @@
+//! Signal quality of the frames received from each peer, to find dead spots in a venue.
+//!
+//! The DW3000 reports the power of every frame it receives, how much of it arrived along the first path, and how far
+//! the carrier of the sender is off from its own. A large gap between the total and the first path power means most of
+//! the signal took a detour, and the line of sight is likely blocked. The stack averages these per peer and reports
+//! them to the controller at a fixed interval.
+
+use serde::{Deserialize, Serialize};
+
+use super::profile::RadioProfile;
+use super::RadioEvent;
+
+/// Time between two reports of the links to the controller, in microseconds.
+const REPORT_INTERVAL_US: u64 = 1_000_000;
+
+/// How much a single frame moves the averages of a link.
+const AVERAGE_WEIGHT: f32 = 0.1;
+
+/// Gap between total and first path power in dB up to which the line of sight is considered clear, and from which
+/// it is considered blocked.
+const LOS_GAP_DB: f32 = 6.0;
+const NLOS_GAP_DB: f32 = 10.0;
+
+/// Frequency offset in Hz per unit of the carrier integrator, at the 6.8 Mbps data rate.
+const CARRIER_INTEGRATOR_HZ: f32 = 998.4e6 / 2.0 / 1024.0 / 131072.0;
+
+/// What the DW3000 reports about a received frame, as read from its diagnostic registers.
+#[derive(Debug, Clone, Copy)]
+pub struct RawDiagnostics {
+    /// The power of the channel impulse response.
+    pub cir_power: u32,
+    /// The amplitudes of the first three harmonics of the first path.
+    pub first_path_amplitudes: [u32; 3],
+    /// The number of preamble symbols accumulated.
+    pub preamble_count: u16,
+    /// The 21-bit carrier integrator, sign-extended.
+    pub carrier_integrator: i32,
+}
+
+/// The signal quality of a single received frame.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct LinkDiagnostics {
+    /// The estimated power of the whole frame in dBm.
+    pub rx_power_dbm: f32,
+    /// The estimated power of the frame arriving along the first path in dBm.
+    pub first_path_power_dbm: f32,
+    /// How much faster the sender's clock runs compared to this controller's, in parts per million.
+    pub clock_offset_ppm: f32,
+    /// How likely the line of sight to the sender is blocked, between 0.0 (clear) and 1.0 (blocked).
+    pub nlos: f32,
+}
+
+impl LinkDiagnostics {
+    /// Convert the register values of a frame received on the given profile, following the DW3000 user manual.
+    pub fn from_raw(raw: RawDiagnostics, profile: RadioProfile) -> Option<Self> {
+        if raw.preamble_count == 0 {
+            return None;
+        }
+
+        let correction_db = if profile.prf_mhz() == 16 { 113.8 } else { 121.7 };
+        let count_squared = (raw.preamble_count as f32).powi(2);
+        let rx_power_dbm = 10.0 * (raw.cir_power as f32 * 2f32.powi(21) / count_squared).log10() - correction_db;
+        let first_path = raw.first_path_amplitudes.iter().map(|amplitude| (*amplitude as f32).powi(2)).sum::<f32>();
+        let first_path_power_dbm = 10.0 * (first_path / count_squared).log10() - correction_db;
+
+        // The carrier integrator is positive when the sender's clock runs faster.
+        let carrier_ghz = if profile.channel() == 9 { 7.9872 } else { 6.4896 };
+        let clock_offset_ppm = raw.carrier_integrator as f32 * CARRIER_INTEGRATOR_HZ / carrier_ghz / 1e3;
+
+        Some(Self::new(rx_power_dbm, first_path_power_dbm, clock_offset_ppm))
+    }
+
+    pub fn new(rx_power_dbm: f32, first_path_power_dbm: f32, clock_offset_ppm: f32) -> Self {
+        let gap_db = rx_power_dbm - first_path_power_dbm;
+
+        Self {
+            rx_power_dbm,
+            first_path_power_dbm,
+            clock_offset_ppm,
+            nlos: ((gap_db - LOS_GAP_DB) / (NLOS_GAP_DB - LOS_GAP_DB)).clamp(0.0, 1.0),
+        }
+    }
+}
+
+/// The signal quality of the frames from a peer, averaged over the recent ones.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LinkStats {
+    /// The mesh ID of the peer.
+    pub peer: u16,
+    /// The number of frames received from the peer since the last report.
+    pub frames: u32,
+    pub rx_power_dbm: f32,
+    pub first_path_power_dbm: f32,
+    pub clock_offset_ppm: f32,
+    pub nlos: f32,
+}
+
+impl LinkStats {
+    fn add(&mut self, diagnostics: &LinkDiagnostics) {
+        self.frames += 1;
+        self.rx_power_dbm += AVERAGE_WEIGHT * (diagnostics.rx_power_dbm - self.rx_power_dbm);
+        self.first_path_power_dbm += AVERAGE_WEIGHT * (diagnostics.first_path_power_dbm - self.first_path_power_dbm);
+        self.clock_offset_ppm += AVERAGE_WEIGHT * (diagnostics.clock_offset_ppm - self.clock_offset_ppm);
+        self.nlos += AVERAGE_WEIGHT * (diagnostics.nlos - self.nlos);
+    }
+}
+
+/// Collects the diagnostics of the received frames per peer.
+pub struct LinkMonitor {
+    links: Vec<LinkStats>,
+    reported_us: u64,
+}
+
+impl Default for LinkMonitor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LinkMonitor {
+    pub fn new() -> Self {
+        Self {
+            links: vec![],
+            reported_us: 0,
+        }
+    }
+
+    /// Count the diagnostics of a frame from the peer with the given mesh ID.
+    pub fn on_received(&mut self, peer: u16, diagnostics: &LinkDiagnostics) {
+        match self.links.iter_mut().find(|link| link.peer == peer) {
+            Some(link) => link.add(diagnostics),
+            None => self.links.push(LinkStats {
+                peer,
+                frames: 1,
+                rx_power_dbm: diagnostics.rx_power_dbm,
+                first_path_power_dbm: diagnostics.first_path_power_dbm,
+                clock_offset_ppm: diagnostics.clock_offset_ppm,
+                nlos: diagnostics.nlos,
+            }),
+        }
+    }
+
+    /// The links heard from since the last report, once it is time for the next one.
+    ///
+    /// Peers which were silent for a whole interval are left out and start over once they are heard again.
+    pub fn report(&mut self, now_us: u64) -> Option<RadioEvent> {
+        if now_us.saturating_sub(self.reported_us) < REPORT_INTERVAL_US {
+            return None;
+        }
+        self.reported_us = now_us;
+
+        self.links.retain(|link| link.frames > 0);
+        let report = self.links.clone();
+        for link in self.links.iter_mut() {
+            link.frames = 0;
+        }
+
+        (!report.is_empty()).then_some(RadioEvent::Links(report))
+    }
+}
@@ -0,0 +1,374 @@
+//! The loop of the radio thread, which runs the [`RadioStack`] against the DW3000.
+//!
+//! The [`Driver`] keeps the radio set up the way the stack asks for, with the address of the controller, the radio
+//! profile, the antenna delays and the scrambled timestamp sequence of the current slot. It hands the radio what the
+//! stack wants to send, and the stack what the radio received. Everything touching the registers of the DW3000 is
+//! behind the [`Transceiver`] trait, which `crate::uwb` implements for the board, so the rest builds and is tested on
+//! a host machine as well.
+
+use colored::*;
+use ledswarm_protocol::InternalMessage;
+
+use crate::hal::Clock;
+use crate::mesh::Payload;
+use crate::ranging::SendAt;
+
+use super::profile::RadioProfile;
+use super::stack::Reception;
+use super::sts::Sts;
+use super::{RadioEndpoint, RadioStack};
+
+/// How long the radio task sleeps when neither an interrupt nor a transmission is pending, in microseconds.
+///
+/// Short enough to hit the TDMA slots, which are announced to the stack only a few milliseconds ahead.
+const IDLE_US: u32 = 100;
+
+/// How the DW3000 is set up for sending and receiving.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RadioSettings {
+    pub profile: RadioProfile,
+    /// The PAN ID and short address the receiver filters frames for, once this controller joined a session.
+    pub address: Option<(u16, u16)>,
+    /// The antenna delays for transmission and reception in DW3000 time units.
+    pub antenna_delays: (u16, u16),
+    /// The scrambled timestamp sequence frames are sent and received with, if the current slot uses one.
+    pub sts: Option<Sts>,
+}
+
+/// The flags of the status register which the DW3000 raises its IRQ line for.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct IrqStatus {
+    /// A frame with a good checksum arrived.
+    pub rx_good: bool,
+    /// A frame arrived, but was corrupted or overran the receive buffer.
+    pub rx_error: bool,
+    /// The receiver gave up waiting for a preamble, SFD or frame.
+    pub rx_timeout: bool,
+    pub tx_done: bool,
+}
+
+/// Why the DW3000 raised its IRQ line.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IrqEvent {
+    RxGood,
+    RxError,
+    RxTimeout,
+    TxDone,
+    /// None of the events the interrupts are enabled for.
+    Spurious,
+}
+
+impl IrqStatus {
+    /// The event the IRQ was raised for, where a frame which arrived takes precedence over everything else.
+    pub fn event(&self) -> IrqEvent {
+        if self.rx_good {
+            IrqEvent::RxGood
+        } else if self.rx_error {
+            IrqEvent::RxError
+        } else if self.rx_timeout {
+            IrqEvent::RxTimeout
+        } else if self.tx_done {
+            IrqEvent::TxDone
+        } else {
+            IrqEvent::Spurious
+        }
+    }
+}
+
+/// A frame read from the DW3000.
+#[derive(Debug, Clone)]
+pub struct Received {
+    /// The PAN ID the frame was sent to.
+    pub pan_id: u16,
+    /// The MAC payload of the frame.
+    pub bytes: Vec<u8>,
+    pub reception: Reception,
+}
+
+/// The registers of a DW3000, which keeps its receiver armed all the time, except while it is transmitting.
+pub trait Transceiver {
+    /// The current DW3000 system time.
+    fn sys_time(&mut self) -> anyhow::Result<u64>;
+
+    /// Apply the given settings, leaving the receiver armed with them.
+    fn configure(&mut self, settings: &RadioSettings) -> anyhow::Result<()>;
+
+    /// Send a frame with the given settings and wait until it went out, arming the receiver again afterwards.
+    ///
+    /// Returns the TX timestamp, or `None` if the frame never went out, like a delayed one whose time already passed.
+    fn transmit(&mut self, bytes: &[u8], send_at: SendAt, settings: &RadioSettings) -> anyhow::Result<Option<u64>>;
+
+    /// The status of the DW3000 if it raised its IRQ line since the last call.
+    fn poll_irq(&mut self) -> anyhow::Result<Option<IrqStatus>>;
+
+    /// Read the frame or the error the given event announced and arm the receiver again.
+    ///
+    /// Returns the frame if it arrived intact, or `None` if it didn't or the receiver has nothing to read yet.
+    fn receive(&mut self, event: IrqEvent, settings: &RadioSettings) -> anyhow::Result<Option<Received>>;
+}
+
+pub struct Driver<T: Transceiver> {
+    pub radio: T,
+    pub stack: RadioStack,
+    endpoint: RadioEndpoint,
+    /// The protocol frames for the controller.
+    frames: flume::Sender<InternalMessage>,
+    clock: Box<dyn Clock>,
+    /// The settings the radio was last configured with.
+    settings: RadioSettings,
+}
+
+impl<T: Transceiver> Driver<T> {
+    /// Set up the radio for the stack, which starts on its default profile until the controller picks its own.
+    pub fn new(
+        mut radio: T,
+        stack: RadioStack,
+        endpoint: RadioEndpoint,
+        frames: flume::Sender<InternalMessage>,
+        clock: Box<dyn Clock>,
+    ) -> anyhow::Result<Self> {
+        let ranging = stack.ranging_config();
+        let settings = RadioSettings {
+            profile: stack.profile(),
+            address: None,
+            antenna_delays: (ranging.tx_antenna_delay, ranging.rx_antenna_delay),
+            sts: None,
+        };
+        radio.configure(&settings)?;
+
+        Ok(Self {
+            radio,
+            stack,
+            endpoint,
+            frames,
+            clock,
+            settings,
+        })
+    }
+
+    /// Run the radio until it fails.
+    pub fn run(&mut self) -> anyhow::Result<()> {
+        loop {
+            if !self.step()? {
+                // Nothing happened on the air, but a transmission may become due in the meantime.
+                self.clock.delay_us(IDLE_US);
+            }
+        }
+    }
+
+    /// Go through the loop once, returning whether the radio sent or received anything.
+    pub fn step(&mut self) -> anyhow::Result<bool> {
+        while let Ok(command) = self.endpoint.commands.try_recv() {
+            self.stack.handle_command(command);
+        }
+
+        let radio_now = self.radio.sys_time()?;
+        self.follow_stack(radio_now)?;
+
+        while let Some(frame) = self.stack.take_frame() {
+            self.frames
+                .send(InternalMessage::Frame(Box::new(frame)))
+                .map_err(|_| anyhow::anyhow!("The controller stopped taking frames"))?;
+        }
+
+        while let Some(event) = self.stack.take_event() {
+            if self.endpoint.events.try_send(event).is_err() {
+                println!("## {}  Controller is not keeping up with radio events, dropping one", "[uwb]".bright_blue().bold());
+            }
+        }
+
+        // Answers to ranging messages have tight deadlines, so they go out before anything else is looked at.
+        if let Some(transmission) = self.stack.poll_transmit(self.clock.now_us(), radio_now) {
+            let bytes = self.stack.encode(&transmission);
+            // The master switches profiles along with the beacon opening the first superframe on the new one, and
+            // replies are handed over within the slot while the poll may be set up before the slot starts.
+            self.settings.profile = self.stack.profile();
+            self.settings.sts = transmission.sts;
+
+            if let Some(tx_time) = self.radio.transmit(&bytes, transmission.send_at, &self.settings)? {
+                self.stack.on_transmitted(&transmission, tx_time);
+            }
+            return Ok(true);
+        }
+
+        let Some(status) = self.radio.poll_irq()? else {
+            return Ok(false);
+        };
+
+        let event = status.event();
+        match event {
+            IrqEvent::RxGood | IrqEvent::RxError | IrqEvent::RxTimeout => {
+                if let Some(received) = self.radio.receive(event, &self.settings)? {
+                    match Payload::from_mac_payload(received.pan_id, &received.bytes) {
+                        Some(payload) => self.stack.on_received(payload, self.clock.now_us(), received.reception),
+                        None => println!("Failed to parse UWB packet, skipping"),
+                    }
+                }
+            },
+            // Transmissions are awaited while sending, so this is left over from the last one.
+            IrqEvent::TxDone | IrqEvent::Spurious => {},
+        }
+
+        Ok(true)
+    }
+
+    /// Configure the radio anew if the stack asks for other settings than it has.
+    fn follow_stack(&mut self, radio_now: u64) -> anyhow::Result<()> {
+        let ranging = self.stack.ranging_config();
+        let settings = RadioSettings {
+            profile: self.stack.profile(),
+            address: self.stack.address(),
+            antenna_delays: (ranging.tx_antenna_delay, ranging.rx_antenna_delay),
+            // The sequence changes with the ranging slots, so the receiver is ready when the exchange starts.
+            sts: self.stack.sts(radio_now),
+        };
+        if settings == self.settings {
+            return Ok(());
+        }
+
+        if settings.address != self.settings.address {
+            if let Some((pan_id, short_address)) = settings.address {
+                println!("## {}  Filtering frames for {:04x}:{}", "[uwb]".bright_blue().bold(), pan_id, short_address);
+            }
+        }
+        if settings.profile != self.settings.profile {
+            println!("## {}  Switching to the {:?} profile", "[uwb]".bright_blue().bold(), settings.profile);
+        }
+
+        self.radio.configure(&settings)?;
+        self.settings = settings;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use crate::hal::memory::ManualClock;
+    use crate::hal::UwbTransport;
+    use crate::mesh::{MeshMessage, MeshPacket, BROADCAST_PAN_ID};
+    use crate::radio::tdma::TdmaConfig;
+    use crate::radio::{self, RadioCommand, RadioLink};
+    use crate::ranging::RangingConfig;
+
+    use super::*;
+
+    /// A DW3000 which records what it was asked to do and plays back queued interrupts.
+    #[derive(Default)]
+    struct FakeRadio {
+        time: u64,
+        configured: Vec<RadioSettings>,
+        sent: Vec<(Vec<u8>, SendAt)>,
+        irqs: VecDeque<(IrqStatus, Option<Received>)>,
+        received: Vec<IrqEvent>,
+    }
+
+    impl Transceiver for FakeRadio {
+        fn sys_time(&mut self) -> anyhow::Result<u64> {
+            Ok(self.time)
+        }
+
+        fn configure(&mut self, settings: &RadioSettings) -> anyhow::Result<()> {
+            self.configured.push(*settings);
+            Ok(())
+        }
+
+        fn transmit(&mut self, bytes: &[u8], send_at: SendAt, _settings: &RadioSettings) -> anyhow::Result<Option<u64>> {
+            self.sent.push((bytes.to_vec(), send_at));
+            Ok(Some(match send_at {
+                SendAt::Now => self.time,
+                SendAt::Delayed(time) => time,
+            }))
+        }
+
+        fn poll_irq(&mut self) -> anyhow::Result<Option<IrqStatus>> {
+            Ok(self.irqs.front().map(|(status, _)| *status))
+        }
+
+        fn receive(&mut self, event: IrqEvent, _settings: &RadioSettings) -> anyhow::Result<Option<Received>> {
+            self.received.push(event);
+            Ok(self.irqs.pop_front().and_then(|(_, received)| received))
+        }
+    }
+
+    fn driver() -> (Driver<FakeRadio>, RadioLink, flume::Receiver<InternalMessage>) {
+        let (link, endpoint) = radio::link();
+        let (frames_tx, frames_rx) = flume::unbounded();
+        let stack = RadioStack::new(RangingConfig::default(), TdmaConfig::default());
+        let driver = Driver::new(FakeRadio::default(), stack, endpoint, frames_tx, Box::new(ManualClock::new())).unwrap();
+
+        (driver, link, frames_rx)
+    }
+
+    #[test]
+    fn radio_is_set_up_once_and_again_when_the_stack_changes() {
+        let (mut driver, mut link, _) = driver();
+        assert_eq!(driver.radio.configured.len(), 1);
+        assert_eq!(driver.radio.configured[0].address, None);
+
+        driver.step().unwrap();
+        assert_eq!(driver.radio.configured.len(), 1);
+
+        link.command(RadioCommand::SetNodeId(Some(3))).unwrap();
+        link.command(RadioCommand::SetPanId(Some(0x1234))).unwrap();
+        link.command(RadioCommand::SetAntennaDelays { tx: 16_400, rx: 16_500 }).unwrap();
+        driver.step().unwrap();
+
+        let settings = driver.radio.configured.last().unwrap();
+        assert_eq!(settings.address, Some((0x1234, 3)));
+        assert_eq!(settings.antenna_delays, (16_400, 16_500));
+        assert_eq!(driver.radio.configured.len(), 2);
+    }
+
+    #[test]
+    fn received_ranging_poll_is_answered_at_the_reply_time() {
+        let (mut driver, mut link, _) = driver();
+        link.command(RadioCommand::SetNodeId(Some(1))).unwrap();
+        driver.step().unwrap();
+
+        let mut poll = MeshPacket::new(2, 1, MeshMessage::RangingPoll { seq: 5 });
+        poll.pan_id = BROADCAST_PAN_ID;
+        let rx_time = 1_000_000_000;
+        driver.radio.irqs.push_back((IrqStatus { rx_good: true, ..IrqStatus::default() }, Some(Received {
+            pan_id: BROADCAST_PAN_ID,
+            bytes: Payload::Mesh(poll).to_message_bytes(),
+            reception: Reception { rx_time, sts_valid: None, diagnostics: None },
+        })));
+
+        assert!(driver.step().unwrap());
+        assert_eq!(driver.radio.received, [IrqEvent::RxGood]);
+        assert!(driver.radio.sent.is_empty());
+
+        assert!(driver.step().unwrap());
+        let (bytes, send_at) = &driver.radio.sent[0];
+        assert!(String::from_utf8_lossy(bytes).contains("RangingResponse"));
+        assert!(matches!(send_at, SendAt::Delayed(time) if *time > rx_time));
+    }
+
+    #[test]
+    fn receiver_is_rearmed_after_errors_without_passing_anything_on() {
+        let (mut driver, _, frames) = driver();
+        driver.radio.irqs.push_back((IrqStatus { rx_timeout: true, ..IrqStatus::default() }, None));
+        driver.radio.irqs.push_back((IrqStatus { rx_error: true, rx_timeout: true, ..IrqStatus::default() }, None));
+
+        assert!(driver.step().unwrap());
+        assert!(driver.step().unwrap());
+        assert!(!driver.step().unwrap());
+
+        assert_eq!(driver.radio.received, [IrqEvent::RxTimeout, IrqEvent::RxError]);
+        assert!(frames.is_empty());
+    }
+
+    #[test]
+    fn irq_status_takes_arrived_frames_first() {
+        let status = |rx_good, rx_error, rx_timeout, tx_done| IrqStatus { rx_good, rx_error, rx_timeout, tx_done };
+
+        assert_eq!(status(true, true, true, true).event(), IrqEvent::RxGood);
+        assert_eq!(status(false, true, true, true).event(), IrqEvent::RxError);
+        assert_eq!(status(false, false, true, true).event(), IrqEvent::RxTimeout);
+        assert_eq!(status(false, false, false, true).event(), IrqEvent::TxDone);
+        assert_eq!(IrqStatus::default().event(), IrqEvent::Spurious);
+    }
+}
@@ -0,0 +1,297 @@
+//! Splitting payloads which don't fit into a single frame, and putting them back together on the other end.
+//!
+//! An oversized payload is cut into numbered [`Fragment`]s, which go out one after the other in the data slots of the
+//! sender before anything else. The receiver collects the fragments of each payload and decodes it once all of them
+//! arrived. Fragments are not repeated on their own, so a payload which misses some of them for too long is given up
+//! and reported, and reliable messages are sent again as a whole.
+
+use std::collections::VecDeque;
+
+use crate::mesh::{Fragment, Payload, BROADCAST, BROADCAST_PAN_ID, MAX_FRAGMENT_DATA};
+
+use super::RadioEvent;
+
+/// Time without another fragment of an incomplete payload after which it is given up, in microseconds.
+///
+/// Fragments follow each other in the data slots of the sender, which come around once every superframe.
+const REASSEMBLY_TIMEOUT_US: u64 = 2_000_000;
+
+/// Most payloads put back together at once, beyond which the one which waited longest for a fragment is given up.
+const MAX_PARTIALS: usize = 8;
+
+/// Most fragments a payload is split into, which keeps both the frames it takes and the memory to collect it small.
+const MAX_FRAGMENTS: usize = 16;
+
+/// A payload of which only some fragments arrived so far.
+struct Partial {
+    src: u16,
+    id: u16,
+    fragments: Vec<Option<Vec<u8>>>,
+    /// When the last fragment of the payload arrived.
+    updated_us: u64,
+}
+
+impl Partial {
+    fn received(&self) -> usize {
+        self.fragments.iter().filter(|fragment| fragment.is_some()).count()
+    }
+}
+
+pub struct Fragmentation {
+    next_id: u16,
+    /// The remaining fragments of the payload being sent.
+    queue: VecDeque<Payload>,
+    partial: Vec<Partial>,
+}
+
+impl Default for Fragmentation {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Fragmentation {
+    pub fn new() -> Self {
+        Self {
+            next_id: 0,
+            queue: VecDeque::new(),
+            partial: vec![],
+        }
+    }
+
+    /// Whether fragments of an earlier payload are still waiting for a data slot.
+    pub fn is_due(&self) -> bool {
+        !self.queue.is_empty()
+    }
+
+    /// The next fragment of the payload being sent.
+    pub fn poll(&mut self) -> Option<Payload> {
+        self.queue.pop_front()
+    }
+
+    /// The payload to send in place of the given one, which is its first fragment if it is too large for a frame.
+    ///
+    /// The remaining fragments are handed out by [`Fragmentation::poll`]. Payloads too large to be split are dropped.
+    pub fn split(&mut self, payload: Payload, src: u16) -> Option<Payload> {
+        if !payload.is_oversized() {
+            return Some(payload);
+        }
+
+        let dst = match &payload {
+            Payload::Mesh(packet) => packet.dst,
+            Payload::Sealed(sealed) => sealed.dst,
+            Payload::Relayed(relayed) => relayed.via,
+            _ => BROADCAST,
+        };
+        let pan_id = payload.pan_id().unwrap_or(BROADCAST_PAN_ID);
+        let bytes = payload.to_message_bytes();
+        let count = (bytes.len() + MAX_FRAGMENT_DATA - 1) / MAX_FRAGMENT_DATA;
+        if count > MAX_FRAGMENTS {
+            println!("Dropping payload of {} bytes, which exceeds the maximum of {} fragments", bytes.len(), MAX_FRAGMENTS);
+            return None;
+        }
+
+        self.next_id = self.next_id.wrapping_add(1);
+        self.queue.extend(bytes.chunks(MAX_FRAGMENT_DATA).enumerate().map(|(index, data)| {
+            Payload::Fragment(Fragment {
+                pan_id,
+                src,
+                dst,
+                id: self.next_id,
+                index: index as u8,
+                count: count as u8,
+                data: data.to_vec(),
+            })
+        }));
+
+        self.queue.pop_front()
+    }
+
+    /// Collect a received fragment, returning the bytes of the whole payload once its last fragment arrived.
+    pub fn on_received(&mut self, fragment: Fragment, now_us: u64) -> Option<Vec<u8>> {
+        if fragment.index >= fragment.count || fragment.count as usize > MAX_FRAGMENTS {
+            return None;
+        }
+
+        let index = match self.partial.iter().position(|partial| partial.src == fragment.src && partial.id == fragment.id) {
+            Some(index) => index,
+            None => {
+                if self.partial.len() >= MAX_PARTIALS {
+                    let oldest = self.partial.iter().enumerate().min_by_key(|(_, partial)| partial.updated_us).map(|(index, _)| index)?;
+                    let evicted = self.partial.remove(oldest);
+                    println!("Giving up payload {} from {} after {} of {} fragments to make room", evicted.id, evicted.src, evicted.received(), evicted.fragments.len());
+                }
+                self.partial.push(Partial {
+                    src: fragment.src,
+                    id: fragment.id,
+                    fragments: vec![None; fragment.count as usize],
+                    updated_us: now_us,
+                });
+                self.partial.len() - 1
+            },
+        };
+
+        let partial = &mut self.partial[index];
+        let slot = partial.fragments.get_mut(fragment.index as usize)?;
+        *slot = Some(fragment.data);
+        partial.updated_us = now_us;
+
+        if partial.fragments.iter().any(Option::is_none) {
+            return None;
+        }
+
+        let partial = self.partial.remove(index);
+        Some(partial.fragments.into_iter().flatten().flatten().collect())
+    }
+
+    /// Give up the payloads which stopped receiving fragments, reporting them as incomplete.
+    pub fn expire(&mut self, now_us: u64) -> Vec<RadioEvent> {
+        let (expired, partial): (Vec<_>, Vec<_>) = self.partial
+            .drain(..)
+            .partition(|partial| now_us - partial.updated_us >= REASSEMBLY_TIMEOUT_US);
+        self.partial = partial;
+
+        expired
+            .into_iter()
+            .map(|partial| RadioEvent::Incomplete {
+                src: partial.src,
+                received: partial.received(),
+                count: partial.fragments.len(),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mesh::{MeshMessage, MeshPacket, SessionMessage};
+
+    use super::*;
+
+    /// A mesh packet from the given controller whose message takes up exactly the given number of bytes.
+    fn payload(src: u16, len: usize) -> Payload {
+        let packet = |game: String| {
+            Payload::Mesh(MeshPacket::new(src, BROADCAST, MeshMessage::Session(SessionMessage::StartRound { game, at_us: None })))
+        };
+        let padding = len - packet(String::new()).to_message_bytes().len();
+
+        packet("x".repeat(padding))
+    }
+
+    /// Split the payload, returning every fragment it is sent as.
+    fn split(fragmentation: &mut Fragmentation, payload: Payload, src: u16) -> Vec<Fragment> {
+        std::iter::once(fragmentation.split(payload, src))
+            .chain(std::iter::from_fn(|| fragmentation.poll().map(Some)))
+            .flatten()
+            .map(|payload| match payload {
+                Payload::Fragment(fragment) => fragment,
+                other => panic!("Expected a fragment, got {:?}", other),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn payload_of_an_exact_multiple_of_the_fragment_size_fills_every_fragment() {
+        let payload = payload(1, 2 * MAX_FRAGMENT_DATA);
+        let bytes = payload.to_message_bytes();
+
+        let fragments = split(&mut Fragmentation::default(), payload, 1);
+        assert_eq!(fragments.len(), 2);
+        assert!(fragments.iter().all(|fragment| fragment.count == 2 && fragment.data.len() == MAX_FRAGMENT_DATA));
+
+        let mut receiver = Fragmentation::default();
+        assert_eq!(receiver.on_received(fragments[0].clone(), 0), None);
+        assert_eq!(receiver.on_received(fragments[1].clone(), 0), Some(bytes));
+    }
+
+    #[test]
+    fn last_fragment_carries_a_single_byte() {
+        // Payloads only a little larger than a fragment still fit into a frame with its shorter header.
+        let payload = payload(1, 2 * MAX_FRAGMENT_DATA + 1);
+        let bytes = payload.to_message_bytes();
+
+        let fragments = split(&mut Fragmentation::default(), payload, 1);
+        let lengths: Vec<_> = fragments.iter().map(|fragment| fragment.data.len()).collect();
+        assert_eq!(lengths, [MAX_FRAGMENT_DATA, MAX_FRAGMENT_DATA, 1]);
+
+        let mut receiver = Fragmentation::default();
+        assert_eq!(receiver.on_received(fragments[0].clone(), 0), None);
+        assert_eq!(receiver.on_received(fragments[1].clone(), 0), None);
+        assert_eq!(receiver.on_received(fragments[2].clone(), 0), Some(bytes));
+    }
+
+    #[test]
+    fn single_fragment_of_a_single_byte_is_complete() {
+        let fragment = Fragment { pan_id: BROADCAST_PAN_ID, src: 1, dst: BROADCAST, id: 1, index: 0, count: 1, data: vec![42] };
+
+        assert_eq!(Fragmentation::default().on_received(fragment, 0), Some(vec![42]));
+    }
+
+    #[test]
+    fn payloads_fitting_a_frame_are_sent_whole() {
+        let payload = payload(1, 100);
+        let mut fragmentation = Fragmentation::default();
+
+        assert!(matches!(fragmentation.split(payload, 1), Some(Payload::Mesh(_))));
+        assert!(!fragmentation.is_due());
+    }
+
+    #[test]
+    fn fragments_arriving_out_of_order_are_put_back_in_order() {
+        let payload = payload(1, 3 * MAX_FRAGMENT_DATA - 10);
+        let bytes = payload.to_message_bytes();
+        let fragments = split(&mut Fragmentation::default(), payload, 1);
+
+        let mut receiver = Fragmentation::default();
+        assert_eq!(receiver.on_received(fragments[2].clone(), 0), None);
+        assert_eq!(receiver.on_received(fragments[0].clone(), 0), None);
+        assert_eq!(receiver.on_received(fragments[1].clone(), 0), Some(bytes));
+    }
+
+    #[test]
+    fn duplicate_fragment_does_not_complete_the_payload() {
+        let payload = payload(1, 2 * MAX_FRAGMENT_DATA);
+        let bytes = payload.to_message_bytes();
+        let fragments = split(&mut Fragmentation::default(), payload, 1);
+
+        let mut receiver = Fragmentation::default();
+        assert_eq!(receiver.on_received(fragments[0].clone(), 0), None);
+        assert_eq!(receiver.on_received(fragments[0].clone(), 0), None);
+        assert_eq!(receiver.on_received(fragments[1].clone(), 0), Some(bytes));
+    }
+
+    #[test]
+    fn payload_missing_a_fragment_is_given_up() {
+        let fragments = split(&mut Fragmentation::default(), payload(1, 3 * MAX_FRAGMENT_DATA), 1);
+
+        let mut receiver = Fragmentation::default();
+        receiver.on_received(fragments[0].clone(), 1_000);
+        receiver.on_received(fragments[2].clone(), 2_000);
+
+        assert!(receiver.expire(2_000 + REASSEMBLY_TIMEOUT_US - 1).is_empty());
+        let events = receiver.expire(2_000 + REASSEMBLY_TIMEOUT_US);
+        assert!(matches!(events[..], [RadioEvent::Incomplete { src: 1, received: 2, count: 3 }]));
+
+        // The fragments which arrived are gone along with the payload.
+        assert_eq!(receiver.on_received(fragments[1].clone(), 3_000_000), None);
+    }
+
+    #[test]
+    fn interleaved_fragments_of_two_senders_are_kept_apart() {
+        let first = payload(1, 2 * MAX_FRAGMENT_DATA);
+        let second = payload(2, 2 * MAX_FRAGMENT_DATA + 5);
+        let (first_bytes, second_bytes) = (first.to_message_bytes(), second.to_message_bytes());
+
+        // Both senders number their first payload the same.
+        let first = split(&mut Fragmentation::default(), first, 1);
+        let second = split(&mut Fragmentation::default(), second, 2);
+        assert_eq!(first[0].id, second[0].id);
+
+        let mut receiver = Fragmentation::default();
+        assert_eq!(receiver.on_received(first[0].clone(), 0), None);
+        assert_eq!(receiver.on_received(second[0].clone(), 0), None);
+        assert_eq!(receiver.on_received(second[1].clone(), 0), None);
+        assert_eq!(receiver.on_received(first[1].clone(), 0), Some(first_bytes));
+        assert_eq!(receiver.on_received(second[2].clone(), 0), Some(second_bytes));
+    }
+}
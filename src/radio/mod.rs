@@ -0,0 +1,129 @@
+//! The link between the controller and the thread driving the UWB radio.
+//!
+//! The controller talks to the radio through a [`RadioLink`], the radio thread owns the other end, a [`RadioEndpoint`].
+//! Everything the radio side does besides moving bytes in and out of the DW3000 lives in the [`RadioStack`], so the
+//! same protocol logic runs on the board and in the simulator.
+
+use ledswarm_protocol::Frame;
+
+use crate::hal::UwbTransport;
+use crate::mesh::SessionMessage;
+use crate::ranging::Measurement;
+
+use self::diagnostics::LinkStats;
+use self::profile::RadioProfile;
+use self::security::RejectReason;
+use self::sync::NetworkTime;
+
+pub mod delivery;
+pub mod diagnostics;
+pub mod driver;
+pub mod fragment;
+pub mod profile;
+pub mod relay;
+pub mod security;
+pub mod stack;
+pub mod sts;
+pub mod sync;
+pub mod tdma;
+
+pub use stack::RadioStack;
+
+/// Requests from the controller to the radio thread.
+#[derive(Debug, Clone)]
+pub enum RadioCommand {
+    /// Transmit a protocol frame to all controllers in range.
+    Transmit(Frame),
+    /// The mesh ID assigned to this controller, or `None` while it isn't part of a mesh.
+    SetNodeId(Option<u16>),
+    /// Controllers known to be in the mesh, which the radio should range with.
+    SetPeers(Vec<u16>),
+    /// A controller which left the mesh, which the radio should stop ranging with and give no more slots.
+    RemovePeer(u16),
+    /// Whether this controller is the master and publishes the TDMA schedule of the mesh.
+    PublishSchedule(bool),
+    /// The PAN ID of the session this controller belongs to, or `None` to look for a session among all in range.
+    ///
+    /// Once the controller has a PAN ID and a mesh ID, the radio drops frames for other controllers and sessions.
+    SetPanId(Option<u16>),
+    /// The nonce of the session this controller belongs to, from which the radio derives the key of its traffic.
+    SetSession(Option<[u8; 16]>),
+    /// Let the member with the given mesh ID range without scrambled timestamp sequences, since its radio can't sync.
+    DisableSts(u16),
+    /// Use the given radio profile right away, which only reaches controllers on the same profile.
+    SetProfile(RadioProfile),
+    /// Move the whole mesh to the given radio profile, which the master announces ahead of time.
+    SwitchProfile(RadioProfile),
+    /// Antenna delays in DW3000 time units for transmission and reception, as calibrated for this board.
+    SetAntennaDelays { tx: u16, rx: u16 },
+    /// Send a message to another controller, or to all of them with [`BROADCAST`](crate::mesh::BROADCAST).
+    Send { dst: u16, message: SessionMessage },
+    /// Send a message like [`RadioCommand::Send`], but repeat it until every recipient acknowledged it.
+    ///
+    /// The outcome for each recipient comes back as [`RadioEvent::Delivered`] or [`RadioEvent::Undelivered`].
+    SendReliable { dst: u16, message: SessionMessage },
+}
+
+/// Notifications from the radio thread to the controller.
+#[derive(Debug, Clone)]
+pub enum RadioEvent {
+    /// A ranging exchange with a peer completed.
+    Distance(Measurement),
+    /// Another controller sent a message to this one.
+    Received { src: u16, message: SessionMessage },
+    /// A controller acknowledged a message sent with [`RadioCommand::SendReliable`].
+    Delivered { dst: u16, message: SessionMessage },
+    /// A controller never acknowledged a message sent with [`RadioCommand::SendReliable`], or left the mesh before it did.
+    Undelivered { dst: u16, message: SessionMessage },
+    /// Only some fragments of a large payload from a controller arrived, and the rest were given up.
+    Incomplete { src: u16, received: usize, count: usize },
+    /// A payload from the controller with the given mesh ID was dropped for being unauthentic.
+    Rejected { src: u16, reason: RejectReason },
+    /// The radio keeps failing to sync to the scrambled timestamp sequences of the others.
+    StsFailed,
+    /// The radio moved to another profile, following the master or looking for a mesh.
+    ProfileChanged(RadioProfile),
+    /// The signal quality of the frames received from each peer recently.
+    Links(Vec<LinkStats>),
+    /// The network time shared by the mesh, whenever the master stamped or this controller followed a beacon.
+    NetworkTime(NetworkTime),
+}
+
+/// The controller's end of the connection to the radio thread.
+pub struct RadioLink {
+    commands: flume::Sender<RadioCommand>,
+    events:   flume::Receiver<RadioEvent>,
+}
+
+/// The radio thread's end of the connection to the controller.
+pub struct RadioEndpoint {
+    pub commands: flume::Receiver<RadioCommand>,
+    pub events:   flume::Sender<RadioEvent>,
+}
+
+/// Create both ends of a connection between the controller and the radio thread.
+pub fn link() -> (RadioLink, RadioEndpoint) {
+    let (commands_tx, commands_rx) = flume::bounded(512);
+    let (events_tx, events_rx) = flume::bounded(512);
+
+    (
+        RadioLink {
+            commands: commands_tx,
+            events:   events_rx,
+        },
+        RadioEndpoint {
+            commands: commands_rx,
+            events:   events_tx,
+        },
+    )
+}
+
+impl UwbTransport for RadioLink {
+    fn command(&mut self, command: RadioCommand) -> anyhow::Result<()> {
+        self.commands.try_send(command).map_err(|e| anyhow::anyhow!("Failed to queue radio command: {}", e))
+    }
+
+    fn poll_event(&mut self) -> Option<RadioEvent> {
+        self.events.try_recv().ok()
+    }
+}
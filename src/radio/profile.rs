@@ -0,0 +1,88 @@
+//! Named trade-offs between range and airtime of the UWB radio, which the whole mesh switches between at once.
+//!
+//! Controllers only hear each other on the same profile, so the master announces a switch in its beacons a few
+//! superframes ahead, and everyone following the schedule moves over when that superframe begins. Controllers which
+//! missed the announcement lose the mesh, and after a while without a schedule they go through all profiles in turn
+//! until they hear it again.
+
+use serde::{Deserialize, Serialize};
+
+/// Duration of a preamble symbol in microseconds, which is nearly the same at both pulse repetition frequencies.
+const SYMBOL_US: f64 = 1.0;
+
+/// Length of the start frame delimiter in symbols.
+const SFD_SYMBOLS: u32 = 8;
+
+/// Time on air of the PHY header, which is always sent at the low bitrate, in microseconds.
+const PHR_US: f64 = 21.0;
+
+/// Time on air of every byte of the payload at 6.8 Mbps in microseconds, including the Reed-Solomon parity.
+const BYTE_US: f64 = 1.3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum RadioProfile {
+    /// Long preambles on channel 5, which reach the furthest and work through bodies, at the cost of airtime.
+    #[default]
+    LongRange,
+    /// Short preambles at the high pulse repetition frequency, for small venues where every millisecond counts.
+    LowLatency,
+    /// Short frames on channel 9, away from most other UWB devices, so crowded venues see fewer collisions.
+    DenseCrowd,
+}
+
+impl RadioProfile {
+    pub const ALL: [RadioProfile; 3] = [RadioProfile::LongRange, RadioProfile::LowLatency, RadioProfile::DenseCrowd];
+
+    pub fn channel(&self) -> u8 {
+        match self {
+            RadioProfile::LongRange | RadioProfile::LowLatency => 5,
+            RadioProfile::DenseCrowd => 9,
+        }
+    }
+
+    pub fn preamble_symbols(&self) -> u32 {
+        match self {
+            RadioProfile::LongRange => 1024,
+            RadioProfile::LowLatency => 128,
+            RadioProfile::DenseCrowd => 256,
+        }
+    }
+
+    /// The pulse repetition frequency in MHz.
+    pub fn prf_mhz(&self) -> u8 {
+        match self {
+            RadioProfile::LongRange => 16,
+            RadioProfile::LowLatency | RadioProfile::DenseCrowd => 64,
+        }
+    }
+
+    /// How many symbols the receiver waits for the SFD after detecting the preamble, before giving up on the frame.
+    pub fn sfd_timeout(&self) -> u16 {
+        let pac_symbols = match self.preamble_symbols() {
+            0 ..= 128 => 8,
+            129 ..= 256 => 16,
+            _ => 32,
+        };
+
+        (self.preamble_symbols() + 1 + SFD_SYMBOLS - pac_symbols) as u16
+    }
+
+    /// How long a frame with the given number of bytes is on the air, in microseconds.
+    pub fn airtime_us(&self, bytes: usize) -> f64 {
+        (self.preamble_symbols() + SFD_SYMBOLS) as f64 * SYMBOL_US + PHR_US + bytes as f64 * BYTE_US
+    }
+
+    /// The profile to look for the mesh on after this one.
+    pub fn next(&self) -> RadioProfile {
+        let index = RadioProfile::ALL.iter().position(|profile| profile == self).unwrap_or_default();
+        RadioProfile::ALL[(index + 1) % RadioProfile::ALL.len()]
+    }
+}
+
+/// A switch to another profile, announced by the master along with the schedule.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ProfileSwitch {
+    pub profile: RadioProfile,
+    /// The number of the superframe which starts on the new profile.
+    pub superframe: u32,
+}
@@ -0,0 +1,472 @@
+//! Passing payloads on for controllers which are out of range of their destination, so a mesh can span a large field.
+//!
+//! Every controller keeps a table of its neighbours, the controllers it recently received frames from. A controller
+//! sending a session message to someone who isn't among them wraps it in a [`Relayed`] envelope, which its neighbours
+//! pass on towards the destination. Whoever receives such an envelope straight from the controller it comes from knows
+//! that this controller depends on it, and from then on passes on the broadcasts of the others too, including the
+//! beacons of the master, so the controllers out of its range still follow the schedule and hear everything meant for
+//! them. Along the way, every controller learns which neighbour leads back to where an envelope came from, and sends
+//! envelopes for that controller only through this neighbour.
+//!
+//! Every copy of an envelope is signed by the controller sending it, and envelopes are only passed on once both the
+//! copy and the payload inside turned out to be authentic, which is also when a beacon can be told apart from the rest.
+//!
+//! Envelopes are passed on a limited number of times and only in the data slot of the relaying controller, which takes
+//! a single frame per superframe, so only every other beacon is passed on to leave room for the rest. Each of
+//! them is told apart by the controller it comes from and a hash of the payload, so every controller passes it on and
+//! processes it only once, and drops its own copy if another neighbour was quicker. The time every relaying controller
+//! held an envelope adds up in its header, so the receiver of a relayed beacon knows when the superframe started. No
+//! controller holds an envelope for longer than a second, so anything claiming more than that for each hop is dropped.
+
+use std::collections::{HashMap, VecDeque};
+
+use crate::mesh::{MeshMessage, MeshPacket, Payload, Relayed, Sealed, BROADCAST};
+use crate::ranging::twr;
+
+/// How many times a payload may be passed on before it is dropped.
+const MAX_HOPS: u8 = 4;
+
+/// Time after which a controller which wasn't heard anymore is no longer a neighbour, in microseconds.
+const NEIGHBOUR_TIMEOUT_US: u64 = 3_000_000;
+
+/// Time after which a controller which hasn't sent an envelope through this one anymore no longer depends on it, in
+/// microseconds.
+const DEPENDENT_TIMEOUT_US: u64 = 5_000_000;
+
+/// Time after which a route which wasn't confirmed by another envelope is forgotten, in microseconds.
+const ROUTE_TIMEOUT_US: u64 = 10_000_000;
+
+/// How long a payload is remembered as seen, in microseconds.
+const SEEN_WINDOW_US: u64 = 10_000_000;
+
+/// Time after which a payload still waiting to be passed on is dropped, in microseconds.
+const MAX_HOLD_US: u64 = 1_000_000;
+
+/// The neighbour leading towards a controller out of range.
+struct Route {
+    next_hop: u16,
+    hops: u8,
+    learned_us: u64,
+}
+
+/// An envelope waiting for the data slot of this controller.
+struct Waiting {
+    relayed: Relayed,
+    /// Whether the envelope carries a beacon, which is useless once a newer one arrived.
+    beacon: bool,
+    /// The DW3000 time at which the envelope or its payload arrived.
+    rx_time: u64,
+    received_us: u64,
+}
+
+pub struct Relay {
+    /// When each controller in range was last heard, by mesh ID.
+    neighbours: HashMap<u16, u64>,
+    /// When each controller depending on this one last sent an envelope through it, by mesh ID, or [`BROADCAST`] for
+    /// those which don't have one yet.
+    dependents: HashMap<u16, u64>,
+    /// The way to each controller out of range, by mesh ID.
+    routes: HashMap<u16, Route>,
+    /// Recently seen payloads by the controller they come from and their hash, along with when they were seen.
+    seen: VecDeque<(u16, u32, u64)>,
+    queue: VecDeque<Waiting>,
+    /// Whether the last data slot of this controller went to an envelope, and whether that envelope carried a beacon.
+    relayed_last: bool,
+    beacon_last: bool,
+    /// When this controller last decided to pass on a beacon.
+    beacon_queued_us: u64,
+}
+
+impl Default for Relay {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Relay {
+    pub fn new() -> Self {
+        Self {
+            neighbours: HashMap::new(),
+            dependents: HashMap::new(),
+            routes: HashMap::new(),
+            seen: VecDeque::new(),
+            queue: VecDeque::new(),
+            relayed_last: false,
+            beacon_last: false,
+            beacon_queued_us: 0,
+        }
+    }
+
+    /// Note a frame received straight from the controller with the given mesh ID.
+    pub fn on_heard(&mut self, src: u16, now_us: u64) {
+        if src != BROADCAST {
+            self.neighbours.insert(src, now_us);
+        }
+    }
+
+    fn is_neighbour(&self, id: u16, now_us: u64) -> bool {
+        self.neighbours.get(&id).is_some_and(|heard_us| now_us - heard_us < NEIGHBOUR_TIMEOUT_US)
+    }
+
+    /// The controller to hand an envelope for the given destination to, or [`BROADCAST`] if there is no known way.
+    fn next_hop(&self, dst: u16, now_us: u64) -> u16 {
+        if dst == BROADCAST || self.is_neighbour(dst, now_us) {
+            return dst;
+        }
+
+        self.routes.get(&dst)
+            .filter(|route| now_us - route.learned_us < ROUTE_TIMEOUT_US && self.is_neighbour(route.next_hop, now_us))
+            .map_or(BROADCAST, |route| route.next_hop)
+    }
+
+    /// Whether a controller other than the given ones depends on this one to hear broadcasts.
+    fn has_dependents(&self, except: &[u16], now_us: u64) -> bool {
+        self.dependents.iter().any(|(id, sent_us)| !except.contains(id) && now_us - sent_us < DEPENDENT_TIMEOUT_US)
+    }
+
+    /// Remember a payload as seen, returning whether it is new.
+    fn see(&mut self, origin: u16, data: &[u8], now_us: u64) -> bool {
+        while self.seen.front().is_some_and(|(_, _, seen_us)| now_us - seen_us >= SEEN_WINDOW_US) {
+            self.seen.pop_front();
+        }
+
+        let hash = fnv1a(data);
+        if self.seen.iter().any(|(seen_origin, seen_hash, _)| *seen_origin == origin && *seen_hash == hash) {
+            return false;
+        }
+
+        self.seen.push_back((origin, hash, now_us));
+        true
+    }
+
+    /// The payload to send in place of one this controller sealed, which is an envelope if its destination is out of
+    /// range.
+    pub fn wrap(&mut self, payload: Payload, now_us: u64) -> Payload {
+        let Payload::Sealed(sealed) = &payload else {
+            return payload;
+        };
+
+        // Copies passed back by the neighbours must not come through as messages of their own.
+        let data = payload.to_message_bytes();
+        self.see(sealed.src, &data, now_us);
+
+        if sealed.dst == BROADCAST || self.is_neighbour(sealed.dst, now_us) {
+            return payload;
+        }
+
+        Payload::Relayed(Relayed {
+            pan_id: sealed.pan_id,
+            src: sealed.src,
+            via: self.next_hop(sealed.dst, now_us),
+            origin: sealed.src,
+            dst: sealed.dst,
+            ttl: MAX_HOPS,
+            held_us: 0,
+            epoch: [0; 8],
+            counter: 0,
+            tag: [0; 8],
+            data,
+        })
+    }
+
+    /// Check a sealed payload received straight from the controller it comes from, returning whether it is new.
+    pub fn on_direct(&mut self, payload: &Payload, now_us: u64) -> bool {
+        let Payload::Sealed(sealed) = payload else {
+            return true;
+        };
+
+        let data = payload.to_message_bytes();
+        if !self.see(sealed.src, &data, now_us) {
+            self.drop_waiting(sealed.src, &data);
+            return false;
+        }
+
+        true
+    }
+
+    /// Pass an authentic broadcast received straight from the controller it comes from on to the dependents of this
+    /// controller, along with the length of the superframe if it is a beacon.
+    pub fn pass_on_direct(&mut self, sealed: &Sealed, superframe_us: Option<u64>, own_id: Option<u16>, rx_time: u64, now_us: u64) {
+        let Some(own_id) = own_id.filter(|_| sealed.dst == BROADCAST && self.has_dependents(&[sealed.src], now_us)) else {
+            return;
+        };
+
+        let relayed = Relayed {
+            pan_id: sealed.pan_id,
+            src: own_id,
+            via: BROADCAST,
+            origin: sealed.src,
+            dst: sealed.dst,
+            ttl: MAX_HOPS - 1,
+            held_us: 0,
+            epoch: [0; 8],
+            counter: 0,
+            tag: [0; 8],
+            data: Payload::Sealed(sealed.clone()).to_message_bytes(),
+        };
+        self.enqueue(relayed, superframe_us, rx_time, now_us);
+    }
+
+    /// Take an authentic envelope meant for this controller, returning it if it is new.
+    ///
+    /// [`Relay::pass_on`] takes it further once the payload inside turned out to be authentic as well.
+    pub fn on_received(&mut self, relayed: Relayed, own_id: Option<u16>, now_us: u64) -> Option<Relayed> {
+        // The neighbours pass the broadcasts of this controller, beacons included, back to it.
+        if Some(relayed.origin) == own_id {
+            return None;
+        }
+
+        let hops = MAX_HOPS.checked_sub(relayed.ttl)?;
+        if relayed.held_us as u64 > hops as u64 * MAX_HOLD_US {
+            println!("Dropping payload from {} through {}, held for {} us over {} hops", relayed.origin, relayed.src, relayed.held_us, hops);
+            return None;
+        }
+
+        if !self.see(relayed.origin, &relayed.data, now_us) {
+            // Another neighbour was quicker, and the controllers which depend on this one heard that copy too.
+            self.drop_waiting(relayed.origin, &relayed.data);
+            return None;
+        }
+
+        // Whoever sends an envelope of its own needs the neighbours to reach the others.
+        if relayed.src == relayed.origin {
+            self.dependents.insert(relayed.origin, now_us);
+        }
+        if relayed.origin != BROADCAST && Some(relayed.origin) != own_id {
+            let hops = MAX_HOPS.saturating_sub(relayed.ttl) + 1;
+            let known = self.routes.get(&relayed.origin)
+                .is_some_and(|route| route.hops < hops && now_us - route.learned_us < ROUTE_TIMEOUT_US);
+            if !known {
+                self.routes.insert(relayed.origin, Route { next_hop: relayed.src, hops, learned_us: now_us });
+            }
+        }
+
+        Some(relayed)
+    }
+
+    /// Pass an envelope with an authentic payload on if it has to go further, along with the length of the superframe
+    /// if it carries a beacon.
+    pub fn pass_on(&mut self, relayed: Relayed, superframe_us: Option<u64>, own_id: Option<u16>, rx_time: u64, now_us: u64) {
+        // Only controllers in the mesh pass envelopes on, since only they have a data slot to do it in.
+        let onward = match own_id {
+            Some(_) if relayed.ttl == 0 => false,
+            Some(own_id) if relayed.dst == BROADCAST => self.has_dependents(&[relayed.src, relayed.origin, own_id], now_us),
+            Some(own_id) => relayed.dst != own_id,
+            None => false,
+        };
+        let (true, Some(own_id)) = (onward, own_id) else {
+            return;
+        };
+
+        let relayed = Relayed {
+            src: own_id,
+            via: self.next_hop(relayed.dst, now_us),
+            ttl: relayed.ttl - 1,
+            ..relayed
+        };
+        self.enqueue(relayed, superframe_us, rx_time, now_us);
+    }
+
+    /// Queue an envelope for the data slot, along with the length of the superframe if it carries a beacon.
+    fn enqueue(&mut self, relayed: Relayed, superframe_us: Option<u64>, rx_time: u64, now_us: u64) {
+        if let Some(superframe_us) = superframe_us {
+            // Every other beacon is enough to keep the schedule, and leaves every other data slot to the rest.
+            if now_us - self.beacon_queued_us < superframe_us * 3 / 2 {
+                return;
+            }
+            self.beacon_queued_us = now_us;
+            self.queue.retain(|waiting| !waiting.beacon);
+        }
+
+        self.queue.push_back(Waiting { relayed, beacon: superframe_us.is_some(), rx_time, received_us: now_us });
+    }
+
+    /// Drop a waiting envelope, since a copy from another neighbour got there first.
+    fn drop_waiting(&mut self, origin: u16, data: &[u8]) {
+        self.queue.retain(|waiting| waiting.relayed.origin != origin || waiting.relayed.data != data);
+    }
+
+    /// Whether an envelope is waiting for the data slot.
+    pub fn is_due(&self) -> bool {
+        !self.queue.is_empty()
+    }
+
+    /// The next envelope to pass on at the given DW3000 time, adding the time it was held here to its header.
+    ///
+    /// Every controller has a single data slot per superframe, so envelopes take turns with the payloads of this
+    /// controller if it has any waiting, and beacons go first but take turns with the other envelopes.
+    pub fn poll(&mut self, tx_time: u64, now_us: u64, contended: bool) -> Option<Payload> {
+        self.neighbours.retain(|_, heard_us| now_us - *heard_us < NEIGHBOUR_TIMEOUT_US);
+        self.dependents.retain(|_, sent_us| now_us - *sent_us < DEPENDENT_TIMEOUT_US);
+        self.routes.retain(|_, route| now_us - route.learned_us < ROUTE_TIMEOUT_US);
+        self.queue.retain(|waiting| now_us - waiting.received_us < MAX_HOLD_US);
+
+        if contended && self.relayed_last {
+            self.relayed_last = false;
+            return None;
+        }
+
+        let beacon = self.queue.iter().position(|waiting| waiting.beacon);
+        let other = self.queue.iter().position(|waiting| !waiting.beacon);
+        let index = match (beacon, other) {
+            (Some(_), Some(other)) if self.beacon_last => other,
+            (Some(beacon), _) => beacon,
+            (None, other) => other?,
+        };
+        let waiting = self.queue.remove(index)?;
+        self.relayed_last = true;
+        self.beacon_last = waiting.beacon;
+
+        // The receivers wouldn't take an envelope held for longer than allowed.
+        let held_us = twr::units_to_us(twr::elapsed(waiting.rx_time, tx_time)) as u32;
+        if held_us as u64 > MAX_HOLD_US {
+            return None;
+        }
+        Some(Payload::Relayed(Relayed {
+            held_us: waiting.relayed.held_us.saturating_add(held_us),
+            ..waiting.relayed
+        }))
+    }
+}
+
+/// The length of the superframe announced by a beacon, or `None` for any other payload.
+pub fn superframe_us(payload: &Payload) -> Option<u64> {
+    match payload {
+        Payload::Mesh(MeshPacket { message: MeshMessage::Beacon { schedule }, .. }) => Some(schedule.duration_us()),
+        _ => None,
+    }
+}
+
+/// FNV-1a, which is quick and good enough to tell payloads apart, with their counters and nonces.
+fn fnv1a(data: &[u8]) -> u32 {
+    data.iter().fold(0x811C_9DC5u32, |hash, byte| (hash ^ *byte as u32).wrapping_mul(0x0100_0193))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mesh::SessionMessage;
+    use crate::radio::security::{RejectReason, Security};
+
+    use super::*;
+
+    /// A controller of the session, with the security and relay of its radio.
+    fn controller() -> (Security, Relay) {
+        let mut security = Security::default();
+        security.set_session(Some([7; 16]));
+        (security, Relay::default())
+    }
+
+    /// An envelope from controller 3 for controller 1, which is out of its range.
+    fn envelope(security: &mut Security, relay: &mut Relay) -> Relayed {
+        let packet = MeshPacket::new(3, 1, MeshMessage::Session(SessionMessage::Eliminated));
+        let sealed = security.seal(Payload::Mesh(packet), 3).unwrap();
+
+        match security.sign_relayed(relay.wrap(sealed, 0)) {
+            Payload::Relayed(relayed) => relayed,
+            other => panic!("Expected an envelope, got {:?}", other),
+        }
+    }
+
+    /// Pass on an envelope received by a controller, returning the copy it sends if it does.
+    fn hop(security: &mut Security, relay: &mut Relay, relayed: Relayed, own_id: u16, now_us: u64) -> Option<Relayed> {
+        security.verify_relayed(&relayed).ok()?;
+        let relayed = relay.on_received(relayed, Some(own_id), now_us)?;
+        relay.pass_on(relayed, None, Some(own_id), 0, now_us);
+
+        // The envelope arrived at DW3000 time 0, and goes out half a millisecond later.
+        match security.sign_relayed(relay.poll(twr::us_to_units(500), now_us, false)?) {
+            Payload::Relayed(relayed) => Some(relayed),
+            other => panic!("Expected an envelope, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn envelope_is_passed_on_towards_its_destination() {
+        let (mut origin, mut origin_relay) = controller();
+        let (mut security, mut relay) = controller();
+        let relayed = envelope(&mut origin, &mut origin_relay);
+        assert_eq!((relayed.src, relayed.origin, relayed.dst, relayed.ttl), (3, 3, 1, MAX_HOPS));
+
+        let onward = hop(&mut security, &mut relay, relayed.clone(), 2, 10).unwrap();
+        assert_eq!((onward.src, onward.origin, onward.dst, onward.ttl), (2, 3, 1, MAX_HOPS - 1));
+        assert_eq!(onward.data, relayed.data);
+        assert_eq!(onward.held_us, 500);
+    }
+
+    #[test]
+    fn forged_hop_is_dropped() {
+        let (mut origin, mut origin_relay) = controller();
+        let (mut security, _) = controller();
+        let relayed = envelope(&mut origin, &mut origin_relay);
+
+        let unsigned = Relayed { tag: [0; 8], ..relayed.clone() };
+        assert_eq!(security.verify_relayed(&unsigned), Err(RejectReason::Forged));
+
+        let other_hop = Relayed { src: 4, ..relayed.clone() };
+        assert_eq!(security.verify_relayed(&other_hop), Err(RejectReason::Forged));
+
+        let held_longer = Relayed { held_us: 500, ..relayed.clone() };
+        assert_eq!(security.verify_relayed(&held_longer), Err(RejectReason::Forged));
+
+        let mut other_payload = relayed.clone();
+        *other_payload.data.last_mut().unwrap() ^= 1;
+        assert_eq!(security.verify_relayed(&other_payload), Err(RejectReason::Forged));
+
+        assert_eq!(security.verify_relayed(&relayed), Ok(()));
+    }
+
+    #[test]
+    fn envelope_held_too_long_is_dropped() {
+        let (mut origin, mut origin_relay) = controller();
+        let relayed = envelope(&mut origin, &mut origin_relay);
+
+        // Nobody held the envelope yet, so it can't claim any time.
+        let unheld = Relayed { held_us: 1, ..relayed.clone() };
+        assert!(Relay::default().on_received(unheld, Some(2), 0).is_none());
+
+        let one_hop = Relayed { ttl: MAX_HOPS - 1, held_us: MAX_HOLD_US as u32, ..relayed.clone() };
+        assert!(Relay::default().on_received(one_hop.clone(), Some(2), 0).is_some());
+        let too_long = Relayed { held_us: MAX_HOLD_US as u32 + 1, ..one_hop };
+        assert!(Relay::default().on_received(too_long, Some(2), 0).is_none());
+    }
+
+    #[test]
+    fn replayed_envelope_is_dropped() {
+        let (mut origin, mut origin_relay) = controller();
+        let (mut neighbour, mut neighbour_relay) = controller();
+        let (mut security, mut relay) = controller();
+        let relayed = envelope(&mut origin, &mut origin_relay);
+
+        assert!(security.verify_relayed(&relayed).is_ok());
+        assert!(relay.on_received(relayed.clone(), Some(2), 10).is_some());
+
+        // The same copy again reuses the counter of the controller which signed it.
+        assert_eq!(security.verify_relayed(&relayed), Err(RejectReason::Replayed));
+
+        // A fresh copy of the same payload from another neighbour is authentic, but the payload was seen already.
+        let copy = hop(&mut neighbour, &mut neighbour_relay, relayed, 4, 10).unwrap();
+        assert!(security.verify_relayed(&copy).is_ok());
+        assert!(relay.on_received(copy, Some(2), 20).is_none());
+    }
+
+    #[test]
+    fn envelope_stops_at_the_hop_limit() {
+        let (mut origin, mut origin_relay) = controller();
+        let mut relayed = envelope(&mut origin, &mut origin_relay);
+
+        // Every controller on the way passes the envelope on, until it may not go any further.
+        for hop_id in 10 .. 10 + MAX_HOPS as u16 {
+            let (mut security, mut relay) = controller();
+            relayed = hop(&mut security, &mut relay, relayed, hop_id, 0).unwrap();
+        }
+        assert_eq!(relayed.ttl, 0);
+
+        let (mut security, mut relay) = controller();
+        assert!(hop(&mut security, &mut relay, relayed, 20, 0).is_none());
+        assert!(!relay.is_due());
+
+        // Envelopes claiming to have more hops left than any controller gives them are dropped outright.
+        let relayed = Relayed { ttl: MAX_HOPS + 1, ..envelope(&mut origin, &mut origin_relay) };
+        assert!(Relay::default().on_received(relayed, Some(2), 0).is_none());
+    }
+}
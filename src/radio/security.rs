@@ -0,0 +1,518 @@
+//! Encryption and authentication of the session traffic, so that only controllers of the swarm can take part.
+//!
+//! Every controller knows the pre-shared swarm key, set through `LEDSWARM_SWARM_KEY` at build time. The master opens
+//! a session with a random nonce, and the session key is the swarm key applied to the nonce with AES. Joining and the
+//! beacons of the master are sealed with the swarm key, so controllers which haven't joined yet can follow the schedule
+//! and take the nonce from the master, and everything else except ranging is sealed with the session key, both with
+//! AES-CCM. The nonce of every sealed payload is made up of a random epoch the sender draws on boot and a counter,
+//! which receivers also use to drop payloads which were recorded and replayed. Since relayed copies take longer, a
+//! window of recent counters below the highest one is still accepted, each of them once.
+//!
+//! Every controller passing a payload on for others signs its copy of the envelope with the swarm key, its own epoch
+//! and the next value of its counter, so neither the header nor the time the payload was held can be changed on the way.
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+
+use aes::Aes128;
+use aes::cipher::{BlockEncrypt, KeyInit};
+use ccm::aead::{Aead, Payload as AeadPayload};
+use ccm::consts::{U13, U8};
+use ccm::Ccm;
+
+use crate::mesh::{KeyKind, MeshMessage, Payload, Relayed, Sealed, SessionMessage, BROADCAST, BROADCAST_PAN_ID};
+
+use super::sts::StsKeys;
+
+type Cipher = Ccm<Aes128, U8, U13>;
+
+/// A controller without a mesh ID, told apart by the key, the PAN ID and the epoch it sends with.
+type Anonymous = (KeyKind, u16, [u8; 8]);
+
+/// The pre-shared swarm key as 32 hexadecimal digits, which the build script requires for the firmware.
+#[cfg(feature = "esp-idf")]
+const SWARM_KEY: &str = env!("LEDSWARM_SWARM_KEY");
+
+/// The pre-shared swarm key as 32 hexadecimal digits. Builds for the host only talk to each other in the simulator and
+/// tests, so they fall back to a fixed key.
+#[cfg(not(feature = "esp-idf"))]
+const SWARM_KEY: &str = match option_env!("LEDSWARM_SWARM_KEY") {
+    Some(key) => key,
+    None => "4c4544737761726d2d686f73742d6f6e",
+};
+
+/// How many earlier epochs of each sender are remembered to reject payloads replayed from before its last reboot.
+const MAX_RETIRED_EPOCHS: usize = 8;
+
+/// How many counters below the highest one of a sender are still accepted, since relayed copies arrive late.
+const REPLAY_WINDOW: u32 = u128::BITS;
+
+/// How many controllers without a mesh ID are told apart by their epoch, before the one heard longest ago is forgotten.
+const MAX_ANONYMOUS: usize = 32;
+
+/// The last byte of the nonce of relayed envelopes, which keeps it apart from the nonces of sealed payloads.
+const RELAYED_NONCE: u8 = 0xFF;
+
+/// Why a received payload was dropped.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RejectReason {
+    /// A session message or protocol frame arrived in the clear.
+    Unauthenticated,
+    /// The payload was not sealed with the key of the swarm or the session, or was tampered with.
+    Forged,
+    /// The payload was received before.
+    Replayed,
+}
+
+impl fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RejectReason::Unauthenticated => write!(f, "not encrypted"),
+            RejectReason::Forged => write!(f, "failed authentication"),
+            RejectReason::Replayed => write!(f, "replayed"),
+        }
+    }
+}
+
+/// The counters a sender used within its current epoch, to tell new payloads from replayed ones.
+#[derive(Default)]
+struct Window {
+    /// The highest counter received so far.
+    counter: u32,
+    /// Which of the counters below the highest one were received, the lowest bit standing for the one right below.
+    below: u128,
+}
+
+impl Window {
+    /// Accept a counter if it wasn't received before and isn't too far behind the highest one.
+    fn check(&mut self, counter: u32) -> Result<(), RejectReason> {
+        if counter > self.counter {
+            // The highest counter so far moves into the window, unless there was none yet since senders start at 1.
+            let shift = counter - self.counter;
+            let previous = (self.counter > 0) as u128;
+            self.below = match shift {
+                shift if shift > REPLAY_WINDOW => 0,
+                shift => (self.below << 1 | previous) << (shift - 1),
+            };
+            self.counter = counter;
+            return Ok(());
+        }
+
+        let behind = self.counter - counter;
+        if behind == 0 || behind > REPLAY_WINDOW || self.below & 1 << (behind - 1) != 0 {
+            return Err(RejectReason::Replayed);
+        }
+        self.below |= 1 << (behind - 1);
+        Ok(())
+    }
+}
+
+/// How far a sender got with its counter, along with the epochs it used before.
+#[derive(Default)]
+struct Freshness {
+    epoch: [u8; 8],
+    window: Window,
+    retired: VecDeque<[u8; 8]>,
+}
+
+pub struct Security {
+    swarm_key: [u8; 16],
+    swarm: Cipher,
+    session: Option<Cipher>,
+    sts_keys: Option<StsKeys>,
+    epoch: [u8; 8],
+    counter: u32,
+    /// The freshness of the payloads from every sender, by key, PAN ID and mesh ID.
+    peers: HashMap<(KeyKind, u16, u16), Freshness>,
+    /// The freshness of the payloads from controllers without a mesh ID, the one heard last at the back.
+    anonymous: VecDeque<(Anonymous, Window)>,
+}
+
+impl Default for Security {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Security {
+    pub fn new() -> Self {
+        let swarm_key = parse_key(SWARM_KEY).expect("LEDSWARM_SWARM_KEY has to be 32 hexadecimal digits");
+        let mut epoch = [0; 8];
+        epoch.copy_from_slice(&uuid::Uuid::new_v4().as_bytes()[.. 8]);
+
+        Self {
+            swarm_key,
+            swarm: Cipher::new(&swarm_key.into()),
+            session: None,
+            sts_keys: None,
+            epoch,
+            counter: 0,
+            peers: HashMap::new(),
+            anonymous: VecDeque::new(),
+        }
+    }
+
+    /// Derive the key of the session with the given nonce, or forget it with `None`.
+    pub fn set_session(&mut self, nonce: Option<[u8; 16]>) {
+        let session_key: Option<[u8; 16]> = nonce.map(|nonce| {
+            let mut block = aes::Block::from(nonce);
+            Aes128::new(&self.swarm_key.into()).encrypt_block(&mut block);
+            block.into()
+        });
+        self.session = session_key.map(|key| Cipher::new(&key.into()));
+        self.sts_keys = session_key.as_ref().map(StsKeys::derive);
+        self.peers.retain(|(key, _, _), _| *key == KeyKind::Swarm);
+        self.anonymous.retain(|((key, _, _), _)| *key == KeyKind::Swarm);
+    }
+
+    /// Whether the key of a session is known.
+    pub fn has_session(&self) -> bool {
+        self.session.is_some()
+    }
+
+    /// The STS key and IV of the session, if the key of a session is known.
+    pub fn sts_keys(&self) -> Option<&StsKeys> {
+        self.sts_keys.as_ref()
+    }
+
+    /// Encrypt a session message, beacon or protocol frame for transmission, passing everything else through.
+    ///
+    /// Returns `None` if the payload needs the key of a session which this controller doesn't know.
+    pub fn seal(&mut self, payload: Payload, src: u16) -> Option<Payload> {
+        let (dst, key) = match &payload {
+            Payload::Mesh(packet) => match &packet.message {
+                MeshMessage::Session(SessionMessage::Join { .. } | SessionMessage::Welcome { .. }) | MeshMessage::Beacon { .. } => {
+                    (packet.dst, KeyKind::Swarm)
+                },
+                MeshMessage::Session(_) | MeshMessage::Ack { .. } | MeshMessage::RangingReport { .. } => (packet.dst, KeyKind::Session),
+                _ => return Some(payload),
+            },
+            Payload::Frame(_) => (BROADCAST, KeyKind::Session),
+            Payload::Fragment(_) | Payload::Sealed(_) | Payload::Relayed(_) => return Some(payload),
+        };
+        let cipher = match key {
+            KeyKind::Swarm => &self.swarm,
+            KeyKind::Session => self.session.as_ref()?,
+        };
+
+        self.counter = self.counter.wrapping_add(1);
+        let mut sealed = Sealed {
+            pan_id: payload.pan_id().unwrap_or(BROADCAST_PAN_ID),
+            key,
+            src,
+            dst,
+            epoch: self.epoch,
+            counter: self.counter,
+            ciphertext: vec![],
+        };
+
+        let aad = associated_data(&sealed);
+        let message = payload.to_message_bytes();
+        sealed.ciphertext = cipher
+            .encrypt(&nonce(&sealed).into(), AeadPayload { msg: &message, aad: &aad })
+            .expect("Payloads of a single message never exceed the limits of CCM");
+
+        Some(Payload::Sealed(sealed))
+    }
+
+    /// Decrypt a sealed payload, making sure it is authentic, addressed as sealed and not replayed.
+    pub fn open(&mut self, sealed: &Sealed) -> Result<Payload, RejectReason> {
+        let payload = self.decrypt(sealed)?;
+        self.check_freshness(sealed.key, sealed.pan_id, sealed.src, sealed.epoch, sealed.counter)?;
+        Ok(payload)
+    }
+
+    /// Make sure a sealed payload for another controller is authentic, leaving its counter to its recipient.
+    pub fn authentic(&self, sealed: &Sealed) -> Result<(), RejectReason> {
+        self.decrypt(sealed).map(|_| ())
+    }
+
+    /// Decrypt a sealed payload, making sure it is authentic and addressed as sealed.
+    fn decrypt(&self, sealed: &Sealed) -> Result<Payload, RejectReason> {
+        let cipher = match sealed.key {
+            KeyKind::Swarm => &self.swarm,
+            KeyKind::Session => self.session.as_ref().ok_or(RejectReason::Forged)?,
+        };
+
+        let aad = associated_data(sealed);
+        let message = cipher
+            .decrypt(&nonce(sealed).into(), AeadPayload { msg: &sealed.ciphertext, aad: &aad })
+            .map_err(|_| RejectReason::Forged)?;
+
+        // The addresses inside have to be the authenticated ones outside.
+        let payload = match Payload::from_mac_payload(sealed.pan_id, &message) {
+            Some(Payload::Mesh(packet)) if packet.src == sealed.src && packet.dst == sealed.dst => Payload::Mesh(packet),
+            Some(Payload::Frame(frame)) => Payload::Frame(frame),
+            _ => return Err(RejectReason::Forged),
+        };
+
+        Ok(payload)
+    }
+
+    /// Sign an envelope this controller sends, passing everything else through.
+    pub fn sign_relayed(&mut self, payload: Payload) -> Payload {
+        let Payload::Relayed(mut relayed) = payload else {
+            return payload;
+        };
+
+        self.counter = self.counter.wrapping_add(1);
+        relayed.epoch = self.epoch;
+        relayed.counter = self.counter;
+        let tag = self.swarm
+            .encrypt(&relayed_nonce(&relayed).into(), AeadPayload { msg: &[], aad: &relayed_associated_data(&relayed) })
+            .expect("Envelopes of a single message never exceed the limits of CCM");
+        relayed.tag.copy_from_slice(&tag);
+
+        Payload::Relayed(relayed)
+    }
+
+    /// Make sure a received envelope was signed by the controller which sent it, and is not replayed.
+    pub fn verify_relayed(&mut self, relayed: &Relayed) -> Result<(), RejectReason> {
+        self.swarm
+            .decrypt(&relayed_nonce(relayed).into(), AeadPayload { msg: &relayed.tag, aad: &relayed_associated_data(relayed) })
+            .map_err(|_| RejectReason::Forged)?;
+
+        self.check_freshness(KeyKind::Swarm, relayed.pan_id, relayed.src, relayed.epoch, relayed.counter)
+    }
+
+    /// Accept each counter of a sender only once, and none from the epochs before its last reboot.
+    fn check_freshness(&mut self, key: KeyKind, pan_id: u16, src: u16, epoch: [u8; 8], counter: u32) -> Result<(), RejectReason> {
+        // Controllers without a mesh ID all send from the broadcast address, so only their epoch tells them apart.
+        if src == BROADCAST {
+            return self.check_anonymous((key, pan_id, epoch), counter);
+        }
+
+        let freshness = self.peers.entry((key, pan_id, src)).or_default();
+
+        if epoch != freshness.epoch {
+            if freshness.retired.contains(&epoch) {
+                return Err(RejectReason::Replayed);
+            }
+            if freshness.window.counter > 0 {
+                freshness.retired.push_back(freshness.epoch);
+                if freshness.retired.len() > MAX_RETIRED_EPOCHS {
+                    freshness.retired.pop_front();
+                }
+            }
+            freshness.epoch = epoch;
+            freshness.window = Window::default();
+        }
+
+        freshness.window.check(counter)
+    }
+
+    /// Accept each counter of a controller without a mesh ID only once within the epoch it sends with.
+    fn check_anonymous(&mut self, sender: Anonymous, counter: u32) -> Result<(), RejectReason> {
+        let mut window = match self.anonymous.iter().position(|(known, _)| *known == sender) {
+            Some(index) => self.anonymous.remove(index).map(|(_, window)| window).unwrap_or_default(),
+            None => Window::default(),
+        };
+
+        let result = window.check(counter);
+        self.anonymous.push_back((sender, window));
+        if self.anonymous.len() > MAX_ANONYMOUS {
+            self.anonymous.pop_front();
+        }
+        result
+    }
+}
+
+/// The nonce of a sealed payload, unique for every payload sealed under the same key.
+fn nonce(sealed: &Sealed) -> [u8; 13] {
+    let mut nonce = [0; 13];
+    nonce[.. 8].copy_from_slice(&sealed.epoch);
+    nonce[8 .. 12].copy_from_slice(&sealed.counter.to_le_bytes());
+    nonce[12] = sealed.key as u8;
+    nonce
+}
+
+/// Everything of a sealed payload which is sent in the clear, and authenticated along with the ciphertext.
+fn associated_data(sealed: &Sealed) -> Vec<u8> {
+    let mut aad = sealed.pan_id.to_le_bytes().to_vec();
+    aad.extend(sealed.header());
+    aad
+}
+
+/// The nonce of a relayed envelope, unique for every envelope signed by the same controller.
+fn relayed_nonce(relayed: &Relayed) -> [u8; 13] {
+    let mut nonce = [0; 13];
+    nonce[.. 8].copy_from_slice(&relayed.epoch);
+    nonce[8 .. 12].copy_from_slice(&relayed.counter.to_le_bytes());
+    nonce[12] = RELAYED_NONCE;
+    nonce
+}
+
+/// Everything of a relayed envelope except the tag, which the tag authenticates.
+fn relayed_associated_data(relayed: &Relayed) -> Vec<u8> {
+    let mut aad = relayed.pan_id.to_le_bytes().to_vec();
+    aad.extend(relayed.signed_header());
+    aad.extend_from_slice(&relayed.data);
+    aad
+}
+
+/// Parse a key of 32 hexadecimal digits.
+fn parse_key(hex: &str) -> Option<[u8; 16]> {
+    if hex.len() != 32 {
+        return None;
+    }
+
+    let mut key = [0; 16];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hex.get(i * 2 .. i * 2 + 2)?, 16).ok()?;
+    }
+    Some(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mesh::MeshPacket;
+
+    use super::*;
+
+    const NONCE: [u8; 16] = [7; 16];
+
+    fn session(nonce: [u8; 16]) -> Security {
+        let mut security = Security::default();
+        security.set_session(Some(nonce));
+        security
+    }
+
+    fn eliminated() -> MeshPacket {
+        MeshPacket::new(1, 0, MeshMessage::Session(SessionMessage::Eliminated))
+    }
+
+    fn seal(security: &mut Security, packet: MeshPacket) -> Sealed {
+        let src = packet.src;
+        match security.seal(Payload::Mesh(packet), src) {
+            Some(Payload::Sealed(sealed)) => sealed,
+            other => panic!("Expected a sealed payload, got {:?}", other),
+        }
+    }
+
+    /// Open the sealed payload, which has to be a mesh packet if it is authentic.
+    fn open(security: &mut Security, sealed: &Sealed) -> Result<MeshPacket, RejectReason> {
+        match security.open(sealed)? {
+            Payload::Mesh(packet) => Ok(packet),
+            other => panic!("Expected a mesh packet, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn sealed_payload_opens_to_the_original() {
+        let sealed = seal(&mut session(NONCE), eliminated());
+        assert_eq!(sealed.key, KeyKind::Session);
+
+        assert_eq!(open(&mut session(NONCE), &sealed), Ok(eliminated()));
+    }
+
+    #[test]
+    fn join_is_sealed_with_the_swarm_key() {
+        let join = MeshPacket::new(BROADCAST, 0, MeshMessage::Session(SessionMessage::Join {
+            unique_id: "abc".to_string(),
+            anchor: None,
+        }));
+        let sealed = seal(&mut Security::default(), join.clone());
+
+        assert_eq!(sealed.key, KeyKind::Swarm);
+        assert_eq!(open(&mut Security::default(), &sealed), Ok(join));
+    }
+
+    #[test]
+    fn session_payload_is_not_sealed_without_a_session() {
+        assert!(Security::default().seal(Payload::Mesh(eliminated()), 1).is_none());
+    }
+
+    #[test]
+    fn tampered_payload_is_rejected() {
+        let mut sender = session(NONCE);
+        let mut receiver = session(NONCE);
+
+        let mut tag = seal(&mut sender, eliminated());
+        *tag.ciphertext.last_mut().unwrap() ^= 1;
+        assert_eq!(open(&mut receiver, &tag), Err(RejectReason::Forged));
+
+        let mut ciphertext = seal(&mut sender, eliminated());
+        ciphertext.ciphertext[0] ^= 1;
+        assert_eq!(open(&mut receiver, &ciphertext), Err(RejectReason::Forged));
+
+        let mut header = seal(&mut sender, eliminated());
+        header.dst = 2;
+        assert_eq!(open(&mut receiver, &header), Err(RejectReason::Forged));
+    }
+
+    #[test]
+    fn payload_sealed_with_another_key_is_rejected() {
+        let sealed = seal(&mut session(NONCE), eliminated());
+
+        assert_eq!(open(&mut session([8; 16]), &sealed), Err(RejectReason::Forged));
+        assert_eq!(open(&mut Security::default(), &sealed), Err(RejectReason::Forged));
+    }
+
+    #[test]
+    fn replayed_payload_is_rejected() {
+        let mut sender = session(NONCE);
+        let mut receiver = session(NONCE);
+        let first = seal(&mut sender, eliminated());
+        let second = seal(&mut sender, eliminated());
+
+        assert!(open(&mut receiver, &second).is_ok());
+        assert!(open(&mut receiver, &first).is_ok());
+        assert_eq!(open(&mut receiver, &first), Err(RejectReason::Replayed));
+        assert_eq!(open(&mut receiver, &second), Err(RejectReason::Replayed));
+    }
+
+    #[test]
+    fn window_accepts_every_counter_once() {
+        let mut window = Window::default();
+
+        assert_eq!(window.check(1), Ok(()));
+        assert_eq!(window.check(1), Err(RejectReason::Replayed));
+        assert_eq!(window.check(4), Ok(()));
+        assert_eq!(window.check(3), Ok(()));
+        assert_eq!(window.check(3), Err(RejectReason::Replayed));
+        assert_eq!(window.check(2), Ok(()));
+        assert_eq!(window.check(4), Err(RejectReason::Replayed));
+    }
+
+    #[test]
+    fn window_rejects_counters_too_far_behind() {
+        let mut window = Window::default();
+        assert_eq!(window.check(1_000), Ok(()));
+
+        assert_eq!(window.check(1_000 - REPLAY_WINDOW), Ok(()));
+        assert_eq!(window.check(1_000 - REPLAY_WINDOW - 1), Err(RejectReason::Replayed));
+    }
+
+    #[test]
+    fn window_forgets_counters_it_jumped_beyond() {
+        let mut window = Window::default();
+        assert_eq!(window.check(1), Ok(()));
+        assert_eq!(window.check(2), Ok(()));
+
+        // The counter right at the end of the window is still remembered.
+        assert_eq!(window.check(2 + REPLAY_WINDOW), Ok(()));
+        assert_eq!(window.check(2), Err(RejectReason::Replayed));
+        assert_eq!(window.check(1), Err(RejectReason::Replayed));
+
+        // Beyond it, everything below falls out of the window.
+        assert_eq!(window.check(3 + 2 * REPLAY_WINDOW), Ok(()));
+        assert_eq!(window.check(3 + REPLAY_WINDOW), Ok(()));
+        assert_eq!(window.check(2 + REPLAY_WINDOW), Err(RejectReason::Replayed));
+    }
+
+    #[test]
+    fn new_epoch_starts_over_and_retires_the_old_one() {
+        let mut security = Security::default();
+        let check = |security: &mut Security, epoch, counter| security.check_freshness(KeyKind::Session, 1, 2, [epoch; 8], counter);
+
+        assert_eq!(check(&mut security, 1, 50), Ok(()));
+        // The sender rebooted and counts from the start again.
+        assert_eq!(check(&mut security, 2, 1), Ok(()));
+        assert_eq!(check(&mut security, 2, 1), Err(RejectReason::Replayed));
+        assert_eq!(check(&mut security, 1, 51), Err(RejectReason::Replayed));
+        assert_eq!(check(&mut security, 2, 2), Ok(()));
+
+        // Other senders are told apart by their mesh ID.
+        assert_eq!(security.check_freshness(KeyKind::Session, 1, 3, [1; 8], 51), Ok(()));
+    }
+}
@@ -0,0 +1,631 @@
+//! The radio-side protocol logic, independent of the driver moving bytes in and out of the DW3000.
+
+use std::collections::{HashSet, VecDeque};
+
+use ledswarm_protocol::Frame;
+
+use crate::mesh::{KeyKind, MeshMessage, MeshPacket, Payload, Sealed, BROADCAST, BROADCAST_PAN_ID};
+use crate::ranging::twr;
+use crate::ranging::{Outgoing, RangingConfig, RangingEngine, SendAt};
+
+use super::delivery::Delivery;
+use super::diagnostics::{LinkDiagnostics, LinkMonitor};
+use super::fragment::Fragmentation;
+use super::profile::RadioProfile;
+use super::relay::{self, Relay};
+use super::security::{RejectReason, Security};
+use super::sts::{Sts, StsMonitor};
+use super::sync::NetworkClock;
+use super::tdma::{self, Tdma, TdmaConfig, Traffic};
+use super::{RadioCommand, RadioEvent};
+
+/// How long a reliable packet waits for its acknowledgements before it is repeated, while there is no schedule to
+/// derive it from, in microseconds.
+const DEFAULT_RETRY_US: u64 = 200_000;
+
+/// How many superframes ahead the master announces a switch of the radio profile, so every client hears about it.
+const PROFILE_SWITCH_SUPERFRAMES: u32 = 8;
+
+/// Time without a schedule after which a controller which isn't the master looks for the mesh on other radio profiles,
+/// in microseconds.
+const SCAN_AFTER_US: u64 = 10_000_000;
+
+/// How long a controller looking for the mesh listens on each radio profile, in microseconds.
+const SCAN_DWELL_US: u64 = 2_000_000;
+
+/// A payload the driver should transmit, and when.
+#[derive(Debug, Clone)]
+pub struct Transmission {
+    pub payload: Payload,
+    pub send_at: SendAt,
+    /// The scrambled timestamp sequence to send the frame with, if any.
+    pub sts: Option<Sts>,
+}
+
+impl From<Outgoing> for Transmission {
+    fn from(outgoing: Outgoing) -> Self {
+        Self {
+            payload: Payload::Mesh(outgoing.packet),
+            send_at: outgoing.send_at,
+            sts: None,
+        }
+    }
+}
+
+/// What the driver knows about a received frame besides its payload.
+#[derive(Debug, Clone, Copy)]
+pub struct Reception {
+    /// The DW3000 time at which the frame arrived.
+    pub rx_time: u64,
+    /// Whether the scrambled timestamp sequence of the frame matched, if the receiver expected one.
+    pub sts_valid: Option<bool>,
+    /// The signal quality of the frame, if the driver could read it.
+    pub diagnostics: Option<LinkDiagnostics>,
+}
+
+pub struct RadioStack {
+    ranging: RangingEngine,
+    tdma: Tdma,
+    /// Delayed answers to ranging messages, which have to be sent before anything else to meet their deadline.
+    replies: VecDeque<Transmission>,
+    /// Protocol frames and session messages queued by the controller.
+    outbox: VecDeque<Payload>,
+    /// Acknowledgements and session messages which are repeated until they are acknowledged.
+    delivery: Delivery,
+    /// Payloads too large for a single frame, on their way out and in.
+    fragmentation: Fragmentation,
+    /// Payloads passed on for controllers out of range of each other.
+    relay: Relay,
+    security: Security,
+    sts_monitor: StsMonitor,
+    /// The peers whose scrambled timestamp sequences matched in this session, which never range without them again.
+    sts_verified: HashSet<u16>,
+    links: LinkMonitor,
+    /// The network time, which the master stamps on its beacons and the clients follow.
+    clock: NetworkClock,
+    /// Whether the network time moved since the controller was last told about it.
+    clock_updated: bool,
+    /// The counter the last beacon of this controller was sealed with, to tell it apart once it went out.
+    beacon_counter: Option<u32>,
+    /// Received protocol frames for the controller.
+    frames: VecDeque<Frame>,
+    events: VecDeque<RadioEvent>,
+    /// Sequence number for the MAC header of the next transmission.
+    mac_seq: u8,
+    /// The PAN ID of the session this controller belongs to, or `None` while it listens to every session in range.
+    pan_id: Option<u16>,
+    profile: RadioProfile,
+    /// When this controller last followed a schedule or was told which profile to use, and when it last moved on to
+    /// another profile looking for a mesh.
+    schedule_seen_us: Option<u64>,
+    scanned_us: u64,
+}
+
+impl RadioStack {
+    pub fn new(ranging: RangingConfig, tdma: TdmaConfig) -> Self {
+        Self {
+            ranging: RangingEngine::new(ranging),
+            tdma: Tdma::new(tdma),
+            replies: VecDeque::new(),
+            outbox: VecDeque::new(),
+            delivery: Delivery::new(),
+            fragmentation: Fragmentation::new(),
+            relay: Relay::new(),
+            security: Security::new(),
+            sts_monitor: StsMonitor::new(),
+            sts_verified: HashSet::new(),
+            links: LinkMonitor::new(),
+            clock: NetworkClock::new(),
+            clock_updated: false,
+            beacon_counter: None,
+            frames: VecDeque::new(),
+            events: VecDeque::new(),
+            mac_seq: 0,
+            pan_id: None,
+            profile: RadioProfile::default(),
+            schedule_seen_us: None,
+            scanned_us: 0,
+        }
+    }
+
+    pub fn ranging_config(&self) -> &RangingConfig {
+        &self.ranging.config
+    }
+
+    pub fn handle_command(&mut self, command: RadioCommand) {
+        match command {
+            RadioCommand::Transmit(frame) => self.outbox.push_back(Payload::Frame(frame)),
+            RadioCommand::SetNodeId(node_id) => {
+                self.ranging.set_node_id(node_id);
+                self.tdma.set_node_id(node_id);
+            },
+            RadioCommand::SetPeers(peers) => {
+                self.ranging.add_peers(&peers);
+                self.tdma.add_members(&peers);
+            },
+            RadioCommand::RemovePeer(peer) => {
+                self.ranging.remove_peer(peer);
+                self.tdma.remove_member(peer);
+                self.sts_verified.remove(&peer);
+                self.events.extend(self.delivery.forget(peer));
+            },
+            RadioCommand::PublishSchedule(publish) => self.tdma.set_coordinator(publish),
+            RadioCommand::SetPanId(pan_id) => {
+                // Another mesh counts another network time.
+                if self.pan_id != pan_id {
+                    self.clock.reset();
+                }
+                self.pan_id = pan_id;
+            },
+            RadioCommand::SetSession(nonce) => {
+                self.security.set_session(nonce);
+                self.sts_monitor = StsMonitor::new();
+                self.sts_verified.clear();
+            },
+            RadioCommand::DisableSts(member) => {
+                // A peer which synced to the sequences before still can, so letting it range without would only help
+                // someone spoofing distances.
+                if self.sts_verified.contains(&member) {
+                    println!("Controller {} ranged with scrambled timestamp sequences before, not letting it range without", member);
+                } else {
+                    self.tdma.disable_sts(member);
+                }
+            },
+            RadioCommand::SetProfile(profile) => {
+                self.set_profile(profile);
+                self.schedule_seen_us = None;
+            },
+            RadioCommand::SwitchProfile(profile) => {
+                if self.tdma.is_coordinator() {
+                    self.tdma.announce_switch(profile, PROFILE_SWITCH_SUPERFRAMES);
+                } else {
+                    self.set_profile(profile);
+                }
+            },
+            RadioCommand::SetAntennaDelays { tx, rx } => {
+                self.ranging.config.tx_antenna_delay = tx;
+                self.ranging.config.rx_antenna_delay = rx;
+            },
+            RadioCommand::Send { dst, message } => {
+                // Controllers without a mesh ID yet send from the broadcast address.
+                let src = self.tdma.node_id().unwrap_or(BROADCAST);
+                self.outbox.push_back(Payload::Mesh(MeshPacket::new(src, dst, MeshMessage::Session(message))));
+            },
+            RadioCommand::SendReliable { dst, message } => {
+                let src = self.tdma.node_id().unwrap_or(BROADCAST);
+                let recipients = match dst {
+                    BROADCAST => self.tdma.schedule()
+                        .map(|schedule| schedule.members.iter().copied().filter(|member| *member != src).collect())
+                        .unwrap_or_default(),
+                    dst => vec![dst],
+                };
+                self.delivery.send(MeshPacket::new(src, dst, MeshMessage::Session(message)), recipients);
+            },
+        }
+    }
+
+    /// The PAN ID and short address the radio filters frames for in hardware, once this controller joined a session.
+    pub fn address(&self) -> Option<(u16, u16)> {
+        Some((self.pan_id?, self.tdma.node_id()?))
+    }
+
+    /// The radio profile the driver should configure the DW3000 with.
+    pub fn profile(&self) -> RadioProfile {
+        self.profile
+    }
+
+    fn set_profile(&mut self, profile: RadioProfile) {
+        if self.profile != profile {
+            self.profile = profile;
+            self.events.push_back(RadioEvent::ProfileChanged(profile));
+        }
+    }
+
+    /// Follow a switch of the radio profile announced by the master, or look for a lost mesh on the other profiles.
+    ///
+    /// Takes the DW3000 time of the next transmission, so the master sends the first beacon after a switch on the new
+    /// profile. Everyone switches a little early, during the last ranging slot, which is never used.
+    fn update_profile(&mut self, now_us: u64, time: u64) {
+        let Some(schedule) = self.tdma.schedule() else {
+            let lost = now_us.saturating_sub(*self.schedule_seen_us.get_or_insert(now_us)) >= SCAN_AFTER_US;
+            if lost && !self.tdma.is_coordinator() && now_us.saturating_sub(self.scanned_us) >= SCAN_DWELL_US {
+                self.scanned_us = now_us;
+                self.set_profile(self.profile.next());
+            }
+            return;
+        };
+        self.schedule_seen_us = Some(now_us);
+
+        let Some(switch) = schedule.profile_switch else {
+            return;
+        };
+        let ahead = twr::add(time, twr::us_to_units(self.tdma.config.lead_us as u64));
+        if self.tdma.superframe_at(ahead).is_some_and(|(superframe, _)| !tdma::is_before(superframe, switch.superframe)) {
+            self.set_profile(switch.profile);
+        }
+    }
+
+    /// The scrambled timestamp sequence of frames on the air at the given DW3000 time, if they carry one.
+    ///
+    /// Only exchanges in the ranging slots of a session use one, unless one side failed to sync, so the driver switches
+    /// its receiver along with the slots.
+    pub fn sts(&self, radio_time: u64) -> Option<Sts> {
+        if !self.ranging.config.sts {
+            return None;
+        }
+
+        let keys = self.security.sts_keys()?;
+        let node_id = self.tdma.node_id()?;
+        let schedule = self.tdma.schedule()?;
+        let (initiator, superframe) = self.tdma.ranging_slot_at(radio_time)?;
+        // Everyone else only ever hears from the initiator in its slot, which only knows its peer once it started.
+        let peer = if initiator == node_id { self.ranging.exchange_peer()? } else { initiator };
+
+        // A peer which ranged with the sequences before keeps them, whatever the schedule says.
+        if schedule.without_sts.contains(&node_id) || (schedule.without_sts.contains(&peer) && !self.sts_verified.contains(&peer)) {
+            return None;
+        }
+
+        Some(keys.at(superframe, initiator))
+    }
+
+    /// How long to wait for acknowledgements, which every member sends in its own data slot of the next superframe.
+    fn retry_us(&self) -> u64 {
+        self.tdma.schedule().map_or(DEFAULT_RETRY_US, |schedule| schedule.duration_us() * 3 / 2)
+    }
+
+    /// The next payload to transmit, if there is one.
+    ///
+    /// Takes the time of the host for timeouts and the DW3000 system time to place transmissions in their TDMA slots.
+    pub fn poll_transmit(&mut self, now_us: u64, radio_now: u64) -> Option<Transmission> {
+        let transmission = self.next_transmission(now_us, radio_now);
+        self.update_profile(now_us, match transmission.as_ref().map(|transmission| transmission.send_at) {
+            Some(SendAt::Delayed(time)) => time,
+            _ => radio_now,
+        });
+
+        // The host and DW3000 times were taken together, so they pin the network time to the host clock best.
+        self.clock.refresh(radio_now);
+        if std::mem::take(&mut self.clock_updated) {
+            self.events.extend(self.clock.sample(now_us, radio_now).map(RadioEvent::NetworkTime));
+        }
+
+        let mut transmission = transmission?;
+        transmission.payload.set_pan_id(self.pan_id.unwrap_or(BROADCAST_PAN_ID));
+        transmission.sts = self.sts(match transmission.send_at {
+            SendAt::Now => radio_now,
+            SendAt::Delayed(time) => time,
+        });
+
+        Some(transmission)
+    }
+
+    fn next_transmission(&mut self, now_us: u64, radio_now: u64) -> Option<Transmission> {
+        if let Some(reply) = self.replies.pop_front() {
+            return Some(reply);
+        }
+
+        if let Some((mut schedule, send_at)) = self.tdma.poll_beacon(radio_now) {
+            let node_id = self.tdma.node_id()?;
+
+            // Only beacons sent at a known time can carry the network time, which a late one simply goes without.
+            if let SendAt::Delayed(time) = send_at {
+                let start_us = now_us as f64 + twr::units_to_us(twr::elapsed(radio_now, time));
+                schedule.network_us = Some(self.clock.stamp(time, start_us));
+                self.clock_updated = true;
+            }
+
+            // Beacons are sealed with the swarm key, so only the master can send them, and the PAN ID is authenticated
+            // along with them.
+            let mut beacon = Payload::Mesh(MeshPacket::new(node_id, BROADCAST, MeshMessage::Beacon { schedule }));
+            beacon.set_pan_id(self.pan_id.unwrap_or(BROADCAST_PAN_ID));
+            let payload = self.security.seal(beacon, node_id)?;
+            if let Payload::Sealed(sealed) = &payload {
+                self.beacon_counter = Some(sealed.counter);
+            }
+
+            return Some(Transmission {
+                payload,
+                send_at,
+                sts: None,
+            });
+        }
+
+        self.events.extend(self.delivery.expire(now_us));
+        self.events.extend(self.fragmentation.expire(now_us));
+        self.events.extend(self.links.report(now_us));
+
+        if self.fragmentation.is_due() || self.delivery.is_due(now_us) || self.relay.is_due() || !self.outbox.is_empty() {
+            if let Some(opportunity) = self.tdma.opportunity(Traffic::Data, radio_now) {
+                self.tdma.take(opportunity);
+
+                // The fragments of a payload stay together, and acknowledgements and repetitions go next so the sender
+                // doesn't repeat its packet needlessly. Payloads passed on for others take turns with new ones.
+                if let Some(fragment) = self.fragmentation.poll() {
+                    return Some(Transmission {
+                        payload: fragment,
+                        send_at: opportunity.send_at,
+                        sts: None,
+                    });
+                }
+
+                let retry_us = self.retry_us();
+                let src = self.tdma.node_id().unwrap_or(BROADCAST);
+                let pan_id = self.pan_id.unwrap_or(BROADCAST_PAN_ID);
+                let send_time = match opportunity.send_at {
+                    SendAt::Delayed(time) => time,
+                    SendAt::Now => radio_now,
+                };
+                let contended = !self.outbox.is_empty();
+                return self.delivery.poll(now_us, retry_us)
+                    .map(Payload::Mesh)
+                    .or_else(|| self.relay.poll(send_time, now_us, contended))
+                    .or_else(|| self.outbox.pop_front())
+                    .and_then(|mut payload| {
+                        // The PAN ID is authenticated along with the payload, so it has to be set before sealing.
+                        payload.set_pan_id(pan_id);
+                        self.security.seal(payload, src)
+                    })
+                    .map(|payload| self.relay.wrap(payload, now_us))
+                    .map(|payload| self.security.sign_relayed(payload))
+                    .and_then(|payload| self.fragmentation.split(payload, src))
+                    .map(|payload| Transmission {
+                        payload,
+                        send_at: opportunity.send_at,
+                        sts: None,
+                    });
+            }
+        }
+
+        let opportunity = self.tdma.opportunity(Traffic::Ranging, radio_now)?;
+        let mut outgoing = self.ranging.poll(now_us)?;
+        self.tdma.take(opportunity);
+        outgoing.send_at = opportunity.send_at;
+
+        Some(outgoing.into())
+    }
+
+    /// Encode a transmission into the bytes for the DW3000.
+    pub fn encode(&mut self, transmission: &Transmission) -> Vec<u8> {
+        self.mac_seq = self.mac_seq.wrapping_add(1);
+        transmission.payload.to_bytes(self.mac_seq)
+    }
+
+    /// Tell the stack that a transmission went out at the given DW3000 time.
+    pub fn on_transmitted(&mut self, transmission: &Transmission, tx_time: u64) {
+        match &transmission.payload {
+            Payload::Sealed(sealed) if Some(sealed.counter) == self.beacon_counter => self.tdma.on_beacon_sent(tx_time),
+            Payload::Mesh(packet) => match packet.message {
+                MeshMessage::Beacon { .. } | MeshMessage::Ack { .. } | MeshMessage::Session(_) => {},
+                _ => self.ranging.on_transmitted(packet, tx_time),
+            },
+            _ => {},
+        }
+    }
+
+    /// Process a payload received at the given host time.
+    pub fn on_received(&mut self, payload: Payload, now_us: u64, mut reception: Reception) {
+        // Without hardware filtering, packets from other sessions in range still arrive.
+        if let (Some(own), Some(pan_id)) = (self.pan_id, payload.pan_id()) {
+            if pan_id != own && pan_id != BROADCAST_PAN_ID {
+                return;
+            }
+        }
+
+        // Only frames count, not the payloads put back together from them.
+        if let Some(src) = payload.src().filter(|src| *src != BROADCAST) {
+            self.relay.on_heard(src, now_us);
+            if let Some(diagnostics) = reception.diagnostics.take() {
+                self.links.on_received(src, &diagnostics);
+            }
+        }
+
+        self.process(payload, now_us, reception, true);
+    }
+
+    /// Check and process a payload, which was received straight from the controller it comes from if `direct` is set,
+    /// or taken out of a relayed envelope otherwise.
+    fn process(&mut self, payload: Payload, now_us: u64, reception: Reception, direct: bool) {
+        let own_id = self.tdma.node_id();
+
+        // Payloads arriving both directly and through a neighbour only count once, and broadcasts go on to whoever
+        // depends on this controller.
+        if direct && !self.relay.on_direct(&payload, now_us) {
+            return;
+        }
+
+        match payload {
+            Payload::Fragment(fragment) => {
+                if !fragment.is_for(self.tdma.node_id().unwrap_or(BROADCAST)) {
+                    return;
+                }
+                let pan_id = fragment.pan_id;
+                // Ranging never uses fragments, so the time of the last one serves the whole payload. The link to the
+                // sender was already accounted for by the fragments themselves.
+                if let Some(payload) = self.fragmentation.on_received(fragment, now_us).and_then(|bytes| Payload::from_mac_payload(pan_id, &bytes)) {
+                    self.process(payload, now_us, reception, direct);
+                }
+            },
+            Payload::Sealed(sealed) => {
+                let Some(payload) = self.open(&sealed, own_id) else {
+                    return;
+                };
+
+                if direct {
+                    self.relay.pass_on_direct(&sealed, relay::superframe_us(&payload), own_id, reception.rx_time, now_us);
+                }
+                self.accept(payload, now_us, reception);
+            },
+            Payload::Relayed(relayed) => {
+                if !relayed.is_for(own_id.unwrap_or(BROADCAST)) {
+                    return;
+                }
+
+                if let Err(reason) = self.security.verify_relayed(&relayed) {
+                    self.events.push_back(RadioEvent::Rejected { src: relayed.src, reason });
+                    return;
+                }
+                let Some(relayed) = self.relay.on_received(relayed, own_id, now_us) else {
+                    return;
+                };
+
+                // Only sealed payloads are ever relayed, and their addresses have to be the ones of the envelope.
+                let sealed = match Payload::from_mac_payload(relayed.pan_id, &relayed.data) {
+                    Some(Payload::Sealed(sealed)) if sealed.src == relayed.origin && sealed.dst == relayed.dst => sealed,
+                    _ => {
+                        self.events.push_back(RadioEvent::Rejected { src: relayed.src, reason: RejectReason::Forged });
+                        return;
+                    },
+                };
+
+                if relayed.dst != BROADCAST && Some(relayed.dst) != own_id {
+                    // The payload is opened by its recipient, which is the only one to use up its counter.
+                    if own_id.is_some() {
+                        match self.security.authentic(&sealed) {
+                            Ok(()) => self.relay.pass_on(relayed, None, own_id, reception.rx_time, now_us),
+                            Err(reason) => self.events.push_back(RadioEvent::Rejected { src: sealed.src, reason }),
+                        }
+                    }
+                    return;
+                }
+
+                let Some(payload) = self.open(&sealed, own_id) else {
+                    return;
+                };
+
+                // Beacons mark the start of the superframe at the time they were sent, not when they got here.
+                let rx_time = reception.rx_time.wrapping_sub(twr::us_to_units(relayed.held_us as u64)) & twr::TIMESTAMP_MASK;
+                self.relay.pass_on(relayed, relay::superframe_us(&payload), own_id, reception.rx_time, now_us);
+                self.accept(payload, now_us, Reception { rx_time, sts_valid: None, diagnostics: None });
+            },
+            Payload::Frame(_) => self.events.push_back(RadioEvent::Rejected { src: BROADCAST, reason: RejectReason::Unauthenticated }),
+            Payload::Mesh(packet) if matches!(
+                packet.message,
+                MeshMessage::Session(_) | MeshMessage::Ack { .. } | MeshMessage::Beacon { .. } | MeshMessage::RangingReport { .. }
+            ) => {
+                if packet.is_for(own_id.unwrap_or(BROADCAST)) {
+                    self.events.push_back(RadioEvent::Rejected { src: packet.src, reason: RejectReason::Unauthenticated });
+                }
+            },
+            payload => self.accept(payload, now_us, reception),
+        }
+    }
+
+    /// Open a sealed payload meant for this controller, reporting it if it doesn't pass the checks.
+    fn open(&mut self, sealed: &Sealed, own_id: Option<u16>) -> Option<Payload> {
+        // Before joining, the traffic of the session can't be read and is no reason for concern.
+        if !sealed.is_for(own_id.unwrap_or(BROADCAST)) || (sealed.key == KeyKind::Session && !self.security.has_session()) {
+            return None;
+        }
+
+        match self.security.open(sealed) {
+            Ok(payload) => Some(payload),
+            Err(reason) => {
+                self.events.push_back(RadioEvent::Rejected { src: sealed.src, reason });
+                None
+            },
+        }
+    }
+
+    /// Process a payload which passed all checks, or doesn't need any.
+    fn accept(&mut self, payload: Payload, now_us: u64, reception: Reception) {
+        match payload {
+            Payload::Frame(frame) => self.frames.push_back(frame),
+            Payload::Fragment(_) | Payload::Sealed(_) | Payload::Relayed(_) => {},
+            Payload::Mesh(packet) => match packet.message {
+                MeshMessage::Session(ref message) => {
+                    let own_id = self.tdma.node_id().unwrap_or(BROADCAST);
+                    if !packet.is_for(own_id) {
+                        return;
+                    }
+
+                    // Controllers without a mesh ID yet are never among the recipients, so they don't acknowledge anything.
+                    if let Some(seq) = packet.seq.filter(|_| own_id != BROADCAST) {
+                        if !self.delivery.on_received(own_id, &packet, seq, now_us) {
+                            return;
+                        }
+                    }
+
+                    self.events.push_back(RadioEvent::Received { src: packet.src, message: message.clone() });
+                },
+                MeshMessage::Ack { seq } => {
+                    if packet.dst == self.tdma.node_id().unwrap_or(BROADCAST) {
+                        self.events.extend(self.delivery.on_ack(packet.src, seq));
+                    }
+                },
+                MeshMessage::Beacon { schedule } => {
+                    // Only the master sends beacons, and whoever takes over after a failover takes over its mesh ID too.
+                    if packet.src != 0 {
+                        return;
+                    }
+
+                    // A controller looking for a mesh sticks to the first session it hears, so it follows a single schedule.
+                    if self.pan_id.is_none() && packet.pan_id != BROADCAST_PAN_ID {
+                        self.pan_id = Some(packet.pan_id);
+                    }
+
+                    // Range with everyone in the mesh, not just the controllers heard so far.
+                    self.ranging.add_peers(&schedule.members);
+                    if let Some(network_us) = schedule.network_us.filter(|_| !self.tdma.is_coordinator()) {
+                        self.clock.on_beacon(reception.rx_time, network_us, now_us);
+                        self.clock_updated = true;
+                    }
+                    self.tdma.on_beacon(schedule, reception.rx_time);
+                },
+                MeshMessage::RangingPoll { .. }
+                | MeshMessage::RangingResponse { .. }
+                | MeshMessage::RangingFinal { .. }
+                | MeshMessage::RangingReport { .. } => {
+                    // A timestamp without the expected sequence may have been spoofed, so the frame doesn't count.
+                    if let Some(valid) = reception.sts_valid {
+                        if packet.dst == self.tdma.node_id().unwrap_or(BROADCAST) {
+                            self.events.extend(self.sts_monitor.on_checked(valid));
+                            if valid {
+                                self.sts_verified.insert(packet.src);
+                            }
+                        }
+                        if !valid {
+                            return;
+                        }
+                    }
+
+                    if let Some(reply) = self.ranging.on_received(&packet, reception.rx_time) {
+                        self.queue_reply(reply);
+                    }
+
+                    for mut measurement in self.ranging.take_measurements() {
+                        // Every frame of the exchange passed the check, or it wouldn't have completed.
+                        measurement.secure = reception.sts_valid.is_some();
+                        self.events.push_back(RadioEvent::Distance(measurement));
+                    }
+                },
+            },
+        }
+    }
+
+    /// Queue the answer to a ranging message.
+    ///
+    /// The timestamps of an exchange are embedded while the frame goes out, so only the report is sealed, which tells
+    /// the initiator the distance and must not be forged.
+    fn queue_reply(&mut self, reply: Outgoing) {
+        let mut transmission = Transmission::from(reply);
+        if let Payload::Mesh(MeshPacket { message: MeshMessage::RangingReport { .. }, src, .. }) = transmission.payload {
+            transmission.payload.set_pan_id(self.pan_id.unwrap_or(BROADCAST_PAN_ID));
+            let Some(sealed) = self.security.seal(transmission.payload, src) else {
+                return;
+            };
+            transmission.payload = sealed;
+        }
+        self.replies.push_back(transmission);
+    }
+
+    /// Take the next received protocol frame.
+    pub fn take_frame(&mut self) -> Option<Frame> {
+        self.frames.pop_front()
+    }
+
+    /// Take the next event for the controller.
+    pub fn take_event(&mut self) -> Option<RadioEvent> {
+        self.events.pop_front()
+    }
+}
@@ -0,0 +1,98 @@
+//! Scrambled timestamp sequences (STS), which keep anyone outside the session from spoofing ranging timestamps.
+//!
+//! With STS, the DW3000 sends a pseudo-random sequence after the start of every frame and takes the timestamp from it,
+//! so a receiver only trusts the timestamp if it generates the same sequence. The sequence is AES in counter mode under
+//! a key and IV derived from the session key, which only the members of the session know. Each exchange happens in the
+//! ranging slot of its initiator, so both sides load the IV of that slot when it starts without agreeing on it over the
+//! air, and the radio advances the counter with every frame from there.
+//!
+//! A radio which keeps failing to sync to the sequence of the others reports it, and the master lets it range without
+//! STS from then on.
+
+use aes::Aes128;
+use aes::cipher::{BlockEncrypt, KeyInit};
+
+use super::RadioEvent;
+
+/// Ranging frames failing the STS check in a row, without a single good one, after which the radio reports it.
+const MAX_FAILURES: u32 = 8;
+
+/// The key and IV the DW3000 generates the sequence of a ranging slot from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sts {
+    pub key: [u8; 16],
+    /// The lowest 32 bits are the counter the radio increments with every frame it sends or receives.
+    pub iv: [u8; 16],
+}
+
+/// The STS key and IV of a session, before the counter of a slot is filled in.
+pub struct StsKeys {
+    key: [u8; 16],
+    iv: [u8; 16],
+}
+
+impl StsKeys {
+    /// Derive the STS key and IV from the session key, so they are never sent over the air.
+    pub fn derive(session_key: &[u8; 16]) -> Self {
+        let cipher = Aes128::new(session_key.into());
+        let derive = |label: &[u8; 16]| {
+            let mut block = aes::Block::from(*label);
+            cipher.encrypt_block(&mut block);
+            block.into()
+        };
+
+        Self {
+            key: derive(b"LEDswarm STS key"),
+            iv: derive(b"LEDswarm STS iv\0"),
+        }
+    }
+
+    /// The sequence of the ranging slot of the given initiator in the superframe with the given number.
+    ///
+    /// An exchange takes four frames, so the counters of consecutive slots are four apart. They repeat after about
+    /// a million superframes, which takes days even in small meshes.
+    pub fn at(&self, superframe: u32, initiator: u16) -> Sts {
+        let counter = (superframe << 12) | ((initiator as u32 & 0x3FF) << 2);
+        let mut iv = self.iv;
+        iv[.. 4].copy_from_slice(&counter.to_le_bytes());
+
+        Sts { key: self.key, iv }
+    }
+}
+
+/// Watches the STS checks of received ranging frames for a radio which can't sync.
+pub struct StsMonitor {
+    failures: u32,
+    reported: bool,
+}
+
+impl Default for StsMonitor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StsMonitor {
+    pub fn new() -> Self {
+        Self {
+            failures: 0,
+            reported: false,
+        }
+    }
+
+    /// Count the outcome of the STS check of a ranging frame, returning the event to report once it keeps failing.
+    pub fn on_checked(&mut self, valid: bool) -> Option<RadioEvent> {
+        if valid {
+            self.failures = 0;
+            return None;
+        }
+
+        self.failures += 1;
+        if self.failures < MAX_FAILURES || self.reported {
+            return None;
+        }
+
+        self.reported = true;
+        Some(RadioEvent::StsFailed)
+    }
+}
@@ -0,0 +1,258 @@
+//! A clock shared by the whole mesh, so that every controller can do something at the same moment.
+//!
+//! The master counts the network time with its DW3000 and stamps every beacon with the network time at which it goes
+//! out. Every client timestamps the beacons it receives with its own DW3000, which gives it pairs of local and network
+//! times. From these, an alpha-beta filter tracks the network time at the last beacon and how fast the network time
+//! runs compared to the local one, which is the drift between the two crystals. Only authenticated beacons of the
+//! master are followed, and a beacon further off the prediction than the crystals could have drifted apart since the
+//! last one is ignored rather than taken over. The client only starts over from the time of a beacon after leaving its
+//! mesh, when the radio forgets the network time along with the PAN ID.
+//!
+//! A master which never followed another one starts the network time from its own clock, and one which takes over the
+//! mesh carries on with the network time it followed so far, so the network time doesn't jump when the master changes.
+
+use serde::Serialize;
+
+use crate::ranging::twr::{self, TIMESTAMP_MASK};
+
+/// Share of the difference between the stamp of a beacon and the prediction applied to the network time.
+const ALPHA: f64 = 0.3;
+
+/// Share of the difference between the stamp of a beacon and the prediction, over the time since the last one, applied
+/// to the rate.
+const BETA: f64 = 0.05;
+
+/// Difference from the prediction in microseconds beyond which a client ignores a beacon, on top of the drift since the
+/// last beacon it followed.
+const MAX_CORRECTION_US: f64 = 1_000.0;
+
+/// The crystals of the DW3000 are accurate to 20 ppm, so their rates never differ by more than this.
+const MAX_DRIFT: f64 = 100e-6;
+
+/// Time after which the network time moves on to a later DW3000 time, well before the DW3000 timestamps wrap around,
+/// in microseconds.
+const REFERENCE_AGE_US: f64 = 1_000_000.0;
+
+/// The network time at a moment of the controller's own clock, which the controller extrapolates from.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct NetworkTime {
+    /// The time of the controller's own clock, in microseconds.
+    pub local_us: u64,
+    /// The network time at that moment, in microseconds.
+    pub network_us: u64,
+    /// How much faster the network time runs than the local one, in parts per million.
+    pub drift_ppm: f32,
+}
+
+pub struct NetworkClock {
+    /// A DW3000 time of this controller and the network time at that moment, in microseconds.
+    reference: Option<(u64, f64)>,
+    /// Network microseconds per local microsecond.
+    rate: f64,
+    /// The host time at which the last beacon was followed, in microseconds.
+    followed_us: u64,
+}
+
+impl Default for NetworkClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl NetworkClock {
+    pub fn new() -> Self {
+        Self {
+            reference: None,
+            rate: 1.0,
+            followed_us: 0,
+        }
+    }
+
+    /// Forget the network time, so the next beacon starts it over.
+    pub fn reset(&mut self) {
+        self.reference = None;
+        self.rate = 1.0;
+    }
+
+    /// The network time at the given DW3000 time, if there is one yet.
+    pub fn network_at(&self, time: u64) -> Option<f64> {
+        let (reference, network_us) = self.reference?;
+        Some(network_us + signed_elapsed_us(reference, time) * self.rate)
+    }
+
+    /// Carry the network time on to the given DW3000 time, so it keeps running without beacons, after losing the master
+    /// or when taking over from it.
+    pub fn refresh(&mut self, time: u64) {
+        if self.reference.is_some_and(|(reference, _)| signed_elapsed_us(reference, time) > REFERENCE_AGE_US) {
+            self.reference = self.network_at(time).map(|network_us| (time, network_us));
+        }
+    }
+
+    /// Stamp a beacon of the master which goes out at the given DW3000 time.
+    ///
+    /// Starts the network time from the given one if there is none yet, which is the time of the controller's own
+    /// clock at that moment.
+    pub fn stamp(&mut self, time: u64, start_us: f64) -> u64 {
+        let network_us = self.network_at(time).unwrap_or(start_us);
+        self.reference = Some((time, network_us));
+        network_us as u64
+    }
+
+    /// Follow the network time of a beacon of the master which started its superframe at the given DW3000 time, and
+    /// arrived at the given host time.
+    pub fn on_beacon(&mut self, time: u64, network_us: u64, now_us: u64) {
+        let network_us = network_us as f64;
+        let (Some((reference, _)), Some(predicted_us)) = (self.reference, self.network_at(time)) else {
+            self.reference = Some((time, network_us));
+            self.rate = 1.0;
+            self.followed_us = now_us;
+            return;
+        };
+
+        let residual_us = network_us - predicted_us;
+        let gate_us = MAX_CORRECTION_US + MAX_DRIFT * now_us.saturating_sub(self.followed_us) as f64;
+        if residual_us.abs() > gate_us {
+            println!("Ignoring a beacon {:.0} us off the network time, more than the {:.0} us it could have drifted", residual_us, gate_us);
+            return;
+        }
+        self.followed_us = now_us;
+
+        let elapsed_us = signed_elapsed_us(reference, time);
+        if elapsed_us > 0.0 {
+            self.rate = (self.rate + BETA * residual_us / elapsed_us).clamp(1.0 - MAX_DRIFT, 1.0 + MAX_DRIFT);
+        }
+        self.reference = Some((time, predicted_us + ALPHA * residual_us));
+    }
+
+    /// The network time at a moment of the controller's own clock, when the DW3000 showed the given time.
+    pub fn sample(&self, local_us: u64, time: u64) -> Option<NetworkTime> {
+        Some(NetworkTime {
+            local_us,
+            network_us: self.network_at(time)? as u64,
+            drift_ppm: ((self.rate - 1.0) * 1e6) as f32,
+        })
+    }
+}
+
+/// The microseconds from one DW3000 time to another, negative if the second one comes first.
+fn signed_elapsed_us(from: u64, to: u64) -> f64 {
+    let elapsed = twr::elapsed(from, to);
+    if elapsed > TIMESTAMP_MASK / 2 {
+        -twr::units_to_us(twr::elapsed(to, from))
+    } else {
+        twr::units_to_us(elapsed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Time between two beacons of the master, in microseconds.
+    const BEACON_US: u64 = 100_000;
+
+    /// How much faster the crystal of the master runs than the one of the client.
+    const DRIFT: f64 = 20e-6;
+
+    /// The DW3000 time of the client at the given time of its own clock.
+    fn time(local_us: u64) -> u64 {
+        twr::us_to_units(local_us) & TIMESTAMP_MASK
+    }
+
+    /// The network time of the master at the given time of the client's own clock.
+    fn network_us(local_us: u64) -> u64 {
+        (1_000_000_000.0 + local_us as f64 * (1.0 + DRIFT)) as u64
+    }
+
+    /// A client which followed the beacons of the master for half a minute, along with the time of the last one.
+    fn settled() -> (NetworkClock, u64) {
+        let mut clock = NetworkClock::default();
+        let mut local_us = 0;
+        // The DW3000 time wraps around on the way, after about 17 seconds.
+        for _ in 0 .. 300 {
+            local_us += BEACON_US;
+            clock.on_beacon(time(local_us), network_us(local_us), local_us);
+        }
+
+        (clock, local_us)
+    }
+
+    #[test]
+    fn first_beacon_starts_the_network_time() {
+        let mut clock = NetworkClock::default();
+        assert_eq!(clock.network_at(time(1_000)), None);
+
+        clock.on_beacon(time(1_000), 5_000_000, 1_000);
+        assert_eq!(clock.network_at(time(1_000)), Some(5_000_000.0));
+    }
+
+    #[test]
+    fn drift_between_the_crystals_is_learned() {
+        let (clock, local_us) = settled();
+
+        let sample = clock.sample(local_us, time(local_us)).unwrap();
+        assert!((sample.drift_ppm - 20.0).abs() < 1.0, "Learned a drift of {} ppm", sample.drift_ppm);
+
+        // Without further beacons, the network time stays right for a while.
+        let later_us = local_us + 1_000_000;
+        let error_us = clock.network_at(time(later_us)).unwrap() - network_us(later_us) as f64;
+        assert!(error_us.abs() < 5.0, "Network time {} us off", error_us);
+    }
+
+    #[test]
+    fn beacon_far_off_the_prediction_is_ignored() {
+        let (mut clock, local_us) = settled();
+        let next_us = local_us + BEACON_US;
+        let predicted_us = clock.network_at(time(next_us));
+
+        clock.on_beacon(time(next_us), network_us(next_us) + 5_000, next_us);
+        assert_eq!(clock.network_at(time(next_us)), predicted_us);
+
+        // The next beacon is followed as if the other one never came.
+        let after_us = next_us + BEACON_US;
+        clock.on_beacon(time(after_us), network_us(after_us), after_us);
+        let error_us = clock.network_at(time(after_us)).unwrap() - network_us(after_us) as f64;
+        assert!(error_us.abs() < 5.0, "Network time {} us off", error_us);
+    }
+
+    #[test]
+    fn beacon_after_a_long_gap_may_be_further_off() {
+        let (mut clock, mut local_us) = settled();
+
+        // A minute without beacons, in which the network time keeps running on the client.
+        for _ in 0 .. 60 {
+            local_us += 1_000_000;
+            clock.refresh(time(local_us));
+        }
+
+        // The master is further off than right after a beacon, but within what crystals can drift apart in a minute.
+        let stamp_us = network_us(local_us) + 5_000;
+        let predicted_us = clock.network_at(time(local_us)).unwrap();
+        clock.on_beacon(time(local_us), stamp_us, local_us);
+
+        let moved_us = clock.network_at(time(local_us)).unwrap() - predicted_us;
+        let residual_us = stamp_us as f64 - predicted_us;
+        assert!(residual_us > 4_000.0);
+        assert!((moved_us - ALPHA * residual_us).abs() < 1.0, "Moved by {} us", moved_us);
+    }
+
+    #[test]
+    fn reset_starts_over_from_the_next_beacon() {
+        let (mut clock, local_us) = settled();
+        clock.reset();
+        assert_eq!(clock.network_at(time(local_us)), None);
+
+        clock.on_beacon(time(local_us), 42, local_us);
+        assert_eq!(clock.network_at(time(local_us)), Some(42.0));
+    }
+
+    #[test]
+    fn elapsed_time_is_signed_across_the_wrap_around() {
+        let before = TIMESTAMP_MASK + 1 - twr::us_to_units(10);
+        let after = twr::us_to_units(10);
+
+        assert!((signed_elapsed_us(before, after) - 20.0).abs() < 0.01);
+        assert!((signed_elapsed_us(after, before) + 20.0).abs() < 0.01);
+        assert!((signed_elapsed_us(after, after)).abs() < 0.01);
+    }
+}
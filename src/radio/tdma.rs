@@ -0,0 +1,569 @@
+//! Time division of the air between the controllers of a mesh.
+//!
+//! The master opens every superframe with a beacon carrying the current [`Schedule`], which lays out the slots of the
+//! superframe in a fixed order:
+//!
+//! ```text
+//! | beacon | join | data slot of each member ... | ranging slot of each member ... |
+//! ```
+//!
+//! Clients take the start of the superframe from the RX timestamp of the beacon and only start transmissions in their
+//! own slots, using delayed sends of the DW3000 to hit them precisely. Controllers which aren't in the schedule yet
+//! share the join slot, and answers within a ranging exchange are sent in the ranging slot of the initiator. Without a
+//! recent beacon there is no schedule to follow, and everything is sent right away.
+//!
+//! Every member adds a data and a ranging slot to the superframe, so each member, the master included, gets to send
+//! less often as the mesh grows. With the default slots, a superframe takes 12 ms plus 38 ms per member, and a mesh is
+//! limited to [`MAX_MEMBERS`] so that the superframes a client looks ahead stay within half a wrap of the DW3000 clock.
+
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::ranging::twr::{self, TIMESTAMP_MASK};
+use crate::ranging::SendAt;
+
+use super::profile::{ProfileSwitch, RadioProfile};
+
+/// The most controllers a mesh hands out slots to, the master included.
+pub const MAX_MEMBERS: usize = 32;
+
+#[derive(Debug, Clone)]
+pub struct TdmaConfig {
+    /// Slot lengths in microseconds, published by the master along with the schedule.
+    pub beacon_slot_us: u32,
+    pub join_slot_us: u32,
+    pub data_slot_us: u32,
+    /// This has to fit the three reply delays of a ranging exchange and the report at the end.
+    pub ranging_slot_us: u32,
+    /// Time left free at the start of every slot to absorb the clock offsets between controllers.
+    pub guard_us: u32,
+    /// Transmissions are handed to the radio at most this long before their slot starts.
+    pub lead_us: u32,
+    /// Slots starting sooner than this are skipped, since the delayed transmission couldn't be set up in time.
+    pub min_lead_us: u32,
+    /// Clients stop following a schedule after missing this many beacons in a row.
+    pub max_missed_beacons: u32,
+}
+
+impl Default for TdmaConfig {
+    fn default() -> Self {
+        Self {
+            beacon_slot_us: 3_000,
+            join_slot_us: 9_000,
+            data_slot_us: 3_000,
+            ranging_slot_us: 35_000,
+            guard_us: 300,
+            lead_us: 4_000,
+            min_lead_us: 1_000,
+            max_missed_beacons: 4,
+        }
+    }
+}
+
+/// The layout of a superframe, as published in the beacon of the master.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Schedule {
+    pub beacon_slot_us: u32,
+    pub join_slot_us: u32,
+    pub data_slot_us: u32,
+    pub ranging_slot_us: u32,
+    /// The mesh IDs of all controllers with their own data and ranging slots, in slot order.
+    pub members: Vec<u16>,
+    /// The number of the superframe, which the master counts up with every beacon.
+    #[serde(default)]
+    pub superframe: u32,
+    /// Members whose radio failed to sync to the scrambled timestamp sequences of the others, and ranges without.
+    #[serde(default)]
+    pub without_sts: Vec<u16>,
+    /// A switch of the radio profile the master announced for an upcoming superframe.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profile_switch: Option<ProfileSwitch>,
+    /// The network time at which the superframe starts, in microseconds, if the beacon goes out at a known time.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub network_us: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Slot {
+    Beacon,
+    Join,
+    Data(u16),
+    Ranging(u16),
+}
+
+impl Schedule {
+    /// The length of a whole superframe in microseconds.
+    pub fn duration_us(&self) -> u64 {
+        self.beacon_slot_us as u64
+            + self.join_slot_us as u64
+            + self.members.len() as u64 * (self.data_slot_us as u64 + self.ranging_slot_us as u64)
+    }
+
+    /// The offset of a slot from the start of the superframe and its length in microseconds, if the schedule has it.
+    pub fn slot(&self, slot: Slot) -> Option<(u64, u64)> {
+        let members = self.members.len() as u64;
+        let data_start = self.beacon_slot_us as u64 + self.join_slot_us as u64;
+        let ranging_start = data_start + members * self.data_slot_us as u64;
+        let position = |id: u16| self.members.iter().position(|member| *member == id).map(|i| i as u64);
+
+        match slot {
+            Slot::Beacon => Some((0, self.beacon_slot_us as u64)),
+            Slot::Join => Some((self.beacon_slot_us as u64, self.join_slot_us as u64)),
+            Slot::Data(id) => position(id).map(|i| (data_start + i * self.data_slot_us as u64, self.data_slot_us as u64)),
+            Slot::Ranging(id) => position(id).map(|i| (ranging_start + i * self.ranging_slot_us as u64, self.ranging_slot_us as u64)),
+        }
+    }
+}
+
+/// What a controller wants to send, which decides the slot it has to wait for.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Traffic {
+    /// Protocol frames from the controller.
+    Data,
+    /// The poll starting a ranging exchange.
+    Ranging,
+}
+
+/// A chance to transmit, which is only used up once passed to [`Tdma::take`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Opportunity {
+    pub send_at: SendAt,
+    slot: Option<(Slot, u64)>,
+}
+
+impl Opportunity {
+    fn now() -> Self {
+        Self {
+            send_at: SendAt::Now,
+            slot: None,
+        }
+    }
+}
+
+pub struct Tdma {
+    pub config: TdmaConfig,
+    node_id: Option<u16>,
+    /// Whether this controller is the master and publishes the schedule.
+    coordinator: bool,
+    /// The controllers the coordinator hands out slots to, besides itself.
+    members: Vec<u16>,
+    /// The members the coordinator lets range without scrambled timestamp sequences.
+    without_sts: Vec<u16>,
+    /// The number of the last superframe the coordinator published.
+    superframe: u32,
+    /// The switch of the radio profile the coordinator announces until it happened.
+    profile_switch: Option<ProfileSwitch>,
+    /// The schedule of the current superframe, either received or published by this controller.
+    schedule: Option<Schedule>,
+    /// The DW3000 time at which the current superframe started.
+    superframe_start: u64,
+    /// The DW3000 time after which each slot may be used again, so a slot isn't used twice.
+    used_until: HashMap<Slot, u64>,
+    /// Which part of the join slot the next join transmission goes to, so joining controllers rarely collide.
+    join_offset_us: u64,
+    random: u64,
+}
+
+impl Tdma {
+    pub fn new(config: TdmaConfig) -> Self {
+        Self {
+            config,
+            node_id: None,
+            coordinator: false,
+            members: vec![],
+            without_sts: vec![],
+            superframe: 0,
+            profile_switch: None,
+            schedule: None,
+            superframe_start: 0,
+            used_until: HashMap::new(),
+            join_offset_us: 0,
+            random: 0x2545_F491_4F6C_DD1D,
+        }
+    }
+
+    pub fn schedule(&self) -> Option<&Schedule> {
+        self.schedule.as_ref()
+    }
+
+    pub fn is_coordinator(&self) -> bool {
+        self.coordinator
+    }
+
+    pub fn node_id(&self) -> Option<u16> {
+        self.node_id
+    }
+
+    pub fn set_node_id(&mut self, node_id: Option<u16>) {
+        self.node_id = node_id;
+        self.used_until.clear();
+    }
+
+    /// Start or stop publishing the schedule.
+    pub fn set_coordinator(&mut self, coordinator: bool) {
+        if self.coordinator != coordinator {
+            self.coordinator = coordinator;
+            // A new master continues the numbering, so the sequences of earlier ranging slots never come up again.
+            if let Some(schedule) = self.schedule.take() {
+                self.superframe = schedule.superframe;
+                self.without_sts = schedule.without_sts;
+                self.profile_switch = schedule.profile_switch;
+            }
+            self.used_until.clear();
+        }
+    }
+
+    /// Give the controllers with the given mesh IDs their own slots from the next superframe on, as long as the
+    /// schedule has room for them besides this controller.
+    pub fn add_members(&mut self, members: &[u16]) {
+        for member in members {
+            if *member != crate::mesh::BROADCAST && !self.members.contains(member) {
+                if self.members.len() + 1 >= MAX_MEMBERS {
+                    println!("No slots left for controller {} in a mesh of {}", member, MAX_MEMBERS);
+                    continue;
+                }
+                self.members.push(*member);
+                self.members.sort();
+            }
+        }
+    }
+
+    /// Take the slots away from a controller which left, from the next superframe on.
+    pub fn remove_member(&mut self, member: u16) {
+        self.members.retain(|known| *known != member);
+        self.without_sts.retain(|known| *known != member);
+    }
+
+    /// Announce a switch to another radio profile, which happens the given number of superframes from now.
+    pub fn announce_switch(&mut self, profile: RadioProfile, superframes: u32) {
+        self.profile_switch = Some(ProfileSwitch {
+            profile,
+            superframe: self.superframe.wrapping_add(superframes),
+        });
+    }
+
+    /// Let a member range without scrambled timestamp sequences from the next superframe on.
+    pub fn disable_sts(&mut self, member: u16) {
+        if !self.without_sts.contains(&member) {
+            self.without_sts.push(member);
+        }
+    }
+
+    /// The schedule to publish along with the time to send it, if this controller is the master and a superframe is due.
+    pub fn poll_beacon(&mut self, radio_now: u64) -> Option<(Schedule, SendAt)> {
+        if !self.coordinator {
+            return None;
+        }
+        let node_id = self.node_id?;
+
+        let send_at = match &self.schedule {
+            Some(current) => {
+                let next = twr::add(self.superframe_start, twr::us_to_units(current.duration_us()));
+                match ahead_us(radio_now, next) {
+                    Some(ahead) if ahead > self.config.lead_us as f64 => return None,
+                    Some(ahead) if ahead >= self.config.min_lead_us as f64 => SendAt::Delayed(next),
+                    // The beacon is late, so start a new superframe right away.
+                    _ => SendAt::Now,
+                }
+            },
+            None => SendAt::Now,
+        };
+
+        let mut members = self.members.clone();
+        if !members.contains(&node_id) {
+            members.push(node_id);
+            members.sort();
+        }
+
+        let schedule = Schedule {
+            beacon_slot_us: self.config.beacon_slot_us,
+            join_slot_us: self.config.join_slot_us,
+            data_slot_us: self.config.data_slot_us,
+            ranging_slot_us: self.config.ranging_slot_us,
+            members,
+            superframe: self.superframe.wrapping_add(1),
+            without_sts: self.without_sts.clone(),
+            profile_switch: self.profile_switch,
+            network_us: None,
+        };
+        self.superframe = schedule.superframe;
+        // The superframe of the switch still announces it, for anyone who missed the earlier beacons.
+        if self.profile_switch.is_some_and(|switch| !is_before(schedule.superframe, switch.superframe)) {
+            self.profile_switch = None;
+        }
+
+        // Until the beacon has actually been sent, assume the superframe starts when it was asked to.
+        self.superframe_start = match send_at {
+            SendAt::Now => radio_now,
+            SendAt::Delayed(time) => time,
+        };
+        self.schedule = Some(schedule.clone());
+
+        Some((schedule, send_at))
+    }
+
+    /// Align the superframe with the TX timestamp of the beacon this controller sent.
+    pub fn on_beacon_sent(&mut self, tx_time: u64) {
+        self.superframe_start = tx_time;
+        self.stir(tx_time);
+    }
+
+    /// Follow the schedule from a beacon received at the given DW3000 time.
+    pub fn on_beacon(&mut self, schedule: Schedule, rx_time: u64) {
+        if self.coordinator {
+            return;
+        }
+
+        // The time of flight is a fraction of the guard time, so the beacon marks the start of the superframe.
+        self.superframe_start = rx_time;
+        self.schedule = Some(schedule);
+        self.stir(rx_time);
+    }
+
+    /// The number of the superframe the given DW3000 time falls into, along with the offset into it in microseconds.
+    pub fn superframe_at(&self, time: u64) -> Option<(u32, u64)> {
+        let schedule = self.schedule.as_ref()?;
+        let elapsed = twr::elapsed(self.superframe_start, time);
+        // The master moves the start to the next superframe a little before it begins.
+        if elapsed >= TIMESTAMP_MASK / 2 {
+            return None;
+        }
+
+        let elapsed_us = twr::units_to_us(elapsed) as u64;
+        let duration_us = schedule.duration_us().max(1);
+
+        Some((schedule.superframe.wrapping_add((elapsed_us / duration_us) as u32), elapsed_us % duration_us))
+    }
+
+    /// The member owning the ranging slot the given DW3000 time falls into, along with the number of its superframe.
+    pub fn ranging_slot_at(&self, time: u64) -> Option<(u16, u32)> {
+        let schedule = self.schedule.as_ref()?;
+        let (superframe, offset_us) = self.superframe_at(time)?;
+
+        schedule.members
+            .iter()
+            .copied()
+            .find(|member| schedule.slot(Slot::Ranging(*member)).is_some_and(|(start, length)| (start .. start + length).contains(&offset_us)))
+            .map(|member| (member, superframe))
+    }
+
+    /// The next chance to send the given kind of traffic, if it's time to hand a transmission to the radio.
+    pub fn opportunity(&mut self, traffic: Traffic, radio_now: u64) -> Option<Opportunity> {
+        self.expire(radio_now);
+
+        let schedule = match &self.schedule {
+            Some(schedule) => schedule,
+            None => return Some(Opportunity::now()),
+        };
+
+        let own = self.node_id.filter(|id| schedule.members.contains(id));
+        let (slot, offset_us) = match (traffic, own) {
+            (Traffic::Data, Some(id)) => (Slot::Data(id), 0),
+            (Traffic::Ranging, Some(id)) => (Slot::Ranging(id), 0),
+            (Traffic::Data, None) => (Slot::Join, self.join_offset_us),
+            // Only controllers in the schedule range, everyone else would disturb the exchanges of others.
+            (Traffic::Ranging, None) => return None,
+        };
+
+        let (start_us, _) = schedule.slot(slot)?;
+        let duration_us = schedule.duration_us();
+        let slot_offset_us = start_us + self.config.guard_us as u64 + offset_us;
+
+        // Look at upcoming superframes as well, in case the beacon of the next one is missed.
+        for superframe in 0 ..= self.config.max_missed_beacons as u64 {
+            let start = twr::add(self.superframe_start, twr::us_to_units(superframe * duration_us + slot_offset_us));
+
+            if self.used_until.get(&slot).is_some_and(|used| ahead_us(start, *used).is_some()) {
+                continue;
+            }
+
+            match ahead_us(radio_now, start) {
+                Some(ahead) if ahead > self.config.lead_us as f64 => return None,
+                Some(ahead) if ahead >= self.config.min_lead_us as f64 => {
+                    return Some(Opportunity {
+                        send_at: SendAt::Delayed(start),
+                        slot: Some((slot, start)),
+                    })
+                },
+                _ => continue,
+            }
+        }
+
+        None
+    }
+
+    /// Use up an opportunity after handing a transmission to the radio for it.
+    pub fn take(&mut self, opportunity: Opportunity) {
+        let Some((slot, start)) = opportunity.slot else {
+            return;
+        };
+
+        let mut used_until = start;
+
+        if slot == Slot::Join {
+            if let Some(schedule) = &self.schedule {
+                let duration_us = schedule.duration_us();
+                let part_us = schedule.data_slot_us.max(1) as u64;
+                let parts = (schedule.join_slot_us as u64 / part_us).max(1);
+
+                // Back off for a random number of superframes and pick a random part of the join slot, so that
+                // controllers joining at the same time don't collide over and over again.
+                let superframes = self.next_random() % 3;
+                used_until = twr::add(start, twr::us_to_units(superframes * duration_us));
+                self.join_offset_us = self.next_random() % parts * part_us;
+            }
+        }
+
+        self.used_until.insert(slot, used_until);
+    }
+
+    /// Forget a received schedule once too many beacons were missed.
+    fn expire(&mut self, radio_now: u64) {
+        if self.coordinator {
+            return;
+        }
+
+        if let Some(schedule) = &self.schedule {
+            let valid_us = (self.config.max_missed_beacons as u64 + 1) * schedule.duration_us();
+            let age_us = twr::units_to_us(twr::elapsed(self.superframe_start, radio_now));
+
+            if age_us > valid_us as f64 {
+                println!("Lost the TDMA schedule after missing {} beacons", self.config.max_missed_beacons);
+                self.schedule = None;
+                self.used_until.clear();
+            }
+        }
+    }
+
+    /// Mix a timestamp into the random state, whose lowest bits are noisy enough to tell controllers apart.
+    fn stir(&mut self, time: u64) {
+        self.random ^= time;
+    }
+
+    fn next_random(&mut self) -> u64 {
+        let mut x = self.random.max(1);
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.random = x;
+        x
+    }
+}
+
+/// Whether the superframe with the number `a` comes before the one with the number `b`, across wrap-arounds.
+pub fn is_before(a: u32, b: u32) -> bool {
+    b.wrapping_sub(a).wrapping_sub(1) < u32::MAX / 2
+}
+
+/// How many microseconds `time` lies ahead of `now`, or `None` if it already passed.
+fn ahead_us(now: u64, time: u64) -> Option<f64> {
+    let ahead = twr::elapsed(now, time);
+    (ahead < TIMESTAMP_MASK / 2).then(|| twr::units_to_us(ahead))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schedule(members: usize) -> Schedule {
+        let config = TdmaConfig::default();
+        Schedule {
+            beacon_slot_us: config.beacon_slot_us,
+            join_slot_us: config.join_slot_us,
+            data_slot_us: config.data_slot_us,
+            ranging_slot_us: config.ranging_slot_us,
+            members: (0 .. members as u16).collect(),
+            superframe: 7,
+            without_sts: vec![],
+            profile_switch: None,
+            network_us: None,
+        }
+    }
+
+    #[test]
+    fn slots_of_a_single_member_follow_the_beacon_and_join_slots() {
+        let schedule = schedule(1);
+
+        assert_eq!(schedule.duration_us(), 50_000);
+        assert_eq!(schedule.slot(Slot::Beacon), Some((0, 3_000)));
+        assert_eq!(schedule.slot(Slot::Join), Some((3_000, 9_000)));
+        assert_eq!(schedule.slot(Slot::Data(0)), Some((12_000, 3_000)));
+        assert_eq!(schedule.slot(Slot::Ranging(0)), Some((15_000, 35_000)));
+        assert_eq!(schedule.slot(Slot::Data(1)), None);
+    }
+
+    #[test]
+    fn slots_of_a_full_mesh_fill_the_superframe() {
+        let schedule = schedule(MAX_MEMBERS);
+        let last = MAX_MEMBERS as u16 - 1;
+
+        assert_eq!(schedule.duration_us(), 12_000 + MAX_MEMBERS as u64 * 38_000);
+        assert_eq!(schedule.slot(Slot::Data(last)), Some((12_000 + last as u64 * 3_000, 3_000)));
+        let (start, length) = schedule.slot(Slot::Ranging(last)).unwrap();
+        assert_eq!(start + length, schedule.duration_us());
+    }
+
+    #[test]
+    fn superframes_looked_ahead_in_a_full_mesh_stay_within_half_a_clock_wrap() {
+        let config = TdmaConfig::default();
+        let lookahead_us = (config.max_missed_beacons as u64 + 1) * schedule(MAX_MEMBERS).duration_us();
+
+        assert!((lookahead_us as f64) < twr::units_to_us(TIMESTAMP_MASK / 2));
+    }
+
+    #[test]
+    fn coordinator_hands_out_no_more_slots_than_the_mesh_has() {
+        let mut tdma = Tdma::new(TdmaConfig::default());
+        tdma.set_node_id(Some(0));
+        tdma.set_coordinator(true);
+        tdma.add_members(&(1 ..= MAX_MEMBERS as u16 + 4).collect::<Vec<_>>());
+
+        let (schedule, send_at) = tdma.poll_beacon(0).unwrap();
+        assert_eq!(send_at, SendAt::Now);
+        assert_eq!(schedule.members.len(), MAX_MEMBERS);
+        assert!(schedule.members.contains(&0));
+        assert!(!schedule.members.contains(&(MAX_MEMBERS as u16)));
+    }
+
+    #[test]
+    fn ranging_slots_are_found_at_their_boundaries_across_the_clock_wrap() {
+        let mut tdma = Tdma::new(TdmaConfig::default());
+        let schedule = schedule(MAX_MEMBERS);
+        let last = MAX_MEMBERS as u16 - 1;
+        let (start_us, length_us) = schedule.slot(Slot::Ranging(last)).unwrap();
+        let duration_us = schedule.duration_us();
+        // The superframe starts shortly before the DW3000 clock wraps around.
+        let superframe_start = TIMESTAMP_MASK - twr::us_to_units(1_000);
+        tdma.on_beacon(schedule, superframe_start);
+
+        let at = |us: u64| twr::add(superframe_start, twr::us_to_units(us));
+        assert_eq!(tdma.ranging_slot_at(at(start_us + 1)), Some((last, 7)));
+        assert_eq!(tdma.ranging_slot_at(at(start_us + length_us - 1)), Some((last, 7)));
+        assert_eq!(tdma.ranging_slot_at(at(duration_us + 1)), None);
+        assert_eq!(tdma.superframe_at(at(duration_us + 1)).map(|(superframe, _)| superframe), Some(8));
+        assert_eq!(tdma.ranging_slot_at(at(duration_us + start_us + 1)), Some((last, 8)));
+    }
+
+    #[test]
+    fn client_sends_in_its_own_data_slot_of_the_next_superframe() {
+        let config = TdmaConfig::default();
+        let mut tdma = Tdma::new(config.clone());
+        tdma.set_node_id(Some(3));
+        let schedule = schedule(8);
+        let (start_us, _) = schedule.slot(Slot::Data(3)).unwrap();
+        tdma.on_beacon(schedule, 0);
+
+        // Too early to hand the transmission to the radio.
+        assert_eq!(tdma.opportunity(Traffic::Data, 0), None);
+
+        let slot = twr::us_to_units(start_us + config.guard_us as u64);
+        let radio_now = slot - twr::us_to_units(config.min_lead_us as u64 + 500);
+        let opportunity = tdma.opportunity(Traffic::Data, radio_now).unwrap();
+        assert_eq!(opportunity.send_at, SendAt::Delayed(slot));
+
+        // Once used, the slot only comes up again in the next superframe.
+        tdma.take(opportunity);
+        assert_eq!(tdma.opportunity(Traffic::Data, radio_now), None);
+    }
+}
@@ -0,0 +1,232 @@
+//! Smoothing of the distances to each peer, which jump around by tens of centimetres from one exchange to the next.
+//!
+//! Every peer gets an alpha-beta filter, which predicts the distance from the last estimate and the velocity, and
+//! moves both towards each new measurement by a fixed share of the difference, scaled by the quality of the
+//! measurement. Measurements too far off the prediction are dropped as outliers, unless several in a row agree, in
+//! which case the peer really moved and the filter starts over from there.
+
+use serde::{Deserialize, Serialize};
+
+use super::Measurement;
+
+/// Share of the difference between measurement and prediction applied to the distance.
+const ALPHA: f32 = 0.35;
+
+/// Share of the difference between measurement and prediction, over the time since the last one, applied to the velocity.
+const BETA: f32 = 0.05;
+
+/// Difference from the prediction in meters which is always accepted, however steady the distance was so far.
+const MIN_GATE_M: f32 = 0.3;
+
+/// Differences from the prediction beyond this many standard deviations are treated as outliers.
+const GATE_SIGMAS: f32 = 3.0;
+
+/// Outliers in a row after which the filter gives up its estimate and starts over from the latest measurement.
+const MAX_OUTLIERS: u32 = 3;
+
+/// How much a single measurement moves the variance of the differences from the prediction.
+const VARIANCE_WEIGHT: f32 = 0.1;
+
+/// The variance assumed before the filter has seen enough measurements, in square meters.
+const INITIAL_VARIANCE: f32 = 0.1;
+
+/// Standard deviation in meters at which the confidence in the estimate drops to a half.
+const HALF_CONFIDENCE_SIGMA_M: f32 = 0.15;
+
+/// Velocities beyond this many meters per second are physically impossible for people carrying controllers.
+const MAX_VELOCITY_M_S: f32 = 10.0;
+
+/// The smoothed distance to a peer.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct DistanceEstimate {
+    /// The mesh ID of the other controller.
+    pub peer: u16,
+    pub distance_m: f32,
+    /// How fast the peer moves away, or towards this controller if negative, in meters per second.
+    pub velocity_m_s: f32,
+    /// Confidence in the estimate between 0.0 (useless) and 1.0 (perfect).
+    pub confidence: f32,
+}
+
+pub struct DistanceFilter {
+    peer: u16,
+    distance_m: f32,
+    velocity_m_s: f32,
+    /// The variance of recent differences between measurement and prediction, in square meters.
+    variance: f32,
+    updated_us: u64,
+    outliers: u32,
+}
+
+impl DistanceFilter {
+    /// Start tracking a peer from its first measurement.
+    pub fn new(measurement: &Measurement, now_us: u64) -> Self {
+        Self {
+            peer: measurement.peer,
+            distance_m: measurement.distance_m,
+            velocity_m_s: 0.0,
+            variance: INITIAL_VARIANCE,
+            updated_us: now_us,
+            outliers: 0,
+        }
+    }
+
+    /// Fold a new measurement into the estimate, returning whether it was accepted.
+    pub fn update(&mut self, measurement: &Measurement, now_us: u64) -> bool {
+        let dt_s = now_us.saturating_sub(self.updated_us) as f32 / 1e6;
+        let predicted_m = self.distance_m + self.velocity_m_s * dt_s;
+        let residual_m = measurement.distance_m - predicted_m;
+
+        if residual_m.abs() > MIN_GATE_M.max(GATE_SIGMAS * self.variance.sqrt()) {
+            self.outliers += 1;
+            if self.outliers >= MAX_OUTLIERS {
+                *self = Self::new(measurement, now_us);
+                return true;
+            }
+            return false;
+        }
+
+        // Measurements the radio itself doubts move the estimate less.
+        let weight = measurement.quality.clamp(0.1, 1.0);
+        self.distance_m = predicted_m + ALPHA * weight * residual_m;
+        if dt_s > 0.0 {
+            self.velocity_m_s = (self.velocity_m_s + BETA * weight * residual_m / dt_s).clamp(-MAX_VELOCITY_M_S, MAX_VELOCITY_M_S);
+        }
+        self.variance += VARIANCE_WEIGHT * (residual_m.powi(2) - self.variance);
+        self.updated_us = now_us;
+        self.outliers = 0;
+
+        true
+    }
+
+    pub fn estimate(&self) -> DistanceEstimate {
+        let sigma_m = self.variance.sqrt();
+
+        DistanceEstimate {
+            peer: self.peer,
+            distance_m: self.distance_m.max(0.0),
+            velocity_m_s: self.velocity_m_s,
+            confidence: HALF_CONFIDENCE_SIGMA_M / (HALF_CONFIDENCE_SIGMA_M + sigma_m),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Time between two measurements of the same peer, in microseconds.
+    const INTERVAL_US: u64 = 100_000;
+
+    fn measurement(distance_m: f32) -> Measurement {
+        Measurement {
+            peer: 2,
+            distance_m,
+            quality: 1.0,
+            clock_drift_ppm: 0.0,
+            secure: false,
+        }
+    }
+
+    /// A filter which followed a peer standing still at the given distance for a while.
+    fn settled(distance_m: f32) -> (DistanceFilter, u64) {
+        let mut filter = DistanceFilter::new(&measurement(distance_m), 0);
+        let mut now_us = 0;
+        for i in 0 .. 50 {
+            now_us += INTERVAL_US;
+            let noise_m = if i % 2 == 0 { 0.02 } else { -0.02 };
+            assert!(filter.update(&measurement(distance_m + noise_m), now_us));
+        }
+
+        (filter, now_us)
+    }
+
+    #[test]
+    fn noisy_measurements_are_smoothed() {
+        let mut filter = DistanceFilter::new(&measurement(5.0), 0);
+        let mut estimates = vec![];
+        for i in 1 ..= 100 {
+            let noise_m = if i % 2 == 0 { 0.2 } else { -0.2 };
+            filter.update(&measurement(5.0 + noise_m), i * INTERVAL_US);
+            estimates.push(filter.estimate().distance_m);
+        }
+
+        // The estimate swings far less than the measurements, which are 40 cm apart.
+        let settled = &estimates[50 ..];
+        let min = settled.iter().copied().fold(f32::MAX, f32::min);
+        let max = settled.iter().copied().fold(f32::MIN, f32::max);
+        assert!(max - min < 0.2, "Estimate swings by {} m", max - min);
+        assert!(((min + max) / 2.0 - 5.0).abs() < 0.05);
+        assert!(filter.estimate().velocity_m_s.abs() < 0.1);
+    }
+
+    #[test]
+    fn steady_distance_gains_confidence() {
+        let first = DistanceFilter::new(&measurement(5.0), 0).estimate().confidence;
+        let (filter, _) = settled(5.0);
+
+        assert!(filter.estimate().confidence > first);
+        assert!(filter.estimate().confidence > 0.8);
+    }
+
+    #[test]
+    fn single_outlier_is_dropped() {
+        let (mut filter, now_us) = settled(5.0);
+        let before = filter.estimate();
+
+        assert!(!filter.update(&measurement(8.0), now_us + INTERVAL_US));
+        assert_eq!(filter.estimate(), before);
+
+        assert!(filter.update(&measurement(5.0), now_us + 2 * INTERVAL_US));
+        assert!((filter.estimate().distance_m - 5.0).abs() < 0.05);
+    }
+
+    #[test]
+    fn outliers_in_a_row_start_the_filter_over() {
+        let (mut filter, now_us) = settled(5.0);
+
+        assert!(!filter.update(&measurement(8.0), now_us + INTERVAL_US));
+        assert!(!filter.update(&measurement(8.0), now_us + 2 * INTERVAL_US));
+        assert!(filter.update(&measurement(8.0), now_us + 3 * INTERVAL_US));
+
+        assert_eq!(filter.estimate().distance_m, 8.0);
+        assert_eq!(filter.estimate().velocity_m_s, 0.0);
+    }
+
+    #[test]
+    fn outliers_apart_do_not_start_the_filter_over() {
+        let (mut filter, now_us) = settled(5.0);
+
+        for i in 0 .. 6 {
+            let distance_m = if i % 2 == 0 { 8.0 } else { 5.0 };
+            filter.update(&measurement(distance_m), now_us + (i + 1) * INTERVAL_US);
+        }
+
+        assert!((filter.estimate().distance_m - 5.0).abs() < 0.05);
+    }
+
+    #[test]
+    fn moving_peer_is_followed() {
+        let mut filter = DistanceFilter::new(&measurement(2.0), 0);
+        for i in 1 ..= 100 {
+            // Walking away at 1 m/s.
+            assert!(filter.update(&measurement(2.0 + i as f32 * 0.1), i * INTERVAL_US));
+        }
+
+        let estimate = filter.estimate();
+        assert!((estimate.distance_m - 12.0).abs() < 0.2, "Estimated {} m", estimate.distance_m);
+        assert!((estimate.velocity_m_s - 1.0).abs() < 0.2, "Estimated {} m/s", estimate.velocity_m_s);
+    }
+
+    #[test]
+    fn doubtful_measurements_move_the_estimate_less() {
+        let (mut sure, now_us) = settled(5.0);
+        let (mut doubtful, _) = settled(5.0);
+
+        sure.update(&measurement(5.25), now_us + INTERVAL_US);
+        doubtful.update(&Measurement { quality: 0.2, ..measurement(5.25) }, now_us + INTERVAL_US);
+
+        assert!(doubtful.estimate().distance_m < sure.estimate().distance_m);
+        assert!(doubtful.estimate().distance_m > 5.0);
+    }
+}
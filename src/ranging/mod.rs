@@ -0,0 +1,351 @@
+//! Double-sided two-way ranging (DS-TWR) between controllers.
+//!
+//! A ranging exchange consists of four messages between an initiator A and a responder B:
+//!
+//! 1. A sends a poll and remembers its TX timestamp.
+//! 2. B answers with a response delayed by a fixed reply time.
+//! 3. A sends a final message, delayed as well so it can carry its own TX timestamp next to the poll TX and response RX.
+//! 4. B now knows all six timestamps, computes the distance and reports it back to A.
+//!
+//! The [`RangingEngine`] only deals with timestamps and messages, the radio driver feeds it with both.
+
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::mesh::{MeshMessage, MeshPacket};
+
+pub mod filter;
+pub mod positioning;
+pub mod twr;
+
+use twr::Intervals;
+
+/// Highest relative drift of two crystals within specification (±20 ppm each) before a measurement is distrusted.
+const MAX_PLAUSIBLE_DRIFT_PPM: f64 = 40.0;
+
+/// A distance to a peer, published to the controller after every successful exchange.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Measurement {
+    /// The mesh ID of the other controller.
+    pub peer: u16,
+    pub distance_m: f32,
+    /// Confidence in the measurement between 0.0 (useless) and 1.0 (perfect).
+    pub quality: f32,
+    /// How much faster the initiator's clock runs compared to the responder's, in parts per million.
+    pub clock_drift_ppm: f32,
+    /// Whether the timestamps of the exchange were protected by scrambled timestamp sequences.
+    #[serde(default)]
+    pub secure: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct RangingConfig {
+    /// Time between receiving a message and sending the delayed answer, in microseconds.
+    ///
+    /// This has to cover the processing and SPI transfer on the ESP32, otherwise the delayed transmission is missed.
+    pub reply_delay_us: u64,
+    /// Time between two exchanges started by this node, in microseconds.
+    pub interval_us: u64,
+    /// Give up on an exchange if it didn't complete within this many microseconds.
+    pub timeout_us: u64,
+    /// Antenna delays in DW3000 time units, which the radio applies to TX and RX timestamps.
+    pub tx_antenna_delay: u16,
+    pub rx_antenna_delay: u16,
+    /// Whether exchanges within a session use scrambled timestamp sequences, so nobody outside can spoof distances.
+    pub sts: bool,
+}
+
+impl Default for RangingConfig {
+    fn default() -> Self {
+        Self {
+            reply_delay_us: 10_000,
+            interval_us: 100_000,
+            timeout_us: 50_000,
+            tx_antenna_delay: 16385,
+            rx_antenna_delay: 16385,
+            sts: true,
+        }
+    }
+}
+
+/// When the radio should start transmitting a frame.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SendAt {
+    Now,
+    /// At the given DW3000 system time.
+    Delayed(u64),
+}
+
+/// A mesh packet the engine wants the radio to transmit.
+#[derive(Debug, Clone)]
+pub struct Outgoing {
+    pub packet:  MeshPacket,
+    pub send_at: SendAt,
+}
+
+/// An exchange this node started and is waiting on.
+struct Initiation {
+    peer: u16,
+    seq: u8,
+    started_us: u64,
+    poll_tx: Option<u64>,
+}
+
+/// An exchange another node started with this one.
+struct Response {
+    seq: u8,
+    poll_rx: u64,
+    response_tx: Option<u64>,
+}
+
+pub struct RangingEngine {
+    pub config: RangingConfig,
+    node_id: Option<u16>,
+    /// Peers announced by the controller, in addition to the ones heard on the air.
+    peers: Vec<u16>,
+    next_peer: usize,
+    seq: u8,
+    last_start_us: Option<u64>,
+    initiation: Option<Initiation>,
+    /// The peer of the last exchange this node started, which the report at its end still comes from.
+    last_peer: Option<u16>,
+    /// The peer and sequence number of the exchange whose report this node waits for, after sending its final.
+    report: Option<(u16, u8)>,
+    responses: HashMap<u16, Response>,
+    measurements: Vec<Measurement>,
+}
+
+impl RangingEngine {
+    pub fn new(config: RangingConfig) -> Self {
+        Self {
+            config,
+            node_id: None,
+            peers: vec![],
+            next_peer: 0,
+            seq: 0,
+            last_start_us: None,
+            initiation: None,
+            last_peer: None,
+            report: None,
+            responses: HashMap::new(),
+            measurements: vec![],
+        }
+    }
+
+    /// Set the mesh ID of this node. Without one, the engine neither starts nor answers exchanges.
+    pub fn set_node_id(&mut self, node_id: Option<u16>) {
+        if self.node_id != node_id {
+            self.node_id = node_id;
+            self.initiation = None;
+            self.report = None;
+            self.responses.clear();
+        }
+    }
+
+    /// Make sure the given peers are ranged with, even if they haven't been heard on the air yet.
+    pub fn add_peers(&mut self, peers: &[u16]) {
+        for peer in peers {
+            self.add_peer(*peer);
+        }
+    }
+
+    fn add_peer(&mut self, peer: u16) {
+        if Some(peer) != self.node_id && peer != crate::mesh::BROADCAST && !self.peers.contains(&peer) {
+            self.peers.push(peer);
+            self.peers.sort();
+        }
+    }
+
+    /// Stop ranging with a peer which left, abandoning any exchange with it.
+    pub fn remove_peer(&mut self, peer: u16) {
+        self.peers.retain(|known| *known != peer);
+        self.responses.remove(&peer);
+
+        if self.initiation.as_ref().is_some_and(|initiation| initiation.peer == peer) {
+            self.initiation = None;
+        }
+        if self.report.is_some_and(|(report_peer, _)| report_peer == peer) {
+            self.report = None;
+        }
+    }
+
+    /// The peer of the last exchange this node started.
+    pub fn exchange_peer(&self) -> Option<u16> {
+        self.last_peer
+    }
+
+    /// Start a new exchange if one is due.
+    ///
+    /// Only the node with the lower ID of each pair initiates, so that two nodes never poll each other at once.
+    pub fn poll(&mut self, now_us: u64) -> Option<Outgoing> {
+        let node_id = self.node_id?;
+
+        if let Some(initiation) = &self.initiation {
+            if now_us.saturating_sub(initiation.started_us) < self.config.timeout_us {
+                return None;
+            }
+            println!("Ranging exchange with {} timed out", initiation.peer);
+            self.initiation = None;
+        }
+
+        if self.last_start_us.is_some_and(|last| now_us.saturating_sub(last) < self.config.interval_us) {
+            return None;
+        }
+
+        let candidates: Vec<u16> = self.peers.iter().copied().filter(|peer| *peer > node_id).collect();
+        if candidates.is_empty() {
+            return None;
+        }
+
+        let peer = candidates[self.next_peer % candidates.len()];
+        self.next_peer = self.next_peer.wrapping_add(1);
+        self.seq = self.seq.wrapping_add(1);
+        self.last_start_us = Some(now_us);
+        self.last_peer = Some(peer);
+        self.report = None;
+        self.initiation = Some(Initiation {
+            peer,
+            seq: self.seq,
+            started_us: now_us,
+            poll_tx: None,
+        });
+
+        Some(Outgoing {
+            packet: MeshPacket::new(node_id, peer, MeshMessage::RangingPoll { seq: self.seq }),
+            send_at: SendAt::Now,
+        })
+    }
+
+    /// Record the TX timestamp of a ranging packet after the radio sent it.
+    pub fn on_transmitted(&mut self, packet: &MeshPacket, tx_time: u64) {
+        match packet.message {
+            MeshMessage::RangingPoll { seq } => {
+                if let Some(initiation) = self.initiation.as_mut().filter(|i| i.peer == packet.dst && i.seq == seq) {
+                    initiation.poll_tx = Some(tx_time);
+                }
+            },
+            MeshMessage::RangingResponse { seq } => {
+                if let Some(response) = self.responses.get_mut(&packet.dst).filter(|r| r.seq == seq) {
+                    response.response_tx = Some(tx_time);
+                }
+            },
+            _ => {},
+        }
+    }
+
+    /// Process a received ranging packet along with its RX timestamp, returning the answer to send if there is one.
+    pub fn on_received(&mut self, packet: &MeshPacket, rx_time: u64) -> Option<Outgoing> {
+        let node_id = self.node_id?;
+        self.add_peer(packet.src);
+
+        if packet.dst != node_id {
+            return None;
+        }
+
+        let reply_at = twr::add(rx_time, twr::us_to_units(self.config.reply_delay_us));
+
+        match packet.message {
+            MeshMessage::RangingPoll { seq } => {
+                self.responses.insert(packet.src, Response {
+                    seq,
+                    poll_rx: rx_time,
+                    response_tx: None,
+                });
+
+                Some(Outgoing {
+                    packet: MeshPacket::new(node_id, packet.src, MeshMessage::RangingResponse { seq }),
+                    send_at: SendAt::Delayed(reply_at),
+                })
+            },
+
+            MeshMessage::RangingResponse { seq } => {
+                let initiation = self.initiation.take()?;
+
+                let poll_tx = match initiation.poll_tx {
+                    Some(poll_tx) if initiation.peer == packet.src && initiation.seq == seq => poll_tx,
+                    _ => {
+                        self.initiation = Some(initiation);
+                        return None;
+                    },
+                };
+                self.report = Some((packet.src, seq));
+
+                Some(Outgoing {
+                    packet: MeshPacket::new(node_id, packet.src, MeshMessage::RangingFinal {
+                        seq,
+                        poll_tx,
+                        response_rx: rx_time,
+                        final_tx: twr::delayed_tx_timestamp(reply_at, self.config.tx_antenna_delay),
+                    }),
+                    send_at: SendAt::Delayed(reply_at),
+                })
+            },
+
+            MeshMessage::RangingFinal { seq, poll_tx, response_rx, final_tx } => {
+                let response = self.responses.remove(&packet.src).filter(|r| r.seq == seq)?;
+                let response_tx = response.response_tx?;
+
+                let intervals = Intervals::from_timestamps(poll_tx, response.poll_rx, response_tx, response_rx, final_tx, rx_time);
+                let measurement = measure(packet.src, &intervals)?;
+                self.measurements.push(measurement);
+
+                Some(Outgoing {
+                    packet: MeshPacket::new(node_id, packet.src, MeshMessage::RangingReport {
+                        seq,
+                        distance_m: measurement.distance_m,
+                        quality: measurement.quality,
+                        clock_drift_ppm: measurement.clock_drift_ppm,
+                    }),
+                    send_at: SendAt::Now,
+                })
+            },
+
+            MeshMessage::RangingReport { seq, distance_m, quality, clock_drift_ppm } => {
+                // Only the report of the exchange this node finished counts, anything else is stale or forged.
+                if self.report != Some((packet.src, seq)) {
+                    return None;
+                }
+                self.report = None;
+
+                self.measurements.push(Measurement {
+                    peer: packet.src,
+                    distance_m,
+                    quality,
+                    clock_drift_ppm,
+                    secure: false,
+                });
+                None
+            },
+
+            MeshMessage::Beacon { .. } | MeshMessage::Ack { .. } | MeshMessage::Session(_) => None,
+        }
+    }
+
+    /// Remove and return all measurements completed since the last call.
+    pub fn take_measurements(&mut self) -> Vec<Measurement> {
+        std::mem::take(&mut self.measurements)
+    }
+}
+
+/// Turn the intervals of a completed exchange into a measurement, discarding implausible ones.
+fn measure(peer: u16, intervals: &Intervals) -> Option<Measurement> {
+    let tof = intervals.time_of_flight()?;
+    let drift_ppm = intervals.clock_drift_ppm();
+
+    if drift_ppm.abs() > MAX_PLAUSIBLE_DRIFT_PPM {
+        println!("Discarding ranging result with {} with implausible clock drift of {:.1} ppm", peer, drift_ppm);
+        return None;
+    }
+
+    // Slightly negative times of flight happen at very short distances with imperfect antenna delays.
+    let distance_m = twr::tof_to_meters(tof).max(0.0);
+
+    Some(Measurement {
+        peer,
+        distance_m: distance_m as f32,
+        quality: (1.0 - drift_ppm.abs() / MAX_PLAUSIBLE_DRIFT_PPM) as f32,
+        clock_drift_ppm: drift_ppm as f32,
+        secure: false,
+    })
+}
@@ -0,0 +1,472 @@
+//! Placing controllers on a plane from the distances between them.
+//!
+//! The layout starts from classical multidimensional scaling of the distance matrix, where missing distances are
+//! filled in along the shortest path through the others, unless the previous layout has every controller already.
+//! It is then refined by stress majorization, which moves one controller at a time to where its measured distances
+//! to the others fit best. A single distance far off, like a reflection measured instead of the direct path, would
+//! pull every controller a little out of place, so distances which fit badly count less in the next round of refining.
+//! Those which still don't fit after that are dropped as outliers, and the layout is refined once more without them.
+//!
+//! Distances only fix the layout up to where it lies and how it is turned, so the first controller is put at the
+//! origin, the second on the positive x axis and the third above it.
+//!
+//! A single controller can also be located on its own from its distances to anchors, controllers fixed at known
+//! coordinates of the venue, by least squares from where the anchors are on average.
+
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+/// Rounds of stress majorization the layout is refined with, before and after dropping outliers.
+const REFINE_ITERATIONS: usize = 100;
+
+/// Rounds of refining in which distances which fit badly count less, before picking out the outliers.
+const ROBUST_ROUNDS: usize = 5;
+
+/// Error of a distance in the layout in meters up to which it counts fully in the robust rounds.
+const ROBUST_SCALE_M: f32 = 0.2;
+
+/// Rounds of Gauss-Newton iteration when locating a controller from its distances to anchors.
+const LOCATE_ITERATIONS: usize = 20;
+
+/// Spread of the anchors across their narrowest direction in square meters, below which they count as a line.
+const MIN_ANCHOR_SPREAD_M2: f32 = 0.01;
+
+/// Rounds of power iteration for each eigenvector of the scaled distance matrix.
+const POWER_ITERATIONS: usize = 50;
+
+/// Error of a distance in the layout in meters which never makes it an outlier.
+const MIN_OUTLIER_M: f32 = 0.5;
+
+/// Distances whose error in the layout exceeds this many times the median error are outliers.
+const OUTLIER_FACTOR: f32 = 3.0;
+
+/// A distance between two controllers, and how much it is trusted compared to the others.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Edge {
+    pub a: u16,
+    pub b: u16,
+    pub distance_m: f32,
+    pub weight: f32,
+}
+
+/// Where a controller is on the plane, in meters.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Position {
+    /// The mesh ID of the controller.
+    pub id: u16,
+    pub x_m: f32,
+    pub y_m: f32,
+}
+
+/// The coordinates of an anchor in the venue, in meters.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Anchor {
+    pub x_m: f32,
+    pub y_m: f32,
+}
+
+/// Where a single controller is among the anchors.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Fix {
+    pub position: Position,
+    /// The root mean square error of the distances to the anchors, in meters.
+    pub residual_m: f32,
+}
+
+/// The positions of all controllers which could be placed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Layout {
+    pub positions: Vec<Position>,
+    /// The root mean square error of the distances kept in the layout, in meters.
+    pub residual_m: f32,
+    /// The number of distances which were dropped as outliers.
+    pub outliers: usize,
+}
+
+/// Place the controllers with the given mesh IDs, the first of which ends up at the origin.
+///
+/// Controllers without a chain of distances to the first one can't be placed and are left out.
+pub fn solve(ids: &[u16], edges: &[Edge], previous: &HashMap<u16, Position>) -> Option<Layout> {
+    let ids = connected(ids, edges);
+    let n = ids.len();
+    if n < 2 {
+        return None;
+    }
+
+    let index: HashMap<u16, usize> = ids.iter().enumerate().map(|(i, id)| (*id, i)).collect();
+    let mut distances = vec![vec![0.0; n]; n];
+    let mut weights = vec![vec![0.0; n]; n];
+    for edge in edges {
+        let (Some(&a), Some(&b)) = (index.get(&edge.a), index.get(&edge.b)) else {
+            continue;
+        };
+        if a == b || edge.weight <= 0.0 {
+            continue;
+        }
+        distances[a][b] = edge.distance_m;
+        distances[b][a] = edge.distance_m;
+        weights[a][b] = edge.weight;
+        weights[b][a] = edge.weight;
+    }
+
+    let mut points: Vec<(f32, f32)> = match ids.iter().map(|id| previous.get(id).map(|p| (p.x_m, p.y_m))).collect() {
+        Some(points) => points,
+        None => initial_layout(&distances, &weights),
+    };
+
+    // Distances count less the worse they fit, so a single one far off doesn't drag the others along.
+    let mut robust = weights.clone();
+    for _ in 0 .. ROBUST_ROUNDS {
+        refine(&mut points, &distances, &robust);
+        for (a, b) in pairs(n).filter(|&(a, b)| weights[a][b] > 0.0) {
+            let error = (distance(points[a], points[b]) - distances[a][b]).abs();
+            robust[a][b] = weights[a][b] * ROBUST_SCALE_M / error.max(ROBUST_SCALE_M);
+            robust[b][a] = robust[a][b];
+        }
+    }
+
+    // Drop the distances which don't fit, then let the others settle without them.
+    let errors: Vec<f32> = pairs(n)
+        .filter(|&(a, b)| weights[a][b] > 0.0)
+        .map(|(a, b)| (distance(points[a], points[b]) - distances[a][b]).abs())
+        .collect();
+    let mut outliers = 0;
+    if !errors.is_empty() {
+        let mut sorted = errors.clone();
+        sorted.sort_by(f32::total_cmp);
+        let threshold = MIN_OUTLIER_M.max(OUTLIER_FACTOR * sorted[sorted.len() / 2]);
+
+        for (a, b) in pairs(n) {
+            if weights[a][b] > 0.0 && (distance(points[a], points[b]) - distances[a][b]).abs() > threshold {
+                weights[a][b] = 0.0;
+                weights[b][a] = 0.0;
+                outliers += 1;
+            }
+        }
+        if outliers > 0 {
+            refine(&mut points, &distances, &weights);
+        }
+    }
+
+    let (sum, count) = pairs(n)
+        .filter(|&(a, b)| weights[a][b] > 0.0)
+        .fold((0.0, 0), |(sum, count), (a, b)| (sum + (distance(points[a], points[b]) - distances[a][b]).powi(2), count + 1));
+
+    normalize(&mut points);
+
+    Some(Layout {
+        positions: ids.iter().zip(points).map(|(id, (x_m, y_m))| Position { id: *id, x_m, y_m }).collect(),
+        residual_m: if count > 0 { (sum / count as f32).sqrt() } else { 0.0 },
+        outliers,
+    })
+}
+
+/// Locate the controller with the given mesh ID from its distances to anchors, as pairs of anchor position and meters.
+///
+/// It takes at least three anchors which aren't in a line, or else either side of the line would fit just as well.
+pub fn locate(id: u16, ranges: &[(Position, f32)]) -> Option<Fix> {
+    if ranges.len() < 3 {
+        return None;
+    }
+
+    let n = ranges.len() as f32;
+    let (cx, cy) = ranges.iter().fold((0.0, 0.0), |(x, y), (anchor, _)| (x + anchor.x_m / n, y + anchor.y_m / n));
+
+    // The smaller eigenvalue of the covariance of the anchors is their spread across the narrowest direction.
+    let (sxx, sxy, syy) = ranges.iter().fold((0.0, 0.0, 0.0), |(xx, xy, yy), (anchor, _)| {
+        let (dx, dy) = (anchor.x_m - cx, anchor.y_m - cy);
+        (xx + dx * dx / n, xy + dx * dy / n, yy + dy * dy / n)
+    });
+    let narrowest = (sxx + syy) / 2.0 - (((sxx - syy) / 2.0).powi(2) + sxy * sxy).sqrt();
+    if narrowest < MIN_ANCHOR_SPREAD_M2 {
+        return None;
+    }
+
+    let mut point = (cx, cy);
+    for _ in 0 .. LOCATE_ITERATIONS {
+        // Solve the normal equations of the distances linearized around the current point.
+        let (mut jxx, mut jxy, mut jyy, mut gx, mut gy) = (0.0, 0.0, 0.0, 0.0, 0.0);
+        for (anchor, distance_m) in ranges {
+            let apart = distance(point, (anchor.x_m, anchor.y_m));
+            if apart < 1e-6 {
+                continue;
+            }
+            let (ux, uy) = ((point.0 - anchor.x_m) / apart, (point.1 - anchor.y_m) / apart);
+            let error = apart - distance_m;
+            jxx += ux * ux;
+            jxy += ux * uy;
+            jyy += uy * uy;
+            gx += ux * error;
+            gy += uy * error;
+        }
+
+        let determinant = jxx * jyy - jxy * jxy;
+        if determinant.abs() < 1e-9 {
+            break;
+        }
+        let step = ((jyy * gx - jxy * gy) / determinant, (jxx * gy - jxy * gx) / determinant);
+        point = (point.0 - step.0, point.1 - step.1);
+        if step.0.abs() + step.1.abs() < 1e-4 {
+            break;
+        }
+    }
+
+    let squared = ranges.iter().map(|(anchor, distance_m)| (distance(point, (anchor.x_m, anchor.y_m)) - distance_m).powi(2)).sum::<f32>();
+
+    Some(Fix {
+        position: Position { id, x_m: point.0, y_m: point.1 },
+        residual_m: (squared / n).sqrt(),
+    })
+}
+
+/// The given IDs which are connected to the first one through the edges, in the given order.
+fn connected(ids: &[u16], edges: &[Edge]) -> Vec<u16> {
+    let Some(first) = ids.first() else {
+        return vec![];
+    };
+
+    let mut reached = vec![*first];
+    let mut next = 0;
+    while next < reached.len() {
+        let id = reached[next];
+        next += 1;
+        for edge in edges.iter().filter(|edge| edge.weight > 0.0) {
+            let other = if edge.a == id { edge.b } else if edge.b == id { edge.a } else { continue };
+            if ids.contains(&other) && !reached.contains(&other) {
+                reached.push(other);
+            }
+        }
+    }
+
+    ids.iter().copied().filter(|id| reached.contains(id)).collect()
+}
+
+/// Every pair of indices below `n` once.
+fn pairs(n: usize) -> impl Iterator<Item = (usize, usize)> {
+    (0 .. n).flat_map(move |a| (a + 1 .. n).map(move |b| (a, b)))
+}
+
+fn distance(a: (f32, f32), b: (f32, f32)) -> f32 {
+    ((a.0 - b.0).powi(2) + (a.1 - b.1).powi(2)).sqrt()
+}
+
+/// A first layout by classical multidimensional scaling, with missing distances taken along the shortest path.
+fn initial_layout(distances: &[Vec<f32>], weights: &[Vec<f32>]) -> Vec<(f32, f32)> {
+    let n = distances.len();
+
+    let mut complete: Vec<Vec<f32>> = (0 .. n)
+        .map(|a| (0 .. n).map(|b| if a == b { 0.0 } else if weights[a][b] > 0.0 { distances[a][b] } else { f32::INFINITY }).collect())
+        .collect();
+    for k in 0 .. n {
+        for a in 0 .. n {
+            for b in 0 .. n {
+                let through = complete[a][k] + complete[k][b];
+                if through < complete[a][b] {
+                    complete[a][b] = through;
+                }
+            }
+        }
+    }
+
+    // Double centering turns the squared distances into the inner products of the centered points.
+    let squared: Vec<Vec<f32>> = complete.iter().map(|row| row.iter().map(|d| d * d).collect()).collect();
+    let row_means: Vec<f32> = squared.iter().map(|row| row.iter().sum::<f32>() / n as f32).collect();
+    let mean = row_means.iter().sum::<f32>() / n as f32;
+    let mut products: Vec<Vec<f32>> = (0 .. n)
+        .map(|a| (0 .. n).map(|b| -0.5 * (squared[a][b] - row_means[a] - row_means[b] + mean)).collect())
+        .collect();
+
+    let mut axes = vec![];
+    for axis in 0 .. 2 {
+        // Any start which isn't orthogonal to the eigenvector works, and a fixed one keeps the result reproducible.
+        let mut vector: Vec<f32> = (0 .. n).map(|i| 1.0 + (i * (axis + 2)) as f32 % 3.0).collect();
+        let mut value = 0.0;
+        for _ in 0 .. POWER_ITERATIONS {
+            let next: Vec<f32> = products.iter().map(|row| row.iter().zip(&vector).map(|(p, v)| p * v).sum()).collect();
+            let norm = next.iter().map(|v| v * v).sum::<f32>().sqrt();
+            if norm == 0.0 {
+                break;
+            }
+            value = norm;
+            vector = next.into_iter().map(|v| v / norm).collect();
+        }
+
+        // Remove the axis found, so the next round finds the second largest.
+        for a in 0 .. n {
+            for b in 0 .. n {
+                products[a][b] -= value * vector[a] * vector[b];
+            }
+        }
+        axes.push(vector.into_iter().map(|v| v * value.sqrt()).collect::<Vec<f32>>());
+    }
+
+    // Controllers in a line leave the second axis empty, which refining could never leave.
+    (0 .. n).map(|i| (axes[0][i], axes[1][i] + 0.01 * (i % 2) as f32)).collect()
+}
+
+/// Move one point at a time to where its distances to the others fit best, given where the others are.
+fn refine(points: &mut [(f32, f32)], distances: &[Vec<f32>], weights: &[Vec<f32>]) {
+    let n = points.len();
+
+    for _ in 0 .. REFINE_ITERATIONS {
+        for a in 0 .. n {
+            let (mut x, mut y, mut total) = (0.0, 0.0, 0.0);
+            for b in (0 .. n).filter(|&b| b != a && weights[a][b] > 0.0) {
+                let apart = distance(points[a], points[b]);
+                let (dx, dy) = if apart > 1e-6 {
+                    ((points[a].0 - points[b].0) / apart, (points[a].1 - points[b].1) / apart)
+                } else {
+                    (1.0, 0.0)
+                };
+                x += weights[a][b] * (points[b].0 + distances[a][b] * dx);
+                y += weights[a][b] * (points[b].1 + distances[a][b] * dy);
+                total += weights[a][b];
+            }
+            if total > 0.0 {
+                points[a] = (x / total, y / total);
+            }
+        }
+    }
+}
+
+/// Move the first point to the origin, turn the second onto the positive x axis and mirror the third above it.
+fn normalize(points: &mut [(f32, f32)]) {
+    let Some(&(x0, y0)) = points.first() else {
+        return;
+    };
+    for point in points.iter_mut() {
+        *point = (point.0 - x0, point.1 - y0);
+    }
+
+    if let Some(&(x1, y1)) = points.get(1) {
+        let angle = -y1.atan2(x1);
+        let (sin, cos) = angle.sin_cos();
+        for point in points.iter_mut() {
+            *point = (point.0 * cos - point.1 * sin, point.0 * sin + point.1 * cos);
+        }
+    }
+
+    if points.iter().skip(2).find(|point| point.1.abs() > 1e-3).is_some_and(|point| point.1 < 0.0) {
+        for point in points.iter_mut() {
+            point.1 = -point.1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn anchor(id: u16, x_m: f32, y_m: f32) -> Position {
+        Position { id, x_m, y_m }
+    }
+
+    /// The distances from the given point to each anchor, each off by the matching error.
+    fn ranges(anchors: &[Position], (x_m, y_m): (f32, f32), errors_m: &[f32]) -> Vec<(Position, f32)> {
+        anchors
+            .iter()
+            .zip(errors_m.iter().chain(std::iter::repeat(&0.0)))
+            .map(|(anchor, error_m)| (*anchor, distance((x_m, y_m), (anchor.x_m, anchor.y_m)) + error_m))
+            .collect()
+    }
+
+    fn corners() -> Vec<Position> {
+        vec![anchor(10, 0.0, 0.0), anchor(11, 10.0, 0.0), anchor(12, 10.0, 8.0), anchor(13, 0.0, 8.0)]
+    }
+
+    #[test]
+    fn controller_is_located_among_the_anchors() {
+        for point in [(3.0, 4.0), (9.5, 0.5), (5.0, 4.0), (0.0, 0.0)] {
+            let fix = locate(1, &ranges(&corners(), point, &[])).unwrap();
+
+            assert_eq!(fix.position.id, 1);
+            assert!(distance((fix.position.x_m, fix.position.y_m), point) < 1e-3, "Located {:?} at {:?}", point, fix.position);
+            assert!(fix.residual_m < 1e-3);
+        }
+    }
+
+    #[test]
+    fn controller_outside_the_anchors_is_located() {
+        let fix = locate(1, &ranges(&corners()[.. 3], (14.0, -3.0), &[])).unwrap();
+
+        assert!(distance((fix.position.x_m, fix.position.y_m), (14.0, -3.0)) < 1e-2, "Located at {:?}", fix.position);
+    }
+
+    #[test]
+    fn noisy_distances_are_fit_as_well_as_they_can() {
+        let fix = locate(1, &ranges(&corners(), (3.0, 4.0), &[0.1, -0.1, 0.05, -0.05])).unwrap();
+
+        assert!(distance((fix.position.x_m, fix.position.y_m), (3.0, 4.0)) < 0.2, "Located at {:?}", fix.position);
+        assert!(fix.residual_m > 0.0 && fix.residual_m < 0.1);
+    }
+
+    #[test]
+    fn anchors_in_a_line_give_no_fix() {
+        let line = [anchor(10, 0.0, 0.0), anchor(11, 5.0, 5.0), anchor(12, 10.0, 10.0)];
+        assert_eq!(locate(1, &ranges(&line, (2.0, 6.0), &[])), None);
+
+        let almost = [anchor(10, 0.0, 0.0), anchor(11, 5.0, 0.01), anchor(12, 10.0, 0.0)];
+        assert_eq!(locate(1, &ranges(&almost, (2.0, 6.0), &[])), None);
+
+        let same = [anchor(10, 1.0, 1.0), anchor(11, 1.0, 1.0), anchor(12, 1.0, 1.0)];
+        assert_eq!(locate(1, &ranges(&same, (2.0, 6.0), &[])), None);
+    }
+
+    #[test]
+    fn fewer_than_three_anchors_give_no_fix() {
+        assert_eq!(locate(1, &ranges(&corners()[.. 2], (3.0, 4.0), &[])), None);
+        assert_eq!(locate(1, &[]), None);
+    }
+
+    #[test]
+    fn impossible_distances_still_give_a_finite_fix() {
+        let ranges: Vec<_> = corners().into_iter().map(|anchor| (anchor, 0.0)).collect();
+        let fix = locate(1, &ranges).unwrap();
+
+        assert!(fix.position.x_m.is_finite() && fix.position.y_m.is_finite() && fix.residual_m.is_finite());
+    }
+
+    /// The edges between every pair of the given points, weighted equally.
+    fn edges(points: &[(u16, f32, f32)]) -> Vec<Edge> {
+        let mut edges = vec![];
+        for (i, &(a, ax, ay)) in points.iter().enumerate() {
+            for &(b, bx, by) in &points[i + 1 ..] {
+                edges.push(Edge { a, b, distance_m: distance((ax, ay), (bx, by)), weight: 1.0 });
+            }
+        }
+        edges
+    }
+
+    #[test]
+    fn layout_reproduces_the_measured_distances() {
+        let points = [(1, 0.0, 0.0), (2, 4.0, 0.0), (3, 4.0, 3.0), (4, 1.0, 5.0)];
+        let layout = solve(&[1, 2, 3, 4], &edges(&points), &HashMap::new()).unwrap();
+
+        assert!(layout.residual_m < 1e-2);
+        assert_eq!(layout.outliers, 0);
+        for (position, &(id, x_m, y_m)) in layout.positions.iter().zip(&points) {
+            assert_eq!(position.id, id);
+            assert!(distance((position.x_m, position.y_m), (x_m, y_m)) < 0.05, "Placed {} at {:?}", id, position);
+        }
+    }
+
+    #[test]
+    fn controllers_in_a_line_are_placed_without_nan() {
+        let points = [(1, 0.0, 0.0), (2, 2.0, 0.0), (3, 4.0, 0.0), (4, 6.0, 0.0)];
+        let layout = solve(&[1, 2, 3, 4], &edges(&points), &HashMap::new()).unwrap();
+
+        assert!(layout.residual_m.is_finite() && layout.residual_m < 0.05);
+        assert!(layout.positions.iter().all(|position| position.x_m.is_finite() && position.y_m.is_finite()));
+    }
+
+    #[test]
+    fn unreachable_controllers_are_left_out() {
+        let mut edges = edges(&[(1, 0.0, 0.0), (2, 3.0, 0.0), (3, 0.0, 3.0)]);
+        edges.push(Edge { a: 4, b: 5, distance_m: 1.0, weight: 1.0 });
+
+        let layout = solve(&[1, 2, 3, 4, 5], &edges, &HashMap::new()).unwrap();
+        assert_eq!(layout.positions.iter().map(|position| position.id).collect::<Vec<_>>(), [1, 2, 3]);
+        assert_eq!(solve(&[4], &edges, &HashMap::new()), None);
+    }
+}
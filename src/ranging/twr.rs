@@ -0,0 +1,168 @@
+//! Timestamp arithmetic for double-sided two-way ranging with DW3000 time units.
+
+/// Duration of one DW3000 time unit in seconds, 1 / (128 * 499.2 MHz) or about 15.65 ps.
+pub const TIME_UNIT_S: f64 = 1.0 / (128.0 * 499.2e6);
+
+/// DW3000 time units per microsecond.
+pub const UNITS_PER_US: f64 = 1e-6 / TIME_UNIT_S;
+
+/// Speed of light in air in meters per second.
+pub const SPEED_OF_LIGHT_M_S: f64 = 299_702_547.0;
+
+/// The DW3000 system time and timestamps are 40 bits wide and wrap after about 17.2 seconds.
+pub const TIMESTAMP_MASK: u64 = (1 << 40) - 1;
+
+/// Delayed transmissions ignore the lowest 9 bits of the requested time.
+const DELAYED_TX_RESOLUTION_MASK: u64 = !0x1FF;
+
+/// The time between two timestamps, taking a single wrap-around of the 40-bit counter into account.
+pub fn elapsed(from: u64, to: u64) -> u64 {
+    to.wrapping_sub(from) & TIMESTAMP_MASK
+}
+
+/// Add a duration in time units to a timestamp, wrapping like the DW3000 counter does.
+pub fn add(timestamp: u64, units: u64) -> u64 {
+    timestamp.wrapping_add(units) & TIMESTAMP_MASK
+}
+
+pub fn us_to_units(us: u64) -> u64 {
+    (us as f64 * UNITS_PER_US) as u64
+}
+
+pub fn units_to_us(units: u64) -> f64 {
+    units as f64 / UNITS_PER_US
+}
+
+/// The TX timestamp the DW3000 will report for a transmission delayed until `requested`.
+///
+/// The radio starts transmitting at the requested time with its lowest 9 bits cleared and adds the TX antenna delay to
+/// the timestamp, so the result can be embedded into the very frame being transmitted.
+pub fn delayed_tx_timestamp(requested: u64, tx_antenna_delay: u16) -> u64 {
+    add(requested & DELAYED_TX_RESOLUTION_MASK, tx_antenna_delay as u64)
+}
+
+/// The four intervals of a double-sided exchange, each measured on the clock of the node named in the field.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Intervals {
+    /// Initiator: from sending the poll to receiving the response.
+    pub round_a: u64,
+    /// Initiator: from receiving the response to sending the final message.
+    pub reply_a: u64,
+    /// Responder: from sending the response to receiving the final message.
+    pub round_b: u64,
+    /// Responder: from receiving the poll to sending the response.
+    pub reply_b: u64,
+}
+
+impl Intervals {
+    /// Derive the intervals from the six timestamps of an exchange.
+    pub fn from_timestamps(poll_tx: u64, poll_rx: u64, response_tx: u64, response_rx: u64, final_tx: u64, final_rx: u64) -> Self {
+        Self {
+            round_a: elapsed(poll_tx, response_rx),
+            reply_a: elapsed(response_rx, final_tx),
+            round_b: elapsed(response_tx, final_rx),
+            reply_b: elapsed(poll_rx, response_tx),
+        }
+    }
+
+    /// The time of flight in time units, using the asymmetric DS-TWR formula.
+    ///
+    /// Unlike the symmetric variant, this formula cancels the first-order error from the different crystal frequencies
+    /// of both nodes even when the reply delays differ.
+    pub fn time_of_flight(&self) -> Option<f64> {
+        let (ra, da, rb, db) = (self.round_a as i128, self.reply_a as i128, self.round_b as i128, self.reply_b as i128);
+        let denominator = ra + rb + da + db;
+
+        if denominator == 0 {
+            return None;
+        }
+
+        Some((ra * rb - da * db) as f64 / denominator as f64)
+    }
+
+    /// How much faster the initiator's clock runs compared to the responder's, in parts per million.
+    pub fn clock_drift_ppm(&self) -> f64 {
+        let initiator = (self.round_a + self.reply_a) as f64;
+        let responder = (self.round_b + self.reply_b) as f64;
+
+        if responder == 0.0 {
+            return 0.0;
+        }
+
+        (initiator / responder - 1.0) * 1e6
+    }
+}
+
+/// Convert a time of flight in time units to meters.
+pub fn tof_to_meters(tof: f64) -> f64 {
+    tof * TIME_UNIT_S * SPEED_OF_LIGHT_M_S
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Time of flight of the exchanges below in time units, about 10 m.
+    const TOF: u64 = 2_132;
+
+    /// The six timestamps of an exchange starting at `poll_tx`, with the responder's clock `offset` ahead of the
+    /// initiator's and running `drift` faster than it.
+    fn exchange(poll_tx: u64, offset: u64, reply_a: u64, reply_b: u64, drift: f64) -> [u64; 6] {
+        let on_responder = |units: u64| (units as f64 * (1.0 + drift)).round() as u64;
+
+        let poll_rx = add(poll_tx, offset + TOF);
+        let response_tx = add(poll_rx, on_responder(reply_b));
+        let response_rx = add(poll_tx, TOF + reply_b + TOF);
+        let final_tx = add(response_rx, reply_a);
+        let final_rx = add(response_tx, on_responder(TOF + reply_a + TOF));
+
+        [poll_tx, poll_rx, response_tx, response_rx, final_tx, final_rx]
+    }
+
+    fn intervals([poll_tx, poll_rx, response_tx, response_rx, final_tx, final_rx]: [u64; 6]) -> Intervals {
+        Intervals::from_timestamps(poll_tx, poll_rx, response_tx, response_rx, final_tx, final_rx)
+    }
+
+    #[test]
+    fn elapsed_wraps_around_the_40_bit_counter() {
+        assert_eq!(elapsed(100, 250), 150);
+        assert_eq!(elapsed(TIMESTAMP_MASK - 9, 10), 20);
+        assert_eq!(add(TIMESTAMP_MASK - 9, 20), 10);
+    }
+
+    #[test]
+    fn time_of_flight_is_exact_with_equal_clocks() {
+        let intervals = intervals(exchange(1_000, 5_000_000, 40_000_000, 30_000_000, 0.0));
+
+        assert_eq!(intervals.time_of_flight(), Some(TOF as f64));
+        assert_eq!(intervals.clock_drift_ppm(), 0.0);
+        assert!((tof_to_meters(TOF as f64) - 10.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn time_of_flight_survives_a_wrap_of_the_counter() {
+        let poll_tx = TIMESTAMP_MASK - 20_000_000;
+        let timestamps = exchange(poll_tx, TIMESTAMP_MASK - 1_000, 40_000_000, 30_000_000, 0.0);
+        assert!(timestamps.iter().any(|timestamp| *timestamp < poll_tx));
+
+        assert_eq!(intervals(timestamps).time_of_flight(), Some(TOF as f64));
+    }
+
+    #[test]
+    fn clock_drift_cancels_out_with_different_reply_delays() {
+        // The responder's crystal runs 10 ppm fast, which would be 300 units of error in its reply delay alone.
+        let intervals = intervals(exchange(1_000, 5_000_000, 60_000_000, 30_000_000, 10e-6));
+
+        let tof = intervals.time_of_flight().unwrap();
+        assert!((tof - TOF as f64).abs() < 1.0, "time of flight {} instead of {}", tof, TOF);
+        assert!((intervals.clock_drift_ppm() + 10.0).abs() < 0.01, "drift {} ppm", intervals.clock_drift_ppm());
+    }
+
+    #[test]
+    fn time_of_flight_needs_intervals() {
+        let intervals = Intervals { round_a: 0, reply_a: 0, round_b: 0, reply_b: 0 };
+
+        assert_eq!(intervals.time_of_flight(), None);
+        assert_eq!(intervals.clock_drift_ppm(), 0.0);
+    }
+}
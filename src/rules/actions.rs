@@ -0,0 +1,54 @@
+//! The actions built into the firmware, which every rule file can use.
+
+use crate::controller::Controller;
+
+use super::{Action, Parameters};
+
+/// Changes the LED color of the controller while a game mode runs on the rule engine.
+///
+/// Takes either a `color` as a list of red, green, blue and optionally white values, or a `target` whose color should
+/// be adopted. The only supported target is `closest`, the closest controller ranged over UWB.
+pub struct ChangeColor;
+
+impl Action for ChangeColor {
+    fn execute(
+        &self,
+        controller: &mut Controller,
+        parameters: &Parameters,
+    ) -> Result<(), String> {
+        let color = match (parameters.get("color"), parameters.get("target").and_then(|target| target.as_str())) {
+            (Some(color), _) => parse_color(color)?,
+            (None, Some("closest")) => {
+                let closest = controller.sensors.closest_peer().ok_or("No controller has been ranged yet")?.peer;
+                *controller.sensors.peer_colors.get(&closest)
+                    .ok_or_else(|| format!("The color of controller {} is unknown", closest))?
+            },
+            (None, Some(target)) => return Err(format!("Unknown color target {}", target)),
+            (None, None) => return Err("Expected a color or a target".to_string()),
+        };
+
+        controller.game_color = Some(color);
+
+        Ok(())
+    }
+}
+
+fn parse_color(value: &serde_json::Value) -> Result<(u8, u8, u8, u8), String> {
+    let channels: Vec<u8> = value.as_array()
+        .ok_or("Expected a color as a list of numbers")?
+        .iter()
+        .map(|channel| channel.as_u64().and_then(|channel| u8::try_from(channel).ok()))
+        .collect::<Option<_>>()
+        .ok_or("Color channels have to be numbers between 0 and 255")?;
+
+    match channels[..] {
+        [red, green, blue] => Ok((red, green, blue, 0)),
+        [red, green, blue, white] => Ok((red, green, blue, white)),
+        _ => Err(format!("Expected 3 or 4 color channels, got {}", channels.len())),
+    }
+}
+
+/// Register the built-in actions with a controller.
+pub fn register_builtin(controller: &mut Controller) {
+    controller.register_action("change_color", &ChangeColor);
+}
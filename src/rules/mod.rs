@@ -0,0 +1,326 @@
+//! A rule engine which runs game modes defined as data, like `config/territory.yml`.
+//!
+//! A [`GameDefinition`] consists of triggers and actions. Every tick, the [`RuleEngine`] checks the conditions of each
+//! trigger against the current [`Inputs`]. When they become true, the actions listed by the trigger are evaluated, and
+//! those whose own conditions hold as well produce an [`Invocation`] of their `onSuccess` method. The controller then
+//! runs the [`Action`] registered under that method name.
+//!
+//! The rule files in `config/` are converted to JSON by the build script and bundled with the firmware, see [`bundled`].
+
+use serde::{Deserialize, Serialize};
+
+use crate::controller::Controller;
+use crate::ranging::filter::DistanceEstimate;
+
+pub mod actions;
+
+include!(concat!(env!("OUT_DIR"), "/game_modes.rs"));
+
+/// Named parameters passed from a rule file to an action.
+pub type Parameters = serde_json::Map<String, serde_json::Value>;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GameDefinition {
+    pub name: String,
+    /// Rounds of game modes with a duration end after this many seconds.
+    #[serde(default)]
+    pub duration_s: Option<u32>,
+    pub triggers: Vec<Trigger>,
+    #[serde(default)]
+    pub actions: Vec<ActionDefinition>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Trigger {
+    #[serde(rename = "type")]
+    pub kind: TriggerKind,
+    #[serde(default)]
+    pub description: Option<String>,
+    /// Names of the actions to evaluate when the trigger fires.
+    pub actions: Vec<String>,
+    #[serde(default)]
+    pub conditions: Vec<Condition>,
+}
+
+/// The input a trigger watches, which `threshold` conditions compare against.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TriggerKind {
+    /// The accelerometer jolt of the controller.
+    Jolt,
+    /// The distance to the closest controller in meters.
+    Proximity,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ActionDefinition {
+    pub name: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub conditions: Vec<Condition>,
+    #[serde(rename = "onSuccess")]
+    pub on_success: MethodCall,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MethodCall {
+    /// The name of a registered [`Action`].
+    pub method: String,
+    #[serde(default)]
+    pub parameters: Parameters,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Condition {
+    #[serde(rename = "type")]
+    pub kind: ConditionKind,
+    pub operator: Operator,
+    pub value: f32,
+    #[serde(default)]
+    pub unit: Option<Unit>,
+    /// How far past `value` the input has to go before a condition which held stops holding, in the same unit, so it
+    /// doesn't flicker while the input hovers around `value`.
+    #[serde(default)]
+    pub hysteresis: Option<f32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConditionKind {
+    /// Compares the input of the trigger which fired.
+    Threshold,
+    /// Compares the distance to the closest controller, and fails while no controller has been ranged.
+    Distance,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Operator {
+    #[serde(rename = "<")]
+    Less,
+    #[serde(rename = "<=")]
+    LessOrEqual,
+    #[serde(rename = ">")]
+    Greater,
+    #[serde(rename = ">=")]
+    GreaterOrEqual,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Unit {
+    Meters,
+    Centimeters,
+}
+
+impl Operator {
+    /// Compare the input on the left against the value on the right, which is moved by the margin in favour of the
+    /// condition if it held before.
+    fn compare(self, left: f32, right: f32, margin: f32) -> bool {
+        let right = match self {
+            Operator::Less | Operator::LessOrEqual => right + margin,
+            Operator::Greater | Operator::GreaterOrEqual => right - margin,
+        };
+
+        match self {
+            Operator::Less => left < right,
+            Operator::LessOrEqual => left <= right,
+            Operator::Greater => left > right,
+            Operator::GreaterOrEqual => left >= right,
+        }
+    }
+}
+
+impl Condition {
+    fn holds(&self, trigger_value: Option<f32>, inputs: &Inputs, held: bool) -> bool {
+        let value = match self.kind {
+            ConditionKind::Threshold => trigger_value,
+            ConditionKind::Distance => inputs.closest.map(|closest| closest.distance_m),
+        };
+        // Distances in the rule file may be given in another unit than the meters of the ranging results.
+        let scale = match self.unit {
+            Some(Unit::Centimeters) => 0.01,
+            Some(Unit::Meters) | None => 1.0,
+        };
+        let margin = if held { self.hysteresis.unwrap_or(0.0) * scale } else { 0.0 };
+
+        value.is_some_and(|value| self.operator.compare(value, self.value * scale, margin))
+    }
+}
+
+/// A piece of code a rule file can invoke by name through `onSuccess`.
+pub trait Action {
+    fn execute(
+        &self,
+        controller: &mut Controller,
+        parameters: &Parameters,
+    ) -> Result<(), String>;
+}
+
+/// The sensor and ranging readings the rules are evaluated against.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Inputs {
+    pub jolt: f32,
+    /// The smoothed distance to the closest controller.
+    pub closest: Option<DistanceEstimate>,
+}
+
+/// An action method the controller should run, along with its parameters from the rule file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Invocation {
+    pub method: String,
+    pub parameters: Parameters,
+}
+
+pub struct RuleEngine {
+    pub definition: GameDefinition,
+    /// Whether the conditions of each trigger held on the last tick, so a trigger only fires once per crossing.
+    fired: Vec<bool>,
+    /// Whether the conditions of each action held when it was last evaluated.
+    held: Vec<bool>,
+}
+
+impl RuleEngine {
+    /// Prepare a game definition for running, making sure every action a trigger refers to exists.
+    pub fn new(definition: GameDefinition) -> Result<Self, String> {
+        for trigger in &definition.triggers {
+            for name in &trigger.actions {
+                if !definition.actions.iter().any(|action| &action.name == name) {
+                    return Err(format!("Trigger {:?} refers to unknown action {}", trigger.kind, name));
+                }
+            }
+        }
+
+        Ok(Self {
+            fired: vec![false; definition.triggers.len()],
+            held: vec![false; definition.actions.len()],
+            definition,
+        })
+    }
+
+    /// Evaluate all triggers against the current inputs and return the action methods to run.
+    pub fn tick(&mut self, inputs: &Inputs) -> Vec<Invocation> {
+        let mut invocations = vec![];
+
+        for (trigger, fired) in self.definition.triggers.iter().zip(self.fired.iter_mut()) {
+            let value = match trigger.kind {
+                TriggerKind::Jolt => Some(inputs.jolt),
+                TriggerKind::Proximity => inputs.closest.map(|closest| closest.distance_m),
+            };
+
+            let holds = trigger.conditions.iter().all(|condition| condition.holds(value, inputs, *fired));
+            let fires = holds && !*fired;
+            *fired = holds;
+
+            if !fires {
+                continue;
+            }
+
+            for name in &trigger.actions {
+                let Some(index) = self.definition.actions.iter().position(|action| &action.name == name) else {
+                    continue;
+                };
+                let action = &self.definition.actions[index];
+
+                let holds = action.conditions.iter().all(|condition| condition.holds(value, inputs, self.held[index]));
+                self.held[index] = holds;
+                if holds {
+                    invocations.push(Invocation {
+                        method: action.on_success.method.clone(),
+                        parameters: action.on_success.parameters.clone(),
+                    });
+                }
+            }
+        }
+
+        invocations
+    }
+}
+
+/// The game mode bundled from `config/<name>.yml`, if there is one.
+pub fn bundled(name: &str) -> Option<Result<GameDefinition, String>> {
+    GAME_MODES.iter()
+        .find(|(bundled_name, _)| *bundled_name == name)
+        .map(|(_, json)| serde_json::from_str(json).map_err(|e| format!("Invalid game mode {}: {}", name, e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn inputs(jolt: f32, closest_m: Option<f32>) -> Inputs {
+        Inputs {
+            jolt,
+            closest: closest_m.map(|distance_m| DistanceEstimate { peer: 1, distance_m, velocity_m_s: 0.0, confidence: 1.0 }),
+        }
+    }
+
+    fn methods(invocations: Vec<Invocation>) -> Vec<String> {
+        invocations.into_iter().map(|invocation| invocation.method).collect()
+    }
+
+    #[test]
+    fn bundled_rule_files_are_converted_from_yaml() {
+        let territory = bundled("territory").unwrap().unwrap();
+        assert_eq!(territory.name, "Territory");
+        assert_eq!(territory.duration_s, Some(60));
+        assert_eq!(territory.triggers[0].kind, TriggerKind::Jolt);
+        assert_eq!(territory.actions[0].conditions[0].hysteresis, Some(0.2));
+
+        let hot_and_cold = bundled("hot_and_cold").unwrap().unwrap();
+        assert_eq!(hot_and_cold.triggers.len(), 2);
+        assert!(RuleEngine::new(hot_and_cold).is_ok());
+
+        assert!(bundled("missing").is_none());
+    }
+
+    #[test]
+    fn territory_adopts_the_color_of_the_closest_controller_on_a_jolt() {
+        let mut engine = RuleEngine::new(bundled("territory").unwrap().unwrap()).unwrap();
+
+        // Too far away, and the jolt is over by the next tick.
+        assert!(engine.tick(&inputs(0.5, Some(2.0))).is_empty());
+        assert!(engine.tick(&inputs(0.0, Some(1.0))).is_empty());
+
+        let invocations = engine.tick(&inputs(0.5, Some(1.0)));
+        assert_eq!(invocations.len(), 1);
+        assert_eq!(invocations[0].method, "change_color");
+        assert_eq!(invocations[0].parameters.get("target").and_then(|target| target.as_str()), Some("closest"));
+
+        // A jolt only fires once while it lasts.
+        assert!(engine.tick(&inputs(0.6, Some(1.0))).is_empty());
+
+        // Having been in range, the controller stays in range a little further out.
+        engine.tick(&inputs(0.0, Some(1.3)));
+        assert_eq!(methods(engine.tick(&inputs(0.5, Some(1.3)))), ["change_color"]);
+        engine.tick(&inputs(0.0, Some(1.5)));
+        assert!(engine.tick(&inputs(0.5, Some(1.5))).is_empty());
+    }
+
+    #[test]
+    fn hot_and_cold_follows_the_distance_to_the_closest_controller() {
+        let mut engine = RuleEngine::new(bundled("hot_and_cold").unwrap().unwrap()).unwrap();
+
+        assert!(engine.tick(&inputs(0.0, None)).is_empty());
+        assert!(engine.tick(&inputs(0.0, Some(2.0))).is_empty());
+        assert_eq!(methods(engine.tick(&inputs(0.0, Some(0.8)))), ["change_color"]);
+        assert_eq!(engine.definition.actions[0].on_success.parameters.get("color"), Some(&serde_json::json!([255, 60, 0])));
+
+        // Hovering around the threshold doesn't fire again.
+        assert!(engine.tick(&inputs(0.0, Some(1.1))).is_empty());
+        assert!(engine.tick(&inputs(0.0, Some(0.9))).is_empty());
+
+        assert!(engine.tick(&inputs(0.0, Some(2.5))).is_empty());
+        assert_eq!(methods(engine.tick(&inputs(0.0, Some(3.1)))), ["change_color"]);
+        assert!(engine.tick(&inputs(0.0, Some(2.9))).is_empty());
+        assert_eq!(methods(engine.tick(&inputs(0.0, Some(0.5)))), ["change_color"]);
+    }
+
+    #[test]
+    fn rules_referring_to_unknown_actions_are_refused() {
+        let mut definition = bundled("hot_and_cold").unwrap().unwrap();
+        definition.actions.pop();
+
+        assert!(RuleEngine::new(definition).is_err());
+    }
+}
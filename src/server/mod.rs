@@ -1,11 +1,15 @@
 //! Peripheral controller for HTTP communication and WebSocket streams.
 
 use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
 use embedded_svc::{http::Method, ws::FrameType};
 use esp_idf_hal::sys::{ESP_ERR_INVALID_SIZE, EspError};
 use esp_idf_svc::http::server::EspHttpServer;
+use esp_idf_svc::http::server::ws::EspHttpWsDetachedSender;
 use ledswarm_protocol::{ClientMessage, InternalMessage};
 
+use crate::controller::Command;
+use crate::event_bus::{self, EventBus};
 use crate::RootDocument;
 
 pub mod handlers;
@@ -16,13 +20,32 @@ const MAX_LEN: usize = 256;
 
 
 /// Initialize HTTP server and WebSocket endpoints.
-pub fn create_endpoints(msg_tx: flume::Sender<InternalMessage>) -> anyhow::Result<()> {
+///
+/// Events published by the controller are forwarded as JSON to every connected WebSocket client, and requests which
+/// aren't protocol messages are handed to the controller as [`Command`]s.
+pub fn create_endpoints(
+    msg_tx: flume::Sender<InternalMessage>,
+    commands: flume::Sender<Command>,
+    event_bus: Arc<EventBus>,
+) -> anyhow::Result<()> {
     let server_configuration = esp_idf_svc::http::server::Configuration {
         stack_size: 10240,
         ..Default::default()
     };
     let mut server = EspHttpServer::new(&server_configuration).unwrap();
 
+    let ws_senders: Arc<Mutex<Vec<EspHttpWsDetachedSender>>> = Arc::new(Mutex::new(vec![]));
+
+    let events = event_bus.subscribe_many(&[event_bus::GAME, event_bus::MESH, event_bus::POSITIONS, event_bus::LINKS]);
+    let senders = ws_senders.clone();
+    std::thread::Builder::new().stack_size(4096).spawn(move || {
+        while let Ok((_tag, event)) = events.recv_blocking() {
+            let json_string = serde_json::to_string(&event).unwrap();
+            // Sending fails once the client is gone, so closed sessions are dropped here.
+            senders.lock().unwrap().retain_mut(|sender| sender.send(FrameType::Text(false), json_string.as_bytes()).is_ok());
+        }
+    })?;
+
     server.fn_handler("/", Method::Get, |req| {
         let root_doc = RootDocument {
             version: "0.1.0".to_string(),
@@ -38,6 +61,11 @@ pub fn create_endpoints(msg_tx: flume::Sender<InternalMessage>) -> anyhow::Resul
                 // sessions.insert(ws.session(), GuessingGame::new((rand() % 100) + 1));
                 println!("New WebSocket session");
 
+                match ws.create_detached_sender() {
+                    Ok(sender) => ws_senders.lock().unwrap().push(sender),
+                    Err(e) => println!("Failed to subscribe WebSocket session to events: {}", e),
+                }
+
                 // We need to send at least one message to keep the connection alive.
                 let msg = ClientMessage::SetBrightness(0.5);
                 let json_string = serde_json::to_string(&msg).unwrap();
@@ -81,9 +109,17 @@ pub fn create_endpoints(msg_tx: flume::Sender<InternalMessage>) -> anyhow::Resul
             // println!("Example client message: {:?}", serde_json::to_string(&ClientMessage::StartRound("last_one_standing".to_string())).unwrap());
 
             // Remove null terminator
-            match serde_json::from_str::<ClientMessage>(&user_string[0 .. user_string.len() - 1]) {
+            let json = &user_string[0 .. user_string.len() - 1];
+            match serde_json::from_str::<ClientMessage>(json) {
                 Ok(msg) => msg_tx.try_send(InternalMessage::ClientMessage(msg)).unwrap(),
-                Err(e)  => println!("Failed to parse JSON:\n\n{}\n\n{}", e, user_string),
+                Err(e)  => match serde_json::from_str::<Command>(json) {
+                    Ok(command) => {
+                        if commands.try_send(command).is_err() {
+                            println!("Controller is not keeping up with commands, dropping one");
+                        }
+                    },
+                    Err(_) => println!("Failed to parse JSON:\n\n{}\n\n{}", e, user_string),
+                },
             }
             
             ws.send(FrameType::Text(false), user_string.as_bytes())?;
@@ -0,0 +1,249 @@
+//! A simulated broadcast medium which carries frames between the nodes of a simulated swarm.
+
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashSet};
+
+use crate::mesh::Payload;
+use crate::radio::profile::RadioProfile;
+use crate::radio::sts::Sts;
+use crate::ranging::twr::SPEED_OF_LIGHT_M_S;
+
+/// Radio propagation parameters of the simulated medium.
+#[derive(Debug, Clone)]
+pub struct MediumConfig {
+    /// Fixed delay between transmission and reception in microseconds.
+    pub latency_us: u64,
+    /// Maximum random delay added on top of the fixed latency in microseconds.
+    pub jitter_us: u64,
+    /// Probability between 0.0 and 1.0 that a receiver misses a frame.
+    pub loss: f32,
+    /// Receivers further away than this many meters never get the frame.
+    pub range_m: f32,
+    /// Whether frames overlapping at a receiver, or with its own transmission, are lost.
+    pub collisions: bool,
+}
+
+impl Default for MediumConfig {
+    fn default() -> Self {
+        Self {
+            latency_us: 2000,
+            jitter_us: 500,
+            loss: 0.0,
+            range_m: 30.0,
+            collisions: true,
+        }
+    }
+}
+
+/// A small xorshift generator so runs with the same seed are reproducible.
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            // A zero state would make xorshift return zeroes forever.
+            state: seed.max(1),
+        }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        let mut x = self.state;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.state = x;
+        x
+    }
+
+    /// A uniformly distributed number in `0.0 .. 1.0`.
+    pub fn next_f32(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    /// A uniformly distributed number in `0 ..= max`.
+    pub fn below_or_eq(&mut self, max: u64) -> u64 {
+        if max == 0 {
+            0
+        } else {
+            self.next_u64() % (max + 1)
+        }
+    }
+}
+
+/// A frame on its way to a single receiver.
+struct InFlight {
+    /// When the receiving node gets to process the frame.
+    deliver_at_us: u64,
+    /// Breaks ties between frames arriving at the same time in the order they were sent.
+    seq: u64,
+    from: usize,
+    to: usize,
+    distance_m: f32,
+    /// When the frame arrived at the receiving antenna, which determines its RX timestamp.
+    arrival_us: f64,
+    payload: Payload,
+    sts: Option<Sts>,
+    profile: RadioProfile,
+}
+
+/// A frame delivered to a node.
+pub struct Delivery {
+    pub from: usize,
+    pub to: usize,
+    /// The distance between sender and receiver in meters.
+    pub distance_m: f32,
+    pub arrival_us: f64,
+    pub payload: Payload,
+    /// The scrambled timestamp sequence the frame was sent with.
+    pub sts: Option<Sts>,
+    /// The radio profile the frame was sent on, which only receivers on the same profile can hear.
+    pub profile: RadioProfile,
+}
+
+impl PartialEq for InFlight {
+    fn eq(&self, other: &Self) -> bool {
+        self.deliver_at_us == other.deliver_at_us && self.seq == other.seq
+    }
+}
+
+impl Eq for InFlight {}
+
+impl PartialOrd for InFlight {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for InFlight {
+    // Reversed so the `BinaryHeap` pops the earliest frame first.
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.deliver_at_us.cmp(&self.deliver_at_us)
+            .then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+/// Broadcasts every transmitted frame to all nodes in range, applying latency and loss.
+pub struct Medium {
+    pub config: MediumConfig,
+    positions: Vec<(f32, f32)>,
+    in_flight: BinaryHeap<InFlight>,
+    /// Recent frames on the air at each node as start, end and sequence number, to find overlapping ones.
+    ///
+    /// A node's own transmissions are recorded with sequence number 0, which is never used for a frame in flight.
+    on_air: Vec<Vec<(f64, f64, u64)>>,
+    /// Frames in flight which overlapped with another one at their receiver.
+    collided: HashSet<u64>,
+    rng: Rng,
+    seq: u64,
+    /// Number of frames which never reached a receiver because they were lost or out of range.
+    pub dropped: u64,
+    /// Number of frames which were lost because they overlapped with another one.
+    pub collisions: u64,
+}
+
+impl Medium {
+    pub fn new(config: MediumConfig, positions: Vec<(f32, f32)>, seed: u64) -> Self {
+        Self {
+            config,
+            on_air: vec![vec![]; positions.len()],
+            positions,
+            in_flight: BinaryHeap::new(),
+            collided: HashSet::new(),
+            rng: Rng::new(seed),
+            seq: 0,
+            dropped: 0,
+            collisions: 0,
+        }
+    }
+
+    /// The distance between two nodes in meters.
+    pub fn distance(&self, a: usize, b: usize) -> f32 {
+        let (ax, ay) = self.positions[a];
+        let (bx, by) = self.positions[b];
+        ((ax - bx).powi(2) + (ay - by).powi(2)).sqrt()
+    }
+
+    /// Put a payload on the air which leaves the antenna of node `from` at the given time.
+    pub fn transmit(&mut self, leave_us: f64, from: usize, payload: Payload, sts: Option<Sts>, profile: RadioProfile) {
+        let airtime_us = profile.airtime_us(payload.to_bytes(0).len());
+        self.occupy(from, leave_us, leave_us + airtime_us, 0);
+
+        for to in 0 .. self.positions.len() {
+            if to == from {
+                continue;
+            }
+
+            let distance_m = self.distance(from, to);
+            if distance_m > self.config.range_m || self.rng.next_f32() < self.config.loss {
+                self.dropped += 1;
+                continue;
+            }
+
+            let arrival_us = leave_us + distance_m as f64 / SPEED_OF_LIGHT_M_S * 1e6;
+            let deliver_at_us = arrival_us.ceil() as u64 + self.config.latency_us + self.rng.below_or_eq(self.config.jitter_us);
+            self.seq += 1;
+            self.occupy(to, arrival_us, arrival_us + airtime_us, self.seq);
+            self.in_flight.push(InFlight {
+                deliver_at_us,
+                seq: self.seq,
+                from,
+                to,
+                distance_m,
+                arrival_us,
+                payload: payload.clone(),
+                sts,
+                profile,
+            });
+        }
+    }
+
+    /// Take the next payload which has arrived at its receiver by the given time.
+    pub fn receive(&mut self, now_us: u64) -> Option<Delivery> {
+        loop {
+            if self.in_flight.peek()?.deliver_at_us > now_us {
+                return None;
+            }
+
+            let in_flight = self.in_flight.pop()?;
+            if self.collided.remove(&in_flight.seq) {
+                self.collisions += 1;
+                continue;
+            }
+
+            return Some(Delivery {
+                from: in_flight.from,
+                to: in_flight.to,
+                distance_m: in_flight.distance_m,
+                arrival_us: in_flight.arrival_us,
+                payload: in_flight.payload,
+                sts: in_flight.sts,
+                profile: in_flight.profile,
+            });
+        }
+    }
+
+    /// Record a frame on the air at a node, marking it and everything it overlaps with as collided.
+    fn occupy(&mut self, node: usize, start_us: f64, end_us: f64, seq: u64) {
+        if !self.config.collisions {
+            return;
+        }
+
+        let on_air = &mut self.on_air[node];
+        // Delayed transmissions are scheduled a few milliseconds ahead at most, so older frames can be forgotten.
+        on_air.retain(|(_, end, _)| *end > start_us - 1_000_000.0);
+
+        for (other_start, other_end, other_seq) in on_air.iter() {
+            if *other_start < end_us && start_us < *other_end {
+                for seq in [seq, *other_seq] {
+                    if seq != 0 {
+                        self.collided.insert(seq);
+                    }
+                }
+            }
+        }
+
+        on_air.push((start_us, end_us, seq));
+    }
+}
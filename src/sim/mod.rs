@@ -0,0 +1,316 @@
+//! A host-side simulation of a whole swarm of controllers sharing a simulated UWB medium.
+//!
+//! Every node runs an unmodified [`Controller`] on top of the in-memory hardware from [`crate::hal::memory`] and an
+//! emulated radio running the real [`RadioStack`](crate::radio::RadioStack). The nodes are stepped in lockstep on a
+//! shared virtual clock, so a run only depends on its configuration and seed.
+
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+
+use ledswarm_protocol::{ClientMessage, InternalMessage};
+
+use crate::controller::{ClientGameState, Command, Controller, ControllerMode, GameState, UNIQUE_ID_KEY};
+use crate::hal::{Clock, LedSink, Storage};
+use crate::hal::memory::{ManualClock, MemoryBattery, MemoryLed, MemoryStorage, MemoryWifi};
+use crate::radio::RadioEndpoint;
+use crate::radio::tdma::TdmaConfig;
+use crate::ranging::RangingConfig;
+
+pub mod medium;
+pub mod radio;
+
+use medium::{Medium, MediumConfig, Rng};
+use self::radio::SimRadio;
+
+/// How the simulated swarm is set up.
+#[derive(Debug, Clone)]
+pub struct SimConfig {
+    pub nodes: usize,
+    /// Positions of the nodes in meters. Nodes without a position are placed on a line, `spacing_m` apart.
+    pub positions: Vec<(f32, f32)>,
+    pub spacing_m: f32,
+    /// Delay between switching on one node and the next, in microseconds.
+    pub boot_stagger_us: u64,
+    /// Virtual time which passes between two iterations of the controller loops, in microseconds.
+    pub step_us: u64,
+    pub seed: u64,
+    /// Each node's radio crystal deviates from the nominal frequency by up to this many parts per million.
+    pub max_clock_drift_ppm: f64,
+    pub medium: MediumConfig,
+    pub ranging: RangingConfig,
+    pub tdma: TdmaConfig,
+    /// The nodes whose radio never syncs to scrambled timestamp sequences.
+    pub broken_sts: Vec<usize>,
+    /// Each node's antenna delay deviates from the configured one by up to this many DW3000 time units.
+    pub max_antenna_delay_error: u16,
+}
+
+impl Default for SimConfig {
+    fn default() -> Self {
+        Self {
+            nodes: 3,
+            positions: vec![],
+            spacing_m: 2.0,
+            boot_stagger_us: 500_000,
+            step_us: 100,
+            seed: 1,
+            max_clock_drift_ppm: 10.0,
+            medium: MediumConfig::default(),
+            ranging: RangingConfig::default(),
+            tdma: TdmaConfig::default(),
+            broken_sts: vec![],
+            max_antenna_delay_error: 0,
+        }
+    }
+}
+
+/// Something happening to a node from the outside, like a WebSocket command or the player shaking it.
+#[derive(Debug, Clone)]
+pub enum Input {
+    ClientMessage(ClientMessage),
+    /// A request of a WebSocket client beyond the protocol.
+    Command(Command),
+    Jolt(f32),
+    /// Switch the node off and on again after the given time in microseconds, keeping only what it stored.
+    Reboot { off_us: u64 },
+}
+
+struct ScheduledInput {
+    at_us: u64,
+    node:  usize,
+    input: Input,
+}
+
+/// The observable state of a node at a point in time.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NodeState {
+    pub mode:  String,
+    pub color: (u8, u8, u8, u8),
+}
+
+/// A single simulated controller along with the ends of its channels the simulator needs.
+pub struct SimNode {
+    pub controller: Controller<'static>,
+    pub led: MemoryLed,
+    pub radio: SimRadio,
+    /// The node's `nvs` partition, which survives reboots.
+    pub storage: MemoryStorage,
+    pub battery: MemoryBattery,
+    msg_tx: flume::Sender<InternalMessage>,
+    boot_at_us: u64,
+    booted: bool,
+    /// The wrapping loop counter the controller uses as its time base.
+    time: u16,
+}
+
+impl SimNode {
+    /// Create a freshly booted controller along with the ends of its channels.
+    fn boot(
+        led: &MemoryLed,
+        clock: &ManualClock,
+        storage: &MemoryStorage,
+        battery: &MemoryBattery,
+    ) -> (Controller<'static>, flume::Sender<InternalMessage>, RadioEndpoint) {
+        let (msg_tx, msg_rx) = flume::bounded(512);
+        let (radio_link, radio_endpoint) = crate::radio::link();
+
+        let mut controller = Controller::new(
+            msg_rx,
+            Box::new(radio_link),
+            Box::new(led.clone()),
+            Box::new(clock.clone()),
+            Box::new(storage.clone()),
+        );
+        controller.init_battery(Box::new(battery.clone()));
+
+        (controller, msg_tx, radio_endpoint)
+    }
+
+    pub fn state(&self) -> NodeState {
+        NodeState {
+            mode:  mode_name(&self.controller.mode),
+            color: self.led.color(),
+        }
+    }
+}
+
+pub struct Swarm {
+    pub nodes:  Vec<SimNode>,
+    pub medium: Medium,
+    clock: ManualClock,
+    /// Shared among the Wi-Fi mocks so that only the first node creates the network.
+    air: Arc<AtomicBool>,
+    inputs: Vec<ScheduledInput>,
+    /// Number of inputs which never reached a node because its queue was full.
+    pub dropped_inputs: u64,
+    step_us: u64,
+}
+
+impl Swarm {
+    pub fn new(config: SimConfig) -> Self {
+        let clock = ManualClock::new();
+        let mut rng = Rng::new(config.seed);
+
+        let positions = (0 .. config.nodes)
+            .map(|i| config.positions.get(i).copied().unwrap_or((i as f32 * config.spacing_m, 0.0)))
+            .collect();
+
+        let nodes = (0 .. config.nodes)
+            .map(|i| {
+                let led = MemoryLed::new();
+                let storage = MemoryStorage::new();
+                // Unique IDs drawn from the seed keep elections reproducible.
+                storage.clone().store(UNIQUE_ID_KEY, &format!("{:010x}", rng.next_u64() & 0xFF_FFFF_FFFF)).expect("Memory storage can't fail");
+                let battery = MemoryBattery::new(100);
+                let (controller, msg_tx, radio_endpoint) = SimNode::boot(&led, &clock, &storage, &battery);
+                let mut radio = SimRadio::new(radio_endpoint, config.ranging.clone(), config.tdma.clone(), config.max_clock_drift_ppm, &mut rng);
+                radio.sts_broken = config.broken_sts.contains(&i);
+                if config.max_antenna_delay_error > 0 {
+                    radio.randomize_antenna_delay(config.max_antenna_delay_error, &mut rng);
+                }
+
+                SimNode {
+                    controller,
+                    led,
+                    radio,
+                    storage,
+                    battery,
+                    msg_tx,
+                    boot_at_us: i as u64 * config.boot_stagger_us,
+                    booted: false,
+                    time: 0,
+                }
+            })
+            .collect();
+
+        Self {
+            nodes,
+            medium: Medium::new(config.medium, positions, rng.next_u64()),
+            clock,
+            air: Arc::new(AtomicBool::new(false)),
+            inputs: vec![],
+            dropped_inputs: 0,
+            step_us: config.step_us,
+        }
+    }
+
+    /// The virtual time since the start of the simulation in microseconds.
+    pub fn now_us(&self) -> u64 {
+        self.clock.now_us()
+    }
+
+    /// Deliver an input to a node once the simulation reaches the given time.
+    pub fn schedule(&mut self, at_us: u64, node: usize, input: Input) {
+        self.inputs.push(ScheduledInput { at_us, node, input });
+    }
+
+    /// Advance the simulation by a single step of all controller loops.
+    pub fn advance(&mut self) {
+        let now_us = self.now_us();
+
+        self.deliver_inputs(now_us);
+
+        for node in self.nodes.iter_mut() {
+            if !node.booted && node.boot_at_us <= now_us {
+                let wifi = MemoryWifi::new(self.air.clone(), node.controller.mode_sender());
+                node.controller.init_wifi(Box::new(wifi)).expect("Simulated Wi-Fi can't fail");
+                node.controller.begin();
+                node.booted = true;
+            }
+        }
+
+        while let Some(delivery) = self.medium.receive(now_us) {
+            let sender_drift_ppm = self.nodes[delivery.from].radio.drift_ppm();
+            let node = &mut self.nodes[delivery.to];
+            // A node which is still switched off misses the frame, just like its radio would.
+            if node.booted {
+                node.radio.receive(delivery, sender_drift_ppm, &node.msg_tx);
+            } else {
+                self.medium.dropped += 1;
+            }
+        }
+
+        for (i, node) in self.nodes.iter_mut().enumerate() {
+            if !node.booted {
+                continue;
+            }
+
+            node.controller.step(node.time);
+            node.time = node.time.wrapping_add(1);
+            node.radio.transmit(now_us, i, &mut self.medium);
+        }
+
+        self.clock.advance_us(self.step_us);
+    }
+
+    /// Run the simulation until the given time, calling `observe` after every step.
+    pub fn run_until(&mut self, end_us: u64, mut observe: impl FnMut(u64, &[SimNode])) {
+        while self.now_us() < end_us {
+            self.advance();
+            observe(self.now_us(), &self.nodes);
+        }
+    }
+
+    fn deliver_inputs(&mut self, now_us: u64) {
+        let (due, pending): (Vec<_>, Vec<_>) = self.inputs.drain(..).partition(|input| input.at_us <= now_us);
+        self.inputs = pending;
+
+        for scheduled in due {
+            let Some(node) = self.nodes.get_mut(scheduled.node) else {
+                continue;
+            };
+
+            let msg = match scheduled.input {
+                Input::ClientMessage(msg) => InternalMessage::ClientMessage(msg),
+                Input::Command(command) => {
+                    if node.controller.command_sender().try_send(command).is_err() {
+                        self.dropped_inputs += 1;
+                    }
+                    continue;
+                },
+                Input::Jolt(delta) => InternalMessage::AccelerometerJoltDelta(delta),
+                Input::Reboot { off_us } => {
+                    // Everything but the storage is lost, and the node boots like the first time once it is back on.
+                    let (controller, msg_tx, radio_endpoint) = SimNode::boot(&node.led, &self.clock, &node.storage, &node.battery);
+                    node.controller = controller;
+                    node.msg_tx = msg_tx;
+                    node.radio.reset(radio_endpoint);
+                    node.led.write_rgbw((0, 0, 0, 0));
+                    node.boot_at_us = now_us + off_us;
+                    node.booted = false;
+                    node.time = 0;
+                    continue;
+                },
+            };
+
+            // A controller which falls behind under load misses inputs, just like a full queue on the device.
+            if node.msg_tx.try_send(msg).is_err() {
+                self.dropped_inputs += 1;
+            }
+        }
+    }
+}
+
+/// A short, human-readable name of a controller mode.
+pub fn mode_name(mode: &ControllerMode) -> String {
+    match mode {
+        ControllerMode::Discovery => "Discovery".to_string(),
+        ControllerMode::Connecting => "Connecting".to_string(),
+        ControllerMode::Client { id, game } => match game {
+            Some(ClientGameState::LastOneStanding { is_active, .. }) => format!("Client({}):LastOneStanding(active={})", id, is_active),
+            Some(ClientGameState::Territory { team, .. }) => format!("Client({}):Territory(team={})", id, team),
+            None => format!("Client({})", id),
+        },
+        ControllerMode::ServerMeditation => "ServerMeditation".to_string(),
+        ControllerMode::Master { controllers, game, .. } => match game {
+            Some(GameState::LastOneStanding { active_controller_ids, .. }) => {
+                format!("Master({} peers):LastOneStanding({} active)", controllers.len(), active_controller_ids.len())
+            },
+            Some(GameState::Territory { teams, .. }) => {
+                format!("Master({} peers):Territory(team={})", controllers.len(), teams.get(&0).copied().unwrap_or_default())
+            },
+            None => format!("Master({} peers)", controllers.len()),
+        },
+        ControllerMode::Game(game_mode) => format!("Game({:?})", game_mode),
+    }
+}
@@ -0,0 +1,167 @@
+//! An emulated DW3000 which runs the real [`RadioStack`] of a simulated node against the simulated medium.
+
+use ledswarm_protocol::InternalMessage;
+
+use crate::radio::diagnostics::LinkDiagnostics;
+use crate::radio::stack::Reception;
+use crate::radio::tdma::TdmaConfig;
+use crate::radio::{RadioEndpoint, RadioStack};
+use crate::ranging::twr::{self, SPEED_OF_LIGHT_M_S, TIMESTAMP_MASK, UNITS_PER_US};
+use crate::ranging::{RangingConfig, SendAt};
+
+use super::medium::{Delivery, Medium, Rng};
+
+/// The power every radio transmits with in dBm, the regulatory limit of -41.3 dBm/MHz over a 500 MHz channel.
+const TX_POWER_DBM: f32 = -14.3;
+
+/// The free-running 40-bit system time of a DW3000, with its own offset and crystal drift.
+pub struct DwClock {
+    offset: u64,
+    drift_ppm: f64,
+}
+
+impl DwClock {
+    pub fn new(offset: u64, drift_ppm: f64) -> Self {
+        Self {
+            offset: offset & TIMESTAMP_MASK,
+            drift_ppm,
+        }
+    }
+
+    fn units_per_us(&self) -> f64 {
+        UNITS_PER_US * (1.0 + self.drift_ppm * 1e-6)
+    }
+
+    /// The system time at the given simulation time.
+    pub fn at(&self, t_us: f64) -> u64 {
+        twr::add(self.offset, (t_us * self.units_per_us()) as u64)
+    }
+
+    /// The simulation time at which the system time reaches `target`, which must be less than a wrap-around away.
+    pub fn when(&self, now_us: f64, target: u64) -> f64 {
+        now_us + twr::elapsed(self.at(now_us), target) as f64 / self.units_per_us()
+    }
+}
+
+pub struct SimRadio {
+    endpoint: RadioEndpoint,
+    stack: RadioStack,
+    tdma: TdmaConfig,
+    clock: DwClock,
+    /// Delayed transmissions whose start time had already passed, which a real DW3000 would miss as well.
+    pub late_transmissions: u64,
+    /// Whether the radio never syncs to the scrambled timestamp sequence of a frame, like a faulty board.
+    pub sts_broken: bool,
+    /// The actual delay between the antenna and the timestamping of this board in both directions, in time units.
+    antenna_delay: u16,
+}
+
+impl SimRadio {
+    pub fn new(endpoint: RadioEndpoint, ranging: RangingConfig, tdma: TdmaConfig, max_drift_ppm: f64, rng: &mut Rng) -> Self {
+        let drift_ppm = (rng.next_f32() as f64 * 2.0 - 1.0) * max_drift_ppm;
+        let antenna_delay = ranging.tx_antenna_delay;
+
+        Self {
+            endpoint,
+            stack: RadioStack::new(ranging, tdma.clone()),
+            tdma,
+            clock: DwClock::new(rng.next_u64(), drift_ppm),
+            late_transmissions: 0,
+            sts_broken: false,
+            antenna_delay,
+        }
+    }
+
+    /// Let the antenna delay of this board deviate from the nominal one by up to the given number of time units.
+    pub fn randomize_antenna_delay(&mut self, max_error: u16, rng: &mut Rng) {
+        let error = rng.below_or_eq(2 * max_error as u64) as i32 - max_error as i32;
+        self.antenna_delay = (self.antenna_delay as i32 + error) as u16;
+    }
+
+    /// How much faster the crystal of this radio runs than it should, in parts per million.
+    pub fn drift_ppm(&self) -> f64 {
+        self.clock.drift_ppm
+    }
+
+    /// Start over with a fresh stack serving a new controller, like after a power cycle.
+    pub fn reset(&mut self, endpoint: RadioEndpoint) {
+        self.stack = RadioStack::new(self.stack.ranging_config().clone(), self.tdma.clone());
+        self.endpoint = endpoint;
+    }
+
+    /// Apply the controller's commands and put everything the stack wants to send on the air.
+    pub fn transmit(&mut self, now_us: u64, index: usize, medium: &mut Medium) {
+        while let Ok(command) = self.endpoint.commands.try_recv() {
+            self.stack.handle_command(command);
+        }
+
+        let now_us = now_us as f64;
+        let tx_antenna_delay = self.stack.ranging_config().tx_antenna_delay as u64;
+        // The radio adds the configured delay to the timestamp, but the signal leaves after the actual one.
+        let tx_delay_error = self.antenna_delay as i64 - tx_antenna_delay as i64;
+
+        while let Some(transmission) = self.stack.poll_transmit(now_us as u64, self.clock.at(now_us)) {
+            let raw_tx = match transmission.send_at {
+                SendAt::Now => self.clock.at(now_us),
+                // Like the real radio, delayed transmissions start with the lowest 9 bits of the time cleared.
+                SendAt::Delayed(time) => time & !0x1FF,
+            };
+
+            let start_us = self.clock.when(now_us, raw_tx);
+            if matches!(transmission.send_at, SendAt::Delayed(_)) && start_us - now_us > twr::units_to_us(TIMESTAMP_MASK) / 2.0 {
+                self.late_transmissions += 1;
+                continue;
+            }
+
+            // The TX timestamp refers to the signal leaving the antenna, one antenna delay after the digital start.
+            let tx_time = twr::add(raw_tx, tx_antenna_delay);
+            let leave_us = self.clock.when(now_us, twr::add(tx_time, tx_delay_error as u64));
+
+            self.stack.on_transmitted(&transmission, tx_time);
+            medium.transmit(leave_us, index, transmission.payload, transmission.sts, self.stack.profile());
+        }
+
+        // Reliable packets are given up while polling for transmissions.
+        while let Some(event) = self.stack.take_event() {
+            let _ = self.endpoint.events.try_send(event);
+        }
+    }
+
+    /// Hand a frame which arrived at the antenna to the stack, if the radio could make it out.
+    ///
+    /// Every frame arrives along the line of sight, weakened by the free-space path loss to the sender.
+    pub fn receive(&mut self, delivery: Delivery, sender_drift_ppm: f64, msg_tx: &flume::Sender<InternalMessage>) {
+        if delivery.profile != self.stack.profile() {
+            return;
+        }
+
+        // The radio timestamps the frame the actual delay after it arrived, and subtracts the configured one.
+        let rx_delay_error = self.antenna_delay as i64 - self.stack.ranging_config().rx_antenna_delay as i64;
+        let rx_time = twr::add(self.clock.at(delivery.arrival_us), rx_delay_error as u64);
+        let sts_valid = match (delivery.sts, self.stack.sts(rx_time)) {
+            (None, None) => None,
+            (Some(sent), Some(expected)) => Some(sent == expected && !self.sts_broken),
+            // The receiver looks for the header where the other kind of frame has the sequence, so nothing arrives.
+            _ => return,
+        };
+        let carrier_hz = if delivery.profile.channel() == 9 { 7.9872e9 } else { 6.4896e9 };
+        let path_loss_db = 20.0 * (4.0 * std::f32::consts::PI * delivery.distance_m.max(0.1) * carrier_hz / SPEED_OF_LIGHT_M_S as f32).log10();
+        let rx_power_dbm = TX_POWER_DBM - path_loss_db;
+        let diagnostics = LinkDiagnostics::new(rx_power_dbm, rx_power_dbm, (sender_drift_ppm - self.clock.drift_ppm) as f32);
+
+        let reception = Reception {
+            rx_time,
+            sts_valid,
+            diagnostics: Some(diagnostics),
+        };
+        self.stack.on_received(delivery.payload, delivery.arrival_us as u64, reception);
+
+        while let Some(frame) = self.stack.take_frame() {
+            let _ = msg_tx.try_send(InternalMessage::Frame(Box::new(frame)));
+        }
+
+        while let Some(event) = self.stack.take_event() {
+            let _ = self.endpoint.events.try_send(event);
+        }
+    }
+}
@@ -1,6 +1,10 @@
 //! Peripheral controller for the Ultra-Wideband radio
+//!
+//! Only the registers of the DW3000 are handled here, the loop of the radio thread is the
+//! [`Driver`](crate::radio::driver::Driver).
 
 use dw3000_ng::hl::SendTime;
+use dw3000_ng::time::Instant;
 use esp_idf_svc::hal::gpio::{Gpio4, Gpio18, Gpio19, Gpio23, Gpio27, Gpio34};
 use esp_idf_hal::sys::EspError;
 use esp_idf_hal::gpio::{Input, InterruptType, PinDriver};
@@ -8,21 +12,332 @@ use esp_idf_hal::spi::config::{Mode, Phase, Polarity};
 use esp_idf_hal::spi::{config, SpiDeviceDriver, SpiDriver, SpiDriverConfig, SPI3};
 use std::sync::atomic::{AtomicBool, Ordering};
 use esp_idf_svc::hal::prelude::*;
-use std::sync::mpsc::{Receiver, SyncSender};
 use dw3000_ng::{
     configs::{BitRate, Config, PreambleLength, PulseRepetitionFrequency, SfdSequence, StsLen, StsMode, UwbChannel},
+    mac::{Address, PanId, ShortAddress},
+    Ready,
+    SingleBufferReceiving,
     DW3000,
     // block,
 };
 use colored::*;
 
-use ledswarm_protocol::{Frame, InternalMessage};
+use ledswarm_protocol::InternalMessage;
+
+use crate::hal::Clock;
+use crate::hal::esp::EspClock;
+use crate::mesh::BROADCAST_PAN_ID;
+use crate::radio::diagnostics::{LinkDiagnostics, RawDiagnostics};
+use crate::radio::driver::{Driver, IrqEvent, IrqStatus, RadioSettings, Received, Transceiver};
+use crate::radio::profile::RadioProfile;
+use crate::radio::stack::Reception;
+use crate::radio::sts::Sts;
+use crate::radio::tdma::TdmaConfig;
+use crate::radio::{RadioEndpoint, RadioStack};
+use crate::ranging::{RangingConfig, SendAt};
 
 static WAS_INTERRUPT_TRIGGERED: AtomicBool = AtomicBool::new(false);
 
+/// How long to wait between looks at a transmission in progress, in microseconds.
+const TX_POLL_US: u32 = 100;
+
+/// Time after which a transmission which never signalled its end is given up, in microseconds.
+const TX_TIMEOUT_US: u64 = 10_000;
+
+/// The DW3000 on the SPI bus of the board.
+type Radio<State> = DW3000<SpiDeviceDriver<'static, SpiDriver<'static>>, State>;
+
+/// The configuration of the DW3000 for a radio profile.
+fn profile_config(profile: RadioProfile) -> Config {
+    Config {
+        channel: match profile.channel() {
+            9 => UwbChannel::Channel9,
+            _ => UwbChannel::Channel5,
+        },
+        sfd_sequence: SfdSequence::Decawave8,
+        pulse_repetition_frequency: match profile.prf_mhz() {
+            64 => PulseRepetitionFrequency::Mhz64,
+            _ => PulseRepetitionFrequency::Mhz16,
+        },
+        preamble_length: match profile.preamble_symbols() {
+            128 => PreambleLength::Symbols128,
+            256 => PreambleLength::Symbols256,
+            _ => PreambleLength::Symbols1024,
+        },
+        bitrate: BitRate::Kbps6800,
+        // Turned on for the receiver once the controller joined a session, see `rx_config`.
+        frame_filtering: false,
+        ranging_enable: true,
+        // Switched on for the ranging slots of a session, see `RadioStack::sts`.
+        sts_mode: StsMode::StsModeOff,
+        sts_len: StsLen::StsLen64,
+        sfd_timeout: profile.sfd_timeout(),
+    }
+}
+
+/// The receiver configuration, which only lets frames for this controller through once it has an address.
+fn rx_config(settings: &RadioSettings) -> Config {
+    Config {
+        frame_filtering: settings.address.is_some(),
+        ..tx_config(settings)
+    }
+}
+
+/// The transmitter configuration, with a scrambled timestamp sequence after the SFD in ranging slots which use one.
+fn tx_config(settings: &RadioSettings) -> Config {
+    Config {
+        sts_mode: if settings.sts.is_some() { StsMode::StsMode1 } else { StsMode::StsModeOff },
+        ..profile_config(settings.profile)
+    }
+}
+
+/// Load the key and IV of a scrambled timestamp sequence, from which the DW3000 advances the counter with every frame.
+fn load_sts(uwb: &mut Radio<Ready>, sts: &Sts) -> anyhow::Result<()> {
+    let ll = uwb.ll();
+    ll.sts_key()
+        .write(|w| w.value(u128::from_le_bytes(sts.key)))
+        .map_err(|e| anyhow::anyhow!("Failed to set the STS key of the DW3000: {:?}", e))?;
+    ll.sts_iv()
+        .write(|w| w.value(u128::from_le_bytes(sts.iv)))
+        .map_err(|e| anyhow::anyhow!("Failed to set the STS IV of the DW3000: {:?}", e))?;
+    ll.sts_ctrl()
+        .write(|w| w.load_iv(1))
+        .map_err(|e| anyhow::anyhow!("Failed to load the STS IV of the DW3000: {:?}", e))?;
+
+    Ok(())
+}
+
+/// Read the signal quality of the frame which just arrived from the diagnostic registers of the DW3000.
+fn read_diagnostics<State>(uwb: &mut Radio<State>, profile: RadioProfile) -> Option<LinkDiagnostics> {
+    let ll = uwb.ll();
+    let raw = RawDiagnostics {
+        cir_power: ll.ip_diag_1().read().ok()?.ip_carea(),
+        first_path_amplitudes: [
+            ll.ip_diag_2().read().ok()?.ip_fp1m(),
+            ll.ip_diag_3().read().ok()?.ip_fp2m(),
+            ll.ip_diag_4().read().ok()?.ip_fp3m(),
+        ],
+        preamble_count: ll.ip_diag_12().read().ok()?.ip_nacc(),
+        // The register holds 21 bits, with the sign in the highest one.
+        carrier_integrator: ((ll.drx_car_int().read().ok()?.value() << 11) as i32) >> 11,
+    };
+
+    LinkDiagnostics::from_raw(raw, profile)
+}
+
+/// The DW3000 of the board, which keeps its receiver armed between transmissions.
+pub struct Dw3000 {
+    /// The receiving radio, only taken out while it is reconfigured or transmitting.
+    receiving: Option<Radio<SingleBufferReceiving>>,
+    irq: PinDriver<'static, Gpio34, Input>,
+    clock: EspClock,
+    buffer: [u8; 1023],
+    /// The PAN ID and short address the DW3000 filters frames for, once this controller joined a session.
+    address: Option<(u16, u16)>,
+    antenna_delays: (u16, u16),
+    /// The scrambled timestamp sequence the DW3000 was last loaded with, if it currently uses one.
+    sts: Option<Sts>,
+    /// Whether the DW3000 flagged the sequence of the frame which raised the last IRQ as not matching its own.
+    sts_error: bool,
+}
+
+impl Dw3000 {
+    /// Start receiving with the default profile and the given antenna delays for transmission and reception.
+    pub fn new(
+        mut uwb: Radio<Ready>,
+        irq: PinDriver<'static, Gpio34, Input>,
+        antenna_delays: (u16, u16),
+    ) -> anyhow::Result<Self> {
+        let (tx_antenna_delay, rx_antenna_delay) = antenna_delays;
+        uwb.set_antenna_delay(rx_antenna_delay, tx_antenna_delay)
+            .map_err(|e| anyhow::anyhow!("Failed to set antenna delays on the DW3000: {:?}", e))?;
+        uwb.enable_rx_interrupts().map_err(|e| anyhow::anyhow!("Failed to set up RX interrupts on the DW3000: {:?}", e))?;
+        uwb.enable_tx_interrupts().map_err(|e| anyhow::anyhow!("Failed to set up TX interrupts on the DW3000: {:?}", e))?;
+
+        let mut radio = Self {
+            receiving: None,
+            irq,
+            clock: EspClock::new(),
+            buffer: [0; 1023],
+            address: None,
+            antenna_delays,
+            sts: None,
+            sts_error: false,
+        };
+        let settings = RadioSettings {
+            profile: RadioProfile::default(),
+            address: None,
+            antenna_delays,
+            sts: None,
+        };
+        radio.arm(uwb, &settings)?;
+
+        Ok(radio)
+    }
+
+    fn receiving(&mut self) -> anyhow::Result<&mut Radio<SingleBufferReceiving>> {
+        self.receiving.as_mut().ok_or_else(|| anyhow::anyhow!("The DW3000 is not receiving"))
+    }
+
+    fn finish_receiving(&mut self) -> anyhow::Result<Radio<Ready>> {
+        let receiving = self.receiving.take().ok_or_else(|| anyhow::anyhow!("The DW3000 is not receiving"))?;
+        receiving.finish_receiving().map_err(|e| anyhow::anyhow!("Failed to finish receiving: {:?}", e))
+    }
+
+    fn arm(&mut self, uwb: Radio<Ready>, settings: &RadioSettings) -> anyhow::Result<()> {
+        let receiving = uwb.receive(rx_config(settings)).map_err(|e| anyhow::anyhow!("Failed configure receiver: {:?}", e))?;
+        self.receiving = Some(receiving);
+
+        Ok(())
+    }
+
+    fn load_sts(&mut self, uwb: &mut Radio<Ready>, sts: Option<Sts>) -> anyhow::Result<()> {
+        if sts != self.sts {
+            if let Some(sts) = &sts {
+                load_sts(uwb, sts)?;
+            }
+            self.sts = sts;
+        }
+
+        Ok(())
+    }
+}
+
+impl Transceiver for Dw3000 {
+    fn sys_time(&mut self) -> anyhow::Result<u64> {
+        let time = self.receiving()?.sys_time().map_err(|e| anyhow::anyhow!("Failed to read the DW3000 system time: {:?}", e))?;
+        Ok(time.value())
+    }
+
+    fn configure(&mut self, settings: &RadioSettings) -> anyhow::Result<()> {
+        let mut uwb = self.finish_receiving()?;
+
+        if settings.address != self.address {
+            if let Some((pan_id, short_address)) = settings.address {
+                uwb.set_address(PanId(pan_id), ShortAddress(short_address))
+                    .map_err(|e| anyhow::anyhow!("Failed to set the address of the DW3000: {:?}", e))?;
+            }
+            self.address = settings.address;
+        }
+        if settings.antenna_delays != self.antenna_delays {
+            let (tx_antenna_delay, rx_antenna_delay) = settings.antenna_delays;
+            uwb.set_antenna_delay(rx_antenna_delay, tx_antenna_delay)
+                .map_err(|e| anyhow::anyhow!("Failed to set antenna delays on the DW3000: {:?}", e))?;
+            self.antenna_delays = settings.antenna_delays;
+        }
+        self.load_sts(&mut uwb, settings.sts)?;
+
+        self.arm(uwb, settings)
+    }
+
+    fn transmit(&mut self, bytes: &[u8], send_at: SendAt, settings: &RadioSettings) -> anyhow::Result<Option<u64>> {
+        let send_time = match send_at {
+            SendAt::Now => SendTime::Now,
+            SendAt::Delayed(time) => SendTime::Delayed(Instant::new(time).unwrap()),
+        };
+
+        // The DW3000 is half-duplex, so reception pauses for the transmission.
+        let mut uwb = self.finish_receiving()?;
+        self.load_sts(&mut uwb, settings.sts)?;
+
+        let mut sending = uwb
+            .send(bytes, send_time, tx_config(settings))
+            .map_err(|e| anyhow::anyhow!("Failed configure transmitter: {:?}", e))?;
+
+        // The end of the transmission raises the IRQ as well, so only look at the radio once it did.
+        let send_started_us = self.clock.now_us();
+        let tx_time = loop {
+            if WAS_INTERRUPT_TRIGGERED.swap(false, Ordering::AcqRel) {
+                self.irq.enable_interrupt()?;
+            } else if self.clock.now_us() - send_started_us < TX_TIMEOUT_US {
+                self.clock.delay_us(TX_POLL_US);
+                continue;
+            }
+
+            match sending.s_wait() {
+                Ok(tx_time) => break Some(tx_time.value()),
+                Err(nb::Error::WouldBlock) if self.clock.now_us() - send_started_us < TX_TIMEOUT_US => continue,
+                Err(nb::Error::WouldBlock) => {
+                    // A delayed transmission whose start time already passed never goes out.
+                    println!("## {}  Packet was never sent, giving up", "[uwb]".bright_blue().bold());
+                    break None;
+                },
+                Err(nb::Error::Other(e)) => {
+                    println!("## {}  Failed to send packet: {:?}", "[uwb]".bright_blue().bold(), e);
+                    break None;
+                },
+            }
+        };
+
+        let uwb = sending.finish_sending().map_err(|e| anyhow::anyhow!("Failed to finish sending: {:?}", e))?;
+        self.arm(uwb, settings)?;
+
+        Ok(tx_time)
+    }
+
+    fn poll_irq(&mut self) -> anyhow::Result<Option<IrqStatus>> {
+        if !WAS_INTERRUPT_TRIGGERED.swap(false, Ordering::AcqRel) {
+            return Ok(None);
+        }
+        // Re-enable the interrupt as it is disabled every time it is triggered
+        self.irq.enable_interrupt()?;
+
+        let status = self.receiving()?
+            .ll()
+            .sys_status()
+            .read()
+            .map_err(|e| anyhow::anyhow!("Failed to read the DW3000 status: {:?}", e))?;
+        self.sts_error = status.cperr() != 0;
+
+        Ok(Some(IrqStatus {
+            rx_good: status.rxfcg() != 0,
+            rx_error: status.rxphe() != 0 || status.rxfce() != 0 || status.rxfsl() != 0 || status.rxovrr() != 0,
+            rx_timeout: status.rxpto() != 0 || status.rxsto() != 0 || status.rxfto() != 0,
+            tx_done: status.txfrs() != 0,
+        }))
+    }
+
+    fn receive(&mut self, event: IrqEvent, settings: &RadioSettings) -> anyhow::Result<Option<Received>> {
+        let receiving = self.receiving.as_mut().ok_or_else(|| anyhow::anyhow!("The DW3000 is not receiving"))?;
+        let diagnostics = if event == IrqEvent::RxGood { read_diagnostics(receiving, settings.profile) } else { None };
+
+        // Reading the frame or the error clears the status and ends the reception.
+        let received = match receiving.r_wait(&mut self.buffer) {
+            Ok(m) => {
+                let pan_id = match m.frame.header.destination {
+                    Some(Address::Short(pan_id, _)) | Some(Address::Extended(pan_id, _)) => pan_id.0,
+                    None => BROADCAST_PAN_ID,
+                };
+                // The DW3000 flags a sequence which doesn't match the one it generated itself.
+                let reception = Reception {
+                    rx_time: m.rx_time.value(),
+                    sts_valid: settings.sts.map(|_| !self.sts_error),
+                    diagnostics,
+                };
+                m.frame.payload().map(|bytes| Received {
+                    pan_id,
+                    bytes: bytes.to_vec(),
+                    reception,
+                })
+            },
+            Err(nb::Error::WouldBlock) => return Ok(None),
+            Err(nb::Error::Other(e)) => {
+                println!("## {}  Reception failed ({:?}): {:?}", "[uwb]".bright_blue().bold(), event, e);
+                None
+            },
+        };
+
+        // Re-arm the receiver right away so the next frame is not missed.
+        let uwb = self.finish_receiving()?;
+        self.arm(uwb, settings)?;
+
+        Ok(received)
+    }
+}
+
 fn gpio_int_callback() {
-    // Assert FLAG indicating a press button happened
-    WAS_INTERRUPT_TRIGGERED.store(true, Ordering::Relaxed);
+    // Assert FLAG indicating that the DW3000 raised its IRQ line
+    WAS_INTERRUPT_TRIGGERED.store(true, Ordering::Release);
 }
 
 fn initialize_dw3000_interrupts(irq: Gpio34) -> PinDriver<'static, Gpio34, Input> {
@@ -53,7 +368,7 @@ fn reset_dw3000(rst: Gpio27) -> Result<(), EspError> {
 /// Initialize the onboard ultra-wideband radio.
 pub fn start(
     tx:         flume::Sender<InternalMessage>,
-    packet_rx:  flume::Receiver<Frame>,
+    endpoint:   RadioEndpoint,
     spi:        SPI3,
     serial_out: Gpio23,
     serial_in:  Gpio19,
@@ -62,8 +377,6 @@ pub fn start(
     irq:        Gpio34,
     rst:        Gpio27,
 ) -> Result<(), EspError> {
-    let delay = esp_idf_hal::delay::Delay::new_default();
-
     let config = config::Config::new()
         .baudrate(5.MHz().into())
         .data_mode(Mode {
@@ -82,107 +395,32 @@ pub fn start(
     let spi_device = SpiDeviceDriver::new(driver, Some(cs), &config)?;
     // println!("\n\n--------->   SPI initialized\n\n");
 
-    let mut dw3000_irq = initialize_dw3000_interrupts(irq);
+    let dw3000_irq = initialize_dw3000_interrupts(irq);
     let rst_result = reset_dw3000(rst);
 
-    let dw3000_config = Config {
-        channel: UwbChannel::Channel5,
-        sfd_sequence: SfdSequence::Decawave8,
-        pulse_repetition_frequency: PulseRepetitionFrequency::Mhz16,
-        preamble_length: PreambleLength::Symbols1024,
-        bitrate: BitRate::Kbps6800,
-        frame_filtering: false,
-        ranging_enable: true,
-        sts_mode: StsMode::StsModeOff,
-        sts_len: StsLen::StsLen64,
-        sfd_timeout: 129,
-    };
+    // The radio starts on the default profile, the controller picks its own once the driver runs.
+    let dw3000_config = profile_config(RadioProfile::default());
     let dw3000 = DW3000::new(spi_device)
 		.init()
 		.expect("Failed DWM3000 init.");
     let dw_res = dw3000.config(dw3000_config);
 
-    
+
     match dw_res {
-        Ok(mut uwb) => {
+        Ok(uwb) => {
             println!("--------->   🎉  DWM3000 initialized");
 
-            uwb.enable_rx_interrupts().expect("Failed to set up RX interrupts on the DW3000");
-
-            loop {
-                // See if there any packets to be sent
-                if let Ok(packet) = packet_rx.try_recv() {
-                    println!("## {}  Sending packet", "[uwb]".bright_blue().bold());
-                    let packet_bytes = Vec::from(packet.clone());
-                    // Initiate Sending
-                    let mut sending = uwb
-                        .send(&packet_bytes[0 .. packet_bytes.len() - 4], SendTime::Now, Config::default())
-                        .expect("Failed configure transmitter");
-
-                    let send_result;
-
-                    loop {
-                        println!("Waiting to send frame");
-                        if let Ok(t) = sending.s_wait() {
-                            send_result = t;
-                            break;
-                        } else {
-                            delay.delay_ms(1);
-                        }
-                    }
-            
-                    println!("Last frame sent at {}", send_result.value());
-                    uwb = sending.finish_sending().expect("Failed to finish sending");
-                    // println!("## {}  Sent packet {:#?}", "[uwb]".bright_blue().bold(), packet);
-                }
-
-                // Initiate Reception
-                let mut buffer = [0; 1023];
-                let mut receiving = uwb
-                    .receive(Config::default())
-                    .expect("Failed configure receiver.");
-        
-                // Waiting for an incoming frame
-                if WAS_INTERRUPT_TRIGGERED.load(Ordering::Relaxed) {
-                    println!("Got RX interrupt");
-                    // Reset global flag
-                    WAS_INTERRUPT_TRIGGERED.store(false, Ordering::Relaxed);
-                    // Re-enable the interrupt as it is disabled every time it is triggered
-                    dw3000_irq.enable_interrupt().unwrap();
-
-                    let mut i = 0;
-
-                    while i < 10 {
-                        if let Ok(m) = receiving.r_wait(&mut buffer) {
-                            println!("Got RX interrupt message");
-                            let payload = m.frame.payload();
-
-                            if let Some(bytes) = payload {
-                                if let Ok(frame) = Frame::try_from(bytes.to_vec()) {
-                                    // println!("## {}  Received packet: {:?}", "[uwb]".bright_blue().bold(), frame);
-                                    tx.send(InternalMessage::Frame(Box::new(frame))).unwrap();
-                                } else {
-                                    println!("Failed to parse UWB packet, skipping");
-                                }
-                            }
-                            break;
-                        } else {
-                            delay.delay_ms(1);
-                        }
-
-                        i += 1;
-                    }
-
-                } else {
-                    delay.delay_ms(1);
-                }
-
-                // This must always execute at the end.
-                uwb = receiving.finish_receiving().expect("Failed to finish receiving");
-            }
+            let stack = RadioStack::new(RangingConfig::default(), TdmaConfig::default());
+            let ranging = stack.ranging_config();
+            let radio = Dw3000::new(uwb, dw3000_irq, (ranging.tx_antenna_delay, ranging.rx_antenna_delay))
+                .expect("Failed to set up the DW3000");
+
+            Driver::new(radio, stack, endpoint, tx, Box::new(EspClock::new()))
+                .and_then(|mut driver| driver.run())
+                .expect("The DW3000 failed");
         },
         Err(e) => println!("--------->  DW3000 config error: {:?}", e),
     }
 
     Ok(())
-}
\ No newline at end of file
+}